@@ -0,0 +1,40 @@
+; hello.s - Print a greeting and report a result through the debug console
+; Assemble with: ../bin/cl65 -t none -C emu.cfg -o hello.bin hello.s
+; Run with:      mos6502 --machine console.toml hello.bin
+
+CONSOLE_OUT  = $7FF0        ; write: print a character
+CONSOLE_EXIT = $7FF2        ; write: stop the emulator with this exit code
+
+.segment "CODE"
+
+reset:
+    ldx #$00
+print:
+    lda message,x
+    beq count       ; Stop at the terminating zero
+    sta CONSOLE_OUT
+    inx
+    bne print
+
+count:
+    lda #$00        ; Count to 10 like count.s
+loop:
+    clc
+    adc #$01
+    cmp #$0A
+    bne loop
+    sta CONSOLE_EXIT ; Exit code 10 tells the caller the loop finished
+halt:
+    jmp halt
+
+message:
+    .byte "Hello from the 6502!", $0A, $00
+
+nmi:
+irq:
+    rti
+
+.segment "VECTORS"
+    .word nmi       ; NMI vector ($FFFA)
+    .word reset     ; Reset vector ($FFFC)
+    .word irq       ; IRQ/BRK vector ($FFFE)
@@ -0,0 +1,882 @@
+//! Command-line parsing
+//!
+//! Every subcommand is described by an entry in `COMMANDS`. Arguments are
+//! checked against it, so unknown options, missing or malformed values and
+//! stray arguments are reported instead of ignored, and the `--help` text is
+//! generated from the same table.
+//!
+//! Options take their value as `--delay 20` or `--delay=20`. Addresses are
+//! resolved with `SymbolTable::resolve`: `$C000`, `0xC000`, `%1010`, decimal,
+//! labels from `--symbols` and `label+offset`.
+//!
+//! A first argument that isn't a command but looks like a file or an option
+//! runs `run`, so `mos6502 rom.bin --delay 20` still works.
+
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use mos6502::SymbolTable;
+use mos6502::machines;
+use mos6502::symbols::parse_number;
+
+/// Program name in usage lines
+const PROGRAM: &str = "mos6502";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Debug,
+    Disasm,
+    Asm,
+    Test,
+    Profile,
+    TraceDiff,
+}
+
+/// One `--option`
+#[derive(Debug)]
+pub struct Opt {
+    pub name: &'static str,
+    /// Single-letter form, as in `-o`
+    pub short: Option<char>,
+    /// Placeholder for the value; `None` for flags
+    pub value: Option<&'static str>,
+    pub help: &'static str,
+}
+
+impl Opt {
+    const fn short(self, short: char) -> Self {
+        Self {
+            short: Some(short),
+            ..self
+        }
+    }
+}
+
+/// A subcommand's name, arguments and options
+#[derive(Debug)]
+pub struct Spec {
+    pub command: Command,
+    pub name: &'static str,
+    pub about: &'static str,
+    /// Positional arguments and whether each is required
+    pub arguments: &'static [(&'static str, bool)],
+    pub options: &'static [Opt],
+    /// Extra paragraphs for `--help`
+    pub notes: &'static str,
+}
+
+const fn option(name: &'static str, value: &'static str, help: &'static str) -> Opt {
+    Opt {
+        name,
+        short: None,
+        value: Some(value),
+        help,
+    }
+}
+
+const fn flag(name: &'static str, help: &'static str) -> Opt {
+    Opt {
+        name,
+        short: None,
+        value: None,
+        help,
+    }
+}
+
+const MACHINE: Opt = option(
+    "machine",
+    "board.toml|preset",
+    "Machine description file or built-in preset",
+);
+const ROM: Opt = option("rom", "rom.bin", "ROM image, loaded so it ends at $FFFF");
+const SERIAL: Opt = option(
+    "serial",
+    "stdio|pty|none",
+    "Connect the machine's serial ports to this backend",
+);
+const SYMBOLS: Opt = option("symbols", "file", "Load labels for addresses (repeatable)");
+const BREAK: Opt = option(
+    "break",
+    "addr",
+    "Stop when the PC reaches addr (repeatable)",
+);
+const MAX_CYCLES: Opt = option("max-cycles", "n", "Stop after n cycles");
+const EXIT_WRITE: Opt = option(
+    "exit-write",
+    "addr",
+    "A write to addr ends the run with the byte as the exit code",
+);
+const TRACE: Opt = option(
+    "trace",
+    "file",
+    "Write a nestest-style line per instruction",
+);
+
+pub const COMMANDS: [Spec; 7] = [
+    Spec {
+        command: Command::Run,
+        name: "run",
+        about: "Run a program with the register display",
+        arguments: &[("rom.bin", false)],
+        options: &[
+            MACHINE,
+            ROM,
+            SERIAL,
+            option("delay", "ms", "Pause between instructions (default 150)"),
+            option(
+                "clock",
+                "freq",
+                "Run in real time at this clock, e.g. 1.0227MHz (default: the machine's)",
+            ),
+            option(
+                "speed",
+                "factor|turbo",
+                "Scale the clock, e.g. 0.01 for slow motion, or run flat out",
+            ),
+            option(
+                "max",
+                "instructions",
+                "Stop after this many instructions (default 10000 with --delay pacing)",
+            ),
+            SYMBOLS,
+            BREAK,
+            flag(
+                "tui",
+                "Full screen with disassembly, memory and stack panes",
+            ),
+            flag(
+                "headless",
+                "No display; print the final state as JSON and exit with why it stopped",
+            ),
+            MAX_CYCLES,
+            EXIT_WRITE,
+            TRACE,
+        ],
+        notes: "\
+Without --machine the ROM must be a 32KB image ($8000-$FFFF) with the reset
+vector at $FFFC. Machines with a serial port on stdio run without the
+register display at their clock rate; Ctrl-C stops them. --clock or --speed
+runs the register display and --tui in real time instead of --delay.
+--serial pty frees the terminal by giving the serial port a pseudo-terminal
+of its own. --max-cycles and --exit-write need --headless, and --trace can't be used
+with --tui.",
+    },
+    Spec {
+        command: Command::Debug,
+        name: "debug",
+        about: "Stop at reset and read debugger commands",
+        arguments: &[("rom.bin", false)],
+        options: &[MACHINE, ROM, SERIAL, SYMBOLS, BREAK],
+        notes: "Type \"help\" at the prompt for the list of commands.",
+    },
+    Spec {
+        command: Command::Disasm,
+        name: "disasm",
+        about: "Disassemble a ROM as the machine maps it",
+        arguments: &[("rom.bin", false)],
+        options: &[
+            MACHINE,
+            ROM,
+            SYMBOLS,
+            option("start", "addr", "First address (default: the reset vector)"),
+            option("count", "n", "Number of instructions (default 20)"),
+            option("end", "addr", "Disassemble up to addr instead of --count"),
+        ],
+        notes: "",
+    },
+    Spec {
+        command: Command::Asm,
+        name: "asm",
+        about: "Assemble a source file into a ROM image",
+        arguments: &[("source.s", true)],
+        options: &[
+            option(
+                "output",
+                "rom.bin",
+                "Output file (default: the source with .bin)",
+            )
+            .short('o'),
+            option(
+                "size",
+                "bytes",
+                "Image size, ending at $FFFF (default 32768)",
+            ),
+            option("fill", "byte", "Value of unused bytes (default $00)"),
+            option(
+                "labels",
+                "file",
+                "Also write labels as NAME = $ADDR for --symbols",
+            ),
+        ],
+        notes: "\
+Understands the ca65 syntax used in examples/: labels, NAME = value,
+.segment \"CODE\"/\"VECTORS\", .org, .byte, .word and .res.",
+    },
+    Spec {
+        command: Command::Test,
+        name: "test",
+        about: "Run a test ROM headless and report whether it passed",
+        arguments: &[("rom.bin", false)],
+        options: &[
+            MACHINE,
+            ROM,
+            SYMBOLS,
+            option("success", "addr", "Reaching addr means the test passed"),
+            EXIT_WRITE,
+            option("max", "instructions", "Fail after this many instructions"),
+            MAX_CYCLES,
+            TRACE,
+            flag("json", "Print the final state as JSON"),
+            option(
+                "debug-info",
+                "file.dbg",
+                "ld65 debug info (--dbgfile): labels and source lines",
+            ),
+            option(
+                "coverage",
+                "file",
+                "Write the ROM as a listing annotated with coverage",
+            ),
+            option(
+                "lcov",
+                "file",
+                "Write line coverage in lcov format (needs --debug-info)",
+            ),
+            option(
+                "coverage-map",
+                "file.bmp",
+                "Write a 256x256 image of the address space",
+            ),
+        ],
+        notes: "\
+The test passes if the PC reaches the --success address or the program
+exits with code 0; without --success, BRK also passes. It fails on a trap
+(an instruction that jumps to itself while no interrupt can arrive), a
+JAM, a non-zero exit code or a limit. Exits with 0 on a pass and 1 on a
+failure.
+
+Any of the coverage outputs also prints a summary of the ROM bytes that
+were executed, read as data, written or left untouched.",
+    },
+    Spec {
+        command: Command::Profile,
+        name: "profile",
+        about: "Run a program headless and report where the cycles went",
+        arguments: &[("rom.bin", false)],
+        options: &[
+            MACHINE,
+            ROM,
+            SYMBOLS,
+            BREAK,
+            option(
+                "max",
+                "instructions",
+                "Stop after this many instructions (default 10000000)",
+            ),
+            MAX_CYCLES,
+            EXIT_WRITE,
+            option("top", "n", "Rows in each table (default 20)"),
+            option(
+                "output",
+                "file",
+                "Write the report to file instead of stdout",
+            ),
+            option(
+                "folded",
+                "file",
+                "Also write folded call stacks for flame graph tools",
+            ),
+        ],
+        notes: "\
+The run stops like `test` does: at BRK, a JAM, a trap, an exit or a limit.
+The report lists the instructions that took the most cycles, with the
+cycles lost to page crossings and taken branches, then the subroutines by
+cycles including their callees (Total) and excluding them (Self), and any
+places where the program's stack handling threw off the call tracking.",
+    },
+    Spec {
+        command: Command::TraceDiff,
+        name: "trace-diff",
+        about: "Report the first difference between two instruction traces",
+        arguments: &[("ours.log", true), ("reference.log", true)],
+        options: &[
+            option(
+                "context",
+                "lines",
+                "Matching lines to show before the difference (default 5)",
+            ),
+            flag("no-cycles", "Don't compare cycle counts"),
+        ],
+        notes: "Exits with 0 if the logs agree, 1 if they differ and 2 on errors.",
+    },
+];
+
+/// Look up a command by name
+pub fn spec(name: &str) -> Option<&'static Spec> {
+    COMMANDS.iter().find(|spec| spec.name == name)
+}
+
+/// A command line that didn't fit its command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    UnknownCommand(String),
+    UnknownOption {
+        command: &'static str,
+        option: String,
+    },
+    MissingValue {
+        command: &'static str,
+        option: &'static str,
+    },
+    UnexpectedValue {
+        command: &'static str,
+        option: &'static str,
+    },
+    InvalidValue {
+        command: &'static str,
+        option: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+    MissingArgument {
+        command: &'static str,
+        name: &'static str,
+    },
+    UnexpectedArgument {
+        command: &'static str,
+        argument: String,
+    },
+    /// Options that can't be combined, or other misuse
+    Usage {
+        command: &'static str,
+        message: String,
+    },
+}
+
+impl Error {
+    /// The command whose `--help` would explain the mistake
+    pub fn command(&self) -> Option<&'static str> {
+        match self {
+            Error::UnknownCommand(_) => None,
+            Error::UnknownOption { command, .. }
+            | Error::MissingValue { command, .. }
+            | Error::UnexpectedValue { command, .. }
+            | Error::InvalidValue { command, .. }
+            | Error::MissingArgument { command, .. }
+            | Error::UnexpectedArgument { command, .. }
+            | Error::Usage { command, .. } => Some(command),
+        }
+    }
+
+    /// A line suggesting what to do instead
+    pub fn hint(&self) -> String {
+        match (self, self.command()) {
+            (Error::UnknownOption { option, .. }, _) if option == "--debug" => {
+                format!("The debugger is now a command: {PROGRAM} debug <rom.bin>")
+            }
+            (_, Some(command)) => format!("Run '{PROGRAM} {command} --help' for usage."),
+            (_, None) => format!("Run '{PROGRAM} --help' for the list of commands."),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::UnknownCommand(name) => write!(f, "unknown command '{name}'"),
+            Error::UnknownOption { command, option } => {
+                write!(f, "unknown option '{option}' for '{command}'")
+            }
+            Error::MissingValue { option, .. } => write!(f, "--{option} needs a value"),
+            Error::UnexpectedValue { option, .. } => {
+                write!(f, "--{option} doesn't take a value")
+            }
+            Error::InvalidValue {
+                option,
+                value,
+                expected,
+                ..
+            } => write!(
+                f,
+                "invalid value '{value}' for --{option}: expected {expected}"
+            ),
+            Error::MissingArgument { command, name } => {
+                write!(f, "'{command}' needs <{name}>")
+            }
+            Error::UnexpectedArgument { argument, .. } => {
+                write!(f, "unexpected argument '{argument}'")
+            }
+            Error::Usage { message, .. } => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// What the command line asked for
+#[derive(Debug)]
+pub enum Invocation {
+    /// Print this and exit
+    Help(String),
+    Run(&'static Spec, Matches),
+}
+
+/// The options and arguments given to a command
+#[derive(Debug)]
+pub struct Matches {
+    command: &'static str,
+    options: Vec<(&'static str, Option<String>)>,
+    arguments: Vec<String>,
+}
+
+impl Matches {
+    /// Whether a flag or option was given
+    pub fn flag(&self, name: &str) -> bool {
+        self.options.iter().any(|(option, _)| *option == name)
+    }
+
+    /// The last value given for an option
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.values(name).last()
+    }
+
+    /// Every value given for a repeatable option
+    pub fn values(&self, name: &str) -> impl DoubleEndedIterator<Item = &str> {
+        self.options
+            .iter()
+            .filter(move |(option, _)| *option == name)
+            .filter_map(|(_, value)| value.as_deref())
+    }
+
+    /// The positional argument at `index`
+    pub fn argument(&self, index: usize) -> Option<&str> {
+        self.arguments.get(index).map(String::as_str)
+    }
+
+    /// A decimal number option
+    pub fn number<T: FromStr>(&self, name: &'static str) -> Result<Option<T>, Error> {
+        self.value(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| self.invalid(name, value, "a whole number"))
+            })
+            .transpose()
+    }
+
+    /// An option read by `parse`, which returns `None` for bad input
+    pub fn parsed<T>(
+        &self,
+        name: &'static str,
+        parse: impl Fn(&str) -> Option<T>,
+        expected: &'static str,
+    ) -> Result<Option<T>, Error> {
+        self.value(name)
+            .map(|value| parse(value).ok_or_else(|| self.invalid(name, value, expected)))
+            .transpose()
+    }
+
+    /// A byte value: `$FF`, `0xFF`, `%1010` or decimal
+    pub fn byte(&self, name: &'static str) -> Result<Option<u8>, Error> {
+        self.value(name)
+            .map(|value| {
+                parse_number(value)
+                    .and_then(|n| u8::try_from(n).ok())
+                    .ok_or_else(|| self.invalid(name, value, "a byte ($00-$FF)"))
+            })
+            .transpose()
+    }
+
+    /// An address option, which may name a symbol
+    pub fn address(&self, name: &'static str, symbols: &SymbolTable) -> Result<Option<u16>, Error> {
+        self.value(name)
+            .map(|value| self.resolve(name, value, symbols))
+            .transpose()
+    }
+
+    /// Every address given for a repeatable option
+    pub fn addresses(&self, name: &'static str, symbols: &SymbolTable) -> Result<Vec<u16>, Error> {
+        self.values(name)
+            .map(|value| self.resolve(name, value, symbols))
+            .collect()
+    }
+
+    /// Fail unless at most one of `names` was given
+    pub fn exclusive(&self, names: &[&str]) -> Result<(), Error> {
+        let given: Vec<&str> = names.iter().copied().filter(|n| self.flag(n)).collect();
+        match given[..] {
+            [first, second, ..] => {
+                Err(self.usage(format!("--{first} and --{second} can't be used together")))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fail if `name` was given without `needs`
+    pub fn requires(&self, name: &str, needs: &str) -> Result<(), Error> {
+        if self.flag(name) && !self.flag(needs) {
+            return Err(self.usage(format!("--{name} only works with --{needs}")));
+        }
+        Ok(())
+    }
+
+    /// An error about this command's usage
+    pub fn usage(&self, message: String) -> Error {
+        Error::Usage {
+            command: self.command,
+            message,
+        }
+    }
+
+    fn resolve(
+        &self,
+        name: &'static str,
+        value: &str,
+        symbols: &SymbolTable,
+    ) -> Result<u16, Error> {
+        symbols.resolve(value).ok_or_else(|| {
+            self.invalid(
+                name,
+                value,
+                "an address ($C000, 0xC000, 49152) or a known symbol",
+            )
+        })
+    }
+
+    fn invalid(&self, option: &'static str, value: &str, expected: &'static str) -> Error {
+        Error::InvalidValue {
+            command: self.command,
+            option,
+            value: value.to_string(),
+            expected,
+        }
+    }
+}
+
+/// Parse the arguments after the program name
+pub fn parse(args: &[String]) -> Result<Invocation, Error> {
+    let Some(first) = args.first() else {
+        return Ok(Invocation::Help(help()));
+    };
+    match first.as_str() {
+        "-h" | "--help" => Ok(Invocation::Help(help())),
+        "help" => match args.get(1) {
+            Some(name) => spec(name)
+                .map(|spec| Invocation::Help(command_help(spec)))
+                .ok_or_else(|| Error::UnknownCommand(name.clone())),
+            None => Ok(Invocation::Help(help())),
+        },
+        name => match spec(name) {
+            Some(spec) => parse_command(spec, &args[1..]),
+            // `mos6502 rom.bin ...` and `mos6502 --machine apple1` mean `run`
+            None if name.starts_with('-') || looks_like_path(name) => {
+                parse_command(&COMMANDS[0], args)
+            }
+            None => Err(Error::UnknownCommand(name.to_string())),
+        },
+    }
+}
+
+fn looks_like_path(arg: &str) -> bool {
+    arg.contains(['.', '/', '\\']) || Path::new(arg).exists()
+}
+
+fn parse_command(spec: &'static Spec, args: &[String]) -> Result<Invocation, Error> {
+    let mut matches = Matches {
+        command: spec.name,
+        options: Vec::new(),
+        arguments: Vec::new(),
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Ok(Invocation::Help(command_help(spec)));
+        }
+        let Some(long) = arg.strip_prefix("--") else {
+            if arg.len() > 1 && arg.starts_with('-') {
+                let mut letters = arg[1..].chars();
+                let short = match (letters.next(), letters.next()) {
+                    (Some(letter), None) => spec
+                        .options
+                        .iter()
+                        .find(|option| option.short == Some(letter)),
+                    _ => None,
+                };
+                let Some(option) = short else {
+                    return Err(Error::UnknownOption {
+                        command: spec.name,
+                        option: arg.clone(),
+                    });
+                };
+                let value = option_value(spec, option, None, &mut args)?;
+                matches.options.push((option.name, value));
+                continue;
+            }
+            matches.arguments.push(arg.clone());
+            continue;
+        };
+
+        let (name, inline) = match long.split_once('=') {
+            Some((name, value)) => (name, Some(value.to_string())),
+            None => (long, None),
+        };
+        let Some(option) = spec.options.iter().find(|option| option.name == name) else {
+            return Err(Error::UnknownOption {
+                command: spec.name,
+                option: format!("--{name}"),
+            });
+        };
+        let value = option_value(spec, option, inline, &mut args)?;
+        matches.options.push((option.name, value));
+    }
+
+    if let Some(argument) = matches.arguments.get(spec.arguments.len()) {
+        return Err(Error::UnexpectedArgument {
+            command: spec.name,
+            argument: argument.clone(),
+        });
+    }
+    if let Some(&(name, _)) = spec
+        .arguments
+        .iter()
+        .skip(matches.arguments.len())
+        .find(|(_, required)| *required)
+    {
+        return Err(Error::MissingArgument {
+            command: spec.name,
+            name,
+        });
+    }
+    Ok(Invocation::Run(spec, matches))
+}
+
+/// The value of `option`, given inline (`--name=value`) or as the next
+/// argument
+fn option_value(
+    spec: &Spec,
+    option: &Opt,
+    inline: Option<String>,
+    args: &mut std::slice::Iter<String>,
+) -> Result<Option<String>, Error> {
+    Ok(match (option.value, inline) {
+        (None, None) => None,
+        (None, Some(_)) => {
+            return Err(Error::UnexpectedValue {
+                command: spec.name,
+                option: option.name,
+            });
+        }
+        (Some(_), Some(value)) => Some(value),
+        (Some(_), None) => match args.next() {
+            Some(value) if !value.starts_with("--") => Some(value.clone()),
+            _ => {
+                return Err(Error::MissingValue {
+                    command: spec.name,
+                    option: option.name,
+                });
+            }
+        },
+    })
+}
+
+/// The list of commands
+pub fn help() -> String {
+    let mut text = format!(
+        "MOS 6502 Emulator\n\n\
+         Usage: {PROGRAM} <command> [options]\n       \
+         {PROGRAM} <rom.bin> [options]      (same as run)\n\nCommands:\n"
+    );
+    for spec in &COMMANDS {
+        text.push_str(&format!("  {:<12}{}\n", spec.name, spec.about));
+    }
+    text.push_str(&format!(
+        "\nBuilt-in machines: {}\n\
+         Run '{PROGRAM} <command> --help' for a command's options.\n",
+        machines::PRESETS.join(", ")
+    ));
+    text
+}
+
+/// `--help` for one command
+pub fn command_help(spec: &Spec) -> String {
+    let mut usage = format!("{PROGRAM} {}", spec.name);
+    for &(name, required) in spec.arguments {
+        if required {
+            usage.push_str(&format!(" <{name}>"));
+        } else {
+            usage.push_str(&format!(" [{name}]"));
+        }
+    }
+    let mut text = format!("{}\n\nUsage: {usage} [options]\n\nOptions:\n", spec.about);
+
+    let rows: Vec<(String, &str)> = spec
+        .options
+        .iter()
+        .map(|option| {
+            let mut name = match option.short {
+                Some(short) => format!("-{short}, --{}", option.name),
+                None => format!("--{}", option.name),
+            };
+            if let Some(value) = option.value {
+                name.push_str(&format!(" <{value}>"));
+            }
+            (name, option.help)
+        })
+        .chain([(String::from("-h, --help"), "Show this help")])
+        .collect();
+    let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, help) in rows {
+        text.push_str(&format!("  {name:<width$}  {help}\n"));
+    }
+    if !spec.notes.is_empty() {
+        text.push_str(&format!("\n{}\n", spec.notes));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn run(line: &str) -> Result<(Command, Matches), Error> {
+        match parse(&args(line))? {
+            Invocation::Run(spec, matches) => Ok((spec.command, matches)),
+            Invocation::Help(_) => panic!("unexpected help for {line:?}"),
+        }
+    }
+
+    #[test]
+    fn test_commands_and_implicit_run() {
+        let (command, matches) = run("debug rom.bin --break loop --break $8005").unwrap();
+        assert_eq!(command, Command::Debug);
+        assert_eq!(matches.argument(0), Some("rom.bin"));
+        assert_eq!(
+            matches.values("break").collect::<Vec<_>>(),
+            ["loop", "$8005"]
+        );
+
+        let (command, matches) = run("examples/count.bin --delay=20 --tui").unwrap();
+        assert_eq!(command, Command::Run);
+        assert_eq!(matches.number::<u64>("delay"), Ok(Some(20)));
+        assert!(matches.flag("tui") && !matches.flag("headless"));
+
+        let (command, matches) = run("--machine apple1").unwrap();
+        assert_eq!(command, Command::Run);
+        assert_eq!(matches.value("machine"), Some("apple1"));
+
+        assert_eq!(
+            run("dissasm rom.bin").unwrap_err(),
+            Error::UnknownCommand(String::from("dissasm"))
+        );
+    }
+
+    #[test]
+    fn test_values() {
+        let symbols = SymbolTable::parse("loop = $8002\n").unwrap();
+        let (_, matches) =
+            run("test rom.bin --success loop+3 --exit-write 0x6000 --max 100 --max-cycles abc")
+                .unwrap();
+        assert_eq!(matches.address("success", &symbols), Ok(Some(0x8005)));
+        assert_eq!(matches.address("exit-write", &symbols), Ok(Some(0x6000)));
+        assert_eq!(matches.address("trace", &symbols), Ok(None));
+        assert_eq!(matches.number::<u32>("max"), Ok(Some(100)));
+        assert_eq!(
+            matches.number::<u64>("max-cycles").unwrap_err().to_string(),
+            "invalid value 'abc' for --max-cycles: expected a whole number"
+        );
+
+        let (_, matches) = run("run --break nowhere").unwrap();
+        assert_eq!(
+            matches
+                .addresses("break", &symbols)
+                .unwrap_err()
+                .to_string(),
+            "invalid value 'nowhere' for --break: expected an address ($C000, 0xC000, 49152) or a known symbol"
+        );
+
+        let (_, matches) = run("asm a.s --fill $EA --size 256 -o a.rom").unwrap();
+        assert_eq!(matches.byte("fill"), Ok(Some(0xEA)));
+        assert_eq!(matches.value("output"), Some("a.rom"));
+        assert!(matches.byte("size").is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |line: &str| run(line).unwrap_err().to_string();
+        assert_eq!(
+            error("run rom.bin --dealy 5"),
+            "unknown option '--dealy' for 'run'"
+        );
+        assert_eq!(error("run rom.bin --delay"), "--delay needs a value");
+        assert_eq!(
+            error("run rom.bin --symbols --tui"),
+            "--symbols needs a value"
+        );
+        assert_eq!(error("run rom.bin --tui=yes"), "--tui doesn't take a value");
+        assert_eq!(error("run a.bin b.bin"), "unexpected argument 'b.bin'");
+        assert_eq!(
+            error("trace-diff ours.log"),
+            "'trace-diff' needs <reference.log>"
+        );
+        assert_eq!(error("asm"), "'asm' needs <source.s>");
+        assert_eq!(error("asm a.s -o"), "--output needs a value");
+        assert_eq!(error("asm a.s -out"), "unknown option '-out' for 'asm'");
+        assert_eq!(error("run a.bin -o x"), "unknown option '-o' for 'run'");
+
+        let debug = run("rom.bin --debug").unwrap_err();
+        assert_eq!(
+            debug.hint(),
+            "The debugger is now a command: mos6502 debug <rom.bin>"
+        );
+        assert_eq!(
+            run("run -x").unwrap_err().hint(),
+            "Run 'mos6502 run --help' for usage."
+        );
+
+        let (_, matches) = run("run --tui --headless").unwrap();
+        assert_eq!(
+            matches
+                .exclusive(&["tui", "headless"])
+                .unwrap_err()
+                .to_string(),
+            "--tui and --headless can't be used together"
+        );
+        assert_eq!(matches.requires("max-cycles", "headless"), Ok(()));
+    }
+
+    #[test]
+    fn test_help() {
+        let Ok(Invocation::Help(text)) = parse(&args("disasm --help")) else {
+            panic!("expected help");
+        };
+        assert!(text.starts_with("Disassemble a ROM as the machine maps it\n\nUsage: mos6502 disasm [rom.bin] [options]\n"));
+        assert!(text.contains(
+            "\n  --start <addr>                 First address (default: the reset vector)\n"
+        ));
+        assert!(text.contains("\n  -h, --help                     Show this help\n"));
+
+        let Ok(Invocation::Help(text)) = parse(&[]) else {
+            panic!("expected help");
+        };
+        assert!(text.contains(
+            "\n  trace-diff  Report the first difference between two instruction traces\n"
+        ));
+        assert!(
+            matches!(parse(&args("help asm")), Ok(Invocation::Help(text)) if text.contains("<source.s>"))
+        );
+        assert!(
+            matches!(parse(&args("asm -h")), Ok(Invocation::Help(text)) if text.contains("\n  -o, --output <rom.bin>  "))
+        );
+        assert_eq!(
+            parse(&args("help nope")).unwrap_err(),
+            Error::UnknownCommand(String::from("nope"))
+        );
+    }
+}
@@ -0,0 +1,870 @@
+//! Interactive debugger (`mos6502 debug`)
+//!
+//! The machine's bus is wrapped in a `TracingBus` so watchpoints can see
+//! every data access. Memory is inspected with `MappedBus::peek`, so dumps
+//! and disassembly never disturb device registers.
+//!
+//! Addresses and bytes can be given as numbers or as labels from
+//! `--symbols`, including `label+offset`. As in a machine-code monitor, bare
+//! numbers are hex (`C000`); `$C000`, `0xC000` and `%1010` work too. A label
+//! made of hex digits (`add`, `beef`) wins over the number. Offsets after a
+//! label and counts (`step 10`, `delete 2`) are decimal, as labels are shown
+//! (`loop+4`). An empty line repeats `step`, `next`, `memory` and
+//! `disassemble`.
+
+use std::fmt;
+use std::io::{self, Write, stdout};
+
+use mos6502::bus::{AccessKind, HostRequest};
+use mos6502::bus_trace::{Access, AccessType, TracingBus};
+use mos6502::disasm::{disassemble, disassemble_range};
+use mos6502::status::Flag;
+use mos6502::{Bus, Cpu, MappedBus, SymbolTable};
+
+use super::prompt::Prompt;
+use super::terminal;
+
+pub type DebugCpu = Cpu<TracingBus<MappedBus>>;
+
+const HELP: &str = "\
+step [n]            s    execute n instructions (default 1)
+next                n    step, running a JSR's subroutine until it returns
+continue            c    run until a breakpoint, watchpoint or Ctrl-C
+until [addr]        u    run to addr, or until the PC passes this instruction
+break [addr]        b    set a breakpoint; with no address, list them
+watch addr [r|w|rw] w    stop on reads and/or writes (default w) of addr
+delete [n]          d    delete breakpoint/watchpoint n, or all of them
+registers           r    show the registers and the next instruction
+set reg value            set A, X, Y, SP, PC, P or a flag (N V D I Z C)
+memory [addr] [len] m    dump memory (64 bytes by default)
+edit addr byte...   e    write bytes to memory (ROM included)
+disassemble [addr] [n]   disassemble n instructions (default 10) from addr
+reset                    reset the CPU and devices
+history                  list earlier commands
+quit                q    leave the debugger
+
+Addresses, bytes and lengths are hex (C000, $C000) or labels; offsets after
+a label (loop+4) and counts are decimal.";
+
+/// What the front end should do after a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    /// Read another command
+    Prompt,
+    /// Leave the debugger
+    Quit,
+    /// The program asked to exit with this code
+    Exit(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Watch {
+    Read,
+    Write,
+    Access,
+}
+
+impl Watch {
+    fn matches(self, access_type: AccessType) -> bool {
+        match self {
+            Watch::Read => access_type == AccessType::Read,
+            Watch::Write => access_type == AccessType::Write,
+            Watch::Access => true,
+        }
+    }
+}
+
+impl fmt::Display for Watch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Watch::Read => write!(f, "r"),
+            Watch::Write => write!(f, "w"),
+            Watch::Access => write!(f, "rw"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Point {
+    Break(u16),
+    Watch(u16, Watch),
+}
+
+/// Commands an empty line repeats
+#[derive(Debug, Clone, Copy)]
+enum Repeat {
+    Step(u64),
+    Next,
+    Memory,
+    Disassemble,
+}
+
+/// Where a run should stop besides breakpoints
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Anywhere,
+    Address(u16),
+    /// A subroutine called from this stack level has returned here
+    Return {
+        address: u16,
+        sp: u8,
+    },
+    /// The PC moved beyond `pc` without leaving the subroutine
+    Past {
+        pc: u16,
+        sp: u8,
+    },
+}
+
+impl Target {
+    fn reached(self, cpu: &DebugCpu) -> bool {
+        match self {
+            Target::Anywhere => false,
+            Target::Address(address) => cpu.pc == address,
+            Target::Return { address, sp } => cpu.pc == address && cpu.sp >= sp,
+            Target::Past { pc, sp } => cpu.pc > pc && cpu.sp >= sp,
+        }
+    }
+}
+
+enum Stop {
+    Steps,
+    Reached,
+    Breakpoint(usize),
+    Watchpoint(usize, Access),
+    Exit(u8),
+    Interrupted,
+    Halted,
+}
+
+enum Error {
+    Io(io::Error),
+    Command(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+type CommandResult = Result<Flow, Error>;
+
+fn usage(message: &str) -> Error {
+    Error::Command(message.to_string())
+}
+
+/// Debugger state: breakpoints, watchpoints and where the last dump ended
+pub struct Debugger {
+    symbols: SymbolTable,
+    points: Vec<(usize, Point)>,
+    next_id: usize,
+    repeat: Option<Repeat>,
+    memory_cursor: u16,
+    disassembly_cursor: u16,
+    instructions: u64,
+}
+
+impl Debugger {
+    pub fn new(symbols: SymbolTable) -> Self {
+        Self {
+            symbols,
+            points: Vec::new(),
+            next_id: 1,
+            repeat: None,
+            memory_cursor: 0,
+            disassembly_cursor: 0,
+            instructions: 0,
+        }
+    }
+
+    /// Add a breakpoint and return its number
+    pub fn add_breakpoint(&mut self, address: u16) -> usize {
+        self.add_point(Point::Break(address))
+    }
+
+    /// Run one command line, writing its output to `out`
+    pub fn execute(
+        &mut self,
+        cpu: &mut DebugCpu,
+        line: &str,
+        out: &mut impl Write,
+    ) -> io::Result<Flow> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words.split_first() {
+            Some((command, args)) => {
+                self.repeat = None;
+                self.command(cpu, command, args, out)
+            }
+            None => match self.repeat {
+                Some(Repeat::Step(count)) => self.step(cpu, count, out),
+                Some(Repeat::Next) => self.next(cpu, out),
+                Some(Repeat::Memory) => self.memory(cpu, &[], out),
+                Some(Repeat::Disassemble) => {
+                    let address = self.disassembly_cursor;
+                    self.disassemble(cpu, address, 10, out)
+                }
+                None => Ok(Flow::Prompt),
+            },
+        };
+
+        match result {
+            Ok(flow) => Ok(flow),
+            Err(Error::Command(message)) => {
+                writeln!(out, "{message}")?;
+                Ok(Flow::Prompt)
+            }
+            Err(Error::Io(e)) => Err(e),
+        }
+    }
+
+    fn command(
+        &mut self,
+        cpu: &mut DebugCpu,
+        command: &str,
+        args: &[&str],
+        out: &mut impl Write,
+    ) -> CommandResult {
+        match command {
+            "step" | "s" => {
+                let count = match args.first() {
+                    Some(text) => text.parse().map_err(|_| usage("usage: step [count]"))?,
+                    None => 1,
+                };
+                self.repeat = Some(Repeat::Step(count));
+                self.step(cpu, count, out)
+            }
+            "next" | "n" => {
+                self.repeat = Some(Repeat::Next);
+                self.next(cpu, out)
+            }
+            "continue" | "c" => self.run(cpu, None, Target::Anywhere, out),
+            "until" | "u" => {
+                let target = match args.first() {
+                    Some(text) => Target::Address(self.address(text)?),
+                    None => Target::Past {
+                        pc: cpu.pc,
+                        sp: cpu.sp,
+                    },
+                };
+                self.run(cpu, None, target, out)
+            }
+            "break" | "b" => match args.first() {
+                Some(text) => {
+                    let address = self.address(text)?;
+                    let id = self.add_breakpoint(address);
+                    writeln!(
+                        out,
+                        "Breakpoint {id} at {}",
+                        self.symbols.describe(address, 0x100)
+                    )?;
+                    Ok(Flow::Prompt)
+                }
+                None => self.list_points(out),
+            },
+            "watch" | "w" => {
+                let (Some(text), mode) = (args.first(), args.get(1)) else {
+                    return self.list_points(out);
+                };
+                let address = self.address(text)?;
+                let watch = match mode.copied() {
+                    None | Some("w") => Watch::Write,
+                    Some("r") => Watch::Read,
+                    Some("rw") => Watch::Access,
+                    Some(_) => return Err(usage("usage: watch addr [r|w|rw]")),
+                };
+                let id = self.add_point(Point::Watch(address, watch));
+                writeln!(
+                    out,
+                    "Watchpoint {id} ({watch}) at {}",
+                    self.symbols.describe(address, 0x100)
+                )?;
+                Ok(Flow::Prompt)
+            }
+            "delete" | "d" => {
+                match args.first() {
+                    Some(text) => {
+                        let id: usize = text.parse().map_err(|_| usage("usage: delete [n]"))?;
+                        let before = self.points.len();
+                        self.points.retain(|(point_id, _)| *point_id != id);
+                        if self.points.len() == before {
+                            return Err(Error::Command(format!(
+                                "No breakpoint or watchpoint {id}"
+                            )));
+                        }
+                    }
+                    None => self.points.clear(),
+                }
+                Ok(Flow::Prompt)
+            }
+            "registers" | "r" => {
+                writeln!(out, "{}", self.registers(cpu))?;
+                writeln!(out, "{}", self.location(cpu))?;
+                Ok(Flow::Prompt)
+            }
+            "set" => {
+                let [register, value] = args else {
+                    return Err(usage("usage: set reg value"));
+                };
+                self.set(cpu, register, value)
+            }
+            "memory" | "m" => {
+                self.repeat = Some(Repeat::Memory);
+                self.memory(cpu, args, out)
+            }
+            "edit" | "e" => {
+                let Some((start, bytes)) = args.split_first().filter(|(_, b)| !b.is_empty()) else {
+                    return Err(usage("usage: edit addr byte..."));
+                };
+                let start = self.address(start)?;
+                let values = bytes
+                    .iter()
+                    .map(|text| self.byte(text))
+                    .collect::<Result<Vec<u8>, Error>>()?;
+                let bus = cpu.bus.inner_mut();
+                for (offset, &value) in values.iter().enumerate() {
+                    let address = start.wrapping_add(offset as u16);
+                    // RAM and ROM are patched directly, devices take a write
+                    if bus.peek(address).is_some() {
+                        bus.load(address, &[value]);
+                    } else {
+                        bus.write(address, value);
+                    }
+                }
+                Ok(Flow::Prompt)
+            }
+            "disassemble" | "dis" => {
+                let address = match args.first() {
+                    Some(text) => self.address(text)?,
+                    None => cpu.pc,
+                };
+                let count = match args.get(1) {
+                    Some(text) => text
+                        .parse()
+                        .map_err(|_| usage("usage: disassemble [addr] [count]"))?,
+                    None => 10,
+                };
+                self.repeat = Some(Repeat::Disassemble);
+                self.disassemble(cpu, address, count, out)
+            }
+            "reset" => {
+                cpu.reset();
+                writeln!(out, "{}", self.location(cpu))?;
+                Ok(Flow::Prompt)
+            }
+            "help" | "h" | "?" => {
+                writeln!(out, "{HELP}")?;
+                Ok(Flow::Prompt)
+            }
+            "quit" | "q" => Ok(Flow::Quit),
+            other => Err(Error::Command(format!(
+                "Unknown command \"{other}\"; try \"help\""
+            ))),
+        }
+    }
+
+    fn add_point(&mut self, point: Point) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.points.push((id, point));
+        id
+    }
+
+    /// An address, byte or length: a label, `label+offset`, or a number
+    /// that is hex unless prefixed otherwise
+    fn address(&self, text: &str) -> Result<u16, Error> {
+        let bare = !text.is_empty() && text.chars().all(|c| c.is_ascii_hexdigit());
+        let resolved = match self.symbols.address_of(text) {
+            Some(address) => Some(address),
+            None if bare => self.symbols.resolve(&format!("${text}")),
+            None => self.symbols.resolve(text),
+        };
+        resolved.ok_or_else(|| Error::Command(format!("Unknown address or symbol \"{text}\"")))
+    }
+
+    fn byte(&self, text: &str) -> Result<u8, Error> {
+        let value = self.address(text)?;
+        u8::try_from(value).map_err(|_| Error::Command(format!("{text} doesn't fit in a byte")))
+    }
+
+    // ========== Running ==========
+
+    fn step(&mut self, cpu: &mut DebugCpu, count: u64, out: &mut impl Write) -> CommandResult {
+        self.run(cpu, Some(count), Target::Anywhere, out)
+    }
+
+    fn next(&mut self, cpu: &mut DebugCpu, out: &mut impl Write) -> CommandResult {
+        const JSR: u8 = 0x20;
+        if cpu.bus.inner().peek(cpu.pc) == Some(JSR) {
+            let target = Target::Return {
+                address: cpu.pc.wrapping_add(3),
+                sp: cpu.sp,
+            };
+            self.run(cpu, None, target, out)
+        } else {
+            self.step(cpu, 1, out)
+        }
+    }
+
+    /// Execute instructions until `limit` runs out, `target` is reached or
+    /// something else stops the program. Breakpoints at the starting PC are
+    /// ignored so `continue` can leave one.
+    fn run(
+        &mut self,
+        cpu: &mut DebugCpu,
+        limit: Option<u64>,
+        target: Target,
+        out: &mut impl Write,
+    ) -> CommandResult {
+        // A Ctrl-C typed at the prompt shouldn't stop this run
+        terminal::interrupted();
+        cpu.bus.clear();
+
+        let mut count = 0;
+        let stop = loop {
+            if count > 0 {
+                if limit.is_some_and(|limit| count >= limit) {
+                    break Stop::Steps;
+                }
+                if let Some(id) = self.breakpoint_at(cpu.pc) {
+                    break Stop::Breakpoint(id);
+                }
+                if target.reached(cpu) {
+                    break Stop::Reached;
+                }
+            }
+            if cpu.halted {
+                break Stop::Halted;
+            }
+            if terminal::interrupted() {
+                break Stop::Interrupted;
+            }
+
+            cpu.execute_instruction();
+            count += 1;
+            self.instructions += 1;
+
+            let hit = self.watchpoint_hit(cpu.bus.accesses());
+            cpu.bus.clear();
+            if let Some(stop) = hit {
+                break stop;
+            }
+
+            match cpu.bus.host_request() {
+                Some(HostRequest::Exit(code)) => break Stop::Exit(code),
+                Some(HostRequest::DumpRegisters) => writeln!(out, "{}", self.registers(cpu))?,
+                Some(HostRequest::Reset) => cpu.reset(),
+                None => {}
+            }
+        };
+
+        match stop {
+            Stop::Steps | Stop::Reached => {}
+            Stop::Breakpoint(id) => writeln!(
+                out,
+                "Breakpoint {id}, {}",
+                self.symbols.describe(cpu.pc, 0x100)
+            )?,
+            Stop::Watchpoint(id, access) => {
+                let direction = match access.access_type {
+                    AccessType::Read => "read",
+                    AccessType::Write => "write",
+                };
+                writeln!(
+                    out,
+                    "Watchpoint {id}: {direction} {} = ${:02X}",
+                    self.symbols.describe(access.address, 0x100),
+                    access.value
+                )?;
+            }
+            Stop::Exit(code) => {
+                writeln!(out, "Program exited with code {code}")?;
+                return Ok(Flow::Exit(code));
+            }
+            Stop::Interrupted => writeln!(out, "Interrupted")?,
+            Stop::Halted => writeln!(out, "CPU halted")?,
+        }
+        writeln!(out, "{}", self.location(cpu))?;
+        Ok(Flow::Prompt)
+    }
+
+    fn breakpoint_at(&self, pc: u16) -> Option<usize> {
+        self.points.iter().find_map(|(id, point)| match point {
+            Point::Break(address) if *address == pc => Some(*id),
+            _ => None,
+        })
+    }
+
+    /// The first watched data access. Opcode and operand fetches don't count.
+    fn watchpoint_hit(&self, accesses: &[Access]) -> Option<Stop> {
+        accesses
+            .iter()
+            .filter(|access| !matches!(access.kind, AccessKind::OpcodeFetch | AccessKind::Operand))
+            .find_map(|access| {
+                self.points.iter().find_map(|(id, point)| match point {
+                    Point::Watch(address, watch)
+                        if *address == access.address && watch.matches(access.access_type) =>
+                    {
+                        Some(Stop::Watchpoint(*id, *access))
+                    }
+                    _ => None,
+                })
+            })
+    }
+
+    // ========== Inspection ==========
+
+    fn list_points(&self, out: &mut impl Write) -> CommandResult {
+        if self.points.is_empty() {
+            writeln!(out, "No breakpoints or watchpoints")?;
+        }
+        for (id, point) in &self.points {
+            match point {
+                Point::Break(address) => writeln!(
+                    out,
+                    "{id:3}  break       ${address:04X}  {}",
+                    self.symbols.describe(*address, 0x100)
+                )?,
+                Point::Watch(address, watch) => writeln!(
+                    out,
+                    "{id:3}  watch ({watch:<2})  ${address:04X}  {}",
+                    self.symbols.describe(*address, 0x100)
+                )?,
+            }
+        }
+        Ok(Flow::Prompt)
+    }
+
+    fn registers(&self, cpu: &DebugCpu) -> String {
+        let p = cpu.status.to_byte();
+        let flags: String = "NV-BDIZC"
+            .chars()
+            .enumerate()
+            .map(|(i, flag)| {
+                if p & (0x80 >> i) != 0 {
+                    flag
+                } else {
+                    flag.to_ascii_lowercase()
+                }
+            })
+            .collect();
+        format!(
+            "PC=${:04X} A=${:02X} X=${:02X} Y=${:02X} SP=${:02X} P=${p:02X} {flags}  ({} instructions)",
+            cpu.pc, cpu.a, cpu.x, cpu.y, cpu.sp, self.instructions
+        )
+    }
+
+    /// The next instruction, under its label if it has one
+    fn location(&self, cpu: &DebugCpu) -> String {
+        let bus = cpu.bus.inner();
+        let line = disassemble(cpu.pc, |a| bus.peek(a).unwrap_or(0xFF), &self.symbols);
+        match self.symbols.name_of(cpu.pc) {
+            Some(name) => format!("{name}:\n{line}"),
+            None => line.to_string(),
+        }
+    }
+
+    fn set(&mut self, cpu: &mut DebugCpu, register: &str, value: &str) -> CommandResult {
+        let flag = match register.to_ascii_lowercase().as_str() {
+            "a" => {
+                cpu.a = self.byte(value)?;
+                return Ok(Flow::Prompt);
+            }
+            "x" => {
+                cpu.x = self.byte(value)?;
+                return Ok(Flow::Prompt);
+            }
+            "y" => {
+                cpu.y = self.byte(value)?;
+                return Ok(Flow::Prompt);
+            }
+            "sp" => {
+                cpu.sp = self.byte(value)?;
+                return Ok(Flow::Prompt);
+            }
+            "p" => {
+                cpu.status.from_byte(self.byte(value)?);
+                return Ok(Flow::Prompt);
+            }
+            "pc" => {
+                cpu.pc = self.address(value)?;
+                return Ok(Flow::Prompt);
+            }
+            "n" => Flag::Negative,
+            "v" => Flag::Overflow,
+            "d" => Flag::DecimalMode,
+            "i" => Flag::InterruptDisable,
+            "z" => Flag::Zero,
+            "c" => Flag::Carry,
+            _ => {
+                return Err(Error::Command(format!(
+                    "Unknown register \"{register}\"; use A, X, Y, SP, PC, P or N, V, D, I, Z, C"
+                )));
+            }
+        };
+        match value {
+            "0" => cpu.status.set(flag, false),
+            "1" => cpu.status.set(flag, true),
+            _ => return Err(usage("Flags are set to 0 or 1")),
+        }
+        Ok(Flow::Prompt)
+    }
+
+    /// Hex and ASCII dump, 16 bytes a row. Device registers and unmapped
+    /// addresses show as `--` since reading them could change them.
+    fn memory(&mut self, cpu: &DebugCpu, args: &[&str], out: &mut impl Write) -> CommandResult {
+        let start = match args.first() {
+            Some(text) => self.address(text)?,
+            None => self.memory_cursor,
+        };
+        let length: u16 = match args.get(1) {
+            Some(text) => self.address(text)?.max(1),
+            None => 64,
+        };
+
+        let bus = cpu.bus.inner();
+        let mut offset = 0;
+        while offset < length {
+            let row = start.wrapping_add(offset);
+            let count = (length - offset).min(16);
+            let bytes: Vec<Option<u8>> =
+                (0..count).map(|i| bus.peek(row.wrapping_add(i))).collect();
+
+            let hex: Vec<String> = bytes
+                .iter()
+                .map(|byte| byte.map_or(String::from("--"), |b| format!("{b:02X}")))
+                .collect();
+            let text: String = bytes
+                .iter()
+                .map(|byte| match byte {
+                    Some(b) if b.is_ascii_graphic() || *b == b' ' => *b as char,
+                    _ => '.',
+                })
+                .collect();
+            writeln!(out, "${row:04X}  {:<47}  {text}", hex.join(" "))?;
+            offset += count;
+        }
+        self.memory_cursor = start.wrapping_add(length);
+        Ok(Flow::Prompt)
+    }
+
+    fn disassemble(
+        &mut self,
+        cpu: &DebugCpu,
+        address: u16,
+        count: usize,
+        out: &mut impl Write,
+    ) -> CommandResult {
+        let bus = cpu.bus.inner();
+        let lines = disassemble_range(
+            address,
+            count,
+            |a| bus.peek(a).unwrap_or(0xFF),
+            &self.symbols,
+        );
+        for line in &lines {
+            if let Some(name) = self.symbols.name_of(line.address) {
+                writeln!(out, "{name}:")?;
+            }
+            let marker = if line.address == cpu.pc { ">" } else { " " };
+            writeln!(out, "{marker} {line}")?;
+        }
+        if let Some(last) = lines.last() {
+            self.disassembly_cursor = last.next_address();
+        }
+        Ok(Flow::Prompt)
+    }
+}
+
+/// Interactive session on the terminal. Returns the exit code if the
+/// program asked to exit through a console device.
+pub fn run(
+    cpu: &mut DebugCpu,
+    symbols: SymbolTable,
+    breakpoints: &[u16],
+) -> io::Result<Option<u8>> {
+    terminal::catch_interrupt();
+
+    let mut debugger = Debugger::new(symbols);
+    for &address in breakpoints {
+        debugger.add_breakpoint(address);
+    }
+
+    let mut prompt = Prompt::new();
+    let mut out = stdout();
+    writeln!(out, "Type \"help\" for a list of commands.")?;
+    writeln!(out, "{}", debugger.location(cpu))?;
+
+    while let Some(line) = prompt.read_line("(mos6502) ")? {
+        if line.trim() == "history" {
+            for (number, earlier) in prompt.history().iter().enumerate() {
+                writeln!(out, "{:4}  {earlier}", number + 1)?;
+            }
+            continue;
+        }
+        match debugger.execute(cpu, &line, &mut out)? {
+            Flow::Prompt => {}
+            Flow::Quit => break,
+            Flow::Exit(code) => return Ok(Some(code)),
+        }
+        out.flush()?;
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mos6502::MemoryMapBuilder;
+    use mos6502::devices::Console;
+    use mos6502::devices::acia6551::NullBackend;
+
+    #[rustfmt::skip]
+    const PROGRAM: &[u8] = &[
+        0xA2, 0x00,             // main: ldx #0
+        0x20, 0x10, 0xF0,       // loop: jsr count
+        0xE8,                   //       inx
+        0xE0, 0x03,             //       cpx #3
+        0xD0, 0xF8,             //       bne loop
+        0x4C, 0x0A, 0xF0,       // end:  jmp end
+        0xEA, 0xEA, 0xEA,
+        0xE6, 0x10,             // count: inc $10
+        0xA5, 0x10,             //        lda $10
+        0x9D, 0x00, 0x02,       //        sta $0200,x
+        0x60,                   //        rts
+    ];
+
+    const SYMBOLS: &str = "main = $F000\nloop = $F002\nend = $F00A\ncount = $F010\n";
+
+    fn setup(program: &[u8]) -> (Debugger, DebugCpu) {
+        let mut rom = vec![0xEA; 0x1000];
+        rom[..program.len()].copy_from_slice(program);
+        rom[0xFFC..0xFFE].copy_from_slice(&[0x00, 0xF0]);
+
+        let bus = MemoryMapBuilder::new()
+            .ram(0x0000, 0x1000)
+            .device(0x6000, 4, Console::new(NullBackend))
+            .rom(0xF000, 0x1000, &rom)
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::new(TracingBus::new(bus));
+        cpu.reset();
+
+        let symbols = SymbolTable::parse(SYMBOLS).unwrap();
+        (Debugger::new(symbols), cpu)
+    }
+
+    fn execute(debugger: &mut Debugger, cpu: &mut DebugCpu, line: &str) -> (Flow, String) {
+        let mut out = Vec::new();
+        let flow = debugger.execute(cpu, line, &mut out).unwrap();
+        (flow, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_step_and_next() {
+        let (mut debugger, mut cpu) = setup(PROGRAM);
+
+        let (_, output) = execute(&mut debugger, &mut cpu, "step");
+        assert_eq!(output, "loop:\n$F002  20 10 F0  JSR count\n");
+
+        execute(&mut debugger, &mut cpu, "next");
+        assert_eq!(cpu.pc, 0xF005);
+        assert_eq!(cpu.bus.inner().peek(0x10), Some(1));
+
+        // An empty line repeats the step
+        execute(&mut debugger, &mut cpu, "s 3");
+        execute(&mut debugger, &mut cpu, "");
+        assert_eq!(cpu.pc, 0xF014);
+    }
+
+    #[test]
+    fn test_breakpoints_and_until() {
+        let (mut debugger, mut cpu) = setup(PROGRAM);
+
+        let (_, output) = execute(&mut debugger, &mut cpu, "break count");
+        assert_eq!(output, "Breakpoint 1 at count\n");
+        let (_, output) = execute(&mut debugger, &mut cpu, "c");
+        assert!(output.starts_with("Breakpoint 1, count\ncount:\n"));
+        execute(&mut debugger, &mut cpu, "continue");
+        assert_eq!(cpu.bus.inner().peek(0x10), Some(1));
+
+        execute(&mut debugger, &mut cpu, "delete 1");
+        let (_, output) = execute(&mut debugger, &mut cpu, "b");
+        assert_eq!(output, "No breakpoints or watchpoints\n");
+        execute(&mut debugger, &mut cpu, "until end");
+        assert_eq!(cpu.pc, 0xF00A);
+        assert_eq!(cpu.bus.inner().peek(0x10), Some(3));
+    }
+
+    #[test]
+    fn test_until_leaves_loop() {
+        let (mut debugger, mut cpu) = setup(PROGRAM);
+        execute(&mut debugger, &mut cpu, "until $F008");
+        execute(&mut debugger, &mut cpu, "until");
+        assert_eq!((cpu.pc, cpu.x), (0xF00A, 3));
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let (mut debugger, mut cpu) = setup(PROGRAM);
+
+        execute(&mut debugger, &mut cpu, "watch $0201");
+        execute(&mut debugger, &mut cpu, "watch $10 r");
+        let (_, output) = execute(&mut debugger, &mut cpu, "continue");
+        assert!(output.starts_with("Watchpoint 2: read $0010 = $00\n"));
+
+        execute(&mut debugger, &mut cpu, "delete 2");
+        let (_, output) = execute(&mut debugger, &mut cpu, "c");
+        assert!(output.starts_with("Watchpoint 1: write $0201 = $02\n"));
+        assert_eq!(cpu.pc, 0xF017);
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let (mut debugger, mut cpu) = setup(PROGRAM);
+
+        execute(&mut debugger, &mut cpu, "set a $42");
+        execute(&mut debugger, &mut cpu, "set c 1");
+        execute(&mut debugger, &mut cpu, "set pc count");
+        let (_, output) = execute(&mut debugger, &mut cpu, "r");
+        assert!(output.starts_with("PC=$F010 A=$42 X=$00 Y=$00 SP=$FD P=$35 nv-BdIzC"));
+
+        let (_, output) = execute(&mut debugger, &mut cpu, "set a 256");
+        assert_eq!(output, "256 doesn't fit in a byte\n");
+        let (_, output) = execute(&mut debugger, &mut cpu, "set q 1");
+        assert!(output.starts_with("Unknown register"));
+
+        execute(&mut debugger, &mut cpu, "edit $0300 $48 $69");
+        let (_, output) = execute(&mut debugger, &mut cpu, "m $0300 2");
+        assert_eq!(output, format!("$0300  {:<47}  Hi\n", "48 69"));
+        let (_, output) = execute(&mut debugger, &mut cpu, "memory $6000 1");
+        assert!(output.starts_with("$6000  --"));
+
+        // Bare numbers are hex
+        execute(&mut debugger, &mut cpu, "edit 301 2A");
+        let (_, output) = execute(&mut debugger, &mut cpu, "m 0300 3");
+        assert_eq!(output, format!("$0300  {:<47}  H*.\n", "48 2A 00"));
+        let (_, output) = execute(&mut debugger, &mut cpu, "b count+10");
+        assert!(output.starts_with("Breakpoint 1 at count+10\n"));
+        let (_, output) = execute(&mut debugger, &mut cpu, "b 800A");
+        assert!(output.starts_with("Breakpoint 2 at $800A\n"));
+
+        let (_, output) = execute(&mut debugger, &mut cpu, "dis loop 2");
+        assert_eq!(
+            output,
+            "loop:\n  $F002  20 10 F0  JSR count\n  $F005  E8        INX\n"
+        );
+        let (_, output) = execute(&mut debugger, &mut cpu, "");
+        assert!(output.starts_with("  $F006  E0 03     CPX #$03\n"));
+    }
+
+    #[test]
+    fn test_program_exit() {
+        // lda #5, sta $6002
+        let (mut debugger, mut cpu) = setup(&[0xA9, 0x05, 0x8D, 0x02, 0x60]);
+        let (flow, output) = execute(&mut debugger, &mut cpu, "c");
+        assert_eq!(flow, Flow::Exit(5));
+        assert_eq!(output, "Program exited with code 5\n");
+        assert_eq!(execute(&mut debugger, &mut cpu, "q").0, Flow::Quit);
+    }
+}
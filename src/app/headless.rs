@@ -0,0 +1,448 @@
+//! Batch runs without a display (`--headless`)
+//!
+//! The program runs flat out until it stops itself or hits a limit. The
+//! final CPU state is printed as one line of JSON and the exit status says
+//! why the run ended, so CI can tell a ROM that passed from one that jammed
+//! or hung:
+//!
+//! | Status | Reason                                                |
+//! |--------|-------------------------------------------------------|
+//! | 0      | BRK, or the PC reached a `--break` address            |
+//! | 1      | an instruction jumped to itself (`test` only)         |
+//! | n      | the program exited with code n (console or `--exit-write`) |
+//! | 124    | the instruction or cycle limit ran out                |
+//! | 125    | the CPU executed a JAM opcode                         |
+//!
+//! A program can exit with any code, so 1, 124 and 125 are ambiguous on
+//! their own; the `reason` and `exit_code` fields of the report say which
+//! it was.
+
+use std::cell::Cell;
+use std::io;
+use std::rc::Rc;
+
+use mos6502::bus::HostRequest;
+use mos6502::bus_trace::{AccessType, TracingBus};
+use mos6502::memory_map::MappedBus;
+use mos6502::profile::{Profiler, Sample};
+use mos6502::status::Flag;
+use mos6502::trace::{TraceEntry, TraceSink};
+use mos6502::{Bus, Cpu, SymbolTable};
+
+pub type HeadlessCpu = Cpu<TracingBus<MappedBus>>;
+
+const BRK: u8 = 0x00;
+
+/// Exit status when a limit stops the run, as with `timeout(1)`
+pub const LIMIT_STATUS: u8 = 124;
+/// Exit status when the CPU jams
+pub const JAM_STATUS: u8 = 125;
+
+/// What ends a run besides the program itself
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub max_instructions: Option<u64>,
+    pub max_cycles: Option<u64>,
+    /// Stop when the PC reaches one of these
+    pub stop_at: Vec<u16>,
+    /// A write here ends the run with the byte written as the exit code
+    pub exit_write: Option<u16>,
+    /// Stop when an instruction jumps to itself, as test ROMs do on failure,
+    /// unless an interrupt could still get the program out of the loop
+    pub traps: bool,
+}
+
+/// Why a run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// The next instruction is BRK
+    Brk,
+    /// The CPU executed a JAM opcode
+    Jam,
+    /// The PC reached a stop address
+    Address,
+    /// The program exited with a code
+    Exit(u8),
+    /// An instruction jumped to itself
+    Trap,
+    InstructionLimit,
+    CycleLimit,
+}
+
+impl Stop {
+    pub fn name(self) -> &'static str {
+        match self {
+            Stop::Brk => "brk",
+            Stop::Jam => "jam",
+            Stop::Address => "address",
+            Stop::Exit(_) => "exit",
+            Stop::Trap => "trap",
+            Stop::InstructionLimit => "instruction_limit",
+            Stop::CycleLimit => "cycle_limit",
+        }
+    }
+
+    /// Process exit status for the run. A program's own exit code is passed
+    /// through, so it can coincide with the other statuses.
+    pub fn status(self) -> u8 {
+        match self {
+            Stop::Brk | Stop::Address => 0,
+            Stop::Exit(code) => code,
+            Stop::Trap => 1,
+            Stop::InstructionLimit | Stop::CycleLimit => LIMIT_STATUS,
+            Stop::Jam => JAM_STATUS,
+        }
+    }
+}
+
+/// How a run ended and how long it took
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outcome {
+    pub stop: Stop,
+    pub instructions: u64,
+    /// Including the 7 reset cycles
+    pub cycles: u64,
+}
+
+/// Run a freshly reset CPU until it stops, recording each instruction in
+/// `trace` and `profiler` if given
+pub fn run(
+    cpu: &mut HeadlessCpu,
+    limits: &Limits,
+    mut trace: Option<&mut dyn TraceSink>,
+    mut profiler: Option<&mut Profiler>,
+) -> io::Result<Outcome> {
+    cpu.bus.set_recording(false);
+    let exit = Rc::new(Cell::new(None));
+    if let Some(address) = limits.exit_write {
+        let exit = Rc::clone(&exit);
+        cpu.bus.add_hook(move |access| {
+            if access.address == address && access.access_type == AccessType::Write {
+                exit.set(Some(access.value));
+            }
+        });
+    }
+
+    let mut cycles = 0;
+    let mut instructions = 0;
+    let stop = loop {
+        // Reset cycles, then one instruction (or interrupt entry) per pass
+        while cpu.cycles > 0 {
+            cpu.step();
+            cycles += 1;
+        }
+
+        if cpu.halted {
+            break Stop::Jam;
+        }
+        if limits.stop_at.contains(&cpu.pc) {
+            break Stop::Address;
+        }
+        if cpu.bus.inner().peek(cpu.pc) == Some(BRK) {
+            break Stop::Brk;
+        }
+        if limits
+            .max_instructions
+            .is_some_and(|max| instructions >= max)
+        {
+            break Stop::InstructionLimit;
+        }
+        if limits.max_cycles.is_some_and(|max| cycles >= max) {
+            break Stop::CycleLimit;
+        }
+
+        let entry = trace.as_ref().map(|_| {
+            let bus = cpu.bus.inner();
+            TraceEntry::capture(cpu, |a| bus.peek(a).unwrap_or(0xFF))
+        });
+        let pc = cpu.pc;
+        let interrupts = cpu.interrupts;
+        let before = Sample::of(cpu, cpu.bus.inner().peek(pc).unwrap_or(0xFF));
+        cpu.step();
+        cycles += 1;
+        while cpu.cycles > 0 {
+            cpu.step();
+            cycles += 1;
+        }
+        if let Some(profiler) = &mut profiler {
+            profiler.record(before, cpu);
+        }
+        // An interrupt entry runs in place of the instruction, which has
+        // not executed yet
+        let interrupted = cpu.interrupts != interrupts;
+        if !interrupted {
+            instructions += 1;
+            if let (Some(sink), Some(entry)) = (&mut trace, entry) {
+                sink.record(&entry)?;
+            }
+        }
+
+        if let Some(code) = exit.take() {
+            break Stop::Exit(code);
+        }
+        if limits.traps && !interrupted && cpu.pc == pc && !can_wake(cpu) {
+            break Stop::Trap;
+        }
+        match cpu.bus.host_request() {
+            Some(HostRequest::Exit(code)) => break Stop::Exit(code),
+            Some(HostRequest::DumpRegisters) => eprintln!("{}", crate::register_line(cpu)),
+            Some(HostRequest::Reset) => cpu.reset(),
+            None => {}
+        }
+    };
+
+    Ok(Outcome {
+        stop,
+        instructions,
+        cycles,
+    })
+}
+
+/// Whether an interrupt could still end a `jmp *` loop, which is how
+/// interrupt-driven firmware idles
+fn can_wake(cpu: &HeadlessCpu) -> bool {
+    !cpu.status.get(Flag::InterruptDisable) && cpu.bus.interrupts_enabled()
+}
+
+/// The final state as a single line of JSON
+pub fn report(cpu: &HeadlessCpu, outcome: &Outcome, symbols: &SymbolTable) -> String {
+    let exit_code = match outcome.stop {
+        Stop::Exit(code) => code.to_string(),
+        _ => String::from("null"),
+    };
+    let symbol = symbols
+        .name_of(cpu.pc)
+        .map_or(String::from("null"), json_string);
+
+    format!(
+        "{{\"reason\":\"{}\",\"status\":{},\"exit_code\":{exit_code},\"instructions\":{},\"cycles\":{},\
+         \"pc\":{},\"a\":{},\"x\":{},\"y\":{},\"sp\":{},\"p\":{},\"symbol\":{symbol}}}",
+        outcome.stop.name(),
+        outcome.stop.status(),
+        outcome.instructions,
+        outcome.cycles,
+        cpu.pc,
+        cpu.a,
+        cpu.x,
+        cpu.y,
+        cpu.sp,
+        cpu.status.to_byte(),
+    )
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mos6502::MemoryMapBuilder;
+    use mos6502::devices::timer::{Timer, control};
+
+    const CLI: u8 = 0x58;
+    const SEI: u8 = 0x78;
+
+    fn rom(program: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0xEA; 0x1000];
+        rom[..program.len()].copy_from_slice(program);
+        // NMI and IRQ at $F010, reset at $F000
+        rom[0xFFA..].copy_from_slice(&[0x10, 0xF0, 0x00, 0xF0, 0x10, 0xF0]);
+        rom
+    }
+
+    fn setup(program: &[u8]) -> HeadlessCpu {
+        let bus = MemoryMapBuilder::new()
+            .ram(0x0000, 0x1000)
+            .rom(0xF000, 0x1000, &rom(program))
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::new(TracingBus::new(bus));
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_brk_and_report() {
+        // lda #$2A, ldx #$03, brk
+        let mut cpu = setup(&[0xA9, 0x2A, 0xA2, 0x03, 0x00]);
+        let outcome = run(&mut cpu, &Limits::default(), None, None).unwrap();
+        assert_eq!(
+            outcome,
+            Outcome {
+                stop: Stop::Brk,
+                instructions: 2,
+                cycles: 11,
+            }
+        );
+
+        let symbols = SymbolTable::parse("done = $F004\n").unwrap();
+        assert_eq!(
+            report(&cpu, &outcome, &symbols),
+            "{\"reason\":\"brk\",\"status\":0,\"exit_code\":null,\"instructions\":2,\"cycles\":11,\
+             \"pc\":61444,\"a\":42,\"x\":3,\"y\":0,\"sp\":253,\"p\":52,\"symbol\":\"done\"}"
+        );
+    }
+
+    #[test]
+    fn test_jam_and_stop_address() {
+        // nop, jam
+        let mut cpu = setup(&[0xEA, 0x02]);
+        let outcome = run(&mut cpu, &Limits::default(), None, None).unwrap();
+        assert_eq!((outcome.stop, cpu.pc), (Stop::Jam, 0xF001));
+        assert_eq!(outcome.stop.status(), JAM_STATUS);
+
+        let mut cpu = setup(&[0xEA, 0x02]);
+        let limits = Limits {
+            stop_at: vec![0xF001],
+            ..Limits::default()
+        };
+        assert_eq!(
+            run(&mut cpu, &limits, None, None).unwrap().stop,
+            Stop::Address
+        );
+    }
+
+    #[test]
+    fn test_exit_write() {
+        // lda #$05, sta $0FFF, brk
+        let mut cpu = setup(&[0xA9, 0x05, 0x8D, 0xFF, 0x0F, 0x00]);
+        let limits = Limits {
+            exit_write: Some(0x0FFF),
+            ..Limits::default()
+        };
+        let outcome = run(&mut cpu, &limits, None, None).unwrap();
+        assert_eq!((outcome.stop, outcome.instructions), (Stop::Exit(5), 2));
+        assert_eq!(outcome.stop.status(), 5);
+        assert!(report(&cpu, &outcome, &SymbolTable::new()).contains("\"exit_code\":5,"));
+    }
+
+    #[test]
+    fn test_trace() {
+        // lda #$05, sta $0FFF, brk
+        let mut cpu = setup(&[0xA9, 0x05, 0x8D, 0xFF, 0x0F, 0x00]);
+        let mut entries: Vec<TraceEntry> = Vec::new();
+        let mut profiler = Profiler::new();
+        let outcome = run(
+            &mut cpu,
+            &Limits::default(),
+            Some(&mut entries),
+            Some(&mut profiler),
+        )
+        .unwrap();
+        assert_eq!(outcome.instructions, 2);
+        assert_eq!((profiler.instructions(), profiler.cycles()), (2, 6));
+        assert_eq!(
+            entries[1].to_string(),
+            "F002  8D FF 0F  STA $0FFF = 00                  A:05 X:00 Y:00 P:24 SP:FD CYC:9"
+        );
+    }
+
+    #[test]
+    fn test_interrupt_is_not_an_instruction() {
+        // nop, nop, brk, and an rti at $F010
+        let mut program = vec![0xEA, 0xEA, 0x00];
+        program.resize(0x10, 0xEA);
+        program.push(0x40);
+        let mut cpu = setup(&program);
+        cpu.request_nmi();
+
+        let mut entries: Vec<TraceEntry> = Vec::new();
+        let outcome = run(&mut cpu, &Limits::default(), Some(&mut entries), None).unwrap();
+        assert_eq!((outcome.instructions, outcome.cycles), (3, 24));
+        let lines: Vec<String> = entries.iter().map(|entry| entry.to_string()).collect();
+        assert_eq!(
+            lines,
+            [
+                "F010  40        RTI                             A:00 X:00 Y:00 P:24 SP:FA CYC:14",
+                "F000  EA        NOP                             A:00 X:00 Y:00 P:24 SP:FD CYC:20",
+                "F001  EA        NOP                             A:00 X:00 Y:00 P:24 SP:FD CYC:22",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_idle_loop_is_not_a_trap() {
+        // Start a timer with `control`, then `flag` (cli or sei) and idle in
+        // `jmp *`; the handler at $F030 stops at a brk on the third interrupt
+        let run_idle = |control: u8, flag: u8| {
+            let mut rom = rom(&[
+                0xA9, 0x20, 0x8D, 0x00, 0xE0, // lda #$20, sta reload low
+                0xA9, 0x00, 0x8D, 0x01, 0xE0, // lda #0, sta reload high
+                0xA9, control, 0x8D, 0x02, 0xE0, // lda #control, sta control
+                flag, 0x4C, 0x10, 0xF0, // loop: jmp loop
+            ]);
+            rom[0x30..0x3D].copy_from_slice(&[
+                0xE6, 0x10, // inc $10
+                0x8D, 0x03, 0xE0, // sta acknowledge
+                0xA5, 0x10, 0xC9, 0x03, // lda $10, cmp #3
+                0xD0, 0x01, 0x00, // bne +1, brk
+                0x40, // rti
+            ]);
+            rom[0xFFE] = 0x30;
+            let bus = MemoryMapBuilder::new()
+                .ram(0x0000, 0x1000)
+                .device(0xE000, 4, Timer::new())
+                .rom(0xF000, 0x1000, &rom)
+                .build()
+                .unwrap();
+            let mut cpu = Cpu::new(TracingBus::new(bus));
+            cpu.reset();
+            let limits = Limits {
+                traps: true,
+                ..Limits::default()
+            };
+            let outcome = run(&mut cpu, &limits, None, None).unwrap();
+            (outcome.stop, cpu.pc)
+        };
+
+        let enable_irq = control::ENABLE | control::IRQ;
+        assert_eq!(run_idle(enable_irq, CLI), (Stop::Brk, 0xF03B));
+        // Masked, or with nothing to raise one, no interrupt can come
+        assert_eq!(run_idle(enable_irq, SEI), (Stop::Trap, 0xF010));
+        assert_eq!(run_idle(control::IRQ, CLI), (Stop::Trap, 0xF010));
+    }
+
+    #[test]
+    fn test_limits() {
+        // loop: jmp loop
+        let program = [0x4C, 0x00, 0xF0];
+        let mut cpu = setup(&program);
+        let limits = Limits {
+            max_instructions: Some(100),
+            ..Limits::default()
+        };
+        let outcome = run(&mut cpu, &limits, None, None).unwrap();
+        assert_eq!(outcome.stop, Stop::InstructionLimit);
+        assert_eq!((outcome.instructions, outcome.cycles), (100, 307));
+
+        let mut cpu = setup(&program);
+        let limits = Limits {
+            max_cycles: Some(50),
+            ..Limits::default()
+        };
+        let outcome = run(&mut cpu, &limits, None, None).unwrap();
+        assert_eq!(outcome.stop, Stop::CycleLimit);
+        assert_eq!(outcome.cycles, 52);
+        assert_eq!(outcome.stop.status(), LIMIT_STATUS);
+
+        let mut cpu = setup(&program);
+        let limits = Limits {
+            traps: true,
+            ..Limits::default()
+        };
+        let outcome = run(&mut cpu, &limits, None, None).unwrap();
+        assert_eq!((outcome.stop, outcome.instructions), (Stop::Trap, 1));
+        assert_eq!(cpu.pc, 0xF000);
+    }
+}
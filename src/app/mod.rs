@@ -0,0 +1,9 @@
+//! Front-end support for the `mos6502` binary
+
+pub mod cli;
+pub mod debugger;
+pub mod headless;
+pub mod prompt;
+pub mod terminal;
+pub mod trace_diff;
+pub mod tui;
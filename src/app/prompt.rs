@@ -0,0 +1,119 @@
+//! Line input with history for interactive commands
+//!
+//! On a terminal the line is edited in cbreak mode so the arrow keys can
+//! walk back through earlier commands. Piped input is read a line at a time,
+//! which lets scripts drive the debugger.
+
+use std::io::{self, BufRead, IsTerminal, Read, Write, stdin, stdout};
+
+use super::terminal::CbreakGuard;
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7F;
+const CTRL_D: u8 = 0x04;
+const CTRL_U: u8 = 0x15;
+const ESCAPE: u8 = 0x1B;
+
+/// Reads command lines and remembers them
+#[derive(Default)]
+pub struct Prompt {
+    history: Vec<String>,
+}
+
+impl Prompt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Earlier lines, oldest first
+    pub fn history(&self) -> &[String] {
+        &self.history
+    }
+
+    /// Print `prompt` and read a line. Returns `None` at end of input.
+    pub fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        print!("{prompt}");
+        stdout().flush()?;
+
+        let line = if stdin().is_terminal() {
+            self.edit_line(prompt)?
+        } else {
+            let mut line = String::new();
+            match stdin().lock().read_line(&mut line)? {
+                0 => None,
+                _ => Some(line.trim_end_matches(['\r', '\n']).to_string()),
+            }
+        };
+
+        if let Some(line) = &line
+            && !line.trim().is_empty()
+            && self.history.last() != Some(line)
+        {
+            self.history.push(line.clone());
+        }
+        Ok(line)
+    }
+
+    fn edit_line(&self, prompt: &str) -> io::Result<Option<String>> {
+        let _guard = CbreakGuard::enable();
+        let mut input = stdin().lock();
+        let mut out = stdout().lock();
+        let mut line = String::new();
+        // Index into the history while browsing it with the arrow keys
+        let mut recalled = self.history.len();
+
+        loop {
+            match next_byte(&mut input)? {
+                None => return Ok(None),
+                Some(b'\r' | b'\n') => {
+                    writeln!(out)?;
+                    return Ok(Some(line));
+                }
+                Some(CTRL_D) if line.is_empty() => {
+                    writeln!(out)?;
+                    return Ok(None);
+                }
+                Some(BACKSPACE | DELETE) => {
+                    if line.pop().is_some() {
+                        write!(out, "\x08 \x08")?;
+                    }
+                }
+                Some(CTRL_U) => {
+                    line.clear();
+                    write!(out, "\r\x1b[K{prompt}")?;
+                }
+                Some(ESCAPE) => {
+                    // Arrow keys arrive as ESC [ A (up) and ESC [ B (down)
+                    if next_byte(&mut input)? != Some(b'[') {
+                        continue;
+                    }
+                    match next_byte(&mut input)? {
+                        Some(b'A') if recalled > 0 => recalled -= 1,
+                        Some(b'B') if recalled < self.history.len() => recalled += 1,
+                        _ => continue,
+                    }
+                    line = self.history.get(recalled).cloned().unwrap_or_default();
+                    write!(out, "\r\x1b[K{prompt}{line}")?;
+                }
+                Some(byte) if byte == b' ' || byte.is_ascii_graphic() => {
+                    line.push(byte as char);
+                    write!(out, "{}", byte as char)?;
+                }
+                Some(_) => {}
+            }
+            out.flush()?;
+        }
+    }
+}
+
+fn next_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0u8];
+    loop {
+        match input.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
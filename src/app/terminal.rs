@@ -0,0 +1,119 @@
+//! Host terminal control
+//!
+//! Serial devices wired to stdio need keystrokes as they are typed, without
+//! the terminal echoing them, so the binary switches the terminal to cbreak
+//! mode while the machine runs. Ctrl-C still raises SIGINT; it is caught so
+//! the terminal can be restored before exiting.
+
+use std::io::{IsTerminal, stdin};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Puts the terminal in cbreak mode and restores the previous settings when dropped
+pub struct CbreakGuard {
+    saved: Option<String>,
+}
+
+impl CbreakGuard {
+    /// Does nothing when stdin is not a terminal, e.g. when input is piped
+    pub fn enable() -> Self {
+        if !stdin().is_terminal() {
+            return Self { saved: None };
+        }
+
+        let saved = stty(&["-g"]).map(|settings| settings.trim().to_string());
+        if saved.is_some() {
+            stty(&["-icanon", "-echo", "min", "1"]);
+        }
+        Self { saved }
+    }
+}
+
+impl Drop for CbreakGuard {
+    fn drop(&mut self) {
+        if let Some(settings) = &self.saved {
+            stty(&[settings]);
+        }
+    }
+}
+
+/// Terminal size as (columns, rows), if stdin is a terminal. Cheap enough
+/// to poll: it asks the kernel rather than running `stty`.
+#[cfg(unix)]
+pub fn size() -> Option<(usize, usize)> {
+    use std::os::raw::{c_int, c_ulong};
+    use std::os::unix::io::AsRawFd;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct Winsize {
+        rows: u16,
+        columns: u16,
+        x_pixels: u16,
+        y_pixels: u16,
+    }
+    unsafe extern "C" {
+        fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    }
+    #[cfg(target_os = "linux")]
+    const TIOCGWINSZ: c_ulong = 0x5413;
+    // The BSDs and macOS
+    #[cfg(not(target_os = "linux"))]
+    const TIOCGWINSZ: c_ulong = 0x4008_7468;
+
+    let stdin = stdin();
+    if !stdin.is_terminal() {
+        return None;
+    }
+    let mut size = Winsize::default();
+    // SAFETY: TIOCGWINSZ fills in a struct winsize, which `size` matches
+    let result = unsafe { ioctl(stdin.as_raw_fd(), TIOCGWINSZ, &mut size as *mut Winsize) };
+    (result == 0 && size.rows > 0 && size.columns > 0)
+        .then_some((size.columns as usize, size.rows as usize))
+}
+
+#[cfg(not(unix))]
+pub fn size() -> Option<(usize, usize)> {
+    None
+}
+
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Route SIGINT to `interrupted()` instead of killing the process
+#[cfg(unix)]
+pub fn catch_interrupt() {
+    unsafe extern "C" {
+        fn signal(signum: i32, handler: usize) -> usize;
+    }
+    const SIGINT: i32 = 2;
+
+    extern "C" fn on_interrupt(_: i32) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        signal(SIGINT, on_interrupt as extern "C" fn(i32) as usize);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn catch_interrupt() {}
+
+/// True once Ctrl-C has been pressed; reading clears the flag
+pub fn interrupted() -> bool {
+    INTERRUPTED.swap(false, Ordering::SeqCst)
+}
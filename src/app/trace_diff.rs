@@ -0,0 +1,368 @@
+//! `mos6502 trace-diff <ours.log> <reference.log>`
+//!
+//! Lines our `--trace` output up with a reference log (nestest.log or
+//! another emulator's trace) instruction by instruction, and reports the
+//! first line where the PC, a register, a flag or the cycle count differs,
+//! with the lines leading up to it. Each line shows the state before its
+//! instruction runs, so the culprit is usually the instruction on the line
+//! before.
+//!
+//! The reference may start earlier than ours; its lines are skipped until
+//! the PC matches our first line. Cycle counts are compared relative to
+//! that line, so logs counting from different origins still line up.
+//!
+//! Exits with 0 if the logs agree, 1 if they differ and 2 on errors, as
+//! `diff` does. A count that goes backwards (a counter reset or wrap, or
+//! logs run together) is reported as a cycle difference.
+
+use std::fs;
+use std::io::{IsTerminal, stderr};
+
+use mos6502::trace::LogLine;
+
+const RESET: &str = "\x1b[0m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+
+/// Flag names by bit, for P mismatches
+const FLAGS: [(u8, char); 6] = [
+    (0x80, 'N'),
+    (0x40, 'V'),
+    (0x08, 'D'),
+    (0x04, 'I'),
+    (0x02, 'Z'),
+    (0x01, 'C'),
+];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    /// Matching lines to show before the first difference
+    pub context: usize,
+    pub cycles: bool,
+    /// Colour the differing lines, for a terminal
+    pub color: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            context: 5,
+            cycles: true,
+            color: false,
+        }
+    }
+}
+
+/// A parsed line and where it came from
+struct Entry<'a> {
+    number: usize,
+    text: &'a str,
+    line: LogLine,
+}
+
+fn entries(log: &str) -> Vec<Entry<'_>> {
+    log.lines()
+        .enumerate()
+        .filter_map(|(i, text)| {
+            LogLine::parse(text).map(|line| Entry {
+                number: i + 1,
+                text,
+                line,
+            })
+        })
+        .collect()
+}
+
+/// Compare the logs at two paths; returns the exit status
+pub fn run(ours: &str, reference: &str, options: &Options) -> i32 {
+    let error = if stderr().is_terminal() {
+        format!("{RED}Error:{RESET}")
+    } else {
+        String::from("Error:")
+    };
+    let mut logs = Vec::new();
+    for path in [ours, reference] {
+        match fs::read_to_string(path) {
+            Ok(text) => logs.push(text),
+            Err(e) => {
+                eprintln!("{error} Failed to read '{path}': {e}");
+                return 2;
+            }
+        }
+    }
+
+    match compare(&logs[0], &logs[1], options) {
+        Ok((same, report)) => {
+            print!("{report}");
+            if same { 0 } else { 1 }
+        }
+        Err(e) => {
+            eprintln!("{error} {e}");
+            2
+        }
+    }
+}
+
+/// Compare two logs. Returns whether they agree and a report for the user.
+pub fn compare(ours: &str, reference: &str, options: &Options) -> Result<(bool, String), String> {
+    let ours = entries(ours);
+    let reference = entries(reference);
+    let Some(first) = ours.first() else {
+        return Err(String::from("Our log has no trace lines"));
+    };
+    let Some(start) = reference.iter().position(|e| e.line.pc == first.line.pc) else {
+        return Err(format!(
+            "The reference never reaches ${:04X}, where our log starts",
+            first.line.pc
+        ));
+    };
+    let reference = &reference[start..];
+
+    // The inner None is a count that went back below the first line's
+    let cycles = |entries: &[Entry], i: usize| {
+        let origin = entries[0].line.cycle?;
+        Some(entries[i].line.cycle?.checked_sub(origin))
+    };
+    let took = |now: Option<u64>, before: Option<u64>| now?.checked_sub(before?);
+
+    for i in 0..ours.len().min(reference.len()) {
+        let (a, b) = (&ours[i].line, &reference[i].line);
+        let mut problems = registers(a, b);
+        if options.cycles
+            && let (Some(ours_cycles), Some(reference_cycles)) =
+                (cycles(&ours, i), cycles(reference, i))
+            && ours_cycles != reference_cycles
+        {
+            let mut problem = format!(
+                "cycles: ours {}, reference {}",
+                a.cycle.unwrap_or(0),
+                b.cycle.unwrap_or(0)
+            );
+            if i > 0
+                && let (Some(before), Some(reference_before)) =
+                    (cycles(&ours, i - 1), cycles(reference, i - 1))
+            {
+                problem.push_str(&match (
+                    took(ours_cycles, before),
+                    took(reference_cycles, reference_before),
+                ) {
+                    (Some(ours), Some(reference)) => format!(
+                        " (the instruction before took {ours}, {reference} in the reference)"
+                    ),
+                    (None, _) => String::from(" (our count went backwards)"),
+                    (_, None) => String::from(" (the reference's count went backwards)"),
+                });
+            }
+            problems.push(problem);
+        }
+        if problems.is_empty() {
+            continue;
+        }
+
+        let mut report = format!(
+            "First difference after {i} matching instructions, at line {} of ours and line {} of the reference:\n",
+            ours[i].number, reference[i].number
+        );
+        for problem in problems {
+            report.push_str(&format!("  {problem}\n"));
+        }
+        report.push('\n');
+        for entry in &ours[i.saturating_sub(options.context)..i] {
+            report.push_str(&format!("  {:>6}  {}\n", entry.number, entry.text));
+        }
+        let (red, green, reset) = if options.color {
+            (RED, GREEN, RESET)
+        } else {
+            ("", "", "")
+        };
+        report.push_str(&format!(
+            "{red}- {:>6}  {}{reset}\n",
+            ours[i].number, ours[i].text
+        ));
+        report.push_str(&format!(
+            "{green}+ {:>6}  {}{reset}\n",
+            reference[i].number, reference[i].text
+        ));
+        return Ok((false, report));
+    }
+
+    let compared = ours.len().min(reference.len());
+    let ending = match ours.len().cmp(&reference.len()) {
+        std::cmp::Ordering::Less => "; ours ends first",
+        std::cmp::Ordering::Greater => "; the reference ends first",
+        std::cmp::Ordering::Equal => "",
+    };
+    Ok((
+        true,
+        format!("Logs agree for {compared} instructions{ending}\n"),
+    ))
+}
+
+/// Differences in the PC, registers and flags
+fn registers(ours: &LogLine, reference: &LogLine) -> Vec<String> {
+    let mut problems = Vec::new();
+    if ours.pc != reference.pc {
+        problems.push(format!(
+            "PC: ours ${:04X}, reference ${:04X}",
+            ours.pc, reference.pc
+        ));
+    }
+    for (name, a, b) in [
+        ("A", ours.a, reference.a),
+        ("X", ours.x, reference.x),
+        ("Y", ours.y, reference.y),
+        ("SP", ours.sp, reference.sp),
+    ] {
+        if a != b {
+            problems.push(format!("{name}: ours ${a:02X}, reference ${b:02X}"));
+        }
+    }
+    // Bits 4 and 5 aren't real flags; logs disagree on how to show them
+    for (bit, flag) in FLAGS {
+        let (a, b) = (ours.p & bit != 0, reference.p & bit != 0);
+        if a != b {
+            problems.push(format!(
+                "flag {flag}: ours {}, reference {}",
+                a as u8, b as u8
+            ));
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REFERENCE: &str = "\
+C000  4C 05 C0  JMP $C005                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+C005  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10
+C007  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 36 CYC:12
+C009  38        SEC                             A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 45 CYC:15
+C00A  B0 01     BCS $C00D                       A:00 X:00 Y:00 P:27 SP:FD PPU:  0, 51 CYC:17
+C00D  EA        NOP                             A:00 X:00 Y:00 P:27 SP:FD PPU:  0, 60 CYC:20
+";
+
+    /// Our log, starting at the reference's second line, with cycles
+    /// counted from zero
+    fn ours(edit: impl Fn(&mut Vec<String>)) -> String {
+        let mut lines: Vec<String> = REFERENCE
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let (start, cycle) = line.split_once(" PPU:").unwrap();
+                let cycle: u64 = cycle.rsplit_once(':').unwrap().1.parse().unwrap();
+                format!("{start} CYC:{}", cycle - 10)
+            })
+            .collect();
+        edit(&mut lines);
+        lines.join("\n")
+    }
+
+    fn plain(report: &str) -> String {
+        report
+            .replace(RED, "")
+            .replace(GREEN, "")
+            .replace(RESET, "")
+    }
+
+    #[test]
+    fn test_logs_agree() {
+        let (same, report) = compare(&ours(|_| {}), REFERENCE, &Options::default()).unwrap();
+        assert!(same);
+        assert_eq!(report, "Logs agree for 5 instructions\n");
+
+        let (same, report) = compare(
+            &ours(|lines| lines.truncate(2)),
+            REFERENCE,
+            &Options::default(),
+        )
+        .unwrap();
+        assert!(same);
+        assert_eq!(report, "Logs agree for 2 instructions; ours ends first\n");
+    }
+
+    #[test]
+    fn test_flag_difference() {
+        let log = ours(|lines| lines[3] = lines[3].replace("P:27", "P:26"));
+        let options = Options {
+            context: 1,
+            ..Options::default()
+        };
+        let (same, report) = compare(&log, REFERENCE, &options).unwrap();
+        assert!(!same);
+        assert_eq!(
+            report,
+            "\
+First difference after 3 matching instructions, at line 4 of ours and line 5 of the reference:
+  flag C: ours 0, reference 1
+
+       3  C009  38        SEC                             A:00 X:00 Y:00 P:26 SP:FD CYC:5
+-      4  C00A  B0 01     BCS $C00D                       A:00 X:00 Y:00 P:26 SP:FD CYC:7
++      5  C00A  B0 01     BCS $C00D                       A:00 X:00 Y:00 P:27 SP:FD PPU:  0, 51 CYC:17
+"
+        );
+    }
+
+    #[test]
+    fn test_cycle_difference() {
+        let log = ours(|lines| {
+            lines[4] = lines[4].replace("CYC:10", "CYC:9");
+        });
+        let (_, report) = compare(&log, REFERENCE, &Options::default()).unwrap();
+        assert!(report.contains(
+            "  cycles: ours 9, reference 20 (the instruction before took 2, 3 in the reference)\n"
+        ));
+
+        let options = Options {
+            cycles: false,
+            ..Options::default()
+        };
+        assert!(compare(&log, REFERENCE, &options).unwrap().0);
+
+        let options = Options {
+            color: true,
+            ..Options::default()
+        };
+        let (_, report) = compare(&log, REFERENCE, &options).unwrap();
+        assert!(report.contains(&format!("{RED}-      5  C00D")));
+        assert!(plain(&report).ends_with("CYC:20\n"));
+    }
+
+    #[test]
+    fn test_cycles_going_backwards() {
+        // Below where our log started
+        let log = ours(|lines| lines[0] = lines[0].replace("CYC:0", "CYC:100"));
+        let (same, report) = compare(&log, REFERENCE, &Options::default()).unwrap();
+        assert!(!same);
+        assert!(report.contains("  cycles: ours 2, reference 12 (our count went backwards)\n"));
+
+        // Above it, but below the line before
+        let log = ours(|lines| lines[2] = lines[2].replace("CYC:5", "CYC:1"));
+        let (_, report) = compare(&log, REFERENCE, &Options::default()).unwrap();
+        assert!(report.contains("  cycles: ours 1, reference 15 (our count went backwards)\n"));
+
+        let reference = REFERENCE.replace("CYC:15", "CYC:11");
+        let (_, report) = compare(&ours(|_| {}), &reference, &Options::default()).unwrap();
+        assert!(
+            report.contains(
+                "  cycles: ours 5, reference 11 (the reference's count went backwards)\n"
+            )
+        );
+    }
+
+    #[test]
+    fn test_alignment_errors() {
+        assert_eq!(
+            compare("", REFERENCE, &Options::default()),
+            Err(String::from("Our log has no trace lines"))
+        );
+        let log = "8000  EA        NOP                             A:00 X:00 Y:00 P:24 SP:FD CYC:7";
+        assert_eq!(
+            compare(log, REFERENCE, &Options::default()),
+            Err(String::from(
+                "The reference never reaches $8000, where our log starts"
+            ))
+        );
+    }
+}
@@ -0,0 +1,851 @@
+//! Full-screen terminal UI (`--tui`)
+//!
+//! Panes are laid out to fit the terminal: registers, a disassembly that
+//! scrolls with the PC, the stack with return addresses decoded, the zero
+//! page, a memory viewer and any device screens (LCDs). Bytes the program
+//! wrote recently are highlighted. Narrow terminals get a single column
+//! without the stack and zero-page panes.
+//!
+//! Keys: space runs or pauses, `s` steps, `+`/`-` change speed, `[`/`]`
+//! page through memory, `f` makes the memory pane follow writes again, `r`
+//! resets and `q` quits. Tab sends the keys to the machine instead (the
+//! KIM-1 keypad) until the next Tab.
+//!
+//! With a clock (`--clock` or `--speed`) the machine runs in real time at
+//! its clock rate instead of a delay per instruction; `+`/`-` double and
+//! halve the rate and `t` toggles turbo.
+
+use std::io::{self, Read, Write, stdin, stdout};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mos6502::bus::HostRequest;
+use mos6502::bus_trace::AccessType;
+use mos6502::clock::{Speed, Throttle, format_frequency};
+use mos6502::disasm::disassemble_around;
+use mos6502::{Bus, SymbolTable};
+
+use super::debugger::DebugCpu;
+use super::terminal::{self, CbreakGuard};
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const DIM: &str = "\x1b[2m";
+const REVERSE: &str = "\x1b[7m";
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const CYAN: &str = "\x1b[36m";
+
+/// Delay between instructions at each speed in ms; 0 runs flat out
+const SPEEDS: [u64; 7] = [1000, 500, 150, 50, 10, 1, 0];
+
+const FRAME: Duration = Duration::from_millis(33);
+
+const LEFT_WIDTH: usize = 44;
+/// Terminals narrower than this get a single column
+const TWO_COLUMNS: usize = 100;
+/// Title, column header and 16 rows
+const ZERO_PAGE_ROWS: usize = 18;
+
+const JSR: u8 = 0x20;
+const TAB: u8 = 0x09;
+
+/// Clock speed factors `+` and `-` stop at
+const FASTEST: f64 = 64.0;
+const SLOWEST: f64 = 1.0 / 1024.0;
+
+/// What the UI shows and how fast the machine runs
+pub struct Tui {
+    symbols: SymbolTable,
+    breakpoints: Vec<u16>,
+    running: bool,
+    speed: usize,
+    /// Real-time pacing, replacing `speed` when set
+    throttle: Option<Throttle>,
+    memory_start: u16,
+    follow: bool,
+    /// Instruction count at which each address was last written; 0 = never
+    written: Vec<u64>,
+    /// Latest write outside the zero page and stack, followed by the memory pane
+    last_write: Option<u16>,
+    instructions: u64,
+    /// Instructions run in the last batch at full speed
+    batch: u64,
+    message: String,
+    exit_code: Option<u8>,
+    /// Keys go to the machine's devices rather than the UI
+    typing: bool,
+}
+
+impl Tui {
+    /// `delay_ms` picks the nearest speed at or below it
+    pub fn new(symbols: SymbolTable, breakpoints: Vec<u16>, delay_ms: u64) -> Self {
+        Self {
+            symbols,
+            breakpoints,
+            running: true,
+            speed: SPEEDS
+                .iter()
+                .position(|&delay| delay <= delay_ms)
+                .unwrap_or(SPEEDS.len() - 1),
+            throttle: None,
+            memory_start: 0x0200,
+            follow: true,
+            written: vec![0; 0x10000],
+            last_write: None,
+            instructions: 0,
+            batch: 1,
+            message: String::new(),
+            exit_code: None,
+            typing: false,
+        }
+    }
+
+    /// Run at a clock rate instead of a delay per instruction
+    pub fn set_clock(&mut self, throttle: Throttle) {
+        self.throttle = Some(throttle);
+    }
+
+    fn delay(&self) -> Duration {
+        Duration::from_millis(SPEEDS[self.speed])
+    }
+
+    /// True when instructions run in batches rather than one per delay
+    fn batched(&self) -> bool {
+        self.throttle.is_some() || SPEEDS[self.speed] == 0
+    }
+
+    /// Run at the clock rate until `until` or a pause
+    fn run_clocked(&mut self, cpu: &mut DebugCpu, until: Instant) {
+        let Some(mut throttle) = self.throttle.take() else {
+            return;
+        };
+        self.batch = 0;
+        while self.running && Instant::now() < until {
+            let mut budget = throttle.budget(Instant::now());
+            if budget == 0 {
+                let due = throttle.next_due().unwrap_or(until).min(until);
+                thread::sleep(due.saturating_duration_since(Instant::now()));
+                continue;
+            }
+            while self.running && budget > 0 {
+                let before = cpu.total_cycles;
+                self.batch += 1;
+                self.step(cpu);
+                // A jammed CPU stops counting cycles, but its clock runs on
+                let cycles = cpu.total_cycles.saturating_sub(before).max(1);
+                throttle.spend(cycles, Instant::now());
+                budget = budget.saturating_sub(cycles);
+            }
+        }
+        self.throttle = Some(throttle);
+    }
+
+    /// `+`/`-` on the clock: double or halve the rate
+    fn scale_clock(&mut self, faster: bool) {
+        let Some(throttle) = &mut self.throttle else {
+            return;
+        };
+        let speed = match throttle.speed() {
+            Speed::Scaled(factor) if faster => Speed::Scaled((factor * 2.0).min(FASTEST)),
+            Speed::Scaled(factor) => Speed::Scaled((factor / 2.0).max(SLOWEST)),
+            Speed::Unthrottled if faster => Speed::Unthrottled,
+            Speed::Unthrottled => Speed::Scaled(1.0),
+        };
+        throttle.set_speed(speed, Instant::now());
+    }
+
+    fn pause(&mut self, message: String) {
+        self.running = false;
+        self.message = message;
+    }
+
+    /// Execute one instruction. Returns false if the machine paused.
+    fn step(&mut self, cpu: &mut DebugCpu) -> bool {
+        if cpu.halted {
+            self.pause(String::from("CPU halted"));
+            return false;
+        }
+
+        cpu.execute_instruction();
+        self.instructions += 1;
+        for access in cpu.bus.accesses() {
+            if access.access_type == AccessType::Write {
+                self.written[access.address as usize] = self.instructions;
+                if access.address >= 0x0200 {
+                    self.last_write = Some(access.address);
+                }
+            }
+        }
+        cpu.bus.clear();
+
+        match cpu.bus.host_request() {
+            Some(HostRequest::Exit(code)) => {
+                self.exit_code = Some(code);
+                self.pause(format!("Program exited with code {code}; q quits"));
+                return false;
+            }
+            Some(HostRequest::DumpRegisters) => self.message = registers_line(cpu),
+            Some(HostRequest::Reset) => cpu.reset(),
+            None => {}
+        }
+
+        if self.breakpoints.contains(&cpu.pc) {
+            let label = self.symbols.describe(cpu.pc, 0x100);
+            self.pause(format!("Breakpoint at {label}"));
+            return false;
+        }
+        true
+    }
+
+    /// Handle a key press. Returns true to quit.
+    fn key(&mut self, key: u8, cpu: &mut DebugCpu) -> bool {
+        if key == TAB {
+            self.typing = !self.typing;
+            self.message.clear();
+            return false;
+        }
+        if self.typing {
+            if !cpu.bus.inner_mut().key(key) {
+                self.message = String::from("No device takes keys; tab returns");
+            }
+            return false;
+        }
+        match key {
+            b' ' => {
+                self.running = !self.running;
+                self.message.clear();
+                if let Some(throttle) = &mut self.throttle {
+                    throttle.restart(Instant::now());
+                }
+            }
+            b's' => {
+                self.running = false;
+                self.message.clear();
+                self.batch = 1;
+                self.step(cpu);
+            }
+            b'+' | b'=' if self.throttle.is_some() => self.scale_clock(true),
+            b'-' if self.throttle.is_some() => self.scale_clock(false),
+            b'+' | b'=' => self.speed = (self.speed + 1).min(SPEEDS.len() - 1),
+            b'-' => self.speed = self.speed.saturating_sub(1),
+            b't' => {
+                if let Some(throttle) = &mut self.throttle {
+                    let speed = match throttle.speed() {
+                        Speed::Unthrottled => Speed::Scaled(1.0),
+                        Speed::Scaled(_) => Speed::Unthrottled,
+                    };
+                    throttle.set_speed(speed, Instant::now());
+                }
+            }
+            b'[' | b']' => {
+                if self.follow {
+                    self.memory_start = self.last_write.unwrap_or(self.memory_start) & 0xFF00;
+                    self.follow = false;
+                }
+                self.memory_start = if key == b'[' {
+                    self.memory_start.wrapping_sub(0x100)
+                } else {
+                    self.memory_start.wrapping_add(0x100)
+                };
+            }
+            b'f' => self.follow = true,
+            b'r' => {
+                cpu.reset();
+                self.message = String::from("Reset");
+            }
+            b'q' => return true,
+            _ => {}
+        }
+        false
+    }
+
+    /// True if the program wrote `address` in roughly the last half second
+    /// of running at the current speed
+    fn recent(&self, address: u16) -> bool {
+        let window = match SPEEDS[self.speed] {
+            _ if self.batched() => self.batch * 15,
+            delay => 500 / delay,
+        };
+        let at = self.written[address as usize];
+        at != 0 && self.instructions - at < window.max(1)
+    }
+
+    // ========== Layout ==========
+
+    /// The whole screen as `height` lines of `width` columns
+    pub fn render(&self, cpu: &DebugCpu, width: usize, height: usize) -> Vec<String> {
+        let width = width.max(40);
+        let body = height.max(12) - 1;
+
+        let registers = pane("Registers", width.min(LEFT_WIDTH), 4, self.registers(cpu));
+        let screens: Vec<String> = cpu
+            .bus
+            .inner()
+            .screens()
+            .into_iter()
+            .flat_map(|(name, rows)| {
+                let rows: Vec<String> = rows
+                    .into_iter()
+                    .map(|row| format!("{BOLD}{GREEN}{row}{RESET}"))
+                    .collect();
+                let height = rows.len() + 1;
+                pane(name, LEFT_WIDTH.min(width), height, rows)
+            })
+            .collect();
+
+        let mut lines = Vec::with_capacity(height);
+        if width >= TWO_COLUMNS {
+            let right_width = width - LEFT_WIDTH - 1;
+            let rest = body - registers.len();
+            let stack_rows = (rest / 3).clamp(3, 17);
+            let mut left = registers;
+            left.extend(self.disassembly(cpu, LEFT_WIDTH, rest - stack_rows));
+            left.extend(self.stack(cpu, LEFT_WIDTH, stack_rows));
+
+            let mut right = Vec::new();
+            if body >= ZERO_PAGE_ROWS + screens.len() + 4 {
+                right.extend(self.zero_page(cpu, right_width));
+            }
+            right.extend(screens.iter().map(|line| fit(line, right_width)));
+            let memory_rows = body - right.len();
+            right.extend(self.memory(cpu, right_width, memory_rows));
+
+            for (left, right) in left.iter().zip(&right) {
+                lines.push(format!("{left} {right}"));
+            }
+        } else {
+            let mut column = registers;
+            column.extend(screens);
+            let rest = body.saturating_sub(column.len());
+            column.extend(self.disassembly(cpu, width, rest / 2));
+            column.extend(self.memory(cpu, width, rest - rest / 2));
+            lines.extend(column.iter().map(|line| fit(line, width)));
+        }
+
+        lines.resize(body, " ".repeat(width));
+        lines.push(self.status(width));
+        lines
+    }
+
+    fn status(&self, width: usize) -> String {
+        let keys = if self.typing {
+            " keys go to the machine  tab returns "
+        } else if self.throttle.is_some() {
+            " space run/pause  s step  +/- speed  t turbo  [ ] memory  f follow  r reset  q quit "
+        } else {
+            " space run/pause  s step  +/- speed  [ ] memory  f follow  r reset  q quit "
+        };
+        fit(&format!("{REVERSE}{keys}{RESET} {}", self.message), width)
+    }
+
+    fn registers(&self, cpu: &DebugCpu) -> Vec<String> {
+        let p = cpu.status.to_byte();
+        let flags: String = "NV-BDIZC"
+            .chars()
+            .enumerate()
+            .map(|(i, flag)| {
+                if p & (0x80 >> i) != 0 {
+                    flag
+                } else {
+                    flag.to_ascii_lowercase()
+                }
+            })
+            .collect();
+        let label = self.symbols.describe(cpu.pc, 0x100);
+        let state = if self.running {
+            format!("{GREEN}running{RESET}")
+        } else {
+            format!("{YELLOW}paused{RESET}")
+        };
+        let speed = match (&self.throttle, SPEEDS[self.speed]) {
+            (Some(throttle), _) => {
+                let rate = throttle
+                    .effective_hz()
+                    .filter(|_| self.running)
+                    .or(throttle.target_hz());
+                match rate {
+                    Some(hz) => format!("{} {}", format_frequency(hz), throttle.speed()),
+                    None => String::from("turbo"),
+                }
+            }
+            (None, 0) => String::from("full speed"),
+            (None, delay) => format!("{delay}ms/instruction"),
+        };
+
+        vec![
+            format!(
+                "{DIM}PC{RESET} {GREEN}${:04X}{RESET} {YELLOW}{label:<16}{RESET} {DIM}SP{RESET} {GREEN}${:02X}{RESET}",
+                cpu.pc, cpu.sp
+            ),
+            format!(
+                "{DIM}A{RESET} {GREEN}${:02X}{RESET}  {DIM}X{RESET} {GREEN}${:02X}{RESET}  {DIM}Y{RESET} {GREEN}${:02X}{RESET}  {DIM}P{RESET} {GREEN}${p:02X}{RESET} {flags}",
+                cpu.a, cpu.x, cpu.y
+            ),
+            format!("{} instructions  {state}  {speed}", self.instructions),
+        ]
+    }
+
+    /// Instructions around the PC; about a third of the pane shows what led up to it
+    fn disassembly(&self, cpu: &DebugCpu, width: usize, rows: usize) -> Vec<String> {
+        let bus = cpu.bus.inner();
+        let content = rows.saturating_sub(1);
+        let lines = disassemble_around(
+            cpu.pc,
+            content / 3,
+            content,
+            |a| bus.peek(a).unwrap_or(0xFF),
+            &self.symbols,
+        );
+
+        let mut text = Vec::new();
+        let mut pc_row = 0;
+        for line in &lines {
+            if let Some(name) = self.symbols.name_of(line.address) {
+                text.push(format!("  {YELLOW}{name}:{RESET}"));
+            }
+            let mark = if self.breakpoints.contains(&line.address) {
+                format!("{RED}●{RESET}")
+            } else {
+                String::from(" ")
+            };
+            if line.address == cpu.pc {
+                pc_row = text.len();
+                text.push(format!("{mark}{REVERSE}{BOLD}{line}{RESET}"));
+            } else {
+                text.push(format!("{mark}{line}"));
+            }
+        }
+
+        let start = pc_row.saturating_sub(content / 3);
+        let text = text.into_iter().skip(start).take(content).collect();
+        pane("Disassembly", width, rows, text)
+    }
+
+    /// Stack from the top down. Pairs of bytes that point just after a JSR
+    /// are shown as the return address they are.
+    fn stack(&self, cpu: &DebugCpu, width: usize, rows: usize) -> Vec<String> {
+        let bus = cpu.bus.inner();
+        let byte = |offset: u16| bus.peek(0x0100 + offset);
+
+        let mut text = Vec::new();
+        let mut offset = cpu.sp as u16 + 1;
+        while offset <= 0xFF && text.len() < rows - 1 {
+            let address = 0x0100 + offset;
+            let low = byte(offset);
+            if offset < 0xFF
+                && let (Some(low), Some(high)) = (low, byte(offset + 1))
+            {
+                let pushed = u16::from_le_bytes([low, high]);
+                let call = pushed.wrapping_sub(2);
+                if bus.peek(call) == Some(JSR) {
+                    let target = self.symbols.describe(pushed.wrapping_add(1), 0x100);
+                    text.push(format!(
+                        "${address:04X}  {low:02X} {high:02X}  {CYAN}→ {target}{RESET}  {DIM}JSR at ${call:04X}{RESET}"
+                    ));
+                    offset += 2;
+                    continue;
+                }
+            }
+            let value = low.map_or(String::from("--"), |b| format!("{b:02X}"));
+            text.push(format!("${address:04X}  {value}"));
+            offset += 1;
+        }
+        if text.is_empty() {
+            text.push(format!("{DIM}(empty){RESET}"));
+        }
+        pane("Stack", width, rows, text)
+    }
+
+    fn zero_page(&self, cpu: &DebugCpu, width: usize) -> Vec<String> {
+        let bus = cpu.bus.inner();
+        let header: String = (0..16).map(|column| format!(" {column:X} ")).collect();
+        let mut text = vec![format!("{DIM}    {header}{RESET}")];
+        for row in 0..16u16 {
+            let bytes: String = (0..16)
+                .map(|column| {
+                    let address = row * 16 + column;
+                    self.byte(address, bus.peek(address))
+                })
+                .collect();
+            text.push(format!("{DIM}${:02X}{RESET}{bytes}", row * 16));
+        }
+        pane("Zero page", width, ZERO_PAGE_ROWS, text)
+    }
+
+    fn memory(&self, cpu: &DebugCpu, width: usize, rows: usize) -> Vec<String> {
+        let bus = cpu.bus.inner();
+        // Address, hex and ASCII columns
+        let per_row: u16 = if width >= 7 + 16 * 4 { 16 } else { 8 };
+        let content = rows.saturating_sub(1) as u16;
+
+        let start = match (self.follow, self.last_write) {
+            (true, Some(address)) => {
+                let row = address - address % per_row;
+                row.wrapping_sub(per_row * (content / 2))
+            }
+            _ => self.memory_start,
+        };
+
+        let mut text = Vec::new();
+        for row in 0..content {
+            let address = start.wrapping_add(row * per_row);
+            let mut hex = String::new();
+            let mut ascii = String::new();
+            for column in 0..per_row {
+                let value = bus.peek(address.wrapping_add(column));
+                hex.push_str(&self.byte(address.wrapping_add(column), value));
+                ascii.push(match value {
+                    Some(b) if b.is_ascii_graphic() || b == b' ' => b as char,
+                    _ => '.',
+                });
+            }
+            text.push(format!("{DIM}${address:04X}{RESET} {hex}  {ascii}"));
+        }
+        let title = if self.follow {
+            "Memory (following writes)"
+        } else {
+            "Memory"
+        };
+        pane(title, width, rows, text)
+    }
+
+    /// ` XX` with recent writes highlighted; `--` for device registers
+    fn byte(&self, address: u16, value: Option<u8>) -> String {
+        match value {
+            None => format!(" {DIM}--{RESET}"),
+            Some(value) if self.recent(address) => format!(" {YELLOW}{BOLD}{value:02X}{RESET}"),
+            Some(value) => format!(" {value:02X}"),
+        }
+    }
+}
+
+fn registers_line(cpu: &DebugCpu) -> String {
+    format!(
+        "PC=${:04X} A=${:02X} X=${:02X} Y=${:02X} SP=${:02X} P=${:02X}",
+        cpu.pc,
+        cpu.a,
+        cpu.x,
+        cpu.y,
+        cpu.sp,
+        cpu.status.to_byte()
+    )
+}
+
+/// A titled pane of exactly `rows` lines, each `width` columns wide
+fn pane(title: &str, width: usize, rows: usize, content: Vec<String>) -> Vec<String> {
+    if rows == 0 {
+        return Vec::new();
+    }
+    let rule = "─".repeat(width.saturating_sub(title.chars().count() + 3));
+    let mut lines = vec![fit(
+        &format!("{CYAN}─ {BOLD}{title}{RESET}{CYAN} {rule}{RESET}"),
+        width,
+    )];
+    lines.extend(content.iter().take(rows - 1).map(|line| fit(line, width)));
+    lines.resize(rows, " ".repeat(width));
+    lines
+}
+
+/// Pad or cut `text` to `width` visible columns, skipping ANSI sequences
+fn fit(text: &str, width: usize) -> String {
+    let mut out = String::with_capacity(text.len() + width);
+    let mut visible = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            out.push(c);
+            for c in chars.by_ref() {
+                out.push(c);
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else if visible < width {
+            out.push(c);
+            visible += 1;
+        }
+    }
+    out.push_str(RESET);
+    out.push_str(&" ".repeat(width - visible));
+    out
+}
+
+/// Switches to the terminal's alternate screen with the cursor hidden and
+/// back again when dropped
+struct AlternateScreen;
+
+impl AlternateScreen {
+    fn enter() -> io::Result<Self> {
+        let mut out = stdout();
+        write!(out, "\x1b[?1049h\x1b[?25l\x1b[2J")?;
+        out.flush()?;
+        Ok(Self)
+    }
+}
+
+impl Drop for AlternateScreen {
+    fn drop(&mut self) {
+        let mut out = stdout();
+        let _ = write!(out, "\x1b[?25h\x1b[?1049l");
+        let _ = out.flush();
+    }
+}
+
+/// Key presses from a background thread, so the UI never blocks on stdin
+fn key_reader() -> Receiver<u8> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for byte in stdin().lock().bytes() {
+            match byte {
+                Ok(byte) if tx.send(byte).is_ok() => {}
+                _ => break,
+            }
+        }
+    });
+    rx
+}
+
+/// Run the machine under the UI until `q` or Ctrl-C. Returns the exit code
+/// the program asked for, if any.
+pub fn run(
+    cpu: &mut DebugCpu,
+    symbols: SymbolTable,
+    breakpoints: &[u16],
+    delay_ms: u64,
+    throttle: Option<Throttle>,
+) -> io::Result<Option<u8>> {
+    let _cbreak = CbreakGuard::enable();
+    let _screen = AlternateScreen::enter()?;
+    terminal::catch_interrupt();
+    let keys = key_reader();
+
+    let mut tui = Tui::new(symbols, breakpoints.to_vec(), delay_ms);
+    if let Some(throttle) = throttle {
+        tui.set_clock(throttle);
+    }
+    let mut out = stdout();
+    let mut size = terminal::size().unwrap_or((80, 24));
+    let mut size_checked = Instant::now();
+    let mut last_frame: Option<Instant> = None;
+    let mut next_step = Instant::now();
+
+    while !terminal::interrupted() {
+        while let Ok(key) = keys.try_recv() {
+            if tui.key(key, cpu) {
+                return Ok(tui.exit_code);
+            }
+            // Show the effect of a key straight away
+            last_frame = None;
+        }
+
+        if tui.running {
+            let delay = tui.delay();
+            if tui.throttle.is_some() {
+                tui.run_clocked(cpu, Instant::now() + FRAME / 2);
+            } else if delay.is_zero() {
+                let deadline = Instant::now() + FRAME / 2;
+                tui.batch = 0;
+                while tui.running && Instant::now() < deadline {
+                    for _ in 0..256 {
+                        tui.batch += 1;
+                        if !tui.step(cpu) {
+                            break;
+                        }
+                    }
+                }
+            } else if Instant::now() >= next_step {
+                tui.step(cpu);
+                next_step = Instant::now() + delay;
+            }
+        }
+
+        if size_checked.elapsed() > Duration::from_millis(250) {
+            let current = terminal::size().unwrap_or(size);
+            if current != size {
+                size = current;
+                write!(out, "\x1b[2J")?;
+            }
+            size_checked = Instant::now();
+        }
+
+        if last_frame.is_none_or(|at| at.elapsed() >= FRAME) {
+            let lines = tui.render(cpu, size.0, size.1);
+            write!(out, "\x1b[H{}", lines.join("\n"))?;
+            out.flush()?;
+            last_frame = Some(Instant::now());
+        }
+
+        if !(tui.running && tui.batched()) {
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+    Ok(tui.exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mos6502::bus_trace::TracingBus;
+    use mos6502::{Cpu, MemoryMapBuilder};
+
+    #[rustfmt::skip]
+    const PROGRAM: &[u8] = &[
+        0x20, 0x10, 0xF0,       // main: jsr work
+        0x4C, 0x03, 0xF0,       // end:  jmp end
+        0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA,
+        0xA9, 0x41,             // work: lda #'A'
+        0x85, 0x10,             //       sta $10
+        0x8D, 0x00, 0x03,       //       sta $0300
+        0x60,                   //       rts
+    ];
+
+    fn setup() -> (Tui, DebugCpu) {
+        let mut rom = vec![0xEA; 0x1000];
+        rom[..PROGRAM.len()].copy_from_slice(PROGRAM);
+        rom[0xFFC..0xFFE].copy_from_slice(&[0x00, 0xF0]);
+
+        let bus = MemoryMapBuilder::new()
+            .ram(0x0000, 0x1000)
+            .rom(0xF000, 0x1000, &rom)
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::new(TracingBus::new(bus));
+        cpu.reset();
+
+        let symbols = SymbolTable::parse("main = $F000\nend = $F003\nwork = $F010\n").unwrap();
+        (Tui::new(symbols, Vec::new(), 150), cpu)
+    }
+
+    /// `line` without colours
+    fn strip(line: &str) -> String {
+        let mut out = String::new();
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                chars.by_ref().find(|c| c.is_ascii_alphabetic());
+            } else {
+                out.push(c);
+            }
+        }
+        out
+    }
+
+    fn plain(line: &str) -> String {
+        strip(line).trim_end().to_string()
+    }
+
+    #[test]
+    fn test_layout_fits_terminal() {
+        let (tui, cpu) = setup();
+        for (width, height) in [(120, 40), (100, 30), (80, 24), (40, 12)] {
+            let lines = tui.render(&cpu, width, height);
+            assert_eq!(lines.len(), height);
+            for line in &lines {
+                let columns = strip(line).chars().count();
+                assert_eq!(columns, width, "{width}x{height}: {}", plain(line));
+            }
+        }
+
+        let wide: Vec<String> = tui.render(&cpu, 120, 40).iter().map(|l| plain(l)).collect();
+        assert!(wide.iter().any(|line| line.contains("Zero page")));
+        assert!(wide.iter().any(|line| line.contains("Stack")));
+        let narrow: Vec<String> = tui.render(&cpu, 80, 24).iter().map(|l| plain(l)).collect();
+        assert!(!narrow.iter().any(|line| line.contains("Zero page")));
+        assert!(
+            narrow
+                .iter()
+                .any(|line| line.contains("$F000  20 10 F0  JSR work"))
+        );
+    }
+
+    #[test]
+    fn test_stack_decodes_return_address() {
+        let (mut tui, mut cpu) = setup();
+        tui.key(b's', &mut cpu);
+        assert_eq!(cpu.pc, 0xF010);
+
+        let stack: Vec<String> = tui
+            .stack(&cpu, LEFT_WIDTH, 4)
+            .iter()
+            .map(|l| plain(l))
+            .collect();
+        assert_eq!(stack[1], "$01FC  02 F0  → end  JSR at $F000");
+        assert_eq!(stack[2], "$01FE  00");
+    }
+
+    #[test]
+    fn test_recent_writes_highlighted() {
+        let (mut tui, mut cpu) = setup();
+        for _ in 0..3 {
+            tui.key(b's', &mut cpu);
+        }
+        assert!(tui.recent(0x10));
+        let zero_page = tui.zero_page(&cpu, 60);
+        assert!(zero_page[3].contains(&format!("{YELLOW}{BOLD}41")));
+
+        tui.key(b's', &mut cpu);
+        let memory: Vec<String> = tui.memory(&cpu, 60, 3).iter().map(|l| plain(l)).collect();
+        assert!(memory[0].starts_with("─ Memory (following writes) ─"));
+        // The written row is kept mid-pane
+        assert!(memory[1].starts_with("$02F8  00 00"));
+        assert!(memory[2].starts_with("$0300  41 00"));
+
+        // 150ms per instruction keeps a write lit for three instructions
+        for _ in 0..4 {
+            tui.key(b's', &mut cpu);
+        }
+        assert!(!tui.recent(0x10));
+    }
+
+    #[test]
+    fn test_keys() {
+        let (mut tui, mut cpu) = setup();
+        assert!(tui.running);
+        tui.key(b' ', &mut cpu);
+        assert!(!tui.running);
+
+        assert_eq!(SPEEDS[tui.speed], 150);
+        tui.key(b'+', &mut cpu);
+        assert_eq!(SPEEDS[tui.speed], 50);
+        for _ in 0..10 {
+            tui.key(b'-', &mut cpu);
+        }
+        assert_eq!(SPEEDS[tui.speed], 1000);
+
+        tui.key(b']', &mut cpu);
+        assert_eq!((tui.follow, tui.memory_start), (false, 0x0300));
+        assert!(tui.key(b'q', &mut cpu));
+
+        // Tab hands the keys to the machine, which has nothing to take them
+        tui.key(TAB, &mut cpu);
+        assert!(!tui.key(b'q', &mut cpu));
+        assert!(tui.message.starts_with("No device takes keys"));
+        tui.key(TAB, &mut cpu);
+        assert!(tui.key(b'q', &mut cpu));
+    }
+
+    #[test]
+    fn test_clock_keys() {
+        let (mut tui, mut cpu) = setup();
+        tui.set_clock(Throttle::new(1_000_000, Speed::Scaled(1.0)));
+        tui.key(b'-', &mut cpu);
+        let speed = |tui: &Tui| tui.throttle.as_ref().unwrap().speed();
+        assert_eq!(speed(&tui), Speed::Scaled(0.5));
+        assert!(plain(&tui.registers(&cpu)[2]).ends_with("running  500.0 kHz 50%"));
+
+        tui.key(b't', &mut cpu);
+        assert_eq!(speed(&tui), Speed::Unthrottled);
+        tui.key(b'+', &mut cpu);
+        assert_eq!(speed(&tui), Speed::Unthrottled);
+        tui.key(b't', &mut cpu);
+        assert_eq!(speed(&tui), Speed::Scaled(1.0));
+        // The delay speeds are left alone
+        assert_eq!(SPEEDS[tui.speed], 150);
+
+        tui.run_clocked(&mut cpu, Instant::now() + Duration::from_millis(20));
+        assert!(tui.instructions > 0);
+    }
+}
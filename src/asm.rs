@@ -0,0 +1,643 @@
+//! Small two-pass 6502 assembler
+//!
+//! Enough of the ca65 syntax to build the examples without cc65:
+//!
+//! - `label:` definitions and `NAME = expression` constants
+//! - all official instructions, with the addressing mode picked from the
+//!   operand syntax (`#imm`, `zp,X`, `(zp),Y`, `(abs)`, ...)
+//! - `.org address`, `.segment "NAME"`, `.byte`/`.db` (numbers and strings),
+//!   `.word`/`.dw` and `.res count[, fill]`
+//! - numbers as `$FF`, `%1010`, `255` or `'c'`; expressions of `+` and `-`
+//!   with `<` (low byte), `>` (high byte) and `*` (current address)
+//!
+//! Segments follow `examples/emu.cfg`: `CODE` starts at $8000, `VECTORS` at
+//! $FFFA, and `ZEROPAGE` ($0000) and `BSS` ($0200) only reserve space.
+//!
+//! Operands that fit in a byte use zero-page addressing when their value is
+//! known on the first pass, i.e. constants and labels defined earlier.
+//! Forward references are assembled as absolute, as ca65 does.
+
+use std::fmt;
+
+use crate::addressing::AddressingMode;
+use crate::instructions::OPCODES;
+use crate::symbols::{SymbolTable, parse_number};
+
+/// A line that could not be assembled (1-based line number)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+fn error(line: usize, message: impl Into<String>) -> AsmError {
+    AsmError {
+        line,
+        message: message.into(),
+    }
+}
+
+/// Consecutive assembled bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chunk {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    /// Source line of the first byte
+    pub line: usize,
+}
+
+/// The result of assembling a source file
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub chunks: Vec<Chunk>,
+    /// Labels and constants
+    pub symbols: SymbolTable,
+}
+
+impl Program {
+    /// A ROM image of `size` bytes ending at $FFFF, with unused bytes set to
+    /// `fill`. Fails if any code lies below the image.
+    pub fn image(&self, size: usize, fill: u8) -> Result<Vec<u8>, AsmError> {
+        let start = 0x10000 - size.min(0x10000);
+        let mut image = vec![fill; 0x10000 - start];
+        for chunk in &self.chunks {
+            let address = chunk.address as usize;
+            if address < start || address + chunk.bytes.len() > 0x10000 {
+                return Err(error(
+                    chunk.line,
+                    format!("${address:04X} is outside the {size}-byte image (${start:04X}-$FFFF)"),
+                ));
+            }
+            image[address - start..][..chunk.bytes.len()].copy_from_slice(&chunk.bytes);
+        }
+        Ok(image)
+    }
+}
+
+/// Where `.segment` names start, and whether they hold bytes
+const SEGMENTS: [(&str, u16, bool); 4] = [
+    ("CODE", 0x8000, true),
+    ("VECTORS", 0xFFFA, true),
+    ("ZEROPAGE", 0x0000, false),
+    ("BSS", 0x0200, false),
+];
+
+/// A statement from the first pass, waiting for its operands
+enum Item {
+    Instruction {
+        code: u8,
+        mode: AddressingMode,
+        operand: String,
+    },
+    Bytes(Vec<String>),
+    Words(Vec<String>),
+    Fill {
+        count: u16,
+        value: u8,
+    },
+}
+
+struct Statement {
+    line: usize,
+    address: u16,
+    /// False in segments that only reserve space
+    emit: bool,
+    item: Item,
+}
+
+/// Assemble `source`
+pub fn assemble(source: &str) -> Result<Program, AsmError> {
+    let mut symbols = SymbolTable::new();
+    let mut statements = Vec::new();
+    // Location counters: `.org` output, then one per segment
+    let mut counters: Vec<(u16, bool)> = vec![(0x8000, true)];
+    counters.extend(SEGMENTS.iter().map(|&(_, start, emit)| (start, emit)));
+    let mut current = 0;
+
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let mut text = strip_comment(text).trim();
+        let (pc, emit) = counters[current];
+
+        // Label definitions
+        while let Some((name, rest)) = text.split_once(':')
+            && is_identifier(name.trim())
+            && !rest.starts_with(':')
+        {
+            define(&mut symbols, line, name.trim(), pc)?;
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        // Constants
+        if let Some((name, value)) = text.split_once('=')
+            && is_identifier(name.trim())
+        {
+            let value = evaluate(value, &symbols, pc).map_err(|message| error(line, message))?;
+            define(&mut symbols, line, name.trim(), value)?;
+            continue;
+        }
+
+        let (word, operand) = match text.split_once(char::is_whitespace) {
+            Some((word, operand)) => (word, operand.trim()),
+            None => (text, ""),
+        };
+
+        let (item, size) = match word.to_ascii_lowercase().as_str() {
+            ".org" => {
+                let address =
+                    evaluate(operand, &symbols, pc).map_err(|message| error(line, message))?;
+                current = 0;
+                counters[0] = (address, true);
+                continue;
+            }
+            ".segment" => {
+                let name = operand.trim_matches('"');
+                let Some(index) = SEGMENTS.iter().position(|&(segment, ..)| segment == name) else {
+                    return Err(error(line, format!("unknown segment \"{name}\"")));
+                };
+                current = index + 1;
+                continue;
+            }
+            ".byte" | ".db" => {
+                let values = split_list(operand);
+                let size = values
+                    .iter()
+                    .map(|value| match string_literal(value) {
+                        Some(text) => text.len(),
+                        None => 1,
+                    })
+                    .sum::<usize>();
+                (Item::Bytes(values), size)
+            }
+            ".word" | ".dw" => {
+                let values = split_list(operand);
+                let size = values.len() * 2;
+                (Item::Words(values), size)
+            }
+            ".res" => {
+                let values = split_list(operand);
+                let value = |i: usize| match values.get(i) {
+                    Some(text) => evaluate(text, &symbols, pc),
+                    None => Ok(0),
+                };
+                let count = value(0).map_err(|message| error(line, message))?;
+                let fill = value(1).map_err(|message| error(line, message))?;
+                let item = Item::Fill {
+                    count,
+                    value: fill as u8,
+                };
+                (item, count as usize)
+            }
+            directive if directive.starts_with('.') => {
+                return Err(error(line, format!("unknown directive {directive}")));
+            }
+            mnemonic => {
+                let (code, mode) = encode(mnemonic, operand, &symbols, pc)
+                    .map_err(|message| error(line, message))?;
+                let size = OPCODES[code as usize].bytes as usize;
+                let operand = operand_expression(operand);
+                (
+                    Item::Instruction {
+                        code,
+                        mode,
+                        operand,
+                    },
+                    size,
+                )
+            }
+        };
+
+        if !emit && !matches!(item, Item::Fill { .. }) {
+            return Err(error(line, "only .res is allowed in ZEROPAGE and BSS"));
+        }
+        statements.push(Statement {
+            line,
+            address: pc,
+            emit,
+            item,
+        });
+        counters[current].0 = pc.wrapping_add(size as u16);
+    }
+
+    let mut chunks: Vec<Chunk> = Vec::new();
+    for statement in statements {
+        if !statement.emit {
+            continue;
+        }
+        let bytes = emit(&statement, &symbols).map_err(|message| error(statement.line, message))?;
+        match chunks.last_mut() {
+            Some(chunk)
+                if chunk.address as usize + chunk.bytes.len() == statement.address as usize =>
+            {
+                chunk.bytes.extend(bytes)
+            }
+            _ => chunks.push(Chunk {
+                address: statement.address,
+                bytes,
+                line: statement.line,
+            }),
+        }
+    }
+    Ok(Program { chunks, symbols })
+}
+
+/// Second pass: the bytes for one statement
+fn emit(statement: &Statement, symbols: &SymbolTable) -> Result<Vec<u8>, String> {
+    let pc = statement.address;
+    match &statement.item {
+        Item::Instruction {
+            code,
+            mode,
+            operand,
+        } => {
+            let mut bytes = vec![*code];
+            match mode {
+                AddressingMode::Implied | AddressingMode::Accumulator => {}
+                AddressingMode::Relative => {
+                    let target = evaluate(operand, symbols, pc)?;
+                    let offset = target as i32 - (pc as i32 + 2);
+                    if !(-128..=127).contains(&offset) {
+                        return Err(format!("branch to ${target:04X} is out of range"));
+                    }
+                    bytes.push(offset as u8);
+                }
+                AddressingMode::Absolute
+                | AddressingMode::AbsoluteX
+                | AddressingMode::AbsoluteY
+                | AddressingMode::Indirect => {
+                    bytes.extend(evaluate(operand, symbols, pc)?.to_le_bytes());
+                }
+                _ => {
+                    let value = evaluate(operand, symbols, pc)?;
+                    if *mode != AddressingMode::Immediate && value > 0xFF {
+                        return Err(format!("${value:04X} is not a zero-page address"));
+                    }
+                    if value > 0xFF && value < 0xFF80 {
+                        return Err(format!("${value:04X} does not fit in a byte"));
+                    }
+                    bytes.push(value as u8);
+                }
+            }
+            Ok(bytes)
+        }
+        Item::Bytes(values) => {
+            let mut bytes = Vec::new();
+            for value in values {
+                match string_literal(value) {
+                    Some(text) => bytes.extend(text.bytes()),
+                    None => bytes.push(evaluate(value, symbols, pc)? as u8),
+                }
+            }
+            Ok(bytes)
+        }
+        Item::Words(values) => {
+            let mut bytes = Vec::new();
+            for value in values {
+                bytes.extend(evaluate(value, symbols, pc)?.to_le_bytes());
+            }
+            Ok(bytes)
+        }
+        Item::Fill { count, value } => Ok(vec![*value; *count as usize]),
+    }
+}
+
+/// Pick the opcode for `mnemonic` from the operand's syntax
+fn encode(
+    mnemonic: &str,
+    operand: &str,
+    symbols: &SymbolTable,
+    pc: u16,
+) -> Result<(u8, AddressingMode), String> {
+    let mnemonic = mnemonic.to_ascii_uppercase();
+    let find = |mode: AddressingMode| {
+        OPCODES
+            .iter()
+            .position(|op| op.mnemonic == mnemonic && op.mode == mode)
+            .map(|code| (code as u8, mode))
+    };
+    if !OPCODES.iter().any(|op| op.mnemonic == mnemonic) {
+        return Err(format!("unknown instruction {mnemonic}"));
+    }
+
+    let upper = operand.to_ascii_uppercase().replace(' ', "");
+    let candidates = if upper.is_empty() {
+        vec![AddressingMode::Implied, AddressingMode::Accumulator]
+    } else if upper == "A" {
+        vec![AddressingMode::Accumulator]
+    } else if upper.starts_with('#') {
+        vec![AddressingMode::Immediate]
+    } else if upper.starts_with('(') && upper.ends_with(",X)") {
+        vec![AddressingMode::IndirectX]
+    } else if upper.starts_with('(') && upper.ends_with("),Y") {
+        vec![AddressingMode::IndirectY]
+    } else if upper.starts_with('(') && upper.ends_with(')') {
+        vec![AddressingMode::Indirect]
+    } else {
+        let expression = operand_expression(operand);
+        let zero_page = evaluate(&expression, symbols, pc).is_ok_and(|value| value <= 0xFF);
+        let (short, long) = if upper.ends_with(",X") {
+            (AddressingMode::ZeroPageX, AddressingMode::AbsoluteX)
+        } else if upper.ends_with(",Y") {
+            (AddressingMode::ZeroPageY, AddressingMode::AbsoluteY)
+        } else {
+            (AddressingMode::ZeroPage, AddressingMode::Absolute)
+        };
+        if zero_page {
+            vec![AddressingMode::Relative, short, long]
+        } else {
+            vec![AddressingMode::Relative, long, short]
+        }
+    };
+
+    candidates
+        .into_iter()
+        .find_map(find)
+        .ok_or_else(|| format!("{mnemonic} can't take the operand \"{operand}\""))
+}
+
+/// The expression inside an operand, without `#`, brackets or index
+fn operand_expression(operand: &str) -> String {
+    let mut text = operand.trim();
+    if text.eq_ignore_ascii_case("a") {
+        return String::new();
+    }
+    text = text.strip_prefix('#').unwrap_or(text);
+    for suffix in [",x)", "),y", ",x", ",y", ")"] {
+        if text.len() >= suffix.len()
+            && text[text.len() - suffix.len()..].eq_ignore_ascii_case(suffix)
+        {
+            text = &text[..text.len() - suffix.len()];
+            break;
+        }
+    }
+    text.strip_prefix('(').unwrap_or(text).trim().to_string()
+}
+
+/// Evaluate `term (+|- term)*`
+fn evaluate(text: &str, symbols: &SymbolTable, pc: u16) -> Result<u16, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err(String::from("missing operand"));
+    }
+
+    let mut total: u16 = 0;
+    let mut sign = '+';
+    let mut rest = text;
+    loop {
+        // A term runs to the next + or - that isn't part of a character literal
+        let end = rest
+            .char_indices()
+            .skip(1)
+            .find(|&(i, c)| (c == '+' || c == '-') && !rest[..i].ends_with('\''))
+            .map_or(rest.len(), |(i, _)| i);
+        let value = term(rest[..end].trim(), symbols, pc)?;
+        total = if sign == '+' {
+            total.wrapping_add(value)
+        } else {
+            total.wrapping_sub(value)
+        };
+        if end == rest.len() {
+            return Ok(total);
+        }
+        sign = rest[end..].chars().next().unwrap_or('+');
+        rest = &rest[end + 1..];
+    }
+}
+
+fn term(text: &str, symbols: &SymbolTable, pc: u16) -> Result<u16, String> {
+    if let Some(inner) = text.strip_prefix('<') {
+        return Ok(term(inner.trim(), symbols, pc)? & 0xFF);
+    }
+    if let Some(inner) = text.strip_prefix('>') {
+        return Ok(term(inner.trim(), symbols, pc)? >> 8);
+    }
+    if text == "*" {
+        return Ok(pc);
+    }
+    if let Some(c) = text.strip_prefix('\'').and_then(|t| t.strip_suffix('\''))
+        && c.len() == 1
+    {
+        return Ok(c.as_bytes()[0] as u16);
+    }
+    if let Some(value) = parse_number(text) {
+        return Ok(value);
+    }
+    if is_identifier(text) {
+        return symbols
+            .address_of(text)
+            .ok_or_else(|| format!("undefined symbol {text}"));
+    }
+    Err(format!("invalid expression \"{text}\""))
+}
+
+fn define(symbols: &mut SymbolTable, line: usize, name: &str, value: u16) -> Result<(), AsmError> {
+    if symbols.address_of(name).is_some() {
+        return Err(error(line, format!("{name} is already defined")));
+    }
+    symbols.insert(name, value);
+    Ok(())
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The text of a `"..."` literal
+fn string_literal(text: &str) -> Option<&str> {
+    text.strip_prefix('"')?.strip_suffix('"')
+}
+
+/// The line without a `;` comment, ignoring semicolons in quotes
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (';', None) => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Split a comma-separated list, keeping commas inside quotes
+fn split_list(text: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut item = String::new();
+    let mut quote = None;
+    for c in text.chars() {
+        match (c, quote) {
+            ('"' | '\'', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (',', None) => {
+                items.push(item.trim().to_string());
+                item.clear();
+                continue;
+            }
+            _ => {}
+        }
+        item.push(c);
+    }
+    if !item.trim().is_empty() {
+        items.push(item.trim().to_string());
+    }
+    items
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(source: &str) -> Vec<u8> {
+        let program = assemble(source).unwrap();
+        program
+            .chunks
+            .into_iter()
+            .flat_map(|chunk| chunk.bytes)
+            .collect()
+    }
+
+    #[test]
+    fn test_addressing_modes() {
+        let source = "
+            ptr = $20
+            .org $8000
+            start:
+                lda #$10
+                lda ptr
+                lda ptr,x
+                ldx ptr,y
+                lda $1234
+                sta $0200,x
+                lda $0200,y
+                lda (ptr,x)
+                sta (ptr),y
+                jmp (vector)
+                asl
+                asl a
+                nop
+            loop:
+                bne loop
+                jsr start
+            vector:
+        ";
+        assert_eq!(
+            bytes(source),
+            [
+                0xA9, 0x10, 0xA5, 0x20, 0xB5, 0x20, 0xB6, 0x20, 0xAD, 0x34, 0x12, 0x9D, 0x00, 0x02,
+                0xB9, 0x00, 0x02, 0xA1, 0x20, 0x91, 0x20, 0x6C, 0x20, 0x80, 0x0A, 0x0A, 0xEA, 0xD0,
+                0xFE, 0x20, 0x00, 0x80,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_data_and_expressions() {
+        let source = "
+            .org $C000
+            table: .byte 1, $02, %11, 'A', \"Hi; there\", <table, >table
+            .word table+2, * - 1
+            .res 2, $EA
+        ";
+        let program = assemble(source).unwrap();
+        assert_eq!(program.symbols.address_of("table"), Some(0xC000));
+        assert_eq!(
+            bytes(source),
+            [
+                1, 2, 3, 0x41, b'H', b'i', b';', b' ', b't', b'h', b'e', b'r', b'e', 0x00, 0xC0,
+                0x02, 0xC0, 0x0E, 0xC0, 0xEA, 0xEA,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forward_references_are_absolute() {
+        // `data` is only known on the second pass, so it stays absolute
+        let source = ".org $0010\nlda data\ndata: .byte 0\nlda data\n";
+        assert_eq!(bytes(source), [0xAD, 0x13, 0x00, 0x00, 0xA5, 0x13]);
+    }
+
+    #[test]
+    fn test_segments_and_image() {
+        let source = "
+            .segment \"ZEROPAGE\"
+            counter: .res 1
+            .segment \"CODE\"
+            reset: inc counter
+                   jmp reset
+            .segment \"VECTORS\"
+            .word reset, reset, reset
+        ";
+        let program = assemble(source).unwrap();
+        let image = program.image(0x8000, 0x00).unwrap();
+        assert_eq!(image.len(), 0x8000);
+        assert_eq!(image[..5], [0xE6, 0x00, 0x4C, 0x00, 0x80]);
+        assert_eq!(image[0x7FFA..], [0x00, 0x80, 0x00, 0x80, 0x00, 0x80]);
+
+        let low = assemble(".org $0300\nnop\n").unwrap();
+        assert_eq!(
+            low.image(0x8000, 0).unwrap_err().to_string(),
+            "line 2: $0300 is outside the 32768-byte image ($8000-$FFFF)"
+        );
+    }
+
+    #[test]
+    fn test_examples_match_cc65_output() {
+        for (source, binary) in [
+            (
+                include_str!("../examples/count.s"),
+                &include_bytes!("../examples/count.bin")[..],
+            ),
+            (
+                include_str!("../examples/hello.s"),
+                &include_bytes!("../examples/hello.bin")[..],
+            ),
+        ] {
+            let program = assemble(source).unwrap();
+            assert_eq!(program.image(binary.len(), 0x00).unwrap(), binary);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let message = |source: &str| assemble(source).unwrap_err().to_string();
+        assert_eq!(
+            message("lda #1\nfoo $10\n"),
+            "line 2: unknown instruction FOO"
+        );
+        assert_eq!(message("jmp nowhere\n"), "line 1: undefined symbol nowhere");
+        assert_eq!(message("a:\na:\n"), "line 2: a is already defined");
+        assert_eq!(
+            message("stx $1234,x\n"),
+            "line 1: STX can't take the operand \"$1234,x\""
+        );
+        assert_eq!(
+            message(".org $8000\nbeq far\n.res 200\nfar: rts\n"),
+            "line 2: branch to $80CA is out of range"
+        );
+        assert_eq!(
+            message(".segment \"BSS\"\nbuffer: .byte 1\n"),
+            "line 2: only .res is allowed in ZEROPAGE and BSS"
+        );
+        assert_eq!(
+            message(".segment \"DATA\"\n"),
+            "line 1: unknown segment \"DATA\""
+        );
+    }
+}
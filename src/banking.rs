@@ -0,0 +1,770 @@
+//! Bank-switched memory
+//!
+//! Reusable pieces for boards whose ROM/RAM is larger than the window the CPU
+//! can see. Everything here implements `Device`, so it can be mapped into a
+//! `MappedBus` or embedded in a hand-written `Bus`.
+//!
+//! - `BankedMemory`: a ROM or RAM window whose bank is picked through a shared
+//!   `BankSelect`, typically driven by a `BankRegister` mapped elsewhere
+//! - `UxRom`, `CnRom`, `Mmc1`: NES cartridge mappers
+//! - `C64Memory`: C64 RAM/ROM/IO banking controlled by the $00/$01 processor port
+
+use std::cell::Cell;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::devices::Device;
+
+pub const WINDOW_8K: usize = 0x2000;
+pub const WINDOW_16K: usize = 0x4000;
+
+/// Shared bank number. Cloning gives another handle to the same selection,
+/// so a control register and the window it controls can live at different
+/// addresses.
+#[derive(Debug, Clone, Default)]
+pub struct BankSelect(Rc<Cell<usize>>);
+
+impl BankSelect {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self) -> usize {
+        self.0.get()
+    }
+
+    pub fn set(&self, bank: usize) {
+        self.0.set(bank);
+    }
+}
+
+/// A window onto one of several equally sized banks.
+pub struct BankedMemory {
+    data: Vec<u8>,
+    window_size: usize,
+    bank_count: usize,
+    select: BankSelect,
+    writable: bool,
+}
+
+impl BankedMemory {
+    /// Read-only banks cut from `data`. A partial last bank is padded with $FF.
+    pub fn rom(window_size: usize, data: &[u8]) -> Self {
+        assert!(window_size > 0, "banked ROM needs a window size above zero");
+        let bank_count = data.len().div_ceil(window_size).max(1);
+        let mut data = data.to_vec();
+        data.resize(bank_count * window_size, 0xFF);
+        Self {
+            data,
+            window_size,
+            bank_count,
+            select: BankSelect::new(),
+            writable: false,
+        }
+    }
+
+    /// Zero-initialised RAM banks
+    pub fn ram(window_size: usize, bank_count: usize) -> Self {
+        assert!(
+            window_size > 0 && bank_count > 0,
+            "banked RAM needs a window size and bank count above zero, not {window_size} x {bank_count}"
+        );
+        Self {
+            data: vec![0; window_size * bank_count],
+            window_size,
+            bank_count,
+            select: BankSelect::new(),
+            writable: true,
+        }
+    }
+
+    /// Share a bank selection with other components
+    pub fn with_select(mut self, select: BankSelect) -> Self {
+        self.select = select;
+        self
+    }
+
+    /// Handle to this window's bank selection
+    pub fn selector(&self) -> BankSelect {
+        self.select.clone()
+    }
+
+    pub fn window_size(&self) -> usize {
+        self.window_size
+    }
+
+    pub fn bank_count(&self) -> usize {
+        self.bank_count
+    }
+
+    /// Currently visible bank. Out-of-range selections wrap around, like the
+    /// unused high bits of a real latch.
+    pub fn bank(&self) -> usize {
+        self.select.get() % self.bank_count
+    }
+
+    pub fn select_bank(&mut self, bank: usize) {
+        self.select.set(bank);
+    }
+
+    fn index(&self, offset: u16) -> usize {
+        self.bank() * self.window_size + offset as usize % self.window_size
+    }
+}
+
+impl Device for BankedMemory {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.data[self.index(offset)]
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        if self.writable {
+            let index = self.index(offset);
+            self.data[index] = value;
+        }
+    }
+}
+
+/// Write-only latch that selects a bank. Reads return the current selection.
+pub struct BankRegister {
+    select: BankSelect,
+    mask: u8,
+}
+
+impl BankRegister {
+    /// Only bits set in `mask` take part in the selection
+    pub fn new(select: BankSelect, mask: u8) -> Self {
+        Self { select, mask }
+    }
+}
+
+impl Device for BankRegister {
+    fn read(&mut self, _offset: u16) -> u8 {
+        self.select.get() as u8
+    }
+
+    fn write(&mut self, _offset: u16, value: u8) {
+        self.select.set((value & self.mask) as usize);
+    }
+}
+
+/// Build a switchable ROM window and the register that controls it.
+///
+/// Covers the common single-latch 16KB or 8KB schemes: map the memory at the
+/// window address and the register at any free address.
+pub fn simple_switcher(window_size: usize, data: &[u8]) -> (BankedMemory, BankRegister) {
+    let memory = BankedMemory::rom(window_size, data);
+    let register = BankRegister::new(memory.selector(), 0xFF);
+    (memory, register)
+}
+
+/// Nametable mirroring reported by NES mappers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    OneScreenLower,
+    OneScreenUpper,
+    Vertical,
+    Horizontal,
+}
+
+/// NES UxROM (mapper 2). Map at $8000 with size $8000.
+///
+/// $8000-$BFFF is a switchable 16KB bank, $C000-$FFFF is fixed to the last
+/// bank. Any write to $8000-$FFFF selects the bank.
+pub struct UxRom {
+    prg: BankedMemory,
+}
+
+impl UxRom {
+    pub fn new(prg: &[u8]) -> Self {
+        Self {
+            prg: BankedMemory::rom(WINDOW_16K, prg),
+        }
+    }
+
+    pub fn bank(&self) -> usize {
+        self.prg.bank()
+    }
+}
+
+impl Device for UxRom {
+    fn read(&mut self, offset: u16) -> u8 {
+        if (offset as usize) < WINDOW_16K {
+            self.prg.read(offset)
+        } else {
+            let last = self.prg.bank_count() - 1;
+            self.prg.data[last * WINDOW_16K + (offset as usize - WINDOW_16K)]
+        }
+    }
+
+    fn write(&mut self, _offset: u16, value: u8) {
+        self.prg.select_bank(value as usize);
+    }
+}
+
+/// NES CNROM (mapper 3). Map at $8000 with size $8000.
+///
+/// PRG is a fixed 16KB (mirrored) or 32KB image. Writes to $8000-$FFFF pick
+/// the 8KB CHR bank the PPU sees through `chr_read`.
+pub struct CnRom {
+    prg: Vec<u8>,
+    chr: BankedMemory,
+}
+
+impl CnRom {
+    pub fn new(prg: &[u8], chr: &[u8]) -> Self {
+        Self {
+            prg: prg.to_vec(),
+            chr: BankedMemory::rom(WINDOW_8K, chr),
+        }
+    }
+
+    pub fn chr_bank(&self) -> usize {
+        self.chr.bank()
+    }
+
+    /// PPU read from $0000-$1FFF
+    pub fn chr_read(&mut self, address: u16) -> u8 {
+        self.chr.read(address)
+    }
+}
+
+impl Device for CnRom {
+    fn read(&mut self, offset: u16) -> u8 {
+        if self.prg.is_empty() {
+            return 0xFF;
+        }
+        self.prg[offset as usize % self.prg.len()]
+    }
+
+    fn write(&mut self, _offset: u16, value: u8) {
+        self.chr.select_bank(value as usize);
+    }
+}
+
+/// NES MMC1 (mapper 1). Map at $6000 with size $A000.
+///
+/// $6000-$7FFF is 8KB of PRG RAM, $8000-$FFFF is PRG ROM. Registers are
+/// loaded one bit at a time through a 5-bit shift register; writing a value
+/// with bit 7 set resets it.
+pub struct Mmc1 {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    chr_writable: bool,
+    prg_ram: Vec<u8>,
+    shift: u8,
+    control: u8,
+    chr_bank0: u8,
+    chr_bank1: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    const SHIFT_RESET: u8 = 0x10;
+    const PRG_RAM_SIZE: usize = 0x2000;
+
+    /// An empty `chr` gives the cartridge 8KB of CHR RAM
+    pub fn new(prg: &[u8], chr: &[u8]) -> Self {
+        let (chr, chr_writable) = if chr.is_empty() {
+            (vec![0; WINDOW_8K], true)
+        } else {
+            (chr.to_vec(), false)
+        };
+        Self {
+            prg: prg.to_vec(),
+            chr,
+            chr_writable,
+            prg_ram: vec![0; Self::PRG_RAM_SIZE],
+            shift: Self::SHIFT_RESET,
+            // Power-on state fixes the last bank at $C000
+            control: 0x0C,
+            chr_bank0: 0,
+            chr_bank1: 0,
+            prg_bank: 0,
+        }
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::OneScreenLower,
+            1 => Mirroring::OneScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn prg_banks(&self) -> usize {
+        (self.prg.len() / WINDOW_16K).max(1)
+    }
+
+    /// Map a CPU address in $8000-$FFFF to an offset into PRG ROM
+    fn prg_index(&self, address: u16) -> usize {
+        let bank = (self.prg_bank & 0x0F) as usize;
+        let offset = (address as usize) & (WINDOW_16K - 1);
+        let upper = address >= 0xC000;
+
+        let bank = match (self.control >> 2) & 0x03 {
+            // 32KB mode ignores the low bit of the bank number
+            0 | 1 => (bank & !1) + upper as usize,
+            // First bank fixed at $8000, switch $C000
+            2 => {
+                if upper {
+                    bank
+                } else {
+                    0
+                }
+            }
+            // Last bank fixed at $C000, switch $8000
+            _ => {
+                if upper {
+                    self.prg_banks() - 1
+                } else {
+                    bank
+                }
+            }
+        };
+        (bank % self.prg_banks()) * WINDOW_16K + offset
+    }
+
+    fn chr_index(&self, address: u16) -> usize {
+        let address = address as usize & 0x1FFF;
+        let index = if self.control & 0x10 == 0 {
+            // One 8KB bank
+            (self.chr_bank0 as usize & !1) * 0x1000 + address
+        } else if address < 0x1000 {
+            self.chr_bank0 as usize * 0x1000 + address
+        } else {
+            self.chr_bank1 as usize * 0x1000 + (address - 0x1000)
+        };
+        index % self.chr.len()
+    }
+
+    /// PPU read from $0000-$1FFF
+    pub fn chr_read(&self, address: u16) -> u8 {
+        self.chr[self.chr_index(address)]
+    }
+
+    /// PPU write to $0000-$1FFF (only has an effect with CHR RAM)
+    pub fn chr_write(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            let index = self.chr_index(address);
+            self.chr[index] = value;
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0x10 == 0
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000..=0x9FFF => self.control = value,
+            0xA000..=0xBFFF => self.chr_bank0 = value,
+            0xC000..=0xDFFF => self.chr_bank1 = value,
+            _ => self.prg_bank = value,
+        }
+    }
+}
+
+impl Device for Mmc1 {
+    fn read(&mut self, offset: u16) -> u8 {
+        let address = 0x6000 + offset;
+        if address < 0x8000 {
+            if self.prg_ram_enabled() {
+                self.prg_ram[offset as usize]
+            } else {
+                0xFF
+            }
+        } else if self.prg.is_empty() {
+            0xFF
+        } else {
+            self.prg[self.prg_index(address)]
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        let address = 0x6000 + offset;
+        if address < 0x8000 {
+            if self.prg_ram_enabled() {
+                self.prg_ram[offset as usize] = value;
+            }
+            return;
+        }
+
+        if value & 0x80 != 0 {
+            self.shift = Self::SHIFT_RESET;
+            self.control |= 0x0C;
+            return;
+        }
+
+        // The marker bit reaching bit 0 means this is the fifth write
+        let complete = self.shift & 1 != 0;
+        self.shift = (self.shift >> 1) | ((value & 1) << 4);
+        if complete {
+            let register = self.shift;
+            self.write_register(address, register);
+            self.shift = Self::SHIFT_RESET;
+        }
+    }
+}
+
+/// What the CPU sees at an address on a C64
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum C64Bank {
+    Ram,
+    BasicRom,
+    KernalRom,
+    CharRom,
+    Io,
+}
+
+/// A device in the C64 I/O area
+struct IoSlot {
+    start: u16,
+    size: usize,
+    device: Box<dyn Device>,
+}
+
+/// C64 memory with processor-port banking. Map at $0000 with size $10000.
+///
+/// $00 is the port's data direction register and $01 its data register.
+/// LORAM (bit 0), HIRAM (bit 1) and CHAREN (bit 2) choose whether BASIC,
+/// KERNAL, the character ROM or I/O hide the RAM underneath. Writes to a ROM
+/// area always land in RAM. Cartridge lines (GAME/EXROM) are not modelled.
+///
+/// Devices in the I/O area are ticked, reset and heard on IRQ/NMI whether
+/// or not I/O is banked in.
+pub struct C64Memory {
+    ram: Vec<u8>,
+    basic: Vec<u8>,
+    kernal: Vec<u8>,
+    chargen: Vec<u8>,
+    io: Vec<IoSlot>,
+    ddr: u8,
+    port: u8,
+}
+
+impl C64Memory {
+    /// `basic` and `kernal` are 8KB images, `chargen` is 4KB
+    pub fn new(basic: &[u8], kernal: &[u8], chargen: &[u8]) -> Self {
+        let rom = |data: &[u8], size: usize| {
+            let mut rom = data.to_vec();
+            rom.resize(size, 0xFF);
+            rom
+        };
+        Self {
+            ram: vec![0; 0x10000],
+            basic: rom(basic, 0x2000),
+            kernal: rom(kernal, 0x2000),
+            chargen: rom(chargen, 0x1000),
+            io: Vec::new(),
+            // KERNAL initialises the port to this configuration
+            ddr: 0x2F,
+            port: 0x37,
+        }
+    }
+
+    /// Device that handles $D000-$DFFF when I/O is banked in
+    pub fn with_io<D: Device + 'static>(self, io: D) -> Self {
+        self.with_io_at(0xD000, 0x1000, io)
+    }
+
+    /// Device that handles `size` bytes from `address` in the I/O area, such
+    /// as a CIA at $DC00. The range must lie inside $D000-$DFFF; addresses
+    /// no device covers read $FF.
+    pub fn with_io_at<D: Device + 'static>(self, address: u16, size: usize, io: D) -> Self {
+        self.with_boxed_io_at(address, size, Box::new(io))
+    }
+
+    pub fn with_boxed_io_at(mut self, address: u16, size: usize, io: Box<dyn Device>) -> Self {
+        assert!(
+            address >= 0xD000 && size > 0 && address as usize + size <= 0xE000,
+            "I/O device at ${address:04X} with size {size} is outside $D000-$DFFF"
+        );
+        self.io.push(IoSlot {
+            start: address,
+            size,
+            device: io,
+        });
+        self
+    }
+
+    fn io_slot(&mut self, address: u16) -> Option<&mut IoSlot> {
+        self.io.iter_mut().find(|slot| {
+            (slot.start as usize..slot.start as usize + slot.size).contains(&(address as usize))
+        })
+    }
+
+    /// Port value as seen by the banking logic. Bits configured as inputs
+    /// are pulled high.
+    fn port_lines(&self) -> u8 {
+        (self.port & self.ddr) | !self.ddr
+    }
+
+    pub fn bank_at(&self, address: u16) -> C64Bank {
+        let lines = self.port_lines();
+        let loram = lines & 0x01 != 0;
+        let hiram = lines & 0x02 != 0;
+        let charen = lines & 0x04 != 0;
+
+        match address {
+            0xA000..=0xBFFF if loram && hiram => C64Bank::BasicRom,
+            0xE000..=0xFFFF if hiram => C64Bank::KernalRom,
+            0xD000..=0xDFFF if loram || hiram => {
+                if charen {
+                    C64Bank::Io
+                } else {
+                    C64Bank::CharRom
+                }
+            }
+            _ => C64Bank::Ram,
+        }
+    }
+}
+
+impl Device for C64Memory {
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset {
+            0x0000 => return self.ddr,
+            0x0001 => return self.port_lines(),
+            _ => {}
+        }
+
+        match self.bank_at(offset) {
+            C64Bank::Ram => self.ram[offset as usize],
+            C64Bank::BasicRom => self.basic[(offset - 0xA000) as usize],
+            C64Bank::KernalRom => self.kernal[(offset - 0xE000) as usize],
+            C64Bank::CharRom => self.chargen[(offset - 0xD000) as usize],
+            C64Bank::Io => match self.io_slot(offset) {
+                Some(slot) => slot.device.read(offset - slot.start),
+                None => 0xFF,
+            },
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset {
+            0x0000 => self.ddr = value,
+            0x0001 => self.port = value,
+            _ => {}
+        }
+
+        if self.bank_at(offset) == C64Bank::Io {
+            if let Some(slot) = self.io_slot(offset) {
+                slot.device.write(offset - slot.start, value);
+            }
+        } else {
+            self.ram[offset as usize] = value;
+        }
+    }
+
+    fn tick(&mut self) {
+        for slot in &mut self.io {
+            slot.device.tick();
+        }
+    }
+
+    /// RES clears the port's direction register, so every line floats high
+    /// and BASIC, KERNAL and I/O are banked in for the reset vector
+    fn reset(&mut self) {
+        self.ddr = 0;
+        self.port = 0;
+        for slot in &mut self.io {
+            slot.device.reset();
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.io.iter().any(|slot| slot.device.irq())
+    }
+
+    fn nmi(&self) -> bool {
+        self.io.iter().any(|slot| slot.device.nmi())
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.io.iter().any(|slot| slot.device.interrupts_enabled())
+    }
+
+    fn serial_path(&self) -> Option<PathBuf> {
+        self.io.iter().find_map(|slot| slot.device.serial_path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::devices::Timer;
+    use crate::devices::timer::control;
+    use crate::memory_map::MemoryMapBuilder;
+
+    /// ROM where every byte holds its bank number
+    fn banked_image(window: usize, banks: usize) -> Vec<u8> {
+        (0..banks)
+            .flat_map(|bank| std::iter::repeat_n(bank as u8, window))
+            .collect()
+    }
+
+    #[test]
+    fn test_simple_switcher_on_mapped_bus() {
+        let (window, register) = simple_switcher(WINDOW_16K, &banked_image(WINDOW_16K, 4));
+        let mut bus = MemoryMapBuilder::new()
+            .device(0x8000, WINDOW_16K, window)
+            .device(0x7FFF, 1, register)
+            .build()
+            .unwrap();
+
+        assert_eq!(bus.read(0x8000), 0);
+        bus.write(0x7FFF, 2);
+        assert_eq!(bus.read(0x8123), 2);
+        bus.write(0x7FFF, 5); // wraps to bank 1
+        assert_eq!(bus.read(0xBFFF), 1);
+    }
+
+    #[test]
+    fn test_banked_ram() {
+        let mut ram = BankedMemory::ram(WINDOW_8K, 2);
+        ram.write(0x10, 0xAA);
+        ram.select_bank(1);
+        assert_eq!(ram.read(0x10), 0x00);
+        ram.write(0x10, 0xBB);
+        ram.select_bank(0);
+        assert_eq!(ram.read(0x10), 0xAA);
+    }
+
+    #[test]
+    #[should_panic(expected = "banked ROM needs a window size above zero")]
+    fn test_zero_window() {
+        BankedMemory::rom(0, &[0xEA; 16]);
+    }
+
+    #[test]
+    fn test_uxrom() {
+        let mut mapper = UxRom::new(&banked_image(WINDOW_16K, 8));
+
+        assert_eq!(mapper.read(0x0000), 0);
+        assert_eq!(mapper.read(0x4000), 7); // fixed last bank
+        mapper.write(0x1234, 3);
+        assert_eq!(mapper.bank(), 3);
+        assert_eq!(mapper.read(0x0000), 3);
+        assert_eq!(mapper.read(0x7FFF), 7);
+    }
+
+    #[test]
+    fn test_cnrom() {
+        let prg = vec![0xEA; WINDOW_16K];
+        let mut mapper = CnRom::new(&prg, &banked_image(WINDOW_8K, 4));
+
+        assert_eq!(mapper.read(0x4000), 0xEA); // 16KB PRG mirrored
+        mapper.write(0x0000, 2);
+        assert_eq!(mapper.chr_bank(), 2);
+        assert_eq!(mapper.chr_read(0x0100), 2);
+    }
+
+    /// Load an MMC1 register with five serial writes
+    fn mmc1_write(mapper: &mut Mmc1, address: u16, value: u8) {
+        for bit in 0..5 {
+            mapper.write(address - 0x6000, (value >> bit) & 1);
+        }
+    }
+
+    #[test]
+    fn test_mmc1_prg_modes() {
+        let mut mapper = Mmc1::new(&banked_image(WINDOW_16K, 8), &[]);
+
+        // Power-on: switchable $8000, last bank fixed at $C000
+        assert_eq!(mapper.read(0xC000 - 0x6000), 7);
+        mmc1_write(&mut mapper, 0xE000, 5);
+        assert_eq!(mapper.read(0x8000 - 0x6000), 5);
+
+        // Mode 2: first bank fixed at $8000, switch $C000
+        mmc1_write(&mut mapper, 0x8000, 0x08 | 0x02);
+        assert_eq!(mapper.read(0x8000 - 0x6000), 0);
+        assert_eq!(mapper.read(0xC000 - 0x6000), 5);
+        assert_eq!(mapper.mirroring(), Mirroring::Vertical);
+
+        // 32KB mode ignores the low bank bit
+        mmc1_write(&mut mapper, 0x8000, 0x00);
+        assert_eq!(mapper.read(0x8000 - 0x6000), 4);
+        assert_eq!(mapper.read(0xC000 - 0x6000), 5);
+    }
+
+    #[test]
+    fn test_mmc1_reset_and_prg_ram() {
+        let mut mapper = Mmc1::new(&banked_image(WINDOW_16K, 4), &[]);
+
+        // A partial load is discarded by a reset write
+        mapper.write(0x2000, 1);
+        mapper.write(0x2000, 1);
+        mapper.write(0x2000, 0x80);
+        mmc1_write(&mut mapper, 0xE000, 2);
+        assert_eq!(mapper.read(0x2000), 2);
+
+        mapper.write(0x0010, 0x42);
+        assert_eq!(mapper.read(0x0010), 0x42);
+    }
+
+    #[test]
+    fn test_mmc1_chr_banks() {
+        let mut mapper = Mmc1::new(&[0; WINDOW_16K], &banked_image(0x1000, 4));
+
+        mmc1_write(&mut mapper, 0x8000, 0x10 | 0x0C); // 4KB CHR mode
+        mmc1_write(&mut mapper, 0xA000, 3);
+        mmc1_write(&mut mapper, 0xC000, 1);
+        assert_eq!(mapper.chr_read(0x0000), 3);
+        assert_eq!(mapper.chr_read(0x1000), 1);
+    }
+
+    #[test]
+    fn test_c64_banking() {
+        let mut memory = C64Memory::new(&[0xBA; 0x2000], &[0xEE; 0x2000], &[0xCC; 0x1000]);
+
+        // Default configuration: BASIC, KERNAL and I/O visible
+        assert_eq!(memory.read(0xA000), 0xBA);
+        assert_eq!(memory.read(0xE000), 0xEE);
+        assert_eq!(memory.bank_at(0xD000), C64Bank::Io);
+
+        // Writes under ROM go to RAM
+        memory.write(0xA000, 0x12);
+        assert_eq!(memory.read(0xA000), 0xBA);
+
+        // LORAM off: BASIC is replaced by RAM
+        memory.write(0x0001, 0x36);
+        assert_eq!(memory.read(0xA000), 0x12);
+        assert_eq!(memory.read(0xE000), 0xEE);
+
+        // CHAREN off: character ROM replaces I/O
+        memory.write(0x0001, 0x33);
+        assert_eq!(memory.read(0xD000), 0xCC);
+
+        // All RAM
+        memory.write(0x0001, 0x30);
+        assert_eq!(memory.bank_at(0xE000), C64Bank::Ram);
+        assert_eq!(memory.bank_at(0xD000), C64Bank::Ram);
+    }
+
+    #[test]
+    fn test_c64_io_devices() {
+        let mut memory =
+            C64Memory::new(&[], &[0xEE; 0x2000], &[]).with_io_at(0xDC00, 4, Timer::new());
+        assert_eq!(memory.read(0xDC04), 0xFF);
+
+        memory.write(0xDC00, 2);
+        memory.write(0xDC01, 0);
+        memory.write(0xDC02, control::ENABLE | control::IRQ);
+        assert!(memory.interrupts_enabled());
+
+        // Banked out, the timer still runs and interrupts
+        memory.write(0x0001, 0x30);
+        for _ in 0..10 {
+            memory.tick();
+        }
+        assert!(memory.irq());
+        assert_eq!(memory.read(0xDC02), 0x00);
+
+        memory.reset();
+        assert!(!memory.irq() && !memory.interrupts_enabled());
+        assert_eq!(memory.read(0xFFFC), 0xEE);
+    }
+}
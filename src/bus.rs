@@ -4,6 +4,36 @@
 //! appropriate memory regions (RAM, ROM, memory-mapped I/O devices, etc.)
 //! and coordinating peripheral device updates.
 
+/// Why the CPU is accessing memory.
+///
+/// The CPU announces the kind before each group of accesses through
+/// `Bus::set_access_kind`, so tracing or coverage tools can tell an opcode
+/// fetch from a data read at the same address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AccessKind {
+    /// First byte of an instruction
+    OpcodeFetch,
+    /// Operand bytes following the opcode
+    Operand,
+    /// Loads, stores, read-modify-write and indirect pointer reads
+    Data,
+    /// Pushes and pulls on page 1
+    Stack,
+    /// Reading the NMI, RESET or IRQ/BRK vector
+    Vector,
+}
+
+/// Something a device asks of the emulator itself rather than the CPU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostRequest {
+    /// Stop the emulator with this exit code
+    Exit(u8),
+    /// Print the CPU registers
+    DumpRegisters,
+    /// Reset the CPU and devices, as a reset button would
+    Reset,
+}
+
 /// Bus trait that all system buses must implement.
 ///
 /// This is the primary interface between the CPU and the memory system.
@@ -20,6 +50,35 @@ pub trait Bus {
     }
 
     fn tick(&mut self);
+
+    /// Level of the IRQ line. The CPU polls this before each instruction,
+    /// so devices can hold it asserted until they are serviced.
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// Level of the NMI line. The CPU takes an NMI when it goes active.
+    fn nmi(&self) -> bool {
+        false
+    }
+
+    /// Whether any device is set up to interrupt the CPU
+    fn interrupts_enabled(&self) -> bool {
+        false
+    }
+
+    /// Called when the CPU is reset so peripherals see the RES line too
+    fn reset(&mut self) {}
+
+    /// Take a pending request for the emulator, if a device has made one.
+    /// Front ends poll this between instructions.
+    fn host_request(&mut self) -> Option<HostRequest> {
+        None
+    }
+
+    /// Called by the CPU to describe the accesses that follow.
+    /// Buses that don't care can ignore it.
+    fn set_access_kind(&mut self, _kind: AccessKind) {}
 }
 
 /// Provides 64KB of RAM for testing.
@@ -0,0 +1,317 @@
+//! Bus access tracing
+//!
+//! `TracingBus` wraps another bus and observes every read and write the CPU
+//! makes, tagged with the cycle count and the `AccessKind` the CPU announced.
+//! Accesses are kept in a log and handed to registered hooks, which is the
+//! building block for watchpoints, profilers and coverage.
+//!
+//! The core performs all memory accesses of an instruction in its first
+//! cycle, so every access of one instruction carries the same cycle number.
+
+use crate::bus::{AccessKind, Bus, HostRequest};
+
+/// Direction of a bus access
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AccessType {
+    Read,
+    Write,
+}
+
+/// A single observed bus access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    /// Number of `tick`s seen before the access
+    pub cycle: u64,
+    pub address: u16,
+    pub value: u8,
+    pub access_type: AccessType,
+    pub kind: AccessKind,
+}
+
+/// Handle returned by `TracingBus::add_hook`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HookId(usize);
+
+type Hook = Box<dyn FnMut(&Access)>;
+
+/// A `Bus` adaptor that records accesses to the wrapped bus.
+pub struct TracingBus<B: Bus> {
+    inner: B,
+    cycle: u64,
+    kind: AccessKind,
+    log: Vec<Access>,
+    recording: bool,
+    hooks: Vec<(HookId, Hook)>,
+    next_hook: usize,
+}
+
+impl<B: Bus> TracingBus<B> {
+    pub fn new(inner: B) -> Self {
+        Self {
+            inner,
+            cycle: 0,
+            kind: AccessKind::Data,
+            log: Vec::new(),
+            recording: true,
+            hooks: Vec::new(),
+            next_hook: 0,
+        }
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+
+    /// Cycles elapsed since the bus was created
+    pub fn cycle(&self) -> u64 {
+        self.cycle
+    }
+
+    /// Keep accesses in the log (on by default). Hooks run either way, so
+    /// long runs that only need hooks can switch the log off.
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+    }
+
+    /// Accesses recorded so far
+    pub fn accesses(&self) -> &[Access] {
+        &self.log
+    }
+
+    /// Take the recorded accesses, leaving the log empty
+    pub fn take_accesses(&mut self) -> Vec<Access> {
+        std::mem::take(&mut self.log)
+    }
+
+    pub fn clear(&mut self) {
+        self.log.clear();
+    }
+
+    /// Call `hook` for every access
+    pub fn add_hook<F: FnMut(&Access) + 'static>(&mut self, hook: F) -> HookId {
+        let id = HookId(self.next_hook);
+        self.next_hook += 1;
+        self.hooks.push((id, Box::new(hook)));
+        id
+    }
+
+    /// Returns false if the hook was already removed
+    pub fn remove_hook(&mut self, id: HookId) -> bool {
+        let before = self.hooks.len();
+        self.hooks.retain(|(hook_id, _)| *hook_id != id);
+        self.hooks.len() != before
+    }
+
+    fn record(&mut self, address: u16, value: u8, access_type: AccessType) {
+        let access = Access {
+            cycle: self.cycle,
+            address,
+            value,
+            access_type,
+            kind: self.kind,
+        };
+        for (_, hook) in &mut self.hooks {
+            hook(&access);
+        }
+        if self.recording {
+            self.log.push(access);
+        }
+    }
+}
+
+impl<B: Bus> Bus for TracingBus<B> {
+    fn read(&mut self, address: u16) -> u8 {
+        let value = self.inner.read(address);
+        self.record(address, value, AccessType::Read);
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.inner.write(address, value);
+        self.record(address, value, AccessType::Write);
+    }
+
+    fn tick(&mut self) {
+        self.cycle += 1;
+        self.inner.tick();
+    }
+
+    fn irq(&self) -> bool {
+        self.inner.irq()
+    }
+
+    fn nmi(&self) -> bool {
+        self.inner.nmi()
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.inner.interrupts_enabled()
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+    }
+
+    fn host_request(&mut self) -> Option<HostRequest> {
+        self.inner.host_request()
+    }
+
+    fn set_access_kind(&mut self, kind: AccessKind) {
+        self.kind = kind;
+        self.inner.set_access_kind(kind);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::SimpleBus;
+    use crate::cpu::Cpu;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn setup_cpu(program: &[u8]) -> Cpu<TracingBus<SimpleBus>> {
+        let mut bus = SimpleBus::new();
+        bus.load(0x8000, program);
+        bus.write(0xFFFC, 0x00);
+        bus.write(0xFFFD, 0x80);
+        let mut cpu = Cpu::new(TracingBus::new(bus));
+        cpu.reset();
+        while cpu.cycles > 0 {
+            cpu.step();
+        }
+        cpu
+    }
+
+    fn summary(accesses: &[Access]) -> Vec<(u16, u8, AccessType, AccessKind)> {
+        accesses
+            .iter()
+            .map(|a| (a.address, a.value, a.access_type, a.kind))
+            .collect()
+    }
+
+    #[test]
+    fn test_reset_reads_vector() {
+        let cpu = setup_cpu(&[]);
+        let accesses = cpu.bus.accesses();
+
+        assert_eq!(
+            summary(accesses),
+            vec![
+                (0xFFFC, 0x00, AccessType::Read, AccessKind::Vector),
+                (0xFFFD, 0x80, AccessType::Read, AccessKind::Vector),
+            ]
+        );
+        assert_eq!(cpu.bus.cycle(), 7);
+    }
+
+    #[test]
+    fn test_absolute_load_and_store() {
+        // LDA $1234, STA $10
+        let mut cpu = setup_cpu(&[0xAD, 0x34, 0x12, 0x85, 0x10]);
+        cpu.bus.inner_mut().write(0x1234, 0x99);
+        cpu.bus.clear();
+
+        cpu.execute_instruction();
+        cpu.execute_instruction();
+
+        assert_eq!(
+            summary(cpu.bus.accesses()),
+            vec![
+                (0x8000, 0xAD, AccessType::Read, AccessKind::OpcodeFetch),
+                (0x8001, 0x34, AccessType::Read, AccessKind::Operand),
+                (0x8002, 0x12, AccessType::Read, AccessKind::Operand),
+                (0x1234, 0x99, AccessType::Read, AccessKind::Data),
+                (0x8003, 0x85, AccessType::Read, AccessKind::OpcodeFetch),
+                (0x8004, 0x10, AccessType::Read, AccessKind::Operand),
+                (0x0010, 0x99, AccessType::Write, AccessKind::Data),
+            ]
+        );
+
+        // LDA abs takes 4 cycles, so STA starts on cycle 7 + 4
+        assert_eq!(cpu.bus.accesses()[4].cycle, 11);
+    }
+
+    #[test]
+    fn test_immediate_operand_kind() {
+        // LDA #$42
+        let mut cpu = setup_cpu(&[0xA9, 0x42]);
+        cpu.bus.clear();
+
+        cpu.execute_instruction();
+        assert_eq!(
+            summary(cpu.bus.accesses()),
+            vec![
+                (0x8000, 0xA9, AccessType::Read, AccessKind::OpcodeFetch),
+                (0x8001, 0x42, AccessType::Read, AccessKind::Operand),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jsr_and_brk_kinds() {
+        // JSR $8004, (pad), BRK
+        let mut cpu = setup_cpu(&[0x20, 0x04, 0x80, 0xEA, 0x00]);
+        cpu.bus.clear();
+
+        cpu.execute_instruction();
+        let kinds: Vec<AccessKind> = cpu.bus.take_accesses().iter().map(|a| a.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                AccessKind::OpcodeFetch,
+                AccessKind::Operand,
+                AccessKind::Operand,
+                AccessKind::Stack,
+                AccessKind::Stack,
+            ]
+        );
+
+        cpu.execute_instruction();
+        let kinds: Vec<AccessKind> = cpu.bus.accesses().iter().map(|a| a.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                AccessKind::OpcodeFetch,
+                AccessKind::Stack,
+                AccessKind::Stack,
+                AccessKind::Stack,
+                AccessKind::Vector,
+                AccessKind::Vector,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hooks() {
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        // LDA #$01, STA $0200, STA $0201
+        let mut cpu = setup_cpu(&[0xA9, 0x01, 0x8D, 0x00, 0x02, 0x8D, 0x01, 0x02]);
+        cpu.bus.clear();
+        cpu.bus.set_recording(false);
+
+        let sink = writes.clone();
+        let id = cpu.bus.add_hook(move |access| {
+            if access.access_type == AccessType::Write {
+                sink.borrow_mut().push(access.address);
+            }
+        });
+
+        cpu.execute_instruction();
+        cpu.execute_instruction();
+        assert!(cpu.bus.remove_hook(id));
+        cpu.execute_instruction();
+
+        assert_eq!(*writes.borrow(), vec![0x0200]);
+        assert!(cpu.bus.accesses().is_empty());
+        assert!(!cpu.bus.remove_hook(id));
+    }
+}
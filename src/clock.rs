@@ -0,0 +1,323 @@
+//! Real-time pacing at a target clock frequency
+//!
+//! `Throttle` keeps a CPU running at its clock rate in wall-clock time. The
+//! front end asks for a budget of cycles, runs whole instructions until it
+//! is spent, reports the cycles that actually ran and asks again; `wait`
+//! sleeps until the next slice of cycles is due. An instruction that runs
+//! past the budget is paid back by waiting longer next time, so the average
+//! rate stays on target.
+//!
+//! A host that falls far behind (a slow terminal, the process stopped in
+//! the background) drops the backlog instead of running flat out to catch
+//! up, so time only ever runs slow, never in bursts.
+//!
+//! ```no_run
+//! use mos6502::clock::{Speed, Throttle};
+//! # use mos6502::{Cpu, bus::SimpleBus};
+//! # let mut cpu = Cpu::new(SimpleBus::new());
+//!
+//! let mut throttle = Throttle::new(1_022_727, Speed::Scaled(1.0));
+//! loop {
+//!     let mut budget = throttle.wait();
+//!     while budget > 0 {
+//!         let before = cpu.total_cycles;
+//!         cpu.execute_instruction();
+//!         let cycles = cpu.total_cycles - before;
+//!         throttle.spend(cycles, std::time::Instant::now());
+//!         budget = budget.saturating_sub(cycles);
+//!     }
+//! }
+//! ```
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Wall-clock time between budgets
+pub const SLICE: Duration = Duration::from_millis(10);
+/// A backlog longer than this is dropped
+const MAX_LAG: Duration = Duration::from_millis(100);
+/// Time over which the effective speed is measured
+const WINDOW: Duration = Duration::from_millis(500);
+/// Cycles per budget when unthrottled, so front ends still get control back
+const TURBO_BUDGET: u64 = 100_000;
+/// Longest wait for a slice, however slow the clock
+const LONGEST_WAIT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Slowest speed factor `Speed::parse` accepts
+pub const MIN_FACTOR: f64 = 1e-6;
+
+/// How fast to run relative to the clock
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    /// The clock times a factor: 1.0 is real time, 0.01 is slow motion
+    Scaled(f64),
+    /// As fast as the host allows
+    Unthrottled,
+}
+
+impl Speed {
+    /// Parse `turbo`/`max`, or a factor such as `1`, `0.25` or `25%`, no
+    /// smaller than `MIN_FACTOR`
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        if text.eq_ignore_ascii_case("turbo") || text.eq_ignore_ascii_case("max") {
+            return Some(Speed::Unthrottled);
+        }
+        let factor = match text.strip_suffix('%') {
+            Some(percent) => percent.trim().parse::<f64>().ok()? / 100.0,
+            None => text.parse::<f64>().ok()?,
+        };
+        (factor.is_finite() && factor >= MIN_FACTOR).then_some(Speed::Scaled(factor))
+    }
+}
+
+impl std::fmt::Display for Speed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Speed::Scaled(factor) => write!(f, "{}%", (factor * 100_000.0).round() / 1000.0),
+            Speed::Unthrottled => write!(f, "turbo"),
+        }
+    }
+}
+
+/// Paces a CPU at `clock_hz` times its speed factor
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    clock_hz: u32,
+    speed: Speed,
+    /// Start of the current pacing period
+    origin: Instant,
+    /// Cycles run since `origin`
+    cycles: u64,
+    meter_start: Instant,
+    meter_cycles: u64,
+    effective_hz: Option<f64>,
+}
+
+impl Throttle {
+    pub fn new(clock_hz: u32, speed: Speed) -> Self {
+        Self::starting_at(clock_hz, speed, Instant::now())
+    }
+
+    /// A throttle whose first cycle is due at `now`
+    pub fn starting_at(clock_hz: u32, speed: Speed, now: Instant) -> Self {
+        Self {
+            clock_hz: clock_hz.max(1),
+            speed,
+            origin: now,
+            cycles: 0,
+            meter_start: now,
+            meter_cycles: 0,
+            effective_hz: None,
+        }
+    }
+
+    pub fn clock_hz(&self) -> u32 {
+        self.clock_hz
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// Cycles per second being aimed for; `None` when unthrottled
+    pub fn target_hz(&self) -> Option<f64> {
+        match self.speed {
+            Speed::Scaled(factor) => Some(self.clock_hz as f64 * factor),
+            Speed::Unthrottled => None,
+        }
+    }
+
+    /// Change speed from `now` on, forgetting any backlog
+    pub fn set_speed(&mut self, speed: Speed, now: Instant) {
+        self.speed = speed;
+        self.restart(now);
+    }
+
+    /// Start pacing afresh, e.g. after the machine was paused
+    pub fn restart(&mut self, now: Instant) {
+        self.origin = now;
+        self.cycles = 0;
+    }
+
+    /// Cycles that may run by `now`
+    pub fn budget(&mut self, now: Instant) -> u64 {
+        let Some(rate) = self.target_hz() else {
+            return TURBO_BUDGET;
+        };
+        let elapsed = now.saturating_duration_since(self.origin).as_secs_f64();
+        let due = elapsed * rate;
+        let max_lag = rate * MAX_LAG.as_secs_f64();
+        if due - self.cycles as f64 > max_lag {
+            // Too far behind to catch up smoothly: keep one slice's worth
+            let keep = (rate * SLICE.as_secs_f64()).max(1.0);
+            self.origin = now.checked_sub(wait(keep / rate)).unwrap_or(now);
+            self.cycles = 0;
+            return keep as u64;
+        }
+        (due - self.cycles as f64).max(0.0) as u64
+    }
+
+    /// Record `cycles` that ran, finishing at `now`
+    pub fn spend(&mut self, cycles: u64, now: Instant) {
+        self.cycles += cycles;
+        self.meter_cycles += cycles;
+        let elapsed = now.saturating_duration_since(self.meter_start);
+        if elapsed >= WINDOW {
+            self.effective_hz = Some(self.meter_cycles as f64 / elapsed.as_secs_f64());
+            self.meter_start = now;
+            self.meter_cycles = 0;
+        }
+    }
+
+    /// When the next slice of cycles is due; `None` when unthrottled
+    pub fn next_due(&self) -> Option<Instant> {
+        let rate = self.target_hz()?;
+        let slice = (rate * SLICE.as_secs_f64()).max(1.0);
+        let seconds = (self.cycles as f64 + slice) / rate;
+        Some(self.origin + wait(seconds))
+    }
+
+    /// Sleep until the next slice is due and return its budget
+    pub fn wait(&mut self) -> u64 {
+        if let Some(due) = self.next_due() {
+            let now = Instant::now();
+            if due > now {
+                thread::sleep(due - now);
+            }
+        }
+        self.budget(Instant::now())
+    }
+
+    /// Clock rate actually achieved over the last half second or so
+    pub fn effective_hz(&self) -> Option<f64> {
+        self.effective_hz
+    }
+}
+
+/// `seconds` as a wait, no longer than `LONGEST_WAIT`
+fn wait(seconds: f64) -> Duration {
+    Duration::try_from_secs_f64(seconds).map_or(LONGEST_WAIT, |wait| wait.min(LONGEST_WAIT))
+}
+
+/// Parse a frequency: `1.0227MHz`, `985kHz`, `1000000Hz`, or a bare number
+/// of MHz such as `1.79`
+pub fn parse_frequency(text: &str) -> Option<u32> {
+    let text = text.trim();
+    let lower = text.to_ascii_lowercase();
+    let (number, scale) = if let Some(n) = lower.strip_suffix("mhz") {
+        (n, 1e6)
+    } else if let Some(n) = lower.strip_suffix("khz") {
+        (n, 1e3)
+    } else if let Some(n) = lower.strip_suffix("hz") {
+        (n, 1.0)
+    } else {
+        (lower.as_str(), 1e6)
+    };
+    let hz = (number.trim().parse::<f64>().ok()? * scale).round();
+    (hz >= 1.0 && hz <= u32::MAX as f64).then_some(hz as u32)
+}
+
+/// `1.023 MHz`, `985.0 kHz` or `12 Hz`
+pub fn format_frequency(hz: f64) -> String {
+    if hz >= 1e6 {
+        format!("{:.3} MHz", hz / 1e6)
+    } else if hz >= 1e3 {
+        format!("{:.1} kHz", hz / 1e3)
+    } else {
+        format!("{hz:.0} Hz")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn test_budget_follows_the_clock() {
+        let start = Instant::now();
+        let mut throttle = Throttle::starting_at(1_000_000, Speed::Scaled(1.0), start);
+        assert_eq!(throttle.budget(start), 0);
+        assert_eq!(throttle.next_due(), Some(start + ms(10)));
+        assert_eq!(throttle.budget(start + ms(10)), 10_000);
+
+        // Overspending is paid back by waiting longer
+        throttle.spend(10_500, start + ms(10));
+        assert_eq!(throttle.budget(start + ms(10)), 0);
+        assert_eq!(throttle.budget(start + ms(20)), 9_500);
+        assert_eq!(
+            throttle.next_due(),
+            Some(start + ms(20) + Duration::from_micros(500))
+        );
+    }
+
+    #[test]
+    fn test_speed_factor_and_turbo() {
+        let start = Instant::now();
+        let mut throttle = Throttle::starting_at(1_000_000, Speed::Scaled(0.001), start);
+        assert_eq!(throttle.target_hz(), Some(1000.0));
+        assert_eq!(throttle.budget(start + ms(50)), 50);
+        // Slices are at least one cycle long
+        assert_eq!(throttle.next_due(), Some(start + ms(10)));
+
+        throttle.set_speed(Speed::Unthrottled, start + ms(50));
+        assert_eq!(throttle.next_due(), None);
+        assert_eq!(throttle.budget(start + ms(50)), TURBO_BUDGET);
+    }
+
+    #[test]
+    fn test_backlog_is_dropped() {
+        let start = Instant::now();
+        let mut throttle = Throttle::starting_at(1_000_000, Speed::Scaled(1.0), start);
+        // Stopped for two seconds: only one slice runs, not two seconds' worth
+        assert_eq!(throttle.budget(start + ms(2000)), 10_000);
+        throttle.spend(10_000, start + ms(2000));
+        assert_eq!(throttle.budget(start + ms(2005)), 5_000);
+    }
+
+    #[test]
+    fn test_tiny_rates() {
+        let start = Instant::now();
+        for factor in [MIN_FACTOR, 1e-30, f64::MIN_POSITIVE] {
+            let mut throttle = Throttle::starting_at(1, Speed::Scaled(factor), start);
+            assert_eq!(throttle.budget(start + ms(2000)), 1);
+            assert!(throttle.next_due().unwrap() <= start + ms(2000) + LONGEST_WAIT);
+        }
+    }
+
+    #[test]
+    fn test_effective_speed() {
+        let start = Instant::now();
+        let mut throttle = Throttle::starting_at(2_000_000, Speed::Scaled(1.0), start);
+        throttle.spend(100_000, start + ms(250));
+        assert_eq!(throttle.effective_hz(), None);
+        throttle.spend(150_000, start + ms(500));
+        assert_eq!(throttle.effective_hz(), Some(500_000.0));
+        assert_eq!(format_frequency(500_000.0), "500.0 kHz");
+        assert_eq!(format_frequency(1_022_727.0), "1.023 MHz");
+        assert_eq!(format_frequency(60.0), "60 Hz");
+    }
+
+    #[test]
+    fn test_parsing() {
+        assert_eq!(parse_frequency("1.0227MHz"), Some(1_022_700));
+        assert_eq!(parse_frequency("1.79"), Some(1_790_000));
+        assert_eq!(parse_frequency("985 kHz"), Some(985_000));
+        assert_eq!(parse_frequency("1000000hz"), Some(1_000_000));
+        assert_eq!(parse_frequency("fast"), None);
+        assert_eq!(parse_frequency("0"), None);
+
+        assert_eq!(Speed::parse("turbo"), Some(Speed::Unthrottled));
+        assert_eq!(Speed::parse("0.25"), Some(Speed::Scaled(0.25)));
+        assert_eq!(Speed::parse("10%"), Some(Speed::Scaled(0.1)));
+        assert_eq!(Speed::parse("-1"), None);
+        assert_eq!(Speed::parse("1e-30"), None);
+        assert_eq!(Speed::parse("0.000001"), Some(Speed::Scaled(MIN_FACTOR)));
+        assert_eq!(Speed::Scaled(0.5).to_string(), "50%");
+        assert_eq!(Speed::Scaled(0.1).to_string(), "10%");
+    }
+}
@@ -0,0 +1,489 @@
+//! Code and data coverage of the address space
+//!
+//! `Coverage` watches the bus through a `TracingBus` hook and marks how each
+//! address was used: fetched as an opcode, fetched as an operand, read as
+//! data or written. The `AccessKind` the CPU announces tells them apart, so
+//! a table that is only ever read shows up as data even though it sits among
+//! the code. Opcode fetches are also counted, for per-line hit counts.
+//!
+//! Three exports turn it into something to look at:
+//! - `listing`: the ROM disassembled around what ran, with hit counts, the
+//!   bytes read as data and the code that never ran
+//! - `lcov`: an lcov tracefile keyed to source lines from ld65 debug info,
+//!   for `genhtml` and CI coverage tools
+//! - `bitmap`: a 256x256 BMP image of the 64KB space, one pixel per address
+//!   and one row per page
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::rc::Rc;
+
+use crate::bus::{AccessKind, Bus};
+use crate::bus_trace::{Access, AccessType, HookId, TracingBus};
+use crate::debug_info::DebugInfo;
+use crate::disasm::{Line, disassemble};
+use crate::instructions::OPCODES;
+use crate::symbols::SymbolTable;
+
+/// Untouched runs of one value at least this long are listed as one line
+const FILL_RUN: usize = 16;
+/// Data bytes per listing line
+const BYTES_PER_LINE: usize = 8;
+
+/// How one address was used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Fetched as the first byte of an instruction
+    pub opcode: bool,
+    /// Fetched as an instruction's operand
+    pub operand: bool,
+    /// Read by a load, read-modify-write, pull, pointer or vector fetch
+    pub read: bool,
+    pub written: bool,
+}
+
+impl Usage {
+    pub fn executed(&self) -> bool {
+        self.opcode || self.operand
+    }
+
+    pub fn touched(&self) -> bool {
+        self.executed() || self.read || self.written
+    }
+
+    /// Colour of the address in `Coverage::bitmap`, as RGB
+    fn color(&self) -> [u8; 3] {
+        match *self {
+            Usage { opcode: true, .. } => [0x40, 0xE0, 0x40],
+            Usage { operand: true, .. } => [0x20, 0x90, 0x20],
+            Usage {
+                read: true,
+                written: true,
+                ..
+            } => [0xF0, 0xA0, 0x20],
+            Usage { written: true, .. } => [0xE0, 0x40, 0x30],
+            Usage { read: true, .. } => [0x40, 0x80, 0xF0],
+            _ => [0x18, 0x18, 0x18],
+        }
+    }
+}
+
+/// Byte counts over a range of addresses
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub bytes: usize,
+    /// Executed as opcodes or operands
+    pub executed: usize,
+    /// Executed as opcodes
+    pub opcodes: usize,
+    /// Read as data and never executed
+    pub data: usize,
+    pub written: usize,
+    pub untouched: usize,
+}
+
+impl fmt::Display for Summary {
+    /// `40 of 32768 bytes executed (31 opcodes), 14 read as data, 0 written, 32714 untouched`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} bytes executed ({} opcodes), {} read as data, {} written, {} untouched",
+            self.executed, self.bytes, self.opcodes, self.data, self.written, self.untouched
+        )
+    }
+}
+
+/// Usage of every address in the 64KB space
+#[derive(Debug, Clone)]
+pub struct Coverage {
+    usage: Vec<Usage>,
+    /// Opcode fetches per address
+    executions: Vec<u32>,
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self {
+            usage: vec![Usage::default(); 0x10000],
+            executions: vec![0; 0x10000],
+        }
+    }
+
+    /// Record every access made through `bus` into `coverage`. Attach it
+    /// before resetting the CPU so the reset vector counts as read.
+    pub fn attach<B: Bus>(coverage: &Rc<RefCell<Coverage>>, bus: &mut TracingBus<B>) -> HookId {
+        let coverage = Rc::clone(coverage);
+        bus.add_hook(move |access| coverage.borrow_mut().record(access))
+    }
+
+    pub fn record(&mut self, access: &Access) {
+        let address = access.address as usize;
+        let usage = &mut self.usage[address];
+        match (access.access_type, access.kind) {
+            (AccessType::Write, _) => usage.written = true,
+            (AccessType::Read, AccessKind::OpcodeFetch) => {
+                usage.opcode = true;
+                self.executions[address] = self.executions[address].saturating_add(1);
+            }
+            (AccessType::Read, AccessKind::Operand) => usage.operand = true,
+            (AccessType::Read, _) => usage.read = true,
+        }
+    }
+
+    pub fn usage(&self, address: u16) -> Usage {
+        self.usage[address as usize]
+    }
+
+    /// How many times an instruction started at `address`
+    pub fn executions(&self, address: u16) -> u32 {
+        self.executions[address as usize]
+    }
+
+    /// Counts over the inclusive ranges `(first, last)`
+    pub fn summary(&self, ranges: &[(u16, u16)]) -> Summary {
+        let mut summary = Summary::default();
+        for &(first, last) in ranges {
+            for usage in &self.usage[first as usize..=last as usize] {
+                summary.bytes += 1;
+                summary.executed += usage.executed() as usize;
+                summary.opcodes += usage.opcode as usize;
+                summary.data += (usage.read && !usage.executed()) as usize;
+                summary.written += usage.written as usize;
+                summary.untouched += !usage.touched() as usize;
+            }
+        }
+        summary
+    }
+
+    /// An lcov tracefile with a hit count for every source line in `info`.
+    ///
+    /// A line's count is how often its instructions ran; a line that only
+    /// produced data counts once if any of it was read.
+    pub fn lcov(&self, info: &DebugInfo) -> String {
+        let mut files: BTreeMap<&str, BTreeMap<u32, u32>> = BTreeMap::new();
+        for line in &info.lines {
+            let addresses = line
+                .spans
+                .iter()
+                .flat_map(|&(start, size)| (0..size).map(move |i| start.wrapping_add(i)));
+            let mut hits = 0;
+            let mut read = false;
+            for address in addresses {
+                hits = hits.max(self.executions(address));
+                read |= self.usage(address).read;
+            }
+            if hits == 0 && read {
+                hits = 1;
+            }
+            // A line can appear more than once, e.g. a macro used twice
+            let count = files
+                .entry(&line.file)
+                .or_default()
+                .entry(line.line)
+                .or_default();
+            *count = (*count).max(hits);
+        }
+
+        let mut out = String::from("TN:\n");
+        for (file, lines) in files {
+            let _ = writeln!(out, "SF:{file}");
+            for (line, hits) in &lines {
+                let _ = writeln!(out, "DA:{line},{hits}");
+            }
+            let hit = lines.values().filter(|&&hits| hits > 0).count();
+            let _ = writeln!(out, "LF:{}\nLH:{hit}\nend_of_record", lines.len());
+        }
+        out
+    }
+
+    /// A 256x256 24-bit BMP of the whole address space, page $00 at the
+    /// top. Opcodes are bright green, operands dark green, data read blue,
+    /// written red, read and written orange and untouched bytes dark grey.
+    pub fn bitmap(&self) -> Vec<u8> {
+        const HEADER: u32 = 14 + 40;
+        let size = HEADER + 3 * 0x10000;
+        let mut bmp = Vec::with_capacity(size as usize);
+        // File header
+        bmp.extend_from_slice(b"BM");
+        bmp.extend_from_slice(&size.to_le_bytes());
+        bmp.extend_from_slice(&[0; 4]);
+        bmp.extend_from_slice(&HEADER.to_le_bytes());
+        // BITMAPINFOHEADER: 256x256, 1 plane, 24 bits, uncompressed
+        bmp.extend_from_slice(&40u32.to_le_bytes());
+        bmp.extend_from_slice(&256i32.to_le_bytes());
+        bmp.extend_from_slice(&256i32.to_le_bytes());
+        bmp.extend_from_slice(&1u16.to_le_bytes());
+        bmp.extend_from_slice(&24u16.to_le_bytes());
+        bmp.extend_from_slice(&[0; 4]);
+        bmp.extend_from_slice(&(3 * 0x10000u32).to_le_bytes());
+        bmp.extend_from_slice(&2835i32.to_le_bytes());
+        bmp.extend_from_slice(&2835i32.to_le_bytes());
+        bmp.extend_from_slice(&[0; 8]);
+        // Rows are stored bottom up, pixels as BGR
+        for page in self.usage.chunks(0x100).rev() {
+            for usage in page {
+                let [r, g, b] = usage.color();
+                bmp.extend_from_slice(&[b, g, r]);
+            }
+        }
+        bmp
+    }
+
+    /// An annotated disassembly of the inclusive ranges `(first, last)`.
+    ///
+    /// Instructions that ran show how often. Bytes that never ran are
+    /// listed as data if they were read or written, as code marked `-` if
+    /// they decode as an instruction, and long runs of one fill value are
+    /// folded into a single line. `read` should not have side effects.
+    pub fn listing<F: FnMut(u16) -> u8>(
+        &self,
+        ranges: &[(u16, u16)],
+        symbols: &SymbolTable,
+        mut read: F,
+    ) -> String {
+        let mut out = format!(
+            "; {}\n;\n; count: times executed, -: never executed, r: read as data, w: written\n",
+            self.summary(ranges)
+        );
+        for &(first, last) in ranges {
+            let _ = writeln!(out, "\n; ${first:04X}-${last:04X}");
+            let memory: Vec<u8> = (first..=last).map(&mut read).collect();
+            let mut address = first;
+            loop {
+                if let Some(name) = symbols.name_of(address) {
+                    let _ = writeln!(out, "{name}:");
+                }
+                let offset = (address - first) as usize;
+                let (mark, length, line) = self.listing_line(address, &memory[offset..], symbols);
+                let _ = writeln!(out, "{mark:>10}  {line}");
+                let next = address as usize + length;
+                if next > last as usize {
+                    break;
+                }
+                address = next as u16;
+            }
+        }
+        out
+    }
+
+    /// The listing line for the start of `memory`, which holds the bytes
+    /// from `address` to the end of its range: the mark, the number of
+    /// bytes it covers and the line itself
+    fn listing_line(
+        &self,
+        address: u16,
+        memory: &[u8],
+        symbols: &SymbolTable,
+    ) -> (String, usize, Line) {
+        let usage = self.usage(address);
+        let at = |i: usize| address.wrapping_add(i as u16);
+        // How many bytes from `address` satisfy `same`, up to `limit`; a
+        // run stops at a label, which starts a line of its own
+        let run = |limit: usize, same: &dyn Fn(usize) -> bool| {
+            (1..limit.min(memory.len()))
+                .take_while(|&i| symbols.name_of(at(i)).is_none() && same(i))
+                .count()
+                + 1
+        };
+        let read = |a: u16| {
+            memory
+                .get(a.wrapping_sub(address) as usize)
+                .copied()
+                .unwrap_or(0xFF)
+        };
+
+        if usage.opcode {
+            let line = disassemble(address, read, symbols);
+            if line.bytes.len() <= memory.len() {
+                return (self.executions(address).to_string(), line.bytes.len(), line);
+            }
+        }
+
+        if !usage.touched() {
+            let value = memory[0];
+            let fill = run(usize::MAX, &|i| {
+                !self.usage(at(i)).touched() && memory[i] == value
+            });
+            if fill >= FILL_RUN {
+                let line = Line {
+                    address,
+                    bytes: Vec::new(),
+                    text: format!("{fill} bytes of ${value:02X}"),
+                };
+                return (String::from("-"), fill, line);
+            }
+            let opcode = &OPCODES[value as usize];
+            let length = opcode.bytes as usize;
+            if opcode.mnemonic != "???" && run(length, &|i| !self.usage(at(i)).touched()) == length
+            {
+                return (
+                    String::from("-"),
+                    length,
+                    disassemble(address, read, symbols),
+                );
+            }
+        }
+
+        let kind = |u: Usage| (u.opcode, u.read || u.operand, u.written);
+        let count = run(BYTES_PER_LINE, &|i| {
+            let other = self.usage(at(i));
+            !other.opcode && kind(other) == kind(usage)
+        });
+        let values: Vec<String> = memory[..count]
+            .iter()
+            .map(|b| format!("${b:02X}"))
+            .collect();
+        let mark = match (usage.read || usage.operand, usage.written) {
+            (true, true) => "rw",
+            (true, false) => "r",
+            (false, true) => "w",
+            (false, false) => "-",
+        };
+        let line = Line {
+            address,
+            bytes: Vec::new(),
+            text: format!(".byte {}", values.join(",")),
+        };
+        (mark.to_string(), count, line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cpu;
+    use crate::bus::SimpleBus;
+    use crate::debug_info::SourceLine;
+
+    /// Copies a three-byte table to $0200, leaving a routine that never runs
+    fn run() -> (Coverage, SimpleBus) {
+        let mut bus = SimpleBus::new();
+        bus.load(
+            0x8000,
+            &[
+                0xA2, 0x00, // LDX #$00
+                0xBD, 0x30, 0x80, // loop: LDA table,X
+                0x9D, 0x00, 0x02, // STA $0200,X
+                0xE8, // INX
+                0xE0, 0x03, // CPX #$03
+                0xD0, 0xF5, // BNE loop
+                0x00, // BRK
+                0xA9, 0x01, // unused: LDA #$01
+                0x60, // RTS
+            ],
+        );
+        bus.load(0x8030, &[0x11, 0x22, 0x33, 0x44]);
+        bus.load(0xFFFC, &[0x00, 0x80]);
+
+        let coverage = Rc::new(RefCell::new(Coverage::new()));
+        let mut cpu = Cpu::new(TracingBus::new(bus));
+        Coverage::attach(&coverage, &mut cpu.bus);
+        cpu.reset();
+        for _ in 0..16 {
+            cpu.execute_instruction();
+        }
+        let coverage = coverage.borrow().clone();
+        (coverage, cpu.bus.into_inner())
+    }
+
+    #[test]
+    fn test_usage_and_summary() {
+        let (coverage, _) = run();
+        assert_eq!(coverage.executions(0x8002), 3);
+        assert!(coverage.usage(0x8000).opcode);
+        assert!(coverage.usage(0x8001).operand);
+        assert!(coverage.usage(0x8031).read && !coverage.usage(0x8033).touched());
+        assert!(coverage.usage(0x0202).written);
+        assert!(coverage.usage(0xFFFC).read);
+
+        assert_eq!(
+            coverage.summary(&[(0x8000, 0x803F)]),
+            Summary {
+                bytes: 64,
+                executed: 13,
+                opcodes: 6,
+                data: 3,
+                written: 0,
+                untouched: 48,
+            }
+        );
+    }
+
+    #[test]
+    fn test_listing() {
+        let (coverage, mut bus) = run();
+        let symbols = SymbolTable::parse("loop = $8002\ntable = $8030\n").unwrap();
+        let listing = coverage.listing(&[(0x8000, 0x803F)], &symbols, |a| bus.read(a));
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(
+            lines[0],
+            "; 13 of 64 bytes executed (6 opcodes), 3 read as data, 0 written, 48 untouched"
+        );
+        assert_eq!(lines[4], "; $8000-$803F");
+        assert_eq!(lines[5], "         1  $8000  A2 00     LDX #$00");
+        assert_eq!(lines[6], "loop:");
+        assert_eq!(lines[7], "         3  $8002  BD 30 80  LDA table,X");
+        assert_eq!(lines[12], "         -  $800D  00        BRK");
+        assert_eq!(lines[13], "         -  $800E  A9 01     LDA #$01");
+        assert_eq!(lines[15], "         -  $8011            31 bytes of $00");
+        assert_eq!(lines[16], "table:");
+        assert_eq!(lines[17], "         r  $8030            .byte $11,$22,$33");
+        assert_eq!(
+            lines[18],
+            "         -  $8033            .byte $44,$00,$00,$00,$00,$00,$00,$00"
+        );
+    }
+
+    #[test]
+    fn test_lcov() {
+        let (coverage, _) = run();
+        let line = |line, spans| SourceLine {
+            file: String::from("copy.s"),
+            line,
+            spans,
+        };
+        let info = DebugInfo {
+            lines: vec![
+                line(3, vec![(0x8002, 3)]),
+                line(12, vec![(0x8030, 4)]),
+                line(9, vec![(0x800E, 2)]),
+                line(3, vec![(0x8002, 3)]),
+            ],
+            symbols: SymbolTable::new(),
+        };
+        assert_eq!(
+            coverage.lcov(&info),
+            "TN:\nSF:copy.s\nDA:3,3\nDA:9,0\nDA:12,1\nLF:3\nLH:2\nend_of_record\n"
+        );
+    }
+
+    #[test]
+    fn test_bitmap() {
+        let (coverage, _) = run();
+        let bmp = coverage.bitmap();
+        assert_eq!(bmp.len(), 54 + 3 * 0x10000);
+        assert_eq!(&bmp[..2], b"BM");
+        let pixel = |address: usize| {
+            let row = 255 - address / 0x100;
+            let offset = 54 + 3 * (row * 0x100 + address % 0x100);
+            [bmp[offset + 2], bmp[offset + 1], bmp[offset]]
+        };
+        assert_eq!(
+            pixel(0x8000),
+            Usage {
+                opcode: true,
+                ..Usage::default()
+            }
+            .color()
+        );
+        assert_eq!(pixel(0x0201), [0xE0, 0x40, 0x30]);
+        assert_eq!(pixel(0x8033), [0x18, 0x18, 0x18]);
+    }
+}
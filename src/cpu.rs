@@ -7,10 +7,25 @@
 //! - Interrupt handling (NMI, IRQ, RESET)
 
 use crate::addressing::AddressingMode;
-use crate::bus::Bus;
-use crate::instructions::{Opcode, get_opcode};
+use crate::bus::{AccessKind, Bus};
+use crate::instructions::{Opcode, get_opcode, is_jam};
 use crate::status::{Flag, StatusRegister};
 
+/// Cycles an instruction took beyond its base count
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Penalties {
+    /// An indexed access, or a taken branch, crossing into another page
+    pub page_crossing: u8,
+    /// A branch being taken
+    pub branch_taken: u8,
+}
+
+impl Penalties {
+    pub fn total(&self) -> u8 {
+        self.page_crossing + self.branch_taken
+    }
+}
+
 pub struct Cpu<B: Bus> {
     // Registers
     pub pc: u16,
@@ -22,12 +37,20 @@ pub struct Cpu<B: Bus> {
 
     // Internal state
     pub cycles: u8,
+    /// Cycles run since reset, including the 7 reset cycles
+    pub total_cycles: u64,
     pub halted: bool,
+    /// Extra cycles taken by the last instruction
+    pub penalties: Penalties,
+    /// Interrupts (NMI and IRQ, not BRK) entered since reset
+    pub interrupts: u64,
 
     // Interrupt flags
     nmi_pending: bool,
     irq_pending: bool,
     nmi_edge_detected: bool,
+    /// Last sampled level of the bus NMI line
+    bus_nmi: bool,
 
     pub bus: B,
 }
@@ -42,33 +65,44 @@ impl<B: Bus> Cpu<B> {
             y: 0,
             status: StatusRegister::new(),
             cycles: 0,
+            total_cycles: 0,
             halted: false,
+            penalties: Penalties::default(),
+            interrupts: 0,
             nmi_pending: false,
             irq_pending: false,
             nmi_edge_detected: false,
+            bus_nmi: false,
             bus,
         }
     }
 
     /// Reads the reset vector from $FFFC-$FFFD and jumps there
     pub fn reset(&mut self) {
+        self.bus.reset();
         self.a = 0;
         self.x = 0;
         self.y = 0;
         self.sp = 0xFD;
         self.status = StatusRegister::new();
+        self.bus.set_access_kind(AccessKind::Vector);
         self.pc = self.read_word(0xFFFC);
         self.cycles = 7; // Reset takes 7 cycles
+        self.total_cycles = 0;
         self.halted = false;
+        self.penalties = Penalties::default();
+        self.interrupts = 0;
         self.nmi_pending = false;
         self.irq_pending = false;
         self.nmi_edge_detected = false;
+        self.bus_nmi = self.bus.nmi();
     }
 
     pub fn step(&mut self) {
         if self.halted {
             return;
         }
+        self.total_cycles += 1;
 
         if self.cycles > 0 {
             self.cycles -= 1;
@@ -76,18 +110,33 @@ impl<B: Bus> Cpu<B> {
             return;
         }
 
+        // NMI is edge triggered: only a new assertion of the line counts
+        let nmi = self.bus.nmi();
+        if nmi && !self.bus_nmi {
+            self.nmi_pending = true;
+        }
+        self.bus_nmi = nmi;
+
         if self.nmi_pending {
             self.handle_nmi();
             return;
         }
 
-        if self.irq_pending && !self.status.get(Flag::InterruptDisable) {
+        let irq = self.irq_pending || self.bus.irq();
+        if irq && !self.status.get(Flag::InterruptDisable) {
             self.handle_irq();
             return;
         }
 
         // Fetch and execute instruction
+        self.bus.set_access_kind(AccessKind::OpcodeFetch);
         let opcode_byte = self.fetch_byte();
+        if is_jam(opcode_byte) {
+            // The chip stops until reset; leave the PC on the culprit
+            self.pc = self.pc.wrapping_sub(1);
+            self.halted = true;
+            return;
+        }
         let opcode = get_opcode(opcode_byte);
 
         self.execute_opcode(opcode);
@@ -161,12 +210,14 @@ impl<B: Bus> Cpu<B> {
 
     // ========== Stack Operations ==========
     pub fn push_byte(&mut self, value: u8) {
+        self.bus.set_access_kind(AccessKind::Stack);
         self.bus.write(0x0100 | (self.sp as u16), value);
         self.sp = self.sp.wrapping_sub(1);
     }
 
     pub fn pull_byte(&mut self) -> u8 {
         self.sp = self.sp.wrapping_add(1);
+        self.bus.set_access_kind(AccessKind::Stack);
         self.bus.read(0x0100 | (self.sp as u16))
     }
 
@@ -187,10 +238,15 @@ impl<B: Bus> Cpu<B> {
         self.push_byte(self.status.to_byte() & !0x10); // Clear B flag
 
         self.status.set(Flag::InterruptDisable, true);
+        self.bus.set_access_kind(AccessKind::Vector);
         self.pc = self.read_word(0xFFFA);
 
-        self.cycles = 7;
+        // 7 cycles, counting this one
+        self.cycles = 6;
+        self.bus.tick();
         self.nmi_pending = false;
+        self.penalties = Penalties::default();
+        self.interrupts += 1;
     }
 
     fn handle_irq(&mut self) {
@@ -198,13 +254,19 @@ impl<B: Bus> Cpu<B> {
         self.push_byte(self.status.to_byte() & !0x10); // Clear B flag
 
         self.status.set(Flag::InterruptDisable, true);
+        self.bus.set_access_kind(AccessKind::Vector);
         self.pc = self.read_word(0xFFFE);
 
-        self.cycles = 7;
+        // 7 cycles, counting this one
+        self.cycles = 6;
+        self.bus.tick();
+        self.penalties = Penalties::default();
+        self.interrupts += 1;
     }
 
     // ========== Addressing Mode Helpers ==========
     fn get_operand_address(&mut self, mode: AddressingMode) -> (u16, bool) {
+        self.bus.set_access_kind(AccessKind::Operand);
         match mode {
             AddressingMode::Implied | AddressingMode::Accumulator => (0, false),
 
@@ -253,6 +315,7 @@ impl<B: Bus> Cpu<B> {
             AddressingMode::IndirectX => {
                 let base = self.fetch_byte();
                 let ptr = base.wrapping_add(self.x);
+                self.bus.set_access_kind(AccessKind::Data);
                 let low = self.bus.read(ptr as u16) as u16;
                 let high = self.bus.read(ptr.wrapping_add(1) as u16) as u16;
                 ((high << 8) | low, false)
@@ -260,6 +323,7 @@ impl<B: Bus> Cpu<B> {
 
             AddressingMode::IndirectY => {
                 let ptr = self.fetch_byte();
+                self.bus.set_access_kind(AccessKind::Data);
                 let low = self.bus.read(ptr as u16) as u16;
                 let high = self.bus.read(ptr.wrapping_add(1) as u16) as u16;
                 let base = (high << 8) | low;
@@ -271,6 +335,7 @@ impl<B: Bus> Cpu<B> {
             AddressingMode::Indirect => {
                 let ptr = self.fetch_word();
                 // NMOS 6502 bug: if ptr is $xxFF, high byte wraps within page
+                self.bus.set_access_kind(AccessKind::Data);
                 let low = self.bus.read(ptr) as u16;
                 let high = if (ptr & 0x00FF) == 0x00FF {
                     self.bus.read(ptr & 0xFF00) as u16
@@ -293,12 +358,14 @@ impl<B: Bus> Cpu<B> {
         let mode = opcode.mode;
 
         let (address, page_crossed) = self.get_operand_address(mode);
-
-        let mut extra_cycles: u8 = 0;
-        if opcode.page_boundary_cycle && page_crossed {
-            extra_cycles += 1;
+        // An immediate operand is read below, but it is still an operand fetch
+        if mode != AddressingMode::Immediate {
+            self.bus.set_access_kind(AccessKind::Data);
         }
 
+        let page_penalty = u8::from(opcode.page_boundary_cycle && page_crossed);
+        let mut extra_cycles: u8 = page_penalty;
+
         match mnemonic {
             "LDA" => {
                 let value = self.bus.read(address);
@@ -469,6 +536,13 @@ impl<B: Bus> Cpu<B> {
             }
         }
 
+        // A taken branch costs one cycle, and one more if it crosses a page
+        let branch_cycles = extra_cycles - page_penalty;
+        self.penalties = Penalties {
+            page_crossing: page_penalty + branch_cycles.saturating_sub(1),
+            branch_taken: branch_cycles.min(1),
+        };
+
         self.cycles = opcode.cycles + extra_cycles - 1; // -1 because we'll tick at the end
         self.bus.tick();
     }
@@ -591,5 +665,74 @@ mod tests {
 
         assert_eq!(cpu.pc, 0x9000);
         assert!(cpu.status.get(Flag::InterruptDisable));
+        assert_eq!(cpu.interrupts, 1);
+        // 7 for reset, 7 for the interrupt entry
+        assert_eq!(cpu.total_cycles, 14);
+    }
+
+    #[test]
+    fn test_irq() {
+        // CLI, NOP
+        let mut cpu = setup_cpu(&[0x58, 0xEA]);
+        cpu.bus.write(0xFFFE, 0x00);
+        cpu.bus.write(0xFFFF, 0x90);
+
+        cpu.execute_instruction(); // CLI
+        let before = cpu.total_cycles;
+        cpu.request_irq();
+        cpu.execute_instruction(); // Takes the IRQ instead of the NOP
+
+        assert_eq!(cpu.pc, 0x9000);
+        assert!(cpu.status.get(Flag::InterruptDisable));
+        assert_eq!(cpu.interrupts, 1);
+        assert_eq!(cpu.total_cycles - before, 7);
+        assert_eq!(cpu.pull_byte() & 0x10, 0, "B flag is clear");
+        assert_eq!(cpu.pull_word(), 0x8001);
+    }
+
+    #[test]
+    fn test_penalties() {
+        // LDX #$01, LDA $10FF,X, BEQ +0, BNE +0, then BEQ from $80FE to $8100
+        let mut cpu = setup_cpu(&[0xA2, 0x01, 0xBD, 0xFF, 0x10, 0xF0, 0x00, 0xD0, 0x00]);
+        cpu.bus.load(0x80FC, &[0xF0, 0x02]);
+
+        cpu.execute_instruction();
+        assert_eq!(cpu.penalties, Penalties::default());
+        cpu.execute_instruction();
+        assert_eq!(cpu.penalties.page_crossing, 1);
+        cpu.execute_instruction();
+        assert_eq!(
+            (cpu.penalties.page_crossing, cpu.penalties.branch_taken),
+            (0, 1)
+        );
+        cpu.execute_instruction();
+        assert_eq!(cpu.penalties.total(), 0);
+
+        cpu.pc = 0x80FC;
+        cpu.execute_instruction();
+        assert_eq!(cpu.pc, 0x8100);
+        assert_eq!(
+            cpu.penalties,
+            Penalties {
+                page_crossing: 1,
+                branch_taken: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_jam_halts() {
+        // LDA #$01, JAM, LDA #$02
+        let mut cpu = setup_cpu(&[0xA9, 0x01, 0x02, 0xA9, 0x02]);
+        cpu.execute_instruction();
+        cpu.execute_instruction();
+        assert!(cpu.halted);
+        assert_eq!(cpu.pc, 0x8002);
+
+        cpu.execute_instruction();
+        assert_eq!((cpu.a, cpu.pc), (0x01, 0x8002));
+
+        cpu.reset();
+        assert!(!cpu.halted);
     }
 }
@@ -0,0 +1,266 @@
+//! ld65 debug info files (`ld65 --dbgfile`)
+//!
+//! The debug info ties every span of output bytes back to the source lines
+//! that produced it, which is what coverage needs to report by line. Only
+//! the records needed for that are read: `file`, `seg`, `span`, `line` and
+//! the label and equate `sym` records, which also make a `SymbolTable`.
+//!
+//! Each record is a type, a tab and `key=value` pairs:
+//!
+//! ```text
+//! seg id=0,name="CODE",start=0x008000,size=0x0025,addrsize=absolute,type=ro
+//! span id=0,seg=0,start=0,size=2
+//! line id=0,file=0,line=7,span=0
+//! ```
+//!
+//! Spans in writable segments (`BSS`, `ZEROPAGE`) only reserve RAM, so their
+//! lines are left out.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::symbols::{SymbolError, SymbolTable};
+
+/// A source line that assembled to bytes in ROM
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file: String,
+    /// 1-based line number
+    pub line: u32,
+    /// Bytes the line produced, as (first address, length)
+    pub spans: Vec<(u16, u16)>,
+}
+
+/// Source lines and symbols from a debug info file
+#[derive(Debug, Clone, Default)]
+pub struct DebugInfo {
+    pub lines: Vec<SourceLine>,
+    pub symbols: SymbolTable,
+}
+
+/// A segment's load address and whether it holds ROM
+struct Segment {
+    start: u32,
+    writable: bool,
+}
+
+impl DebugInfo {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SymbolError> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, SymbolError> {
+        let mut files: HashMap<u32, String> = HashMap::new();
+        let mut segments: HashMap<u32, Segment> = HashMap::new();
+        let mut spans: HashMap<u32, (u32, u32, u32)> = HashMap::new();
+        // File, line number and span ids of each line record, and where it is
+        let mut lines: Vec<(u32, u32, Vec<u32>, usize)> = Vec::new();
+        let mut info = DebugInfo::default();
+
+        for (index, line) in text.lines().enumerate() {
+            let Some((kind, fields)) = line.trim().split_once(char::is_whitespace) else {
+                continue;
+            };
+            let fields = Fields::parse(fields, index)?;
+            match kind {
+                "file" => {
+                    files.insert(fields.number("id")?, fields.text("name")?.to_string());
+                }
+                "seg" => {
+                    let segment = Segment {
+                        start: fields.number("start")?,
+                        writable: fields.get("type") == Some("rw"),
+                    };
+                    segments.insert(fields.number("id")?, segment);
+                }
+                "span" => {
+                    let span = (
+                        fields.number("seg")?,
+                        fields.number("start")?,
+                        fields.number("size")?,
+                    );
+                    spans.insert(fields.number("id")?, span);
+                }
+                "line" => {
+                    let Some(ids) = fields.get("span") else {
+                        continue;
+                    };
+                    let ids = ids
+                        .split('+')
+                        .map(|id| number(id).ok_or_else(|| fields.invalid("span", id)))
+                        .collect::<Result<Vec<u32>, SymbolError>>()?;
+                    lines.push((fields.number("file")?, fields.number("line")?, ids, index));
+                }
+                "sym" => {
+                    // Imports have no value of their own
+                    if !matches!(fields.get("type"), Some("lab" | "equ")) {
+                        continue;
+                    }
+                    if let Some(value) = fields.get("val") {
+                        let value = number(value).ok_or_else(|| fields.invalid("val", value))?;
+                        if let Ok(address) = u16::try_from(value) {
+                            info.symbols.insert(fields.text("name")?, address);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (file, line, ids, index) in lines {
+            let name = files
+                .get(&file)
+                .ok_or_else(|| parse_error(index, &format!("unknown file id {file}")))?;
+            let mut ranges = Vec::new();
+            for id in ids {
+                let &(segment, start, size) = spans
+                    .get(&id)
+                    .ok_or_else(|| parse_error(index, &format!("unknown span id {id}")))?;
+                let segment = segments
+                    .get(&segment)
+                    .ok_or_else(|| parse_error(index, &format!("unknown segment id {segment}")))?;
+                let address = segment.start + start;
+                if !segment.writable && size > 0 && address <= 0xFFFF {
+                    ranges.push((address as u16, size.min(0x10000 - address) as u16));
+                }
+            }
+            if !ranges.is_empty() {
+                info.lines.push(SourceLine {
+                    file: name.clone(),
+                    line,
+                    spans: ranges,
+                });
+            }
+        }
+        Ok(info)
+    }
+}
+
+/// The `key=value` pairs of one record
+struct Fields<'a> {
+    pairs: Vec<(&'a str, &'a str)>,
+    index: usize,
+}
+
+impl<'a> Fields<'a> {
+    fn parse(text: &'a str, index: usize) -> Result<Self, SymbolError> {
+        let mut pairs = Vec::new();
+        let mut rest = text.trim();
+        while !rest.is_empty() {
+            let (key, after) = rest
+                .split_once('=')
+                .ok_or_else(|| parse_error(index, "expected key=value"))?;
+            // Quoted values (file names) may contain commas
+            let end = if let Some(quoted) = after.strip_prefix('"') {
+                quoted
+                    .find('"')
+                    .ok_or_else(|| parse_error(index, "unterminated string"))?
+                    + 2
+            } else {
+                after.find(',').unwrap_or(after.len())
+            };
+            pairs.push((key.trim(), &after[..end]));
+            rest = after[end..].trim_start_matches(',');
+        }
+        Ok(Self { pairs, index })
+    }
+
+    fn get(&self, key: &str) -> Option<&'a str> {
+        self.pairs.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+    }
+
+    fn required(&self, key: &str) -> Result<&'a str, SymbolError> {
+        self.get(key)
+            .ok_or_else(|| parse_error(self.index, &format!("missing `{key}`")))
+    }
+
+    fn number(&self, key: &str) -> Result<u32, SymbolError> {
+        let value = self.required(key)?;
+        number(value).ok_or_else(|| self.invalid(key, value))
+    }
+
+    /// A quoted string value
+    fn text(&self, key: &str) -> Result<&'a str, SymbolError> {
+        let value = self.required(key)?;
+        Ok(value.trim_matches('"'))
+    }
+
+    fn invalid(&self, key: &str, value: &str) -> SymbolError {
+        parse_error(self.index, &format!("invalid {key} `{value}`"))
+    }
+}
+
+/// Decimal or `0x` hex
+fn number(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn parse_error(index: usize, message: &str) -> SymbolError {
+    SymbolError::Parse {
+        line: index + 1,
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO: &str = "\
+version\tmajor=2,minor=0
+info\tcsym=0,file=1,lib=0,line=4,mod=1,scope=1,seg=3,span=4,sym=3,type=0
+file\tid=0,name=\"src/hello, world.s\",size=402,mtime=0x5F3C2B10,mod=0
+line\tid=0,file=0,line=1
+line\tid=1,file=0,line=5,span=0
+line\tid=2,file=0,line=6,span=1+2
+line\tid=3,file=0,line=9,span=3
+mod\tid=0,name=\"hello.o\",file=0
+seg\tid=0,name=\"CODE\",start=0x008000,size=0x0010,addrsize=absolute,type=ro,oname=\"hello.bin\",ooffs=0
+seg\tid=1,name=\"VECTORS\",start=0x00FFFA,size=0x0006,addrsize=absolute,type=ro
+seg\tid=2,name=\"BSS\",start=0x000200,size=0x0010,addrsize=absolute,type=rw
+span\tid=0,seg=0,start=0,size=2
+span\tid=1,seg=0,start=2,size=3
+span\tid=2,seg=1,start=2,size=2
+span\tid=3,seg=2,start=0,size=16
+sym\tid=0,name=\"reset\",addrsize=absolute,scope=0,def=1,val=0x8000,seg=0,type=lab
+sym\tid=1,name=\"CONSOLE\",addrsize=absolute,scope=0,def=2,val=0xF001,type=equ
+sym\tid=2,name=\"putc\",addrsize=absolute,scope=0,def=3,type=imp
+";
+
+    #[test]
+    fn test_parse_lines_and_symbols() {
+        let info = DebugInfo::parse(HELLO).unwrap();
+        assert_eq!(
+            info.lines,
+            vec![
+                SourceLine {
+                    file: "src/hello, world.s".to_string(),
+                    line: 5,
+                    spans: vec![(0x8000, 2)],
+                },
+                SourceLine {
+                    file: "src/hello, world.s".to_string(),
+                    line: 6,
+                    spans: vec![(0x8002, 3), (0xFFFC, 2)],
+                },
+            ]
+        );
+        assert_eq!(info.symbols.address_of("reset"), Some(0x8000));
+        assert_eq!(info.symbols.address_of("CONSOLE"), Some(0xF001));
+        assert_eq!(info.symbols.address_of("putc"), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = DebugInfo::parse("line\tid=0,file=0,line=3,span=7\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: unknown file id 0");
+        let err = DebugInfo::parse("seg\tid=0,start=0x8000\nspan\tid=0,seg=x,start=0,size=1\n")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "line 2: invalid seg `x`");
+    }
+}
@@ -0,0 +1,662 @@
+//! MOS 6551 Asynchronous Communications Interface Adapter
+//!
+//! Register map (offset from the base address):
+//! - $0: transmit data (write) / receive data (read)
+//! - $1: status (read) / programmed reset (write)
+//! - $2: command
+//! - $3: control
+//!
+//! Characters take as many CPU cycles as they would on the wire at the
+//! programmed baud rate, so the receive and transmit flags behave like the
+//! real chip. Bytes go to and come from a `SerialBackend`: the terminal, any
+//! reader/writer pair such as a pipe, a Linux pseudo-terminal, or memory.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use super::Device;
+use super::state::{StateError, StateReader, StateWriter};
+
+/// Status register bits
+pub mod status {
+    pub const PARITY_ERROR: u8 = 0x01;
+    pub const FRAMING_ERROR: u8 = 0x02;
+    pub const OVERRUN: u8 = 0x04;
+    pub const RDRF: u8 = 0x08;
+    pub const TDRE: u8 = 0x10;
+    pub const DCD: u8 = 0x20;
+    pub const DSR: u8 = 0x40;
+    pub const IRQ: u8 = 0x80;
+}
+
+const DATA: u16 = 0;
+const STATUS: u16 = 1;
+const COMMAND: u16 = 2;
+const CONTROL: u16 = 3;
+
+/// Rates selected by control register bits 0-3. Zero selects the external
+/// 16x clock, which on typical boards is the 1.8432MHz crystal / 16.
+const BAUD_RATES: [u32; 16] = [
+    115_200, 50, 75, 110, 135, 150, 300, 600, 1200, 1800, 2400, 3600, 4800, 7200, 9600, 19_200,
+];
+
+/// Host side of a serial line
+pub trait SerialBackend {
+    /// Next received byte, if one is waiting. Must not block.
+    fn read_byte(&mut self) -> Option<u8>;
+
+    fn write_byte(&mut self, byte: u8);
+
+    /// True once no more input will ever arrive (end of a pipe or file)
+    fn is_closed(&self) -> bool {
+        false
+    }
+
+    /// Device node a terminal program opens to reach the line, if any
+    fn device_path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// Discards output and never receives anything
+pub struct NullBackend;
+
+impl SerialBackend for NullBackend {
+    fn read_byte(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn write_byte(&mut self, _byte: u8) {}
+}
+
+/// In-memory line for tests and scripted input. Clones share the same
+/// buffers, so one handle can be given to the device and one kept.
+#[derive(Clone, Default)]
+pub struct MemoryBackend {
+    input: Rc<RefCell<VecDeque<u8>>>,
+    output: Rc<RefCell<Vec<u8>>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue bytes for the device to receive
+    pub fn send(&self, bytes: &[u8]) {
+        self.input.borrow_mut().extend(bytes);
+    }
+
+    /// Take everything the device has transmitted
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut self.output.borrow_mut())
+    }
+}
+
+impl SerialBackend for MemoryBackend {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.input.borrow_mut().pop_front()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.output.borrow_mut().push(byte);
+    }
+}
+
+/// Any reader/writer pair. The reader is drained on a background thread so
+/// that `read_byte` never blocks the emulator.
+pub struct StreamBackend {
+    rx: Receiver<u8>,
+    writer: Box<dyn Write + Send>,
+    closed: bool,
+}
+
+impl StreamBackend {
+    pub fn new<R, W>(mut reader: R, writer: W) -> Self
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 256];
+            loop {
+                match reader.read(&mut buffer) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if buffer[..n].iter().any(|&byte| tx.send(byte).is_err()) {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            rx,
+            writer: Box::new(writer),
+            closed: false,
+        }
+    }
+
+    /// The emulator's own stdin and stdout
+    pub fn stdio() -> Self {
+        Self::new(io::stdin(), io::stdout())
+    }
+}
+
+impl SerialBackend for StreamBackend {
+    fn read_byte(&mut self) -> Option<u8> {
+        match self.rx.try_recv() {
+            Ok(byte) => Some(byte),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.closed = true;
+                None
+            }
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        // A closed pipe just loses output, like an unplugged cable
+        let _ = self.writer.write_all(&[byte]);
+        let _ = self.writer.flush();
+    }
+
+    /// The reader has reached end of file and all input was consumed
+    fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+/// A Linux pseudo-terminal. Connect to it with e.g. `screen /dev/pts/N`.
+#[cfg(target_os = "linux")]
+pub struct PtyBackend {
+    stream: StreamBackend,
+    path: PathBuf,
+}
+
+#[cfg(target_os = "linux")]
+impl PtyBackend {
+    pub fn open() -> io::Result<Self> {
+        use std::ffi::CStr;
+        use std::fs::OpenOptions;
+        use std::os::raw::{c_char, c_int, c_void};
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::os::unix::io::AsRawFd;
+
+        unsafe extern "C" {
+            fn grantpt(fd: c_int) -> c_int;
+            fn unlockpt(fd: c_int) -> c_int;
+            fn ptsname_r(fd: c_int, buf: *mut c_char, buflen: usize) -> c_int;
+            fn tcgetattr(fd: c_int, termios: *mut c_void) -> c_int;
+            fn cfmakeraw(termios: *mut c_void);
+            fn tcsetattr(fd: c_int, action: c_int, termios: *const c_void) -> c_int;
+        }
+        const O_NOCTTY: i32 = 0o400;
+        const TCSANOW: c_int = 0;
+
+        let master = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(O_NOCTTY)
+            .open("/dev/ptmx")?;
+        let fd = master.as_raw_fd();
+
+        let mut name = [0 as c_char; 128];
+        // SAFETY: `fd` is an open pty master and `name` outlives the calls
+        unsafe {
+            if grantpt(fd) != 0 || unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        // SAFETY: ptsname_r wrote a NUL-terminated string into `name`
+        let path = unsafe { CStr::from_ptr(name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+
+        // Put the slave in raw mode, so bytes pass through without echo or
+        // newline translation until a terminal program sets its own. Linux
+        // applies terminal settings made on the master to the slave; opening
+        // and closing the slave instead would leave the line hung up.
+        // `termios` has room for any libc's struct termios, opaque here.
+        let mut termios = [0u64; 32];
+        // SAFETY: `fd` is an open pty master and `termios` is larger than the
+        // struct the calls fill in
+        unsafe {
+            let termios = termios.as_mut_ptr().cast::<c_void>();
+            if tcgetattr(fd, termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            cfmakeraw(termios);
+            if tcsetattr(fd, TCSANOW, termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        let reader = master.try_clone()?;
+        Ok(Self {
+            stream: StreamBackend::new(reader, master),
+            path: path.into(),
+        })
+    }
+
+    /// Slave device to open from a terminal program
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl SerialBackend for PtyBackend {
+    fn read_byte(&mut self) -> Option<u8> {
+        self.stream.read_byte()
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        self.stream.write_byte(byte);
+    }
+
+    /// The terminal program at the other end has hung up
+    fn is_closed(&self) -> bool {
+        self.stream.is_closed()
+    }
+
+    fn device_path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
+
+/// Cycle-driven 6551 ACIA.
+pub struct Acia6551 {
+    backend: Box<dyn SerialBackend>,
+    clock_hz: u32,
+
+    status: u8,
+    command: u8,
+    control: u8,
+    irq: bool,
+
+    rx_data: u8,
+    rx_timer: u32,
+
+    tx_data: Option<u8>,
+    tx_shift: Option<u8>,
+    tx_timer: u32,
+}
+
+impl Acia6551 {
+    /// `clock_hz` is the CPU clock, used to convert baud rates to cycles
+    pub fn new<S: SerialBackend + 'static>(backend: S, clock_hz: u32) -> Self {
+        Self::with_boxed_backend(Box::new(backend), clock_hz)
+    }
+
+    pub fn with_boxed_backend(backend: Box<dyn SerialBackend>, clock_hz: u32) -> Self {
+        let mut acia = Self {
+            backend,
+            clock_hz,
+            status: 0,
+            command: 0,
+            control: 0,
+            irq: false,
+            rx_data: 0,
+            rx_timer: 0,
+            tx_data: None,
+            tx_shift: None,
+            tx_timer: 0,
+        };
+        acia.reset();
+        acia
+    }
+
+    /// Hardware reset (RES pin)
+    pub fn reset(&mut self) {
+        self.status = status::TDRE;
+        self.command = 0x02;
+        self.control = 0;
+        self.irq = false;
+        self.tx_data = None;
+        self.tx_shift = None;
+        self.rx_timer = self.char_cycles();
+    }
+
+    pub fn baud_rate(&self) -> u32 {
+        BAUD_RATES[(self.control & 0x0F) as usize]
+    }
+
+    /// Cycles to move one frame: start bit, data bits, parity and stop bits
+    pub fn char_cycles(&self) -> u32 {
+        let data_bits = 8 - ((self.control >> 5) & 0x03) as u32;
+        let parity = (self.command & 0x20 != 0) as u32;
+        let stop_bits = if self.control & 0x80 != 0 { 2 } else { 1 };
+        let bits = 1 + data_bits + parity + stop_bits;
+        ((self.clock_hz as u64 * bits as u64) / self.baud_rate() as u64).max(1) as u32
+    }
+
+    /// DTR enables the receiver and transmitter
+    fn enabled(&self) -> bool {
+        self.command & 0x01 != 0
+    }
+
+    fn rx_irq_enabled(&self) -> bool {
+        self.command & 0x02 == 0
+    }
+
+    fn tx_irq_enabled(&self) -> bool {
+        (self.command >> 2) & 0x03 == 0b01
+    }
+
+    fn echo(&self) -> bool {
+        self.command & 0x10 != 0 && (self.command >> 2) & 0x03 == 0
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if self.status & status::RDRF != 0 {
+            self.status |= status::OVERRUN;
+            return;
+        }
+        self.rx_data = byte;
+        self.status |= status::RDRF;
+        if self.rx_irq_enabled() {
+            self.irq = true;
+        }
+        if self.echo() {
+            self.backend.write_byte(byte);
+        }
+    }
+
+    /// Move the holding register into the shifter
+    fn load_shifter(&mut self) {
+        if self.tx_shift.is_none()
+            && let Some(byte) = self.tx_data.take()
+        {
+            self.tx_shift = Some(byte);
+            self.tx_timer = self.char_cycles();
+            self.status |= status::TDRE;
+            if self.tx_irq_enabled() {
+                self.irq = true;
+            }
+        }
+    }
+}
+
+impl Device for Acia6551 {
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset & 0x03 {
+            DATA => {
+                self.status &= !(status::RDRF | status::OVERRUN | status::PARITY_ERROR);
+                self.rx_data
+            }
+            STATUS => {
+                let value = self.status | if self.irq { status::IRQ } else { 0 };
+                self.irq = false;
+                value
+            }
+            COMMAND => self.command,
+            CONTROL => self.control,
+            _ => unreachable!("register offset is masked to 2 bits"),
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset & 0x03 {
+            DATA => {
+                self.tx_data = Some(value);
+                self.status &= !status::TDRE;
+                self.load_shifter();
+            }
+            STATUS => {
+                // Programmed reset: clears command bits 0-4 and the overrun flag
+                self.command &= 0xE0;
+                self.status &= !status::OVERRUN;
+            }
+            COMMAND => self.command = value,
+            CONTROL => self.control = value,
+            _ => unreachable!("register offset is masked to 2 bits"),
+        }
+    }
+
+    fn tick(&mut self) {
+        if let Some(byte) = self.tx_shift {
+            self.tx_timer = self.tx_timer.saturating_sub(1);
+            if self.tx_timer == 0 {
+                self.tx_shift = None;
+                self.backend.write_byte(byte);
+                self.load_shifter();
+            }
+        }
+
+        self.rx_timer = self.rx_timer.saturating_sub(1);
+        if self.rx_timer == 0 {
+            self.rx_timer = self.char_cycles();
+            if self.enabled()
+                && let Some(byte) = self.backend.read_byte()
+            {
+                self.receive(byte);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        Acia6551::reset(self);
+    }
+
+    fn irq(&self) -> bool {
+        self.irq
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.irq || (self.enabled() && (self.rx_irq_enabled() || self.tx_irq_enabled()))
+    }
+
+    fn serial_path(&self) -> Option<PathBuf> {
+        self.backend.device_path().map(Path::to_path_buf)
+    }
+
+    /// Registers and timers only; the backend's connection is not saved
+    fn save_state(&self) -> Vec<u8> {
+        StateWriter::new()
+            .u8(self.status)
+            .u8(self.command)
+            .u8(self.control)
+            .bool(self.irq)
+            .u8(self.rx_data)
+            .u32(self.rx_timer)
+            .bool(self.tx_data.is_some())
+            .u8(self.tx_data.unwrap_or(0))
+            .bool(self.tx_shift.is_some())
+            .u8(self.tx_shift.unwrap_or(0))
+            .u32(self.tx_timer)
+            .finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(state);
+        self.status = r.u8()?;
+        self.command = r.u8()?;
+        self.control = r.u8()?;
+        self.irq = r.bool()?;
+        self.rx_data = r.u8()?;
+        self.rx_timer = r.u32()?;
+        let has_data = r.bool()?;
+        let data = r.u8()?;
+        self.tx_data = has_data.then_some(data);
+        let shifting = r.bool()?;
+        let shift = r.u8()?;
+        self.tx_shift = shifting.then_some(shift);
+        self.tx_timer = r.u32()?;
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> (Acia6551, MemoryBackend) {
+        let line = MemoryBackend::new();
+        let mut acia = Acia6551::new(line.clone(), 1_000_000);
+        acia.write(CONTROL, 0x1E); // 9600 baud, 8N1
+        acia.write(COMMAND, 0x0B); // DTR, no RX IRQ, no TX IRQ
+        (acia, line)
+    }
+
+    fn run(acia: &mut Acia6551, cycles: u32) {
+        for _ in 0..cycles {
+            acia.tick();
+        }
+    }
+
+    #[test]
+    fn test_char_timing() {
+        let (acia, _) = setup();
+        assert_eq!(acia.baud_rate(), 9600);
+        // 10 bits at 9600 baud on a 1MHz CPU
+        assert_eq!(acia.char_cycles(), 1041);
+    }
+
+    #[test]
+    fn test_transmit() {
+        let (mut acia, line) = setup();
+
+        acia.write(DATA, b'H');
+        // Moved straight to the shifter, so the holding register is free
+        assert_ne!(acia.read(STATUS) & status::TDRE, 0);
+        acia.write(DATA, b'i');
+        assert_eq!(acia.read(STATUS) & status::TDRE, 0);
+
+        run(&mut acia, 1040);
+        assert!(line.take_output().is_empty());
+        run(&mut acia, 1);
+        assert_eq!(line.take_output(), b"H");
+        assert_ne!(acia.read(STATUS) & status::TDRE, 0);
+
+        run(&mut acia, 1041);
+        assert_eq!(line.take_output(), b"i");
+    }
+
+    #[test]
+    fn test_receive_and_overrun() {
+        let (mut acia, line) = setup();
+        line.send(b"AB");
+
+        let cycles = acia.char_cycles();
+        run(&mut acia, cycles);
+        assert_ne!(acia.read(STATUS) & status::RDRF, 0);
+
+        let cycles = acia.char_cycles();
+        run(&mut acia, cycles);
+        assert_ne!(acia.read(STATUS) & status::OVERRUN, 0);
+
+        assert_eq!(acia.read(DATA), b'A');
+        let status = acia.read(STATUS);
+        assert_eq!(status & (status::RDRF | status::OVERRUN), 0);
+    }
+
+    #[test]
+    fn test_receiver_disabled_without_dtr() {
+        let (mut acia, line) = setup();
+        acia.write(COMMAND, 0x00);
+        line.send(b"X");
+        run(&mut acia, 5000);
+        assert_eq!(acia.read(STATUS) & status::RDRF, 0);
+    }
+
+    #[test]
+    fn test_receive_interrupt() {
+        let (mut acia, line) = setup();
+        acia.write(COMMAND, 0x09); // DTR, RX IRQ enabled
+        line.send(b"Z");
+
+        let cycles = acia.char_cycles();
+        run(&mut acia, cycles);
+        assert!(Device::irq(&acia));
+        let status = acia.read(STATUS);
+        assert_ne!(status & status::IRQ, 0);
+        assert!(!Device::irq(&acia)); // reading status clears IRQ
+    }
+
+    #[test]
+    fn test_transmit_interrupt() {
+        let (mut acia, _line) = setup();
+        acia.write(COMMAND, 0x07); // DTR, no RX IRQ, TX IRQ enabled
+        acia.write(DATA, 0x55);
+        assert!(Device::irq(&acia));
+    }
+
+    #[test]
+    fn test_echo_mode() {
+        let (mut acia, line) = setup();
+        acia.write(COMMAND, 0x13); // echo, DTR, no RX IRQ
+        line.send(b"e");
+        let cycles = acia.char_cycles();
+        run(&mut acia, cycles);
+        assert_eq!(line.take_output(), b"e");
+    }
+
+    #[test]
+    fn test_programmed_reset() {
+        let (mut acia, _) = setup();
+        acia.write(COMMAND, 0xFF);
+        acia.write(STATUS, 0x00);
+        assert_eq!(acia.read(COMMAND), 0xE0);
+    }
+
+    #[test]
+    fn test_stream_backend() {
+        let input: &[u8] = b"ok";
+        let mut backend = StreamBackend::new(input, io::sink());
+
+        let mut received = Vec::new();
+        while !backend.is_closed() {
+            if let Some(byte) = backend.read_byte() {
+                received.push(byte);
+            }
+        }
+        assert_eq!(received, b"ok");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pty_backend() {
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::time::{Duration, Instant};
+
+        let mut pty = PtyBackend::open().unwrap();
+        assert_eq!(pty.device_path(), Some(pty.path()));
+        let mut peer = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(0o400) // O_NOCTTY
+            .open(pty.path())
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        // Raw: a newline arrives as is, and is not echoed back
+        pty.write_byte(b'\n');
+        let mut byte = [0];
+        peer.read_exact(&mut byte).unwrap();
+        assert_eq!(byte, *b"\n");
+        peer.write_all(b"x").unwrap();
+        let received = loop {
+            if let Some(byte) = pty.read_byte() {
+                break byte;
+            }
+            assert!(Instant::now() < deadline);
+        };
+        assert_eq!(received, b'x');
+
+        drop(peer);
+        while !pty.is_closed() {
+            assert_eq!(pty.read_byte(), None);
+            assert!(Instant::now() < deadline);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+//! "Magic" debug console for test programs
+//!
+//! Not modelled on any real chip: it gives a ROM the simplest possible way
+//! to print, read input and report a result.
+//!
+//! | Offset | Write                        | Read                          |
+//! |--------|------------------------------|-------------------------------|
+//! | $0     | print the byte               | next input byte ($00 if none) |
+//! | $1     | -                            | status                        |
+//! | $2     | exit with the byte as code   | -                             |
+//! | $3     | dump CPU registers           | -                             |
+//!
+//! Status bit 0 is set while an input byte is waiting and bit 1 once input
+//! has ended. Exit and dump are passed to the front end as a `HostRequest`.
+
+use std::path::{Path, PathBuf};
+
+use super::Device;
+use super::acia6551::SerialBackend;
+use crate::bus::HostRequest;
+
+/// Status register bits
+pub mod status {
+    pub const INPUT_READY: u8 = 0x01;
+    pub const INPUT_CLOSED: u8 = 0x02;
+}
+
+const DATA: u16 = 0;
+const STATUS: u16 = 1;
+const EXIT: u16 = 2;
+const DUMP: u16 = 3;
+
+/// Debug console wired to a `SerialBackend` (usually stdio).
+pub struct Console {
+    backend: Box<dyn SerialBackend>,
+    input: Option<u8>,
+    request: Option<HostRequest>,
+}
+
+impl Console {
+    pub fn new<S: SerialBackend + 'static>(backend: S) -> Self {
+        Self::with_boxed_backend(Box::new(backend))
+    }
+
+    pub fn with_boxed_backend(backend: Box<dyn SerialBackend>) -> Self {
+        Self {
+            backend,
+            input: None,
+            request: None,
+        }
+    }
+
+    fn poll_input(&mut self) {
+        if self.input.is_none() {
+            self.input = self.backend.read_byte();
+        }
+    }
+}
+
+impl Device for Console {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.poll_input();
+        match offset & 0x03 {
+            DATA => self.input.take().unwrap_or(0),
+            STATUS => {
+                let mut value = 0;
+                if self.input.is_some() {
+                    value |= status::INPUT_READY;
+                } else if self.backend.is_closed() {
+                    value |= status::INPUT_CLOSED;
+                }
+                value
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset & 0x03 {
+            DATA => self.backend.write_byte(value),
+            EXIT => self.request = Some(HostRequest::Exit(value)),
+            DUMP => self.request = Some(HostRequest::DumpRegisters),
+            _ => {}
+        }
+    }
+
+    fn host_request(&mut self) -> Option<HostRequest> {
+        self.request.take()
+    }
+
+    fn serial_path(&self) -> Option<PathBuf> {
+        self.backend.device_path().map(Path::to_path_buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::Cpu;
+    use crate::devices::acia6551::MemoryBackend;
+    use crate::memory_map::MemoryMapBuilder;
+
+    #[test]
+    fn test_input_and_status() {
+        let line = MemoryBackend::new();
+        let mut console = Console::new(line.clone());
+
+        assert_eq!(console.read(STATUS), 0);
+        assert_eq!(console.read(DATA), 0);
+
+        line.send(b"ok");
+        assert_eq!(console.read(STATUS), status::INPUT_READY);
+        assert_eq!(console.read(DATA), b'o');
+        assert_eq!(console.read(DATA), b'k');
+    }
+
+    #[test]
+    fn test_program_prints_and_exits() {
+        let line = MemoryBackend::new();
+        // LDA #'H' / STA $6000 / LDA #'i' / STA $6000 / STA $6003 / LDA #3 / STA $6002
+        let program = [
+            0xA9, b'H', 0x8D, 0x00, 0x60, 0xA9, b'i', 0x8D, 0x00, 0x60, 0x8D, 0x03, 0x60, 0xA9,
+            0x03, 0x8D, 0x02, 0x60,
+        ];
+        let mut rom = vec![0xEA; 0x1000];
+        rom[..program.len()].copy_from_slice(&program);
+        rom[0xFFC..0xFFE].copy_from_slice(&[0x00, 0xF0]);
+
+        let bus = MemoryMapBuilder::new()
+            .ram(0x0000, 0x1000)
+            .device(0x6000, 4, Console::new(line.clone()))
+            .rom(0xF000, 0x1000, &rom)
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::new(bus);
+        cpu.reset();
+
+        let mut requests = Vec::new();
+        for _ in 0..7 {
+            cpu.execute_instruction();
+            requests.extend(cpu.bus.host_request());
+        }
+
+        assert_eq!(line.take_output(), b"Hi");
+        assert_eq!(requests, [HostRequest::DumpRegisters, HostRequest::Exit(3)]);
+    }
+}
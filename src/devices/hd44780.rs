@@ -0,0 +1,836 @@
+//! Hitachi HD44780 character LCD controller
+//!
+//! As a `Device` the controller is mapped directly, with RS on address bit
+//! 0:
+//!
+//! | Offset | Write       | Read                            |
+//! |--------|-------------|---------------------------------|
+//! | $0     | instruction | busy flag (bit 7) and address   |
+//! | $1     | data        | data at the address counter     |
+//!
+//! More often it hangs off a VIA or PIA and is driven by toggling port
+//! pins; `PortLcd` wraps such a chip and follows E, RW and RS according to
+//! a `Wiring`.
+//!
+//! Each access is one E strobe. After a function set with DL = 0 the
+//! controller takes bytes as two strobes on D4-D7, high nibble first.
+//! Instructions take 37us (1.52ms for clear and home) during which the
+//! busy flag is set and further instructions and data are ignored.
+
+use super::state::{StateError, StateReader, StateWriter};
+use super::{Device, ParallelPorts, Port};
+
+/// Instruction bits
+pub mod instruction {
+    pub const CLEAR: u8 = 0x01;
+    pub const HOME: u8 = 0x02;
+    pub const ENTRY_MODE: u8 = 0x04;
+    pub const DISPLAY_CONTROL: u8 = 0x08;
+    pub const SHIFT: u8 = 0x10;
+    pub const FUNCTION_SET: u8 = 0x20;
+    pub const SET_CGRAM: u8 = 0x40;
+    pub const SET_DDRAM: u8 = 0x80;
+}
+
+/// Busy flag in the status byte
+pub const BUSY: u8 = 0x80;
+
+const DDRAM_SIZE: usize = 80;
+const CGRAM_SIZE: usize = 64;
+const LINE_LENGTH: u8 = 40;
+const SECOND_LINE: u8 = 0x40;
+
+const SHORT_US: u32 = 37;
+const LONG_US: u32 = 1520;
+const DATA_US: u32 = 41;
+
+/// HD44780 with a character panel of up to 80 characters.
+pub struct Hd44780 {
+    columns: usize,
+    rows: usize,
+    clock_hz: u32,
+
+    ddram: [u8; DDRAM_SIZE],
+    cgram: [u8; CGRAM_SIZE],
+    address: u8,
+    /// The address counter points into CGRAM rather than DDRAM
+    cgram_selected: bool,
+    increment: bool,
+    shift_on_write: bool,
+    display_on: bool,
+    cursor_on: bool,
+    blink_on: bool,
+    eight_bit: bool,
+    two_lines: bool,
+    large_font: bool,
+    /// Columns the display has been shifted left by
+    shift: u8,
+
+    busy: u32,
+    /// First nibble of a 4-bit transfer: the high half of a write, or the
+    /// low half a read has yet to return
+    nibble: Option<u8>,
+}
+
+impl Default for Hd44780 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Hd44780 {
+    /// A 16x2 panel on a 1MHz bus
+    pub fn new() -> Self {
+        Self::with_size(16, 2, 1_000_000)
+    }
+
+    /// A `columns` x `rows` panel. `clock_hz` is the CPU clock, which sets
+    /// how many cycles the busy flag stays up. Four-row panels continue
+    /// rows 0 and 1 in rows 2 and 3.
+    pub fn with_size(columns: usize, rows: usize, clock_hz: u32) -> Self {
+        assert!(
+            matches!(rows, 1 | 2 | 4) && columns > 0 && columns * rows <= DDRAM_SIZE,
+            "unsupported LCD size {columns}x{rows}"
+        );
+        // State after the internal power-on reset
+        Self {
+            columns,
+            rows,
+            clock_hz,
+            ddram: [b' '; DDRAM_SIZE],
+            cgram: [0; CGRAM_SIZE],
+            address: 0,
+            cgram_selected: false,
+            increment: true,
+            shift_on_write: false,
+            display_on: false,
+            cursor_on: false,
+            blink_on: false,
+            eight_bit: true,
+            two_lines: false,
+            large_font: false,
+            shift: 0,
+            busy: 0,
+            nibble: None,
+        }
+    }
+
+    pub fn columns(&self) -> usize {
+        self.columns
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.busy > 0
+    }
+
+    // ========== Bus interface ==========
+
+    /// One write strobe: RS selects data over instruction. In 4-bit mode
+    /// only D4-D7 (the high nibble of `value`) are used.
+    pub fn bus_write(&mut self, rs: bool, value: u8) {
+        let value = if self.eight_bit {
+            value
+        } else {
+            match self.nibble.take() {
+                None => {
+                    self.nibble = Some(value & 0xF0);
+                    return;
+                }
+                Some(high) => high | value >> 4,
+            }
+        };
+
+        if self.busy > 0 {
+            return;
+        }
+        if rs {
+            self.write_data(value);
+        } else {
+            self.write_instruction(value);
+        }
+    }
+
+    /// One read strobe. In 4-bit mode the byte comes back on D4-D7 over
+    /// two strobes, high nibble first.
+    pub fn bus_read(&mut self, rs: bool) -> u8 {
+        if !self.eight_bit
+            && let Some(low) = self.nibble.take()
+        {
+            return low;
+        }
+        let value = if rs {
+            self.read_data()
+        } else {
+            self.read_status()
+        };
+        if !self.eight_bit {
+            self.nibble = Some(value << 4);
+            return value & 0xF0;
+        }
+        value
+    }
+
+    // ========== Controller ==========
+
+    /// Execute an instruction byte, ignoring the interface and busy flag
+    pub fn write_instruction(&mut self, value: u8) {
+        let mut duration = SHORT_US;
+
+        if value & instruction::SET_DDRAM != 0 {
+            self.address = value & 0x7F;
+            self.cgram_selected = false;
+        } else if value & instruction::SET_CGRAM != 0 {
+            self.address = value & 0x3F;
+            self.cgram_selected = true;
+        } else if value & instruction::FUNCTION_SET != 0 {
+            self.eight_bit = value & 0x10 != 0;
+            self.two_lines = value & 0x08 != 0;
+            self.large_font = value & 0x04 != 0;
+            self.nibble = None;
+        } else if value & instruction::SHIFT != 0 {
+            let right = value & 0x04 != 0;
+            if value & 0x08 != 0 {
+                self.shift_display(right);
+            } else {
+                self.move_address(right);
+            }
+        } else if value & instruction::DISPLAY_CONTROL != 0 {
+            self.display_on = value & 0x04 != 0;
+            self.cursor_on = value & 0x02 != 0;
+            self.blink_on = value & 0x01 != 0;
+        } else if value & instruction::ENTRY_MODE != 0 {
+            self.increment = value & 0x02 != 0;
+            self.shift_on_write = value & 0x01 != 0;
+        } else if value & instruction::HOME != 0 {
+            self.address = 0;
+            self.cgram_selected = false;
+            self.shift = 0;
+            duration = LONG_US;
+        } else if value & instruction::CLEAR != 0 {
+            self.ddram = [b' '; DDRAM_SIZE];
+            self.address = 0;
+            self.cgram_selected = false;
+            self.increment = true;
+            self.shift = 0;
+            duration = LONG_US;
+        }
+
+        self.set_busy(duration);
+    }
+
+    /// Write to DDRAM or CGRAM at the address counter
+    pub fn write_data(&mut self, value: u8) {
+        if self.cgram_selected {
+            self.cgram[self.address as usize] = value & 0x1F;
+        } else {
+            let index = self.ddram_index(self.address);
+            self.ddram[index] = value;
+            if self.shift_on_write {
+                self.shift_display(!self.increment);
+            }
+        }
+        self.move_address(self.increment);
+        self.set_busy(DATA_US);
+    }
+
+    /// Busy flag and address counter
+    pub fn read_status(&self) -> u8 {
+        if self.busy > 0 {
+            BUSY | self.address
+        } else {
+            self.address
+        }
+    }
+
+    /// Read DDRAM or CGRAM at the address counter
+    pub fn read_data(&mut self) -> u8 {
+        let value = if self.cgram_selected {
+            self.cgram[self.address as usize]
+        } else {
+            self.ddram[self.ddram_index(self.address)]
+        };
+        self.move_address(self.increment);
+        self.set_busy(DATA_US);
+        value
+    }
+
+    // ========== Host side ==========
+
+    /// Character codes currently visible, one row per panel line
+    pub fn characters(&self) -> Vec<Vec<u8>> {
+        (0..self.rows)
+            .map(|row| {
+                (0..self.columns)
+                    .map(|column| self.ddram[self.visible_index(row, column)])
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// The visible text, one string per line, blank while the display is
+    /// off. Custom characters show as `▒` (see `glyph`); codes with no
+    /// close Unicode equivalent show as `?`.
+    pub fn lines(&self) -> Vec<String> {
+        if !self.display_on {
+            return vec![" ".repeat(self.columns); self.rows];
+        }
+        self.characters()
+            .iter()
+            .map(|row| row.iter().map(|&code| Self::to_char(code)).collect())
+            .collect()
+    }
+
+    /// Row and column of the cursor when it is shown and on screen
+    pub fn cursor(&self) -> Option<(usize, usize)> {
+        if !self.display_on || !(self.cursor_on || self.blink_on) || self.cgram_selected {
+            return None;
+        }
+        let index = self.ddram_index(self.address);
+        (0..self.rows)
+            .flat_map(|row| (0..self.columns).map(move |column| (row, column)))
+            .find(|&(row, column)| self.visible_index(row, column) == index)
+    }
+
+    /// The 5x8 pattern of a custom character (codes $00-$0F), one row per
+    /// byte with the leftmost dot in bit 4
+    pub fn glyph(&self, code: u8) -> [u8; 8] {
+        let start = (code as usize & 0x07) * 8;
+        self.cgram[start..start + 8].try_into().unwrap()
+    }
+
+    /// Characters from the A00 (Japanese) character ROM
+    pub fn to_char(code: u8) -> char {
+        match code {
+            0x00..=0x0F => '▒',
+            b'\\' => '¥',
+            0x20..=0x7D => code as char,
+            0x7E => '→',
+            0x7F => '←',
+            0xA5 => '・',
+            0xDF => '°',
+            0xE0 => 'α',
+            0xE2 => 'β',
+            0xE3 => 'ε',
+            0xE4 => 'μ',
+            0xE5 => 'σ',
+            0xE6 => 'ρ',
+            0xF2 => 'θ',
+            0xF3 => '∞',
+            0xF4 => 'Ω',
+            0xF7 => 'π',
+            0xFD => '÷',
+            0xFF => '█',
+            _ => '?',
+        }
+    }
+
+    // ========== Internals ==========
+
+    fn set_busy(&mut self, us: u32) {
+        self.busy = (us as u64 * self.clock_hz as u64 / 1_000_000).max(1) as u32;
+    }
+
+    /// DDRAM index of a panel position, following the display shift
+    fn visible_index(&self, row: usize, column: usize) -> usize {
+        let (line, offset) = match self.rows {
+            4 => (row % 2, (row / 2) * self.columns),
+            _ => (row, 0),
+        };
+        if self.two_lines {
+            let column = (column + offset + self.shift as usize) % LINE_LENGTH as usize;
+            line * LINE_LENGTH as usize + column
+        } else {
+            (column + offset + self.shift as usize) % DDRAM_SIZE
+        }
+    }
+
+    fn ddram_index(&self, address: u8) -> usize {
+        if self.two_lines {
+            let line = (address >= SECOND_LINE) as u8;
+            let column = (address & !SECOND_LINE) % LINE_LENGTH;
+            (line * LINE_LENGTH + column) as usize
+        } else {
+            address as usize % DDRAM_SIZE
+        }
+    }
+
+    fn move_address(&mut self, forward: bool) {
+        if self.cgram_selected {
+            self.address = if forward {
+                (self.address + 1) % CGRAM_SIZE as u8
+            } else {
+                self.address.wrapping_sub(1) % CGRAM_SIZE as u8
+            };
+            return;
+        }
+
+        if !self.two_lines {
+            let last = DDRAM_SIZE as u8 - 1;
+            self.address = match (forward, self.address) {
+                (true, a) if a >= last => 0,
+                (true, a) => a + 1,
+                (false, 0) => last,
+                (false, a) => a.min(last) - 1,
+            };
+            return;
+        }
+
+        // The end of one line continues at the start of the other
+        let line = self.address & SECOND_LINE;
+        let column = (self.address & !SECOND_LINE) % LINE_LENGTH;
+        self.address = match (forward, column) {
+            (true, c) if c + 1 >= LINE_LENGTH => line ^ SECOND_LINE,
+            (true, c) => line | (c + 1),
+            (false, 0) => (line ^ SECOND_LINE) | (LINE_LENGTH - 1),
+            (false, c) => line | (c - 1),
+        };
+    }
+
+    fn shift_display(&mut self, right: bool) {
+        let width = if self.two_lines {
+            LINE_LENGTH
+        } else {
+            DDRAM_SIZE as u8
+        };
+        self.shift = if right {
+            (self.shift + width - 1) % width
+        } else {
+            (self.shift + 1) % width
+        };
+    }
+}
+
+impl Device for Hd44780 {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.bus_read(offset & 0x01 != 0)
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        self.bus_write(offset & 0x01 != 0, value);
+    }
+
+    fn tick(&mut self) {
+        self.busy = self.busy.saturating_sub(1);
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        StateWriter::new()
+            .bytes(&self.ddram)
+            .bytes(&self.cgram)
+            .u8(self.address)
+            .bool(self.cgram_selected)
+            .bool(self.increment)
+            .bool(self.shift_on_write)
+            .bool(self.display_on)
+            .bool(self.cursor_on)
+            .bool(self.blink_on)
+            .bool(self.eight_bit)
+            .bool(self.two_lines)
+            .bool(self.large_font)
+            .u8(self.shift)
+            .u32(self.busy)
+            .bool(self.nibble.is_some())
+            .u8(self.nibble.unwrap_or(0))
+            .finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(state);
+        self.ddram.copy_from_slice(r.bytes(DDRAM_SIZE)?);
+        self.cgram.copy_from_slice(r.bytes(CGRAM_SIZE)?);
+        self.address = r.u8()?;
+        self.cgram_selected = r.bool()?;
+        self.increment = r.bool()?;
+        self.shift_on_write = r.bool()?;
+        self.display_on = r.bool()?;
+        self.cursor_on = r.bool()?;
+        self.blink_on = r.bool()?;
+        self.eight_bit = r.bool()?;
+        self.two_lines = r.bool()?;
+        self.large_font = r.bool()?;
+        self.shift = r.u8()?;
+        self.busy = r.u32()?;
+        let pending = r.bool()?;
+        let nibble = r.u8()?;
+        self.nibble = pending.then_some(nibble);
+
+        let limit = if self.cgram_selected {
+            CGRAM_SIZE as u8
+        } else {
+            0x80
+        };
+        if self.address >= limit {
+            return Err(StateError::Invalid(format!(
+                "LCD address {:#04X}",
+                self.address
+            )));
+        }
+        if self.shift as usize >= DDRAM_SIZE {
+            return Err(StateError::Invalid(format!("LCD shift {}", self.shift)));
+        }
+        r.finish()
+    }
+
+    fn screen(&self) -> Vec<String> {
+        self.lines()
+    }
+}
+
+/// Which port pins an LCD's data and control lines are wired to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Wiring {
+    /// Port carrying the data lines
+    pub data: Port,
+    /// 4-bit wiring: D4-D7 on four consecutive port bits starting at
+    /// `data_shift` (D0-D3 are not connected)
+    pub four_bit: bool,
+    pub data_shift: u8,
+    /// Port carrying E, RW and RS, given as bit masks
+    pub control: Port,
+    pub e: u8,
+    pub rw: u8,
+    pub rs: u8,
+}
+
+impl Wiring {
+    /// The "hello world" videos: D0-D7 on port B, E/RW/RS on PA7/PA6/PA5
+    pub const BEN_EATER_8BIT: Wiring = Wiring {
+        data: Port::B,
+        four_bit: false,
+        data_shift: 0,
+        control: Port::A,
+        e: 0x80,
+        rw: 0x40,
+        rs: 0x20,
+    };
+
+    /// The later videos: everything on port B, D4-D7 on PB0-PB3 and
+    /// RS/RW/E on PB4/PB5/PB6
+    pub const BEN_EATER_4BIT: Wiring = Wiring {
+        data: Port::B,
+        four_bit: true,
+        data_shift: 0,
+        control: Port::B,
+        e: 0x40,
+        rw: 0x20,
+        rs: 0x10,
+    };
+
+    fn data_mask(&self) -> u8 {
+        if self.four_bit {
+            0x0F << self.data_shift
+        } else {
+            0xFF
+        }
+    }
+}
+
+/// A port chip (VIA, PIA, RIOT) with an HD44780 wired to its pins.
+///
+/// RS and RW are sampled when E rises. On a read the LCD drives the data
+/// pins until E falls; on a write it latches them when E falls.
+pub struct PortLcd<D> {
+    chip: D,
+    lcd: Hd44780,
+    wiring: Wiring,
+    /// RW and RS of the bus cycle in progress while E is high
+    cycle: Option<(bool, bool)>,
+}
+
+impl<D: Device + ParallelPorts> PortLcd<D> {
+    pub fn new(chip: D, lcd: Hd44780, wiring: Wiring) -> Self {
+        let mut port_lcd = Self {
+            chip,
+            lcd,
+            wiring,
+            cycle: None,
+        };
+        port_lcd.update_lcd();
+        port_lcd
+    }
+
+    pub fn chip(&self) -> &D {
+        &self.chip
+    }
+
+    pub fn chip_mut(&mut self) -> &mut D {
+        &mut self.chip
+    }
+
+    pub fn lcd(&self) -> &Hd44780 {
+        &self.lcd
+    }
+
+    /// Follow the control lines after the chip's outputs may have changed.
+    /// Lines not yet set as outputs float and don't count as high.
+    fn update_lcd(&mut self) {
+        let w = self.wiring;
+        let control = self.chip.port_output(w.control) & self.chip.port_direction(w.control);
+        let enable = control & w.e != 0;
+
+        if enable && self.cycle.is_none() {
+            let read = control & w.rw != 0;
+            let rs = control & w.rs != 0;
+            if read {
+                let value = self.lcd.bus_read(rs);
+                let pins = if w.four_bit {
+                    !w.data_mask() | (value >> 4) << w.data_shift
+                } else {
+                    value
+                };
+                self.chip.set_port_input(w.data, pins);
+            }
+            self.cycle = Some((read, rs));
+        } else if !enable && let Some((read, rs)) = self.cycle.take() {
+            if read {
+                self.chip.set_port_input(w.data, 0xFF);
+            } else {
+                let pins = self.chip.port_output(w.data);
+                let value = if w.four_bit {
+                    ((pins & w.data_mask()) >> w.data_shift) << 4
+                } else {
+                    pins
+                };
+                self.lcd.bus_write(rs, value);
+            }
+        }
+    }
+}
+
+impl<D: Device + ParallelPorts> Device for PortLcd<D> {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.chip.read(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        self.chip.write(offset, value);
+        self.update_lcd();
+    }
+
+    fn tick(&mut self) {
+        self.chip.tick();
+        self.lcd.tick();
+    }
+
+    // The LCD is not on the RES line
+    fn reset(&mut self) {
+        self.chip.reset();
+        self.update_lcd();
+    }
+
+    fn irq(&self) -> bool {
+        self.chip.irq()
+    }
+
+    fn nmi(&self) -> bool {
+        self.chip.nmi()
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.chip.interrupts_enabled()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let chip = self.chip.save_state();
+        let lcd = self.lcd.save_state();
+        StateWriter::new()
+            .u32(chip.len() as u32)
+            .bytes(&chip)
+            .u32(lcd.len() as u32)
+            .bytes(&lcd)
+            .u8(match self.cycle {
+                None => 0xFF,
+                Some((read, rs)) => (read as u8) << 1 | rs as u8,
+            })
+            .finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(state);
+        let len = r.u32()? as usize;
+        self.chip.load_state(r.bytes(len)?)?;
+        let len = r.u32()? as usize;
+        self.lcd.load_state(r.bytes(len)?)?;
+        self.cycle = match r.u8()? {
+            0xFF => None,
+            bits @ 0..=3 => Some((bits & 0x02 != 0, bits & 0x01 != 0)),
+            bits => return Err(StateError::Invalid(format!("LCD bus cycle {bits:#04X}"))),
+        };
+        r.finish()
+    }
+
+    fn screen(&self) -> Vec<String> {
+        self.lcd.lines()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::Via6522;
+
+    /// Wait out the busy flag, then strobe
+    fn send(lcd: &mut Hd44780, rs: bool, value: u8) {
+        while lcd.is_busy() {
+            lcd.tick();
+        }
+        lcd.write(rs as u16, value);
+    }
+
+    fn print(lcd: &mut Hd44780, text: &str) {
+        for byte in text.bytes() {
+            send(lcd, true, byte);
+        }
+    }
+
+    fn init(lcd: &mut Hd44780) {
+        send(lcd, false, 0x38); // 8-bit, two lines, 5x8
+        send(lcd, false, 0x0E); // display on, cursor on
+        send(lcd, false, 0x06); // increment
+        send(lcd, false, 0x01); // clear
+    }
+
+    #[test]
+    fn test_hello_world() {
+        let mut lcd = Hd44780::new();
+        init(&mut lcd);
+        print(&mut lcd, "Hello, world!");
+        send(&mut lcd, false, 0xC0); // second line
+        print(&mut lcd, "6502");
+
+        assert_eq!(lcd.lines(), ["Hello, world!   ", "6502            "]);
+        assert_eq!(lcd.cursor(), Some((1, 4)));
+        assert_eq!(lcd.read(0) & !BUSY, 0x44);
+    }
+
+    #[test]
+    fn test_busy_timing() {
+        let mut lcd = Hd44780::new();
+        lcd.write(0, 0x01); // clear: 1.52ms at 1MHz
+        assert_eq!(lcd.read(0), BUSY);
+
+        // Ignored while busy
+        lcd.write(0, 0x0C);
+        for _ in 0..1519 {
+            lcd.tick();
+        }
+        assert!(lcd.is_busy());
+        lcd.tick();
+        assert_eq!(lcd.read(0), 0x00);
+        assert!(lcd.lines()[0].trim().is_empty());
+
+        lcd.write(1, b'A'); // 41us
+        for _ in 0..41 {
+            lcd.tick();
+        }
+        assert_eq!(lcd.read(0), 0x01);
+    }
+
+    #[test]
+    fn test_line_wrap_and_read_back() {
+        let mut lcd = Hd44780::new();
+        init(&mut lcd);
+        send(&mut lcd, false, 0x80 | 39);
+        print(&mut lcd, "ab");
+        assert_eq!(lcd.read_status() & !BUSY, 0x41);
+
+        send(&mut lcd, false, 0xC0);
+        assert_eq!(lcd.read(1), b'b');
+        assert_eq!(lcd.lines()[1], "b               ");
+    }
+
+    #[test]
+    fn test_shifts_and_entry_mode() {
+        let mut lcd = Hd44780::new();
+        init(&mut lcd);
+        print(&mut lcd, "ABC");
+
+        send(&mut lcd, false, 0x18); // shift display left
+        assert_eq!(&lcd.lines()[0][..3], "BC ");
+        send(&mut lcd, false, 0x1C); // and back right
+        assert_eq!(&lcd.lines()[0][..3], "ABC");
+
+        send(&mut lcd, false, 0x10); // cursor left
+        send(&mut lcd, false, 0x04); // decrement
+        print(&mut lcd, "xy");
+        assert_eq!(&lcd.lines()[0][..3], "Ayx");
+        assert_eq!(lcd.read_status() & 0x7F, 0);
+
+        // Decrementing from the start of line 1 wraps to the end of line 2
+        send(&mut lcd, false, 0x80);
+        print(&mut lcd, "z");
+        assert_eq!(lcd.read_status() & 0x7F, 0x67);
+    }
+
+    #[test]
+    fn test_custom_characters() {
+        let mut lcd = Hd44780::new();
+        init(&mut lcd);
+        let heart = [0x00, 0x0A, 0x1F, 0x1F, 0x0E, 0x04, 0x00, 0x00];
+        send(&mut lcd, false, 0x40 | 8); // CGRAM, character 1
+        for row in heart {
+            send(&mut lcd, true, row);
+        }
+        send(&mut lcd, false, 0x80);
+        send(&mut lcd, true, 0x01);
+        send(&mut lcd, true, 0x09); // same glyph
+        send(&mut lcd, true, 0xDF);
+
+        assert_eq!(lcd.glyph(1), heart);
+        assert_eq!(lcd.characters()[0][..3], [0x01, 0x09, 0xDF]);
+        assert_eq!(&lcd.lines()[0][..], "▒▒°             ");
+    }
+
+    #[test]
+    fn test_four_line_panel() {
+        let mut lcd = Hd44780::with_size(20, 4, 1_000_000);
+        init(&mut lcd);
+        print(&mut lcd, &"0123456789".repeat(4));
+        let lines = lcd.lines();
+        assert_eq!(lines[0], "01234567890123456789");
+        assert_eq!(lines[2], "01234567890123456789");
+        assert!(lines[1].trim().is_empty());
+    }
+
+    #[test]
+    fn test_four_bit_via() {
+        // Ben Eater's 4-bit wiring, driven the way his lcd_instruction does
+        let mut board = PortLcd::new(Via6522::new(), Hd44780::new(), Wiring::BEN_EATER_4BIT);
+        let (e, rs) = (0x40, 0x10);
+        let strobe = |board: &mut PortLcd<Via6522>, value: u8| {
+            board.write(0, value);
+            board.write(0, value | e);
+            board.write(0, value);
+            for _ in 0..2000 {
+                board.tick();
+            }
+        };
+        board.write(2, 0xFF); // DDRB
+
+        strobe(&mut board, 0x02); // 4-bit mode, from 8-bit mode
+        for byte in [0x28, 0x0E, 0x06] {
+            strobe(&mut board, byte >> 4);
+            strobe(&mut board, byte & 0x0F);
+        }
+        for byte in *b"Hi" {
+            strobe(&mut board, rs | byte >> 4);
+            strobe(&mut board, rs | byte & 0x0F);
+        }
+        assert_eq!(board.screen()[0], "Hi              ");
+
+        // Busy check: two reads on PB0-PB3 with PB0-PB3 as inputs
+        board.write(2, 0xF0);
+        board.write(0, 0x20);
+        board.write(0, 0x20 | e);
+        let high = board.read(0) & 0x0F;
+        board.write(0, 0x20);
+        board.write(0, 0x20 | e);
+        let low = board.read(0) & 0x0F;
+        board.write(0, 0x20);
+        assert_eq!(high << 4 | low, 0x02);
+    }
+}
@@ -0,0 +1,175 @@
+//! Memory-mapped peripheral devices
+//!
+//! Devices are attached to a bus at a base address and see register
+//! offsets relative to that base, so the same device can be mapped anywhere
+//! in the address space.
+//!
+//! A device that the host also needs to reach (to feed a keyboard or watch a
+//! display) can be shared as `Rc<RefCell<D>>`, which is itself a `Device`.
+
+pub mod acia6551;
+pub mod console;
+pub mod hd44780;
+pub mod pia6821;
+pub mod riot6532;
+pub mod rriot6530;
+pub mod state;
+pub mod timer;
+pub mod via6522;
+
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::bus::HostRequest;
+
+pub use acia6551::Acia6551;
+pub use console::Console;
+pub use hd44780::Hd44780;
+pub use pia6821::Pia6821;
+pub use riot6532::Riot6532;
+pub use rriot6530::Rriot6530;
+pub use state::StateError;
+pub use timer::Timer;
+pub use via6522::Via6522;
+
+/// One of a chip's two 8-bit ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    A,
+    B,
+}
+
+/// Chips with two 8-bit ports that other hardware (an LCD, a keypad) can
+/// be wired to
+pub trait ParallelPorts {
+    /// Pin levels as seen from outside: outputs driven, inputs pulled high
+    fn port_output(&self, port: Port) -> u8;
+
+    /// Data direction register; 1 bits are outputs
+    fn port_direction(&self, port: Port) -> u8;
+
+    /// Levels driven onto the port's input pins by external hardware
+    fn set_port_input(&mut self, port: Port, value: u8);
+}
+
+/// A peripheral that occupies a range of the address space.
+pub trait Device {
+    /// Read a register. `offset` is relative to the start of the device's region.
+    fn read(&mut self, offset: u16) -> u8;
+
+    /// Write a register. `offset` is relative to the start of the device's region.
+    fn write(&mut self, offset: u16, value: u8);
+
+    /// Advance the device by one CPU cycle
+    fn tick(&mut self) {}
+
+    /// RES line asserted
+    fn reset(&mut self) {}
+
+    /// Level of the device's IRQ output (true = asserted)
+    fn irq(&self) -> bool {
+        false
+    }
+
+    /// Level of the device's NMI output (true = asserted). The CPU reacts to
+    /// the line going active, not to its level.
+    fn nmi(&self) -> bool {
+        false
+    }
+
+    /// Whether the device is set up to interrupt the CPU on either line, so
+    /// a program idling in a loop can expect to be woken
+    fn interrupts_enabled(&self) -> bool {
+        false
+    }
+
+    /// Serialise the device's internal state. Devices without state worth
+    /// keeping return an empty buffer.
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Restore a buffer produced by `save_state`
+    fn load_state(&mut self, _state: &[u8]) -> Result<(), StateError> {
+        Ok(())
+    }
+
+    /// Take a pending request for the emulator (exit, register dump, ...)
+    fn host_request(&mut self) -> Option<HostRequest> {
+        None
+    }
+
+    /// Rows of text for a front end to show, such as the lines of an LCD.
+    /// Empty for devices without a display.
+    fn screen(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// A key typed in a front end that draws the screen itself, for devices
+    /// with a keypad or keyboard of their own. Returns false if the device
+    /// has no use for it.
+    fn key(&mut self, _byte: u8) -> bool {
+        false
+    }
+
+    /// Where a terminal program can connect to the device's serial line,
+    /// such as a pseudo-terminal's `/dev/pts/N`
+    fn serial_path(&self) -> Option<PathBuf> {
+        None
+    }
+}
+
+impl<D: Device> Device for Rc<RefCell<D>> {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.borrow_mut().read(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        self.borrow_mut().write(offset, value);
+    }
+
+    fn tick(&mut self) {
+        self.borrow_mut().tick();
+    }
+
+    fn reset(&mut self) {
+        self.borrow_mut().reset();
+    }
+
+    fn irq(&self) -> bool {
+        self.borrow().irq()
+    }
+
+    fn nmi(&self) -> bool {
+        self.borrow().nmi()
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.borrow().interrupts_enabled()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        self.borrow().save_state()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        self.borrow_mut().load_state(state)
+    }
+
+    fn host_request(&mut self) -> Option<HostRequest> {
+        self.borrow_mut().host_request()
+    }
+
+    fn screen(&self) -> Vec<String> {
+        self.borrow().screen()
+    }
+
+    fn key(&mut self, byte: u8) -> bool {
+        self.borrow_mut().key(byte)
+    }
+
+    fn serial_path(&self) -> Option<PathBuf> {
+        self.borrow().serial_path()
+    }
+}
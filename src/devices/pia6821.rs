@@ -0,0 +1,491 @@
+//! MOS 6520 / Motorola 6821 Peripheral Interface Adapter
+//!
+//! Register map (offset from the base address):
+//!
+//! | Offset | CRx bit 2 = 1         | CRx bit 2 = 0 |
+//! |--------|-----------------------|---------------|
+//! | $0     | port A                | DDRA          |
+//! | $1     | CRA                   | CRA           |
+//! | $2     | port B                | DDRB          |
+//! | $3     | CRB                   | CRB           |
+//!
+//! Control register bits: 0 enables the C1 interrupt, 1 picks its active
+//! edge (1 = rising), 2 selects the data register over the DDR, 3-5 set up
+//! C2, and the read-only bits 6 and 7 are the C2 and C1 interrupt flags.
+//! Reading a port's data register clears both of its flags.
+//!
+//! The 6520 and 6821 are register compatible, so one model covers both.
+
+use super::state::{StateError, StateReader, StateWriter};
+use super::{Device, ParallelPorts, Port};
+
+/// Control register flag bits
+pub mod control {
+    pub const C1_IRQ_ENABLE: u8 = 0x01;
+    pub const C1_RISING: u8 = 0x02;
+    pub const DATA_SELECT: u8 = 0x04;
+    pub const C2_FLAG: u8 = 0x40;
+    pub const C1_FLAG: u8 = 0x80;
+}
+
+const PORT_A: u16 = 0;
+const CRA: u16 = 1;
+const PORT_B: u16 = 2;
+const CRB: u16 = 3;
+
+/// Behaviour of CA2/CB2 selected by control register bits 3-5
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ControlMode {
+    /// Input; `rising` selects the active edge, `irq` enables the interrupt
+    Input { rising: bool, irq: bool },
+    /// Output low after a port access until the next active C1 edge
+    Handshake,
+    /// Output low for one cycle after a port access
+    Pulse,
+    /// Output follows control register bit 3
+    Manual(bool),
+}
+
+impl ControlMode {
+    fn from_control(cr: u8) -> Self {
+        let bits = (cr >> 3) & 0x07;
+        match bits {
+            0b000..=0b011 => ControlMode::Input {
+                rising: bits & 0x02 != 0,
+                irq: bits & 0x01 != 0,
+            },
+            0b100 => ControlMode::Handshake,
+            0b101 => ControlMode::Pulse,
+            0b110 => ControlMode::Manual(false),
+            _ => ControlMode::Manual(true),
+        }
+    }
+}
+
+/// One half of the PIA
+struct Side {
+    or: u8,
+    ddr: u8,
+    cr: u8,
+    pins: u8,
+    c1: bool,
+    c2_in: bool,
+    c2_out: bool,
+    c2_pulse: bool,
+}
+
+impl Side {
+    fn new() -> Self {
+        Self {
+            or: 0,
+            ddr: 0,
+            cr: 0,
+            pins: 0xFF,
+            c1: true,
+            c2_in: true,
+            c2_out: true,
+            c2_pulse: false,
+        }
+    }
+
+    fn output(&self) -> u8 {
+        (self.or & self.ddr) | !self.ddr
+    }
+
+    fn irq_enabled(&self) -> bool {
+        self.cr & control::C1_IRQ_ENABLE != 0
+            || matches!(
+                ControlMode::from_control(self.cr),
+                ControlMode::Input { irq: true, .. }
+            )
+    }
+
+    fn irq(&self) -> bool {
+        let c1 = self.cr & control::C1_FLAG != 0 && self.cr & control::C1_IRQ_ENABLE != 0;
+        let c2 = self.cr & control::C2_FLAG != 0
+            && matches!(
+                ControlMode::from_control(self.cr),
+                ControlMode::Input { irq: true, .. }
+            );
+        c1 || c2
+    }
+
+    fn write_control(&mut self, value: u8) {
+        self.cr = (self.cr & 0xC0) | (value & 0x3F);
+        match ControlMode::from_control(self.cr) {
+            ControlMode::Manual(level) => self.c2_out = level,
+            ControlMode::Input { .. } => {}
+            ControlMode::Handshake | ControlMode::Pulse => {
+                if !self.c2_pulse {
+                    self.c2_out = true;
+                }
+            }
+        }
+        // Switching C2 to output clears a pending C2 flag
+        if !matches!(
+            ControlMode::from_control(self.cr),
+            ControlMode::Input { .. }
+        ) {
+            self.cr &= !control::C2_FLAG;
+        }
+    }
+
+    /// Port access that starts a handshake or pulse on C2
+    fn strobe(&mut self) {
+        match ControlMode::from_control(self.cr) {
+            ControlMode::Handshake => self.c2_out = false,
+            ControlMode::Pulse => {
+                self.c2_out = false;
+                self.c2_pulse = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn set_c1(&mut self, level: bool) {
+        let rising = self.cr & control::C1_RISING != 0;
+        if self.c1 != level && level == rising {
+            self.cr |= control::C1_FLAG;
+            if ControlMode::from_control(self.cr) == ControlMode::Handshake {
+                self.c2_out = true;
+            }
+        }
+        self.c1 = level;
+    }
+
+    fn set_c2(&mut self, level: bool) {
+        if let ControlMode::Input { rising, .. } = ControlMode::from_control(self.cr)
+            && self.c2_in != level
+            && level == rising
+        {
+            self.cr |= control::C2_FLAG;
+        }
+        self.c2_in = level;
+    }
+
+    fn save(&self, w: &mut StateWriter) {
+        w.u8(self.or)
+            .u8(self.ddr)
+            .u8(self.cr)
+            .u8(self.pins)
+            .bool(self.c1)
+            .bool(self.c2_in)
+            .bool(self.c2_out)
+            .bool(self.c2_pulse);
+    }
+
+    fn load(r: &mut StateReader) -> Result<Self, StateError> {
+        Ok(Self {
+            or: r.u8()?,
+            ddr: r.u8()?,
+            cr: r.u8()?,
+            pins: r.u8()?,
+            c1: r.bool()?,
+            c2_in: r.bool()?,
+            c2_out: r.bool()?,
+            c2_pulse: r.bool()?,
+        })
+    }
+
+    fn tick(&mut self) {
+        if self.c2_pulse {
+            self.c2_pulse = false;
+            self.c2_out = true;
+        }
+    }
+}
+
+/// 6520/6821 PIA.
+pub struct Pia6821 {
+    a: Side,
+    b: Side,
+}
+
+impl Default for Pia6821 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pia6821 {
+    pub fn new() -> Self {
+        Self {
+            a: Side::new(),
+            b: Side::new(),
+        }
+    }
+
+    /// RES pin: all registers are cleared. Levels driven by external
+    /// hardware stay where they are, so a reset never looks like an edge.
+    pub fn reset(&mut self) {
+        for side in [&mut self.a, &mut self.b] {
+            let (pins, c1, c2_in) = (side.pins, side.c1, side.c2_in);
+            *side = Side::new();
+            side.pins = pins;
+            side.c1 = c1;
+            side.c2_in = c2_in;
+        }
+    }
+
+    // ========== Host side ==========
+
+    /// Levels driven onto port A by external hardware (inputs only)
+    pub fn set_port_a_input(&mut self, value: u8) {
+        self.a.pins = value;
+    }
+
+    /// Levels driven onto port B by external hardware (inputs only)
+    pub fn set_port_b_input(&mut self, value: u8) {
+        self.b.pins = value;
+    }
+
+    /// Port A as seen from outside: outputs driven by ORA, inputs pulled high
+    pub fn port_a_output(&self) -> u8 {
+        self.a.output()
+    }
+
+    /// Port B as seen from outside: outputs driven by ORB, inputs pulled high
+    pub fn port_b_output(&self) -> u8 {
+        self.b.output()
+    }
+
+    pub fn ca2_output(&self) -> bool {
+        self.a.c2_out
+    }
+
+    pub fn cb2_output(&self) -> bool {
+        self.b.c2_out
+    }
+
+    pub fn set_ca1(&mut self, level: bool) {
+        self.a.set_c1(level);
+    }
+
+    pub fn set_ca2(&mut self, level: bool) {
+        self.a.set_c2(level);
+    }
+
+    pub fn set_cb1(&mut self, level: bool) {
+        self.b.set_c1(level);
+    }
+
+    pub fn set_cb2(&mut self, level: bool) {
+        self.b.set_c2(level);
+    }
+
+    /// IRQA output
+    pub fn irq_a(&self) -> bool {
+        self.a.irq()
+    }
+
+    /// IRQB output
+    pub fn irq_b(&self) -> bool {
+        self.b.irq()
+    }
+
+    /// IRQA and IRQB wired together, as most boards do
+    pub fn irq(&self) -> bool {
+        self.irq_a() || self.irq_b()
+    }
+}
+
+impl ParallelPorts for Pia6821 {
+    fn port_output(&self, port: Port) -> u8 {
+        match port {
+            Port::A => self.port_a_output(),
+            Port::B => self.port_b_output(),
+        }
+    }
+
+    fn port_direction(&self, port: Port) -> u8 {
+        match port {
+            Port::A => self.a.ddr,
+            Port::B => self.b.ddr,
+        }
+    }
+
+    fn set_port_input(&mut self, port: Port, value: u8) {
+        match port {
+            Port::A => self.set_port_a_input(value),
+            Port::B => self.set_port_b_input(value),
+        }
+    }
+}
+
+impl Device for Pia6821 {
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset & 0x03 {
+            PORT_A => {
+                if self.a.cr & control::DATA_SELECT == 0 {
+                    return self.a.ddr;
+                }
+                // Port A reads the pins, so outputs read back what is driven
+                let value = (self.a.or & self.a.ddr) | (self.a.pins & !self.a.ddr);
+                self.a.cr &= !(control::C1_FLAG | control::C2_FLAG);
+                self.a.strobe();
+                value
+            }
+            CRA => self.a.cr,
+            PORT_B => {
+                if self.b.cr & control::DATA_SELECT == 0 {
+                    return self.b.ddr;
+                }
+                // Port B outputs read back from the output register
+                let value = (self.b.or & self.b.ddr) | (self.b.pins & !self.b.ddr);
+                self.b.cr &= !(control::C1_FLAG | control::C2_FLAG);
+                value
+            }
+            CRB => self.b.cr,
+            _ => unreachable!("register offset is masked to 2 bits"),
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset & 0x03 {
+            PORT_A => {
+                if self.a.cr & control::DATA_SELECT == 0 {
+                    self.a.ddr = value;
+                } else {
+                    self.a.or = value;
+                }
+            }
+            CRA => self.a.write_control(value),
+            PORT_B => {
+                if self.b.cr & control::DATA_SELECT == 0 {
+                    self.b.ddr = value;
+                } else {
+                    self.b.or = value;
+                    self.b.strobe();
+                }
+            }
+            CRB => self.b.write_control(value),
+            _ => unreachable!("register offset is masked to 2 bits"),
+        }
+    }
+
+    fn tick(&mut self) {
+        self.a.tick();
+        self.b.tick();
+    }
+
+    fn reset(&mut self) {
+        Pia6821::reset(self);
+    }
+
+    fn irq(&self) -> bool {
+        Pia6821::irq(self)
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.a.irq_enabled() || self.b.irq_enabled()
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        self.a.save(&mut w);
+        self.b.save(&mut w);
+        w.finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(state);
+        self.a = Side::load(&mut r)?;
+        self.b = Side::load(&mut r)?;
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ddr_select() {
+        let mut pia = Pia6821::new();
+
+        // CRA bit 2 clear: offset 0 is the DDR
+        pia.write(PORT_A, 0xF0);
+        assert_eq!(pia.read(PORT_A), 0xF0);
+
+        pia.write(CRA, control::DATA_SELECT);
+        pia.write(PORT_A, 0xAA);
+        pia.set_port_a_input(0x05);
+        assert_eq!(pia.port_a_output(), 0xAF);
+        assert_eq!(pia.read(PORT_A), 0xA5);
+    }
+
+    #[test]
+    fn test_ca1_interrupt() {
+        let mut pia = Pia6821::new();
+        // Rising edge, IRQ enabled, data register selected
+        pia.write(
+            CRA,
+            control::C1_IRQ_ENABLE | control::C1_RISING | control::DATA_SELECT,
+        );
+
+        pia.set_ca1(false);
+        assert!(!pia.irq());
+        pia.set_ca1(true);
+        assert!(pia.irq_a());
+        assert_ne!(pia.read(CRA) & control::C1_FLAG, 0);
+
+        // Reading port A acknowledges it
+        pia.read(PORT_A);
+        assert!(!pia.irq());
+    }
+
+    #[test]
+    fn test_flag_without_interrupt() {
+        let mut pia = Pia6821::new();
+        pia.write(CRB, control::DATA_SELECT); // falling edge, IRQ disabled
+
+        pia.set_cb1(false);
+        assert_ne!(pia.read(CRB) & control::C1_FLAG, 0);
+        assert!(!pia.irq());
+    }
+
+    #[test]
+    fn test_c2_input_interrupt() {
+        let mut pia = Pia6821::new();
+        // CA2 input, rising edge, IRQ enabled
+        pia.write(CRA, 0x18 | control::DATA_SELECT);
+
+        pia.set_ca2(false);
+        pia.set_ca2(true);
+        assert!(pia.irq());
+        assert_ne!(pia.read(CRA) & control::C2_FLAG, 0);
+    }
+
+    #[test]
+    fn test_cb2_handshake() {
+        let mut pia = Pia6821::new();
+        // CB2 handshake output, CB1 rising edge
+        pia.write(CRB, 0x20 | control::C1_RISING | control::DATA_SELECT);
+        pia.set_cb1(false);
+        assert!(pia.cb2_output());
+
+        // Writing port B pulls CB2 low until the peripheral acknowledges
+        pia.write(PORT_B, 0x41);
+        assert!(!pia.cb2_output());
+        pia.tick();
+        assert!(!pia.cb2_output());
+
+        pia.set_cb1(true);
+        assert!(pia.cb2_output());
+    }
+
+    #[test]
+    fn test_ca2_pulse_and_manual() {
+        let mut pia = Pia6821::new();
+        pia.write(CRA, 0x28 | control::DATA_SELECT); // pulse mode
+        pia.read(PORT_A);
+        assert!(!pia.ca2_output());
+        pia.tick();
+        assert!(pia.ca2_output());
+
+        pia.write(CRA, 0x30); // manual low
+        assert!(!pia.ca2_output());
+        pia.write(CRA, 0x38); // manual high
+        assert!(pia.ca2_output());
+    }
+}
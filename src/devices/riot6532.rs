@@ -0,0 +1,392 @@
+//! MOS 6532 RAM-I/O-Timer (RIOT)
+//!
+//! The chip has a RAM select pin separate from its address lines. As a
+//! `Device` it is laid out with that pin on address bit 7, which is how most
+//! boards wire it:
+//!
+//! | Offset    | Write                          | Read                  |
+//! |-----------|--------------------------------|-----------------------|
+//! | $00-$7F   | RAM                            | RAM                   |
+//! | $80       | ORA                            | port A                |
+//! | $81       | DDRA                           | DDRA                  |
+//! | $82       | ORB                            | port B                |
+//! | $83       | DDRB                           | DDRB                  |
+//! | $84/$8C   | PA7 edge control (A4 = 0)      | timer (A3 = IRQ on)   |
+//! | $85/$8D   | PA7 edge control (A4 = 0)      | interrupt flags       |
+//! | $94-$97   | timer /1, /8, /64, /1024       |                       |
+//! | $9C-$9F   | same, with timer IRQ enabled   |                       |
+//!
+//! Boards that decode RAM and I/O at unrelated addresses (the Atari 2600
+//! puts RAM at $80 and I/O at $280) can use `read_ram`/`read_io` and
+//! friends from their own bus.
+
+use super::state::{StateError, StateReader, StateWriter};
+use super::{Device, ParallelPorts, Port};
+
+/// Interrupt flag register bits
+pub mod interrupt {
+    pub const PA7: u8 = 0x40;
+    pub const TIMER: u8 = 0x80;
+}
+
+const RAM_SIZE: usize = 128;
+const PRESCALERS: [u16; 4] = [1, 8, 64, 1024];
+
+/// Cycle-driven 6532 RIOT.
+pub struct Riot6532 {
+    ram: [u8; RAM_SIZE],
+
+    ora: u8,
+    orb: u8,
+    ddra: u8,
+    ddrb: u8,
+    port_a_pins: u8,
+    port_b_pins: u8,
+
+    timer: u8,
+    prescaler: u16,
+    prescale_count: u16,
+    timer_irq_enabled: bool,
+
+    pa7: bool,
+    pa7_positive: bool,
+    pa7_irq_enabled: bool,
+
+    flags: u8,
+}
+
+impl Default for Riot6532 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Riot6532 {
+    pub fn new() -> Self {
+        Self {
+            ram: [0; RAM_SIZE],
+            ora: 0,
+            orb: 0,
+            ddra: 0,
+            ddrb: 0,
+            port_a_pins: 0xFF,
+            port_b_pins: 0xFF,
+            timer: 0xFF,
+            prescaler: 1024,
+            prescale_count: 0,
+            timer_irq_enabled: false,
+            pa7: true,
+            pa7_positive: false,
+            pa7_irq_enabled: false,
+            flags: 0,
+        }
+    }
+
+    /// RES pin: ports become inputs and interrupts are disabled. RAM and the
+    /// timer are not affected.
+    pub fn reset(&mut self) {
+        self.ora = 0;
+        self.orb = 0;
+        self.ddra = 0;
+        self.ddrb = 0;
+        self.timer_irq_enabled = false;
+        self.pa7_positive = false;
+        self.pa7_irq_enabled = false;
+        self.flags = 0;
+        self.pa7 = self.read_port_a() & 0x80 != 0;
+    }
+
+    // ========== Host side ==========
+
+    /// Levels driven onto port A by external hardware (inputs only)
+    pub fn set_port_a_input(&mut self, value: u8) {
+        self.port_a_pins = value;
+        self.update_pa7();
+    }
+
+    /// Levels driven onto port B by external hardware (inputs only)
+    pub fn set_port_b_input(&mut self, value: u8) {
+        self.port_b_pins = value;
+    }
+
+    /// Port A as seen from outside: outputs driven by ORA, inputs pulled high
+    pub fn port_a_output(&self) -> u8 {
+        (self.ora & self.ddra) | !self.ddra
+    }
+
+    /// Port B as seen from outside: outputs driven by ORB, inputs pulled high
+    pub fn port_b_output(&self) -> u8 {
+        (self.orb & self.ddrb) | !self.ddrb
+    }
+
+    /// True while the RIOT is asserting its IRQ output
+    pub fn irq(&self) -> bool {
+        (self.timer_irq_enabled && self.flags & interrupt::TIMER != 0)
+            || (self.pa7_irq_enabled && self.flags & interrupt::PA7 != 0)
+    }
+
+    // ========== Split access for custom buses ==========
+
+    pub fn read_ram(&self, offset: u16) -> u8 {
+        self.ram[offset as usize % RAM_SIZE]
+    }
+
+    pub fn write_ram(&mut self, offset: u16, value: u8) {
+        self.ram[offset as usize % RAM_SIZE] = value;
+    }
+
+    /// Read an I/O or timer register; only address bits 0-4 are decoded
+    pub fn read_io(&mut self, offset: u16) -> u8 {
+        if offset & 0x04 == 0 {
+            match offset & 0x03 {
+                0 => self.read_port_a(),
+                1 => self.ddra,
+                2 => (self.orb & self.ddrb) | (self.port_b_pins & !self.ddrb),
+                _ => self.ddrb,
+            }
+        } else if offset & 0x01 == 0 {
+            // Reading the timer clears its flag; A3 sets the IRQ enable
+            self.timer_irq_enabled = offset & 0x08 != 0;
+            self.flags &= !interrupt::TIMER;
+            self.timer
+        } else {
+            let value = self.flags;
+            self.flags &= !interrupt::PA7;
+            value
+        }
+    }
+
+    /// Write an I/O or timer register; only address bits 0-4 are decoded
+    pub fn write_io(&mut self, offset: u16, value: u8) {
+        if offset & 0x04 == 0 {
+            match offset & 0x03 {
+                0 => self.ora = value,
+                1 => self.ddra = value,
+                2 => self.orb = value,
+                _ => self.ddrb = value,
+            }
+            self.update_pa7();
+        } else if offset & 0x10 != 0 {
+            self.prescaler = PRESCALERS[(offset & 0x03) as usize];
+            self.timer_irq_enabled = offset & 0x08 != 0;
+            self.timer = value;
+            self.prescale_count = 0;
+            self.flags &= !interrupt::TIMER;
+        } else {
+            self.pa7_positive = offset & 0x01 != 0;
+            self.pa7_irq_enabled = offset & 0x02 != 0;
+        }
+    }
+
+    // ========== Internals ==========
+
+    fn read_port_a(&self) -> u8 {
+        (self.ora & self.ddra) | (self.port_a_pins & !self.ddra)
+    }
+
+    fn update_pa7(&mut self) {
+        let level = self.read_port_a() & 0x80 != 0;
+        if level != self.pa7 && level == self.pa7_positive {
+            self.flags |= interrupt::PA7;
+        }
+        self.pa7 = level;
+    }
+}
+
+impl ParallelPorts for Riot6532 {
+    fn port_output(&self, port: Port) -> u8 {
+        match port {
+            Port::A => self.port_a_output(),
+            Port::B => self.port_b_output(),
+        }
+    }
+
+    fn port_direction(&self, port: Port) -> u8 {
+        match port {
+            Port::A => self.ddra,
+            Port::B => self.ddrb,
+        }
+    }
+
+    fn set_port_input(&mut self, port: Port, value: u8) {
+        match port {
+            Port::A => self.set_port_a_input(value),
+            Port::B => self.set_port_b_input(value),
+        }
+    }
+}
+
+impl Device for Riot6532 {
+    fn read(&mut self, offset: u16) -> u8 {
+        if offset & 0x80 == 0 {
+            self.read_ram(offset)
+        } else {
+            self.read_io(offset)
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        if offset & 0x80 == 0 {
+            self.write_ram(offset, value);
+        } else {
+            self.write_io(offset, value);
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.prescale_count > 0 {
+            self.prescale_count -= 1;
+            return;
+        }
+
+        if self.timer == 0 {
+            // After the interval expires the timer counts every cycle until
+            // it is written again
+            self.flags |= interrupt::TIMER;
+            self.prescaler = 1;
+        }
+        self.timer = self.timer.wrapping_sub(1);
+        self.prescale_count = self.prescaler - 1;
+    }
+
+    fn reset(&mut self) {
+        Riot6532::reset(self);
+    }
+
+    fn irq(&self) -> bool {
+        Riot6532::irq(self)
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.timer_irq_enabled || self.pa7_irq_enabled
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        StateWriter::new()
+            .bytes(&self.ram)
+            .u8(self.ora)
+            .u8(self.orb)
+            .u8(self.ddra)
+            .u8(self.ddrb)
+            .u8(self.port_a_pins)
+            .u8(self.port_b_pins)
+            .u8(self.timer)
+            .u16(self.prescaler)
+            .u16(self.prescale_count)
+            .bool(self.timer_irq_enabled)
+            .bool(self.pa7)
+            .bool(self.pa7_positive)
+            .bool(self.pa7_irq_enabled)
+            .u8(self.flags)
+            .finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(state);
+        self.ram.copy_from_slice(r.bytes(RAM_SIZE)?);
+        self.ora = r.u8()?;
+        self.orb = r.u8()?;
+        self.ddra = r.u8()?;
+        self.ddrb = r.u8()?;
+        self.port_a_pins = r.u8()?;
+        self.port_b_pins = r.u8()?;
+        self.timer = r.u8()?;
+        self.prescaler = r.u16()?;
+        if !PRESCALERS.contains(&self.prescaler) {
+            return Err(StateError::Invalid(format!("prescaler {}", self.prescaler)));
+        }
+        self.prescale_count = r.u16()?;
+        self.timer_irq_enabled = r.bool()?;
+        self.pa7 = r.bool()?;
+        self.pa7_positive = r.bool()?;
+        self.pa7_irq_enabled = r.bool()?;
+        self.flags = r.u8()?;
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(riot: &mut Riot6532, cycles: u32) {
+        for _ in 0..cycles {
+            riot.tick();
+        }
+    }
+
+    #[test]
+    fn test_ram() {
+        let mut riot = Riot6532::new();
+        riot.write(0x00, 0x12);
+        riot.write(0x7F, 0x34);
+        assert_eq!(riot.read(0x00), 0x12);
+        assert_eq!(riot.read(0x7F), 0x34);
+        assert_eq!(riot.read_ram(0x7F), 0x34);
+    }
+
+    #[test]
+    fn test_ports() {
+        let mut riot = Riot6532::new();
+        riot.write(0x81, 0x0F); // DDRA: low nibble output
+        riot.write(0x80, 0xA5);
+        riot.set_port_a_input(0x30);
+
+        assert_eq!(riot.port_a_output(), 0xF5);
+        assert_eq!(riot.read(0x80), 0x35);
+
+        riot.write(0x83, 0xFF);
+        riot.write(0x82, 0x42);
+        assert_eq!(riot.port_b_output(), 0x42);
+        assert_eq!(riot.read(0x82), 0x42);
+    }
+
+    #[test]
+    fn test_timer_prescaler() {
+        let mut riot = Riot6532::new();
+        riot.write(0x95, 3); // 3 x 8 cycles
+
+        run(&mut riot, 1);
+        assert_eq!(riot.read(0x84), 2);
+        run(&mut riot, 8);
+        assert_eq!(riot.read(0x84), 1);
+        run(&mut riot, 15);
+        assert_eq!(riot.read(0x84), 0);
+        assert_eq!(riot.read(0x85) & interrupt::TIMER, 0);
+
+        // Expires after a full interval at zero, then counts every cycle
+        run(&mut riot, 1);
+        assert_ne!(riot.read(0x85) & interrupt::TIMER, 0);
+        assert_eq!(riot.read(0x84), 0xFF);
+        run(&mut riot, 2);
+        assert_eq!(riot.read(0x84), 0xFD);
+    }
+
+    #[test]
+    fn test_timer_interrupt() {
+        let mut riot = Riot6532::new();
+        riot.write(0x9C, 1); // /1 with IRQ
+
+        run(&mut riot, 2);
+        assert!(riot.irq());
+
+        // Reading the timer clears the flag
+        riot.read(0x8C);
+        assert!(!riot.irq());
+    }
+
+    #[test]
+    fn test_pa7_edge_interrupt() {
+        let mut riot = Riot6532::new();
+        riot.write(0x87, 0); // positive edge, IRQ enabled
+
+        riot.set_port_a_input(0x00);
+        assert!(!riot.irq());
+        riot.set_port_a_input(0x80);
+        assert!(riot.irq());
+
+        let flags = riot.read(0x85);
+        assert_ne!(flags & interrupt::PA7, 0);
+        assert!(!riot.irq());
+    }
+}
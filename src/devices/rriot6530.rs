@@ -0,0 +1,296 @@
+//! MOS 6530 ROM-RAM-I/O-Timer (RRIOT)
+//!
+//! Each 6530 carries a mask-programmed 1KB ROM and 64 bytes of RAM besides
+//! its ports and timer. Boards decode the three parts at unrelated
+//! addresses, so this device only covers the I/O and timer registers; map
+//! the ROM and RAM as ordinary regions.
+//!
+//! | Offset  | Write                          | Read                  |
+//! |---------|--------------------------------|-----------------------|
+//! | $0      | port A data                    | port A                |
+//! | $1      | DDRA                           | DDRA                  |
+//! | $2      | port B data                    | port B                |
+//! | $3      | DDRB                           | DDRB                  |
+//! | $4-$7   | timer /1, /8, /64, /1024       | timer / flag (A0)     |
+//! | $C-$F   | same, with timer IRQ enabled   | same, A3 = IRQ on     |
+//!
+//! The timer IRQ comes out on PB7, so the pin reads low while it is
+//! asserted if PB7 is an input.
+
+use super::state::{StateError, StateReader, StateWriter};
+use super::{Device, ParallelPorts, Port};
+
+/// Interrupt flag register bits
+pub mod interrupt {
+    pub const TIMER: u8 = 0x80;
+}
+
+const PRESCALERS: [u16; 4] = [1, 8, 64, 1024];
+
+/// Cycle-driven 6530 RRIOT (I/O and timer).
+pub struct Rriot6530 {
+    ora: u8,
+    orb: u8,
+    ddra: u8,
+    ddrb: u8,
+    port_a_pins: u8,
+    port_b_pins: u8,
+
+    timer: u8,
+    prescaler: u16,
+    prescale_count: u16,
+    irq_enabled: bool,
+    flags: u8,
+}
+
+impl Default for Rriot6530 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rriot6530 {
+    pub fn new() -> Self {
+        Self {
+            ora: 0,
+            orb: 0,
+            ddra: 0,
+            ddrb: 0,
+            port_a_pins: 0xFF,
+            port_b_pins: 0xFF,
+            timer: 0xFF,
+            prescaler: 1024,
+            prescale_count: 0,
+            irq_enabled: false,
+            flags: 0,
+        }
+    }
+
+    /// RES pin: ports become inputs and the timer IRQ is disabled
+    pub fn reset(&mut self) {
+        self.ora = 0;
+        self.orb = 0;
+        self.ddra = 0;
+        self.ddrb = 0;
+        self.irq_enabled = false;
+        self.flags = 0;
+    }
+
+    // ========== Host side ==========
+
+    /// Levels driven onto port A by external hardware (inputs only)
+    pub fn set_port_a_input(&mut self, value: u8) {
+        self.port_a_pins = value;
+    }
+
+    /// Levels driven onto port B by external hardware (inputs only)
+    pub fn set_port_b_input(&mut self, value: u8) {
+        self.port_b_pins = value;
+    }
+
+    /// Port A as seen from outside: outputs driven by ORA, inputs pulled high
+    pub fn port_a_output(&self) -> u8 {
+        (self.ora & self.ddra) | !self.ddra
+    }
+
+    /// Port B as seen from outside: outputs driven by ORB, inputs pulled high
+    pub fn port_b_output(&self) -> u8 {
+        (self.orb & self.ddrb) | !self.ddrb
+    }
+
+    /// True while the timer is asserting IRQ (on PB7)
+    pub fn irq(&self) -> bool {
+        self.irq_enabled && self.flags & interrupt::TIMER != 0
+    }
+
+    // ========== Internals ==========
+
+    fn read_port_b(&self) -> u8 {
+        let mut pins = self.port_b_pins;
+        if self.irq() {
+            pins &= 0x7F;
+        }
+        (self.orb & self.ddrb) | (pins & !self.ddrb)
+    }
+}
+
+impl ParallelPorts for Rriot6530 {
+    fn port_output(&self, port: Port) -> u8 {
+        match port {
+            Port::A => self.port_a_output(),
+            Port::B => self.port_b_output(),
+        }
+    }
+
+    fn port_direction(&self, port: Port) -> u8 {
+        match port {
+            Port::A => self.ddra,
+            Port::B => self.ddrb,
+        }
+    }
+
+    fn set_port_input(&mut self, port: Port, value: u8) {
+        match port {
+            Port::A => self.set_port_a_input(value),
+            Port::B => self.set_port_b_input(value),
+        }
+    }
+}
+
+impl Device for Rriot6530 {
+    fn read(&mut self, offset: u16) -> u8 {
+        if offset & 0x04 == 0 {
+            match offset & 0x03 {
+                0 => (self.ora & self.ddra) | (self.port_a_pins & !self.ddra),
+                1 => self.ddra,
+                2 => self.read_port_b(),
+                3 => self.ddrb,
+                _ => unreachable!("register offset is masked to 2 bits"),
+            }
+        } else {
+            // Any timer access sets the IRQ enable from A3
+            self.irq_enabled = offset & 0x08 != 0;
+            if offset & 0x01 == 0 {
+                self.flags &= !interrupt::TIMER;
+                self.timer
+            } else {
+                self.flags
+            }
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        if offset & 0x04 == 0 {
+            match offset & 0x03 {
+                0 => self.ora = value,
+                1 => self.ddra = value,
+                2 => self.orb = value,
+                3 => self.ddrb = value,
+                _ => unreachable!("register offset is masked to 2 bits"),
+            }
+        } else {
+            self.prescaler = PRESCALERS[(offset & 0x03) as usize];
+            self.irq_enabled = offset & 0x08 != 0;
+            self.timer = value;
+            self.prescale_count = 0;
+            self.flags &= !interrupt::TIMER;
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.prescale_count > 0 {
+            self.prescale_count -= 1;
+            return;
+        }
+
+        if self.timer == 0 {
+            // As on the 6532, the timer then counts every cycle
+            self.flags |= interrupt::TIMER;
+            self.prescaler = 1;
+        }
+        self.timer = self.timer.wrapping_sub(1);
+        self.prescale_count = self.prescaler - 1;
+    }
+
+    fn reset(&mut self) {
+        Rriot6530::reset(self);
+    }
+
+    fn irq(&self) -> bool {
+        Rriot6530::irq(self)
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.irq_enabled
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        StateWriter::new()
+            .u8(self.ora)
+            .u8(self.orb)
+            .u8(self.ddra)
+            .u8(self.ddrb)
+            .u8(self.port_a_pins)
+            .u8(self.port_b_pins)
+            .u8(self.timer)
+            .u16(self.prescaler)
+            .u16(self.prescale_count)
+            .bool(self.irq_enabled)
+            .u8(self.flags)
+            .finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(state);
+        self.ora = r.u8()?;
+        self.orb = r.u8()?;
+        self.ddra = r.u8()?;
+        self.ddrb = r.u8()?;
+        self.port_a_pins = r.u8()?;
+        self.port_b_pins = r.u8()?;
+        self.timer = r.u8()?;
+        self.prescaler = r.u16()?;
+        if !PRESCALERS.contains(&self.prescaler) {
+            return Err(StateError::Invalid(format!("prescaler {}", self.prescaler)));
+        }
+        self.prescale_count = r.u16()?;
+        self.irq_enabled = r.bool()?;
+        self.flags = r.u8()?;
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(rriot: &mut Rriot6530, cycles: u32) {
+        for _ in 0..cycles {
+            rriot.tick();
+        }
+    }
+
+    #[test]
+    fn test_ports() {
+        let mut rriot = Rriot6530::new();
+        rriot.write(1, 0x7F);
+        rriot.write(0, 0x55);
+        rriot.set_port_a_input(0x00);
+        assert_eq!(rriot.read(0), 0x55);
+        assert_eq!(rriot.port_a_output(), 0xD5);
+
+        rriot.write(3, 0x0F);
+        rriot.write(2, 0x0A);
+        rriot.set_port_b_input(0x30);
+        assert_eq!(rriot.read(2), 0x3A);
+    }
+
+    #[test]
+    fn test_timer() {
+        let mut rriot = Rriot6530::new();
+        rriot.write(0x05, 2); // 2 x 8 cycles
+
+        run(&mut rriot, 1);
+        assert_eq!(rriot.read(0x06), 1);
+        run(&mut rriot, 15);
+        assert_eq!(rriot.read(0x07) & interrupt::TIMER, 0);
+        run(&mut rriot, 1);
+        assert_ne!(rriot.read(0x07) & interrupt::TIMER, 0);
+        assert!(!rriot.irq());
+    }
+
+    #[test]
+    fn test_timer_interrupt_on_pb7() {
+        let mut rriot = Rriot6530::new();
+        rriot.write(0x0C, 1); // /1 with IRQ
+
+        run(&mut rriot, 2);
+        assert!(rriot.irq());
+        assert_eq!(rriot.read(2) & 0x80, 0);
+
+        // Reading the timer clears the flag, A3 keeps the IRQ enabled
+        rriot.read(0x0E);
+        assert!(!rriot.irq());
+        assert_ne!(rriot.read(2) & 0x80, 0);
+    }
+}
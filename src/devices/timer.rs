@@ -0,0 +1,343 @@
+//! Programmable interval timer for test setups
+//!
+//! Not modelled on any real chip: a 16-bit cycle countdown that raises IRQ
+//! or NMI when it reaches zero, for exercising interrupt handlers without a
+//! full VIA.
+//!
+//! | Offset | Write                              | Read          |
+//! |--------|------------------------------------|---------------|
+//! | $0     | reload value, low byte             | counter low   |
+//! | $1     | reload value, high byte; restarts  | counter high  |
+//! | $2     | control                            | control       |
+//! | $3     | acknowledge (clears `FIRED`)       | status        |
+//!
+//! The counter drops by one each cycle while `ENABLE` is set. When it
+//! reaches zero `FIRED` is set and the counter reloads, so a reload value
+//! of N fires every N cycles (0 counts as 65536). In `ONE_SHOT` mode the
+//! timer disables itself after firing.
+//!
+//! The interrupt line follows `FIRED`, so handlers must acknowledge it. For
+//! IRQ that stops the handler re-entering after RTI; NMI is edge triggered,
+//! so an unacknowledged timer only interrupts once.
+
+use super::Device;
+use super::state::{StateError, StateReader, StateWriter};
+
+/// Control register bits
+pub mod control {
+    /// Count down each cycle
+    pub const ENABLE: u8 = 0x01;
+    /// Assert IRQ while `FIRED` is set
+    pub const IRQ: u8 = 0x02;
+    /// Assert NMI while `FIRED` is set
+    pub const NMI: u8 = 0x04;
+    /// Clear `ENABLE` after firing once
+    pub const ONE_SHOT: u8 = 0x08;
+}
+
+/// Status register bits
+pub mod status {
+    pub const FIRED: u8 = 0x80;
+}
+
+const COUNTER_LOW: u16 = 0;
+const COUNTER_HIGH: u16 = 1;
+const CONTROL: u16 = 2;
+const STATUS: u16 = 3;
+
+/// Cycle-driven interval timer.
+#[derive(Default)]
+pub struct Timer {
+    reload: u16,
+    counter: u16,
+    control: u8,
+    status: u8,
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// True once the counter has reached zero, until acknowledged
+    pub fn fired(&self) -> bool {
+        self.status & status::FIRED != 0
+    }
+}
+
+impl Device for Timer {
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset & 0x03 {
+            COUNTER_LOW => self.counter as u8,
+            COUNTER_HIGH => (self.counter >> 8) as u8,
+            CONTROL => self.control,
+            STATUS => self.status,
+            _ => unreachable!("register offset is masked to 2 bits"),
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset & 0x03 {
+            COUNTER_LOW => self.reload = (self.reload & 0xFF00) | value as u16,
+            COUNTER_HIGH => {
+                self.reload = (self.reload & 0x00FF) | (value as u16) << 8;
+                self.counter = self.reload;
+                self.status &= !status::FIRED;
+            }
+            CONTROL => self.control = value & 0x0F,
+            STATUS => self.status &= !status::FIRED,
+            _ => unreachable!("register offset is masked to 2 bits"),
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.control & control::ENABLE == 0 {
+            return;
+        }
+
+        self.counter = self.counter.wrapping_sub(1);
+        if self.counter == 0 {
+            self.status |= status::FIRED;
+            self.counter = self.reload;
+            if self.control & control::ONE_SHOT != 0 {
+                self.control &= !control::ENABLE;
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    fn irq(&self) -> bool {
+        self.fired() && self.control & control::IRQ != 0
+    }
+
+    fn nmi(&self) -> bool {
+        self.fired() && self.control & control::NMI != 0
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        (self.control & control::ENABLE != 0 || self.fired())
+            && self.control & (control::IRQ | control::NMI) != 0
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        StateWriter::new()
+            .u16(self.reload)
+            .u16(self.counter)
+            .u8(self.control)
+            .u8(self.status)
+            .finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(state);
+        self.reload = r.u16()?;
+        self.counter = r.u16()?;
+        self.control = r.u8()?;
+        self.status = r.u8()?;
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::Cpu;
+    use crate::memory_map::{MappedBus, MemoryMapBuilder};
+    use crate::status::Flag;
+
+    fn run(timer: &mut Timer, cycles: u32) {
+        for _ in 0..cycles {
+            timer.tick();
+        }
+    }
+
+    /// RAM at $0000, timers at $6000 and $6010, `program` at $F000 with
+    /// reset at $F000, NMI at $F050 and IRQ at $F030
+    fn machine(program: &[(u16, &[u8])]) -> Cpu<MappedBus> {
+        let mut rom = vec![0xEA; 0x1000];
+        for (address, code) in program {
+            let start = (address - 0xF000) as usize;
+            rom[start..start + code.len()].copy_from_slice(code);
+        }
+        rom[0xFFA..].copy_from_slice(&[0x50, 0xF0, 0x00, 0xF0, 0x30, 0xF0]);
+
+        let bus = MemoryMapBuilder::new()
+            .ram(0x0000, 0x1000)
+            .device(0x6000, 4, Timer::new())
+            .device(0x6010, 4, Timer::new())
+            .rom(0xF000, 0x1000, &rom)
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::new(bus);
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_countdown() {
+        let mut timer = Timer::new();
+        timer.write(COUNTER_LOW, 0x03);
+        timer.write(COUNTER_HIGH, 0x00);
+        run(&mut timer, 5);
+        assert_eq!(timer.read(COUNTER_LOW), 3); // not enabled yet
+
+        timer.write(CONTROL, control::ENABLE | control::IRQ);
+        run(&mut timer, 2);
+        assert_eq!(timer.read(COUNTER_LOW), 1);
+        assert!(!timer.irq());
+        run(&mut timer, 1);
+        assert_eq!(timer.read(STATUS), status::FIRED);
+        assert_eq!(timer.read(COUNTER_LOW), 3);
+        assert!(timer.irq());
+        assert!(!timer.nmi());
+
+        timer.write(STATUS, 0);
+        assert!(!timer.irq());
+        run(&mut timer, 3);
+        assert!(timer.irq());
+    }
+
+    #[test]
+    fn test_one_shot_and_state() {
+        let mut timer = Timer::new();
+        timer.write(COUNTER_LOW, 0x00);
+        timer.write(COUNTER_HIGH, 0x01);
+        timer.write(CONTROL, control::ENABLE | control::NMI | control::ONE_SHOT);
+        run(&mut timer, 0x100);
+        assert!(timer.nmi());
+        assert_eq!(timer.read(CONTROL), control::NMI | control::ONE_SHOT);
+
+        let state = timer.save_state();
+        let mut copy = Timer::new();
+        copy.load_state(&state).unwrap();
+        assert!(copy.nmi());
+        assert_eq!(copy.read(COUNTER_HIGH), 0x01);
+        assert_eq!(copy.load_state(&state[..5]), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn test_periodic_irq() {
+        let mut cpu = machine(&[
+            (
+                0xF000,
+                &[
+                    0xA9, 0x64, 0x8D, 0x00, 0x60, // lda #100, sta reload low
+                    0xA9, 0x00, 0x8D, 0x01, 0x60, // lda #0, sta reload high
+                    0xA9, 0x03, 0x8D, 0x02, 0x60, // lda #(ENABLE | IRQ), sta control
+                    0x58, // cli
+                    0x4C, 0x10, 0xF0, // loop: jmp loop
+                ],
+            ),
+            (
+                0xF030,
+                &[
+                    0xE6, 0x10, // inc $10
+                    0x8D, 0x03, 0x60, // sta acknowledge
+                    0x40, // rti
+                ],
+            ),
+        ]);
+
+        for _ in 0..1000 {
+            cpu.step();
+        }
+        // About one interrupt per 100 cycles
+        assert!((9..=10).contains(&cpu.bus.read(0x10)));
+        assert_eq!(cpu.sp, 0xFD);
+    }
+
+    #[test]
+    fn test_irq_masked_and_nmi_edge() {
+        let mut cpu = machine(&[
+            (
+                0xF000,
+                &[
+                    0xA9, 0x32, 0x8D, 0x00, 0x60, // lda #50, sta $6000
+                    0x8D, 0x10, 0x60, // sta $6010
+                    0xA9, 0x00, 0x8D, 0x01, 0x60, // lda #0, sta $6001
+                    0x8D, 0x11, 0x60, // sta $6011
+                    0xA9, 0x03, 0x8D, 0x02, 0x60, // lda #(ENABLE | IRQ), sta $6002
+                    0xA9, 0x05, 0x8D, 0x12, 0x60, // lda #(ENABLE | NMI), sta $6012
+                    0x4C, 0x1A, 0xF0, // loop: jmp loop (I still set from reset)
+                ],
+            ),
+            (0xF030, &[0xE6, 0x10, 0x40]), // irq: inc $10, rti
+            (0xF050, &[0xE6, 0x11, 0x40]), // nmi: inc $11, rti (no acknowledge)
+        ]);
+
+        for _ in 0..1000 {
+            cpu.step();
+        }
+        assert_eq!(cpu.bus.read(0x10), 0, "IRQ is masked");
+        assert_eq!(cpu.bus.read(0x11), 1, "NMI line never went inactive");
+        assert!(cpu.status.get(Flag::InterruptDisable));
+    }
+
+    #[test]
+    fn test_nmi_during_irq() {
+        let log = &[
+            0xA6, 0x20, // ldx $20
+            0x95, 0x30, // sta $30,x
+            0xE6, 0x20, // inc $20
+            0x60, // rts
+        ];
+        let mut cpu = machine(&[
+            (
+                0xF000,
+                &[
+                    0xA9, 0x40, 0x8D, 0x00, 0x60, // lda #$40, sta $6000
+                    0xA9, 0x00, 0x8D, 0x01, 0x60, // lda #0, sta $6001
+                    0xA9, 0x0B, 0x8D, 0x02, 0x60, // lda #(ENABLE | IRQ | ONE_SHOT), sta $6002
+                    0xA9, 0x00, 0x8D, 0x10, 0x60, // lda #0, sta $6010
+                    0xA9, 0x01, 0x8D, 0x11, 0x60, // lda #1, sta $6011
+                    0xA9, 0x0D, 0x8D, 0x12, 0x60, // lda #(ENABLE | NMI | ONE_SHOT), sta $6012
+                    0x58, // cli
+                    0x4C, 0x1F, 0xF0, // loop: jmp loop
+                ],
+            ),
+            (
+                0xF030,
+                &[
+                    0x48, // pha
+                    0xA9, 0x01, 0x20, 0x60, 0xF0, // lda #1, jsr log
+                    0xA5, 0x11, // wait: lda $11
+                    0xF0, 0xFC, // beq wait
+                    0x8D, 0x03, 0x60, // sta $6003
+                    0xA9, 0x03, 0x20, 0x60, 0xF0, // lda #3, jsr log
+                    0x68, // pla
+                    0x40, // rti
+                ],
+            ),
+            (
+                0xF050,
+                &[
+                    0x48, // pha
+                    0xA9, 0x02, 0x20, 0x60, 0xF0, // lda #2, jsr log
+                    0x85, 0x11, // sta $11
+                    0x8D, 0x13, 0x60, // sta $6013
+                    0x68, // pla
+                    0x40, // rti
+                ],
+            ),
+            (0xF060, log),
+        ]);
+
+        for _ in 0..200 {
+            cpu.execute_instruction();
+        }
+
+        // The NMI interrupted the IRQ handler, which resumed and finished
+        assert_eq!(cpu.bus.read(0x20), 3);
+        assert_eq!(
+            [0x30, 0x31, 0x32].map(|address| cpu.bus.read(address)),
+            [1, 2, 3]
+        );
+        assert_eq!((cpu.pc, cpu.sp), (0xF01F, 0xFD));
+        assert!(!cpu.status.get(Flag::InterruptDisable));
+        assert!(!cpu.bus.irq() && !cpu.bus.nmi());
+    }
+}
@@ -0,0 +1,1019 @@
+//! MOS 6522 Versatile Interface Adapter
+//!
+//! Register map (offset from the base address):
+//!
+//! | Offset | Write                 | Read                  |
+//! |--------|-----------------------|-----------------------|
+//! | $0     | ORB                   | IRB                   |
+//! | $1     | ORA (handshake)       | IRA (handshake)       |
+//! | $2     | DDRB                  | DDRB                  |
+//! | $3     | DDRA                  | DDRA                  |
+//! | $4     | T1 latch low          | T1 counter low        |
+//! | $5     | T1 latch high + start | T1 counter high       |
+//! | $6     | T1 latch low          | T1 latch low          |
+//! | $7     | T1 latch high         | T1 latch high         |
+//! | $8     | T2 latch low          | T2 counter low        |
+//! | $9     | T2 high + start       | T2 counter high       |
+//! | $A     | SR                    | SR                    |
+//! | $B     | ACR                   | ACR                   |
+//! | $C     | PCR                   | PCR                   |
+//! | $D     | IFR (1 clears)        | IFR                   |
+//! | $E     | IER                   | IER                   |
+//! | $F     | ORA (no handshake)    | IRA (no handshake)    |
+//!
+//! The host side drives the pins through `set_port_a_input`, `set_ca1` and
+//! friends, and reads outputs through `port_a_output`, `ca2_output`, etc.
+
+use super::state::{StateError, StateReader, StateWriter};
+use super::{Device, ParallelPorts, Port};
+
+/// IFR/IER bit positions
+pub mod interrupt {
+    pub const CA2: u8 = 0x01;
+    pub const CA1: u8 = 0x02;
+    pub const SR: u8 = 0x04;
+    pub const CB2: u8 = 0x08;
+    pub const CB1: u8 = 0x10;
+    pub const T2: u8 = 0x20;
+    pub const T1: u8 = 0x40;
+    pub const ANY: u8 = 0x80;
+}
+
+const ORB: u16 = 0x0;
+const ORA: u16 = 0x1;
+const DDRB: u16 = 0x2;
+const DDRA: u16 = 0x3;
+const T1CL: u16 = 0x4;
+const T1CH: u16 = 0x5;
+const T1LL: u16 = 0x6;
+const T1LH: u16 = 0x7;
+const T2CL: u16 = 0x8;
+const T2CH: u16 = 0x9;
+const SR: u16 = 0xA;
+const ACR: u16 = 0xB;
+const PCR: u16 = 0xC;
+const IFR: u16 = 0xD;
+const IER: u16 = 0xE;
+const ORA_NH: u16 = 0xF;
+
+/// Behaviour of CA2/CB2 selected by the PCR
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ControlMode {
+    /// Input; `positive` selects the active edge, `independent` means
+    /// port accesses don't clear the flag
+    Input { positive: bool, independent: bool },
+    /// Output low after a port access until the next active C1 edge
+    Handshake,
+    /// Output low for one cycle after a port access
+    Pulse,
+    /// Output held at a fixed level
+    Manual(bool),
+}
+
+impl ControlMode {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0x07 {
+            0b000 => ControlMode::Input {
+                positive: false,
+                independent: false,
+            },
+            0b001 => ControlMode::Input {
+                positive: false,
+                independent: true,
+            },
+            0b010 => ControlMode::Input {
+                positive: true,
+                independent: false,
+            },
+            0b011 => ControlMode::Input {
+                positive: true,
+                independent: true,
+            },
+            0b100 => ControlMode::Handshake,
+            0b101 => ControlMode::Pulse,
+            0b110 => ControlMode::Manual(false),
+            _ => ControlMode::Manual(true),
+        }
+    }
+}
+
+/// Shift register mode from ACR bits 2-4
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ShiftMode {
+    Disabled,
+    InT2,
+    InPhi2,
+    InExternal,
+    OutFreeRunning,
+    OutT2,
+    OutPhi2,
+    OutExternal,
+}
+
+impl ShiftMode {
+    fn from_acr(acr: u8) -> Self {
+        match (acr >> 2) & 0x07 {
+            0 => ShiftMode::Disabled,
+            1 => ShiftMode::InT2,
+            2 => ShiftMode::InPhi2,
+            3 => ShiftMode::InExternal,
+            4 => ShiftMode::OutFreeRunning,
+            5 => ShiftMode::OutT2,
+            6 => ShiftMode::OutPhi2,
+            _ => ShiftMode::OutExternal,
+        }
+    }
+
+    fn is_output(self) -> bool {
+        matches!(
+            self,
+            ShiftMode::OutFreeRunning
+                | ShiftMode::OutT2
+                | ShiftMode::OutPhi2
+                | ShiftMode::OutExternal
+        )
+    }
+
+    fn uses_t2(self) -> bool {
+        matches!(
+            self,
+            ShiftMode::InT2 | ShiftMode::OutT2 | ShiftMode::OutFreeRunning
+        )
+    }
+}
+
+/// Cycle-driven 6522 VIA.
+pub struct Via6522 {
+    // Ports
+    ora: u8,
+    orb: u8,
+    ddra: u8,
+    ddrb: u8,
+    port_a_pins: u8,
+    port_b_pins: u8,
+    port_a_latch: u8,
+    port_b_latch: u8,
+
+    // Timer 1
+    t1_counter: u16,
+    t1_latch: u16,
+    t1_armed: bool,
+    t1_reload: bool,
+    pb7: bool,
+
+    // Timer 2
+    t2_counter: u16,
+    t2_latch_low: u8,
+    t2_armed: bool,
+
+    // Shift register
+    sr: u8,
+    sr_bits: u8,
+    sr_running: bool,
+    sr_timer: u16,
+
+    // Control lines
+    ca1: bool,
+    ca2_in: bool,
+    cb1_in: bool,
+    cb2_in: bool,
+    ca2_out: bool,
+    cb1_out: bool,
+    cb2_out: bool,
+    ca2_pulse: bool,
+    cb2_pulse: bool,
+
+    acr: u8,
+    pcr: u8,
+    ifr: u8,
+    ier: u8,
+}
+
+impl Default for Via6522 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Via6522 {
+    pub fn new() -> Self {
+        Self {
+            ora: 0,
+            orb: 0,
+            ddra: 0,
+            ddrb: 0,
+            port_a_pins: 0xFF,
+            port_b_pins: 0xFF,
+            port_a_latch: 0,
+            port_b_latch: 0,
+            t1_counter: 0xFFFF,
+            t1_latch: 0xFFFF,
+            t1_armed: false,
+            t1_reload: false,
+            pb7: true,
+            t2_counter: 0xFFFF,
+            t2_latch_low: 0xFF,
+            t2_armed: false,
+            sr: 0,
+            sr_bits: 0,
+            sr_running: false,
+            sr_timer: 0,
+            ca1: true,
+            ca2_in: true,
+            cb1_in: true,
+            cb2_in: true,
+            ca2_out: true,
+            cb1_out: true,
+            cb2_out: true,
+            ca2_pulse: false,
+            cb2_pulse: false,
+            acr: 0,
+            pcr: 0,
+            ifr: 0,
+            ier: 0,
+        }
+    }
+
+    /// RES clears the port, control and interrupt registers, which stops
+    /// the timers interrupting and the shift register. The timer counts and
+    /// latches and the shift register's contents are kept, and so are the
+    /// levels external hardware drives onto the pins.
+    pub fn reset(&mut self) {
+        *self = Self {
+            port_a_pins: self.port_a_pins,
+            port_b_pins: self.port_b_pins,
+            t1_counter: self.t1_counter,
+            t1_latch: self.t1_latch,
+            t2_counter: self.t2_counter,
+            t2_latch_low: self.t2_latch_low,
+            sr: self.sr,
+            ca1: self.ca1,
+            ca2_in: self.ca2_in,
+            cb1_in: self.cb1_in,
+            cb2_in: self.cb2_in,
+            ..Self::new()
+        };
+    }
+
+    // ========== Host side ==========
+
+    /// Levels driven onto port A by external hardware (inputs only)
+    pub fn set_port_a_input(&mut self, value: u8) {
+        self.port_a_pins = value;
+    }
+
+    /// Levels driven onto port B by external hardware (inputs only).
+    /// Falling edges on PB6 clock timer 2 in pulse-counting mode.
+    pub fn set_port_b_input(&mut self, value: u8) {
+        let pb6_fell = self.port_b_pins & 0x40 != 0 && value & 0x40 == 0;
+        self.port_b_pins = value;
+        if pb6_fell && self.acr & 0x20 != 0 {
+            self.count_t2();
+        }
+    }
+
+    /// Port A as seen from outside: outputs driven by ORA, inputs pulled high
+    pub fn port_a_output(&self) -> u8 {
+        (self.ora & self.ddra) | !self.ddra
+    }
+
+    /// Port B as seen from outside, including the timer 1 PB7 output
+    pub fn port_b_output(&self) -> u8 {
+        let value = (self.orb & self.ddrb) | !self.ddrb;
+        if self.acr & 0x80 != 0 {
+            (value & 0x7F) | ((self.pb7 as u8) << 7)
+        } else {
+            value
+        }
+    }
+
+    pub fn ca2_output(&self) -> bool {
+        self.ca2_out
+    }
+
+    pub fn cb1_output(&self) -> bool {
+        self.cb1_out
+    }
+
+    pub fn cb2_output(&self) -> bool {
+        self.cb2_out
+    }
+
+    pub fn set_ca1(&mut self, level: bool) {
+        let positive = self.pcr & 0x01 != 0;
+        if self.ca1 != level && level == positive {
+            self.ifr |= interrupt::CA1;
+            if self.acr & 0x01 != 0 {
+                self.port_a_latch = self.read_port_a_pins();
+            }
+            if ControlMode::from_bits(self.pcr >> 1) == ControlMode::Handshake {
+                self.ca2_out = true;
+            }
+        }
+        self.ca1 = level;
+    }
+
+    pub fn set_ca2(&mut self, level: bool) {
+        if let ControlMode::Input { positive, .. } = ControlMode::from_bits(self.pcr >> 1)
+            && self.ca2_in != level
+            && level == positive
+        {
+            self.ifr |= interrupt::CA2;
+        }
+        self.ca2_in = level;
+    }
+
+    /// CB1 is also the external shift clock in shift modes 3 and 7
+    pub fn set_cb1(&mut self, level: bool) {
+        let positive = self.pcr & 0x10 != 0;
+        if self.cb1_in != level {
+            if level == positive {
+                self.ifr |= interrupt::CB1;
+                if self.acr & 0x02 != 0 {
+                    self.port_b_latch = self.read_port_b_pins();
+                }
+                if ControlMode::from_bits(self.pcr >> 5) == ControlMode::Handshake {
+                    self.cb2_out = true;
+                }
+            }
+
+            match ShiftMode::from_acr(self.acr) {
+                ShiftMode::InExternal if level => self.shift_in(),
+                ShiftMode::OutExternal if !level => self.shift_out(),
+                _ => {}
+            }
+        }
+        self.cb1_in = level;
+    }
+
+    /// CB2 is also the serial data input when shifting in
+    pub fn set_cb2(&mut self, level: bool) {
+        if let ControlMode::Input { positive, .. } = ControlMode::from_bits(self.pcr >> 5)
+            && self.cb2_in != level
+            && level == positive
+        {
+            self.ifr |= interrupt::CB2;
+        }
+        self.cb2_in = level;
+    }
+
+    /// True while the VIA is asserting its IRQ output
+    pub fn irq(&self) -> bool {
+        self.ifr & self.ier & 0x7F != 0
+    }
+
+    // ========== Internals ==========
+
+    fn read_port_a_pins(&self) -> u8 {
+        (self.ora & self.ddra) | (self.port_a_pins & !self.ddra)
+    }
+
+    fn read_port_b_pins(&self) -> u8 {
+        (self.orb & self.ddrb) | (self.port_b_pins & !self.ddrb)
+    }
+
+    fn ifr_value(&self) -> u8 {
+        if self.irq() {
+            self.ifr | interrupt::ANY
+        } else {
+            self.ifr
+        }
+    }
+
+    /// Clear CA1/CA2 flags and drive CA2 on an ORA access
+    fn port_a_access(&mut self) {
+        self.ifr &= !interrupt::CA1;
+        match ControlMode::from_bits(self.pcr >> 1) {
+            ControlMode::Input {
+                independent: false, ..
+            } => self.ifr &= !interrupt::CA2,
+            ControlMode::Handshake => self.ca2_out = false,
+            ControlMode::Pulse => {
+                self.ca2_out = false;
+                self.ca2_pulse = true;
+            }
+            _ => {}
+        }
+    }
+
+    /// Clear CB1/CB2 flags and drive CB2 on an ORB access
+    fn port_b_access(&mut self, write: bool) {
+        self.ifr &= !interrupt::CB1;
+        match ControlMode::from_bits(self.pcr >> 5) {
+            ControlMode::Input {
+                independent: false, ..
+            } => self.ifr &= !interrupt::CB2,
+            // CB2 handshaking only happens on writes
+            ControlMode::Handshake if write => self.cb2_out = false,
+            ControlMode::Pulse if write => {
+                self.cb2_out = false;
+                self.cb2_pulse = true;
+            }
+            _ => {}
+        }
+    }
+
+    fn update_manual_outputs(&mut self) {
+        if let ControlMode::Manual(level) = ControlMode::from_bits(self.pcr >> 1) {
+            self.ca2_out = level;
+        }
+        if let ControlMode::Manual(level) = ControlMode::from_bits(self.pcr >> 5) {
+            self.cb2_out = level;
+        }
+    }
+
+    fn start_shift(&mut self) {
+        self.ifr &= !interrupt::SR;
+        self.sr_bits = 0;
+        self.sr_running = ShiftMode::from_acr(self.acr) != ShiftMode::Disabled;
+        self.sr_timer = self.shift_period();
+    }
+
+    /// Cycles between shift clock edges for the modes clocked by timer 2
+    fn shift_period(&self) -> u16 {
+        match ShiftMode::from_acr(self.acr) {
+            mode if mode.uses_t2() => self.t2_latch_low as u16 + 2,
+            _ => 1,
+        }
+    }
+
+    fn shift_in(&mut self) {
+        if !self.sr_running {
+            return;
+        }
+        self.sr = (self.sr << 1) | self.cb2_in as u8;
+        self.finish_bit();
+    }
+
+    fn shift_out(&mut self) {
+        if !self.sr_running {
+            return;
+        }
+        let bit = self.sr & 0x80 != 0;
+        self.sr = self.sr.rotate_left(1);
+        self.cb2_out = bit;
+        self.finish_bit();
+    }
+
+    fn finish_bit(&mut self) {
+        self.sr_bits += 1;
+        if self.sr_bits == 8 {
+            self.sr_bits = 0;
+            if ShiftMode::from_acr(self.acr) != ShiftMode::OutFreeRunning {
+                self.sr_running = false;
+                self.ifr |= interrupt::SR;
+            }
+        }
+    }
+
+    /// Internally clocked shifting: CB1 toggles every period, data moves on
+    /// the rising edge for input and the falling edge for output. Under phi2
+    /// CB1 pulses every cycle, so a bit moves every cycle.
+    fn tick_shift_register(&mut self) {
+        let mode = ShiftMode::from_acr(self.acr);
+        if !self.sr_running || matches!(mode, ShiftMode::InExternal | ShiftMode::OutExternal) {
+            return;
+        }
+        match mode {
+            ShiftMode::InPhi2 => return self.shift_in(),
+            ShiftMode::OutPhi2 => return self.shift_out(),
+            _ => {}
+        }
+
+        self.sr_timer = self.sr_timer.saturating_sub(1);
+        if self.sr_timer > 0 {
+            return;
+        }
+        self.sr_timer = self.shift_period();
+
+        self.cb1_out = !self.cb1_out;
+        if mode.is_output() && !self.cb1_out {
+            self.shift_out();
+        } else if !mode.is_output() && self.cb1_out {
+            self.shift_in();
+        }
+    }
+
+    fn count_t2(&mut self) {
+        self.t2_counter = self.t2_counter.wrapping_sub(1);
+        if self.t2_counter == 0xFFFF && self.t2_armed {
+            self.ifr |= interrupt::T2;
+            self.t2_armed = false;
+        }
+    }
+}
+
+impl ParallelPorts for Via6522 {
+    fn port_output(&self, port: Port) -> u8 {
+        match port {
+            Port::A => self.port_a_output(),
+            Port::B => self.port_b_output(),
+        }
+    }
+
+    fn port_direction(&self, port: Port) -> u8 {
+        match port {
+            Port::A => self.ddra,
+            Port::B => self.ddrb,
+        }
+    }
+
+    fn set_port_input(&mut self, port: Port, value: u8) {
+        match port {
+            Port::A => self.set_port_a_input(value),
+            Port::B => self.set_port_b_input(value),
+        }
+    }
+}
+
+impl Device for Via6522 {
+    fn read(&mut self, offset: u16) -> u8 {
+        match offset & 0x0F {
+            ORB => {
+                self.port_b_access(false);
+                let value = if self.acr & 0x02 != 0 {
+                    (self.orb & self.ddrb) | (self.port_b_latch & !self.ddrb)
+                } else {
+                    self.read_port_b_pins()
+                };
+                if self.acr & 0x80 != 0 {
+                    (value & 0x7F) | ((self.pb7 as u8) << 7)
+                } else {
+                    value
+                }
+            }
+            ORA => {
+                self.port_a_access();
+                self.read(ORA_NH)
+            }
+            ORA_NH => {
+                if self.acr & 0x01 != 0 {
+                    self.port_a_latch
+                } else {
+                    self.read_port_a_pins()
+                }
+            }
+            DDRB => self.ddrb,
+            DDRA => self.ddra,
+            T1CL => {
+                self.ifr &= !interrupt::T1;
+                self.t1_counter as u8
+            }
+            T1CH => (self.t1_counter >> 8) as u8,
+            T1LL => self.t1_latch as u8,
+            T1LH => (self.t1_latch >> 8) as u8,
+            T2CL => {
+                self.ifr &= !interrupt::T2;
+                self.t2_counter as u8
+            }
+            T2CH => (self.t2_counter >> 8) as u8,
+            SR => {
+                self.start_shift();
+                self.sr
+            }
+            ACR => self.acr,
+            PCR => self.pcr,
+            IFR => self.ifr_value(),
+            IER => self.ier | 0x80,
+            _ => unreachable!("register offset is masked to 4 bits"),
+        }
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        match offset & 0x0F {
+            ORB => {
+                self.orb = value;
+                self.port_b_access(true);
+            }
+            ORA => {
+                self.ora = value;
+                self.port_a_access();
+            }
+            ORA_NH => self.ora = value,
+            DDRB => self.ddrb = value,
+            DDRA => self.ddra = value,
+            T1CL | T1LL => self.t1_latch = (self.t1_latch & 0xFF00) | value as u16,
+            T1CH => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | ((value as u16) << 8);
+                self.t1_counter = self.t1_latch;
+                self.ifr &= !interrupt::T1;
+                self.t1_armed = true;
+                self.t1_reload = false;
+                self.pb7 = false;
+            }
+            T1LH => {
+                self.t1_latch = (self.t1_latch & 0x00FF) | ((value as u16) << 8);
+                self.ifr &= !interrupt::T1;
+            }
+            T2CL => self.t2_latch_low = value,
+            T2CH => {
+                self.t2_counter = ((value as u16) << 8) | self.t2_latch_low as u16;
+                self.ifr &= !interrupt::T2;
+                self.t2_armed = true;
+            }
+            SR => {
+                self.sr = value;
+                self.start_shift();
+            }
+            ACR => {
+                self.acr = value;
+                if ShiftMode::from_acr(value) == ShiftMode::Disabled {
+                    self.sr_running = false;
+                }
+            }
+            PCR => {
+                self.pcr = value;
+                self.update_manual_outputs();
+            }
+            IFR => self.ifr &= !(value & 0x7F),
+            IER => {
+                if value & 0x80 != 0 {
+                    self.ier |= value & 0x7F;
+                } else {
+                    self.ier &= !(value & 0x7F);
+                }
+            }
+            _ => unreachable!("register offset is masked to 4 bits"),
+        }
+    }
+
+    fn tick(&mut self) {
+        // Pulse outputs return high after one cycle
+        if self.ca2_pulse {
+            self.ca2_pulse = false;
+            self.ca2_out = true;
+        }
+        if self.cb2_pulse {
+            self.cb2_pulse = false;
+            self.cb2_out = true;
+        }
+
+        // Timer 1: fires when the counter rolls past zero. Free-run mode
+        // reloads from the latch on the following cycle (period N + 2).
+        if self.t1_reload {
+            self.t1_reload = false;
+            self.t1_counter = self.t1_latch;
+        } else {
+            self.t1_counter = self.t1_counter.wrapping_sub(1);
+            if self.t1_counter == 0xFFFF {
+                let free_run = self.acr & 0x40 != 0;
+                if self.t1_armed {
+                    self.ifr |= interrupt::T1;
+                    self.pb7 = !self.pb7;
+                    self.t1_armed = free_run;
+                }
+                if free_run {
+                    self.t1_reload = true;
+                }
+            }
+        }
+
+        // Timer 2 counts cycles unless it is counting PB6 pulses
+        if self.acr & 0x20 == 0 {
+            self.count_t2();
+        }
+
+        self.tick_shift_register();
+    }
+
+    fn reset(&mut self) {
+        Via6522::reset(self);
+    }
+
+    fn irq(&self) -> bool {
+        Via6522::irq(self)
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.ier & 0x7F != 0
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        StateWriter::new()
+            .u8(self.ora)
+            .u8(self.orb)
+            .u8(self.ddra)
+            .u8(self.ddrb)
+            .u8(self.port_a_pins)
+            .u8(self.port_b_pins)
+            .u8(self.port_a_latch)
+            .u8(self.port_b_latch)
+            .u16(self.t1_counter)
+            .u16(self.t1_latch)
+            .bool(self.t1_armed)
+            .bool(self.t1_reload)
+            .bool(self.pb7)
+            .u16(self.t2_counter)
+            .u8(self.t2_latch_low)
+            .bool(self.t2_armed)
+            .u8(self.sr)
+            .u8(self.sr_bits)
+            .bool(self.sr_running)
+            .u16(self.sr_timer)
+            .bool(self.ca1)
+            .bool(self.ca2_in)
+            .bool(self.cb1_in)
+            .bool(self.cb2_in)
+            .bool(self.ca2_out)
+            .bool(self.cb1_out)
+            .bool(self.cb2_out)
+            .bool(self.ca2_pulse)
+            .bool(self.cb2_pulse)
+            .u8(self.acr)
+            .u8(self.pcr)
+            .u8(self.ifr)
+            .u8(self.ier)
+            .finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(state);
+        *self = Self {
+            ora: r.u8()?,
+            orb: r.u8()?,
+            ddra: r.u8()?,
+            ddrb: r.u8()?,
+            port_a_pins: r.u8()?,
+            port_b_pins: r.u8()?,
+            port_a_latch: r.u8()?,
+            port_b_latch: r.u8()?,
+            t1_counter: r.u16()?,
+            t1_latch: r.u16()?,
+            t1_armed: r.bool()?,
+            t1_reload: r.bool()?,
+            pb7: r.bool()?,
+            t2_counter: r.u16()?,
+            t2_latch_low: r.u8()?,
+            t2_armed: r.bool()?,
+            sr: r.u8()?,
+            sr_bits: r.u8()?,
+            sr_running: r.bool()?,
+            sr_timer: r.u16()?,
+            ca1: r.bool()?,
+            ca2_in: r.bool()?,
+            cb1_in: r.bool()?,
+            cb2_in: r.bool()?,
+            ca2_out: r.bool()?,
+            cb1_out: r.bool()?,
+            cb2_out: r.bool()?,
+            ca2_pulse: r.bool()?,
+            cb2_pulse: r.bool()?,
+            acr: r.u8()?,
+            pcr: r.u8()?,
+            ifr: r.u8()?,
+            ier: r.u8()?,
+        };
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(via: &mut Via6522, cycles: u32) {
+        for _ in 0..cycles {
+            via.tick();
+        }
+    }
+
+    #[test]
+    fn test_ports_and_ddr() {
+        let mut via = Via6522::new();
+        via.write(DDRB, 0xF0);
+        via.write(ORB, 0xAA);
+        via.set_port_b_input(0x05);
+
+        assert_eq!(via.read(ORB), 0xA5);
+        assert_eq!(via.port_b_output(), 0xAF);
+
+        via.write(DDRA, 0xFF);
+        via.write(ORA, 0x3C);
+        assert_eq!(via.port_a_output(), 0x3C);
+        assert_eq!(via.read(DDRA), 0xFF);
+    }
+
+    #[test]
+    fn test_timer1_one_shot() {
+        let mut via = Via6522::new();
+        via.write(IER, 0x80 | interrupt::T1);
+        via.write(T1CL, 10);
+        via.write(T1CH, 0);
+
+        run(&mut via, 10);
+        assert!(!via.irq());
+        run(&mut via, 1);
+        assert!(via.irq());
+        assert_eq!(via.read(IFR), interrupt::ANY | interrupt::T1);
+
+        // Reading T1C-L clears the flag; one-shot does not fire again
+        via.read(T1CL);
+        assert!(!via.irq());
+        run(&mut via, 0x10000);
+        assert!(!via.irq());
+    }
+
+    #[test]
+    fn test_timer1_free_run_toggles_pb7() {
+        let mut via = Via6522::new();
+        via.write(ACR, 0xC0); // free-run, PB7 output
+        via.write(T1CL, 4);
+        via.write(T1CH, 0);
+        assert_eq!(via.port_b_output() & 0x80, 0);
+
+        run(&mut via, 5);
+        assert_ne!(via.read(IFR) & interrupt::T1, 0);
+        assert_eq!(via.port_b_output() & 0x80, 0x80);
+
+        via.write(IFR, interrupt::T1);
+        // Period is N + 2 cycles
+        run(&mut via, 5);
+        assert_eq!(via.read(IFR) & interrupt::T1, 0);
+        run(&mut via, 1);
+        assert_ne!(via.read(IFR) & interrupt::T1, 0);
+        assert_eq!(via.port_b_output() & 0x80, 0);
+    }
+
+    #[test]
+    fn test_timer2_one_shot_and_pulse_counting() {
+        let mut via = Via6522::new();
+        via.write(T2CL, 3);
+        via.write(T2CH, 0);
+        run(&mut via, 4);
+        assert_ne!(via.read(IFR) & interrupt::T2, 0);
+        via.read(T2CL);
+        assert_eq!(via.read(IFR) & interrupt::T2, 0);
+
+        via.write(ACR, 0x20); // count PB6 pulses
+        via.write(T2CL, 1);
+        via.write(T2CH, 0);
+        run(&mut via, 100);
+        assert_eq!(via.read(IFR) & interrupt::T2, 0);
+        for _ in 0..2 {
+            via.set_port_b_input(0x00);
+            via.set_port_b_input(0x40);
+        }
+        assert_ne!(via.read(IFR) & interrupt::T2, 0);
+    }
+
+    #[test]
+    fn test_ier_set_and_clear() {
+        let mut via = Via6522::new();
+        via.write(IER, 0x80 | interrupt::T1 | interrupt::CA1);
+        assert_eq!(via.read(IER), 0x80 | interrupt::T1 | interrupt::CA1);
+        via.write(IER, interrupt::T1);
+        assert_eq!(via.read(IER), 0x80 | interrupt::CA1);
+    }
+
+    #[test]
+    fn test_ca1_interrupt_and_latch() {
+        let mut via = Via6522::new();
+        via.write(IER, 0x80 | interrupt::CA1);
+        via.write(ACR, 0x01); // latch port A
+        via.write(PCR, 0x01); // positive edge
+
+        via.set_port_a_input(0x42);
+        via.set_ca1(false);
+        assert!(!via.irq());
+        via.set_ca1(true);
+        assert!(via.irq());
+
+        via.set_port_a_input(0x00);
+        assert_eq!(via.read(ORA), 0x42);
+        assert!(!via.irq()); // reading ORA clears CA1
+    }
+
+    #[test]
+    fn test_ca2_handshake_and_pulse() {
+        let mut via = Via6522::new();
+        via.write(PCR, 0x08); // CA2 handshake, CA1 negative edge
+        via.read(ORA);
+        assert!(!via.ca2_output());
+        via.set_ca1(false);
+        assert!(via.ca2_output());
+
+        via.write(PCR, 0x0A); // CA2 pulse
+        via.write(ORA, 0x00);
+        assert!(!via.ca2_output());
+        via.tick();
+        assert!(via.ca2_output());
+
+        via.write(PCR, 0xCC); // CA2 and CB2 manual low
+        assert!(!via.ca2_output());
+        assert!(!via.cb2_output());
+    }
+
+    #[test]
+    fn test_cb2_independent_interrupt() {
+        let mut via = Via6522::new();
+        via.write(PCR, 0x20); // CB2 independent, negative edge
+        via.set_cb2(false);
+        assert_ne!(via.read(IFR) & interrupt::CB2, 0);
+        via.read(ORB);
+        assert_ne!(via.read(IFR) & interrupt::CB2, 0);
+    }
+
+    #[test]
+    fn test_shift_out_phi2() {
+        let mut via = Via6522::new();
+        via.write(ACR, 0x18); // shift out under phi2
+        via.write(SR, 0b1010_0110);
+
+        // One bit per cycle
+        let mut bits = Vec::new();
+        for _ in 0..8 {
+            assert_eq!(via.ifr & interrupt::SR, 0);
+            via.tick();
+            bits.push(via.cb2_output() as u8);
+        }
+
+        assert_eq!(bits, vec![1, 0, 1, 0, 0, 1, 1, 0]);
+        assert_ne!(via.read(IFR) & interrupt::SR, 0);
+    }
+
+    #[test]
+    fn test_reset_keeps_pins_and_timers() {
+        let mut via = Via6522::new();
+        via.set_port_a_input(0x42);
+        via.set_cb1(false);
+        via.write(DDRA, 0x0F);
+        via.write(ORA, 0x05);
+        via.write(PCR, 0x10); // CB1 interrupts on a rising edge
+        via.write(IER, 0x80 | interrupt::T1 | interrupt::CB1);
+        via.write(T1CL, 0x34);
+        via.write(T1CH, 0x12);
+
+        via.reset();
+        assert_eq!(via.read(ORA), 0x42);
+        assert_eq!((via.ddra, via.pcr, via.ier, via.ifr), (0, 0, 0, 0));
+        assert_eq!(via.read(T1CH), 0x12);
+
+        // CB1 is still held low, so releasing it is an edge
+        via.write(PCR, 0x10);
+        via.set_cb1(true);
+        assert_ne!(via.ifr & interrupt::CB1, 0);
+    }
+
+    #[test]
+    fn test_shift_in_external_clock() {
+        let mut via = Via6522::new();
+        via.write(ACR, 0x0C); // shift in under CB1
+        via.read(SR);
+
+        for bit in [1, 1, 0, 0, 1, 0, 1, 1] {
+            via.set_cb2(bit == 1);
+            via.set_cb1(false);
+            via.set_cb1(true);
+        }
+
+        assert_ne!(via.read(IFR) & interrupt::SR, 0);
+        assert_eq!(via.read(SR), 0b1100_1011);
+    }
+
+    #[test]
+    fn test_timer_interrupts_cpu() {
+        use crate::cpu::Cpu;
+        use crate::memory_map::MemoryMapBuilder;
+
+        let mut rom = vec![0xEA; 0x8000];
+        #[rustfmt::skip]
+        let program = [
+            0xA9, 0xC0,       // LDA #$C0
+            0x8D, 0x0E, 0x60, // STA $600E   ; enable T1 interrupt
+            0xA9, 0x20,       // LDA #$20
+            0x8D, 0x04, 0x60, // STA $6004
+            0xA9, 0x00,       // LDA #$00
+            0x8D, 0x05, 0x60, // STA $6005   ; start T1
+            0x58,             // CLI
+            0x4C, 0x10, 0x80, // loop: JMP loop
+        ];
+        rom[..program.len()].copy_from_slice(&program);
+        rom[0x1000..0x1003].copy_from_slice(&[0x4C, 0x00, 0x90]); // $9000: JMP $9000
+        rom[0x7FFC..].copy_from_slice(&[0x00, 0x80, 0x00, 0x90]);
+
+        let bus = MemoryMapBuilder::new()
+            .ram(0x0000, 0x4000)
+            .device(0x6000, 0x10, Via6522::new())
+            .rom(0x8000, 0x8000, &rom)
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::new(bus);
+        cpu.reset();
+
+        for _ in 0..20 {
+            cpu.execute_instruction();
+        }
+        assert_eq!(cpu.pc, 0x9000);
+        assert!(cpu.status.get(crate::status::Flag::InterruptDisable));
+    }
+
+    #[test]
+    fn test_shift_out_free_running_never_interrupts() {
+        let mut via = Via6522::new();
+        via.write(T2CL, 0);
+        via.write(ACR, 0x10); // free-running at T2 rate
+        via.write(SR, 0xFF);
+        run(&mut via, 200);
+        assert_eq!(via.read(IFR) & interrupt::SR, 0);
+    }
+}
@@ -0,0 +1,204 @@
+//! 6502 disassembler
+//!
+//! Turns bytes back into assembler syntax using the same opcode table the
+//! CPU executes from. Addresses that have a label in the symbol table are
+//! shown by name, so `JSR $8030` reads as `JSR lcd_instruction`.
+//!
+//! Memory is read through a closure rather than a `Bus`, so front ends can
+//! disassemble without triggering device side effects (e.g. with
+//! `MappedBus::peek`).
+
+use std::fmt;
+
+use crate::addressing::AddressingMode;
+use crate::instructions::OPCODES;
+use crate::symbols::SymbolTable;
+
+/// One disassembled instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub address: u16,
+    /// Opcode and operand bytes
+    pub bytes: Vec<u8>,
+    /// Assembler text, e.g. `LDA ($20),Y`
+    pub text: String,
+}
+
+impl Line {
+    /// Address of the instruction that follows
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.bytes.len() as u16)
+    }
+}
+
+impl fmt::Display for Line {
+    /// `$8000  A9 10     LDA #$10`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{b:02X}")).collect();
+        write!(
+            f,
+            "${:04X}  {:<8}  {}",
+            self.address,
+            bytes.join(" "),
+            self.text
+        )
+    }
+}
+
+/// Disassemble the instruction at `address`
+pub fn disassemble<F: FnMut(u16) -> u8>(address: u16, mut read: F, symbols: &SymbolTable) -> Line {
+    let code = read(address);
+    let opcode = &OPCODES[code as usize];
+    if opcode.mnemonic == "???" {
+        return Line {
+            address,
+            bytes: vec![code],
+            text: format!(".byte ${code:02X}"),
+        };
+    }
+
+    let bytes: Vec<u8> = (0..opcode.bytes as u16)
+        .map(|i| read(address.wrapping_add(i)))
+        .collect();
+    let byte = bytes.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, bytes.get(2).copied().unwrap_or(0)]);
+    let name = |target: u16, width: usize| match symbols.name_of(target) {
+        Some(name) => name.to_string(),
+        None if width == 2 => format!("${target:02X}"),
+        None => format!("${target:04X}"),
+    };
+
+    let operand = match opcode.mode {
+        AddressingMode::Implied => String::new(),
+        AddressingMode::Accumulator => String::from("A"),
+        AddressingMode::Immediate => format!("#${byte:02X}"),
+        AddressingMode::ZeroPage => name(byte as u16, 2),
+        AddressingMode::ZeroPageX => format!("{},X", name(byte as u16, 2)),
+        AddressingMode::ZeroPageY => format!("{},Y", name(byte as u16, 2)),
+        AddressingMode::Absolute => name(word, 4),
+        AddressingMode::AbsoluteX => format!("{},X", name(word, 4)),
+        AddressingMode::AbsoluteY => format!("{},Y", name(word, 4)),
+        AddressingMode::IndirectX => format!("({},X)", name(byte as u16, 2)),
+        AddressingMode::IndirectY => format!("({}),Y", name(byte as u16, 2)),
+        AddressingMode::Indirect => format!("({})", name(word, 4)),
+        AddressingMode::Relative => {
+            let target = address.wrapping_add(2).wrapping_add(byte as i8 as u16);
+            name(target, 4)
+        }
+    };
+
+    let text = if operand.is_empty() {
+        opcode.mnemonic.to_string()
+    } else {
+        format!("{} {operand}", opcode.mnemonic)
+    };
+    Line {
+        address,
+        bytes,
+        text,
+    }
+}
+
+/// Disassemble `count` consecutive instructions starting at `address`
+pub fn disassemble_range<F: FnMut(u16) -> u8>(
+    address: u16,
+    count: usize,
+    mut read: F,
+    symbols: &SymbolTable,
+) -> Vec<Line> {
+    let mut lines = Vec::with_capacity(count);
+    let mut address = address;
+    for _ in 0..count {
+        let line = disassemble(address, &mut read, symbols);
+        address = line.next_address();
+        lines.push(line);
+    }
+    lines
+}
+
+/// Up to `before` instructions leading to `address`, then `count`
+/// instructions from `address` on.
+///
+/// 6502 code can't be decoded backwards, so this looks for the earliest
+/// start within reach whose instruction stream lands exactly on `address`.
+/// Data just before code can throw it off; the lines from `address` on are
+/// always right.
+pub fn disassemble_around<F: FnMut(u16) -> u8>(
+    address: u16,
+    before: usize,
+    count: usize,
+    mut read: F,
+    symbols: &SymbolTable,
+) -> Vec<Line> {
+    let mut lines = Vec::new();
+    for back in (1..=before as u16 * 3).rev() {
+        let mut candidate = Vec::new();
+        let mut offset = 0;
+        while offset < back {
+            let line = disassemble(address.wrapping_sub(back - offset), &mut read, symbols);
+            offset += line.bytes.len() as u16;
+            candidate.push(line);
+        }
+        if offset == back {
+            lines = candidate;
+            break;
+        }
+    }
+
+    lines.drain(..lines.len().saturating_sub(before));
+    lines.extend(disassemble_range(address, count, read, symbols));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(bytes: &[u8], symbols: &SymbolTable) -> String {
+        disassemble(0x8000, |a| bytes[(a - 0x8000) as usize], symbols).text
+    }
+
+    #[test]
+    fn test_addressing_modes() {
+        let symbols = SymbolTable::new();
+        assert_eq!(text(&[0xEA], &symbols), "NOP");
+        assert_eq!(text(&[0x0A], &symbols), "ASL A");
+        assert_eq!(text(&[0xA9, 0x10], &symbols), "LDA #$10");
+        assert_eq!(text(&[0xB6, 0x10], &symbols), "LDX $10,Y");
+        assert_eq!(text(&[0x9D, 0x00, 0x02], &symbols), "STA $0200,X");
+        assert_eq!(text(&[0xA1, 0x20], &symbols), "LDA ($20,X)");
+        assert_eq!(text(&[0x91, 0x20], &symbols), "STA ($20),Y");
+        assert_eq!(text(&[0x6C, 0xFC, 0xFF], &symbols), "JMP ($FFFC)");
+        assert_eq!(text(&[0xD0, 0xFE], &symbols), "BNE $8000");
+        assert_eq!(text(&[0x02], &symbols), ".byte $02");
+    }
+
+    #[test]
+    fn test_symbols_and_range() {
+        let symbols = SymbolTable::parse("ptr = $20\nprint = $8005\n").unwrap();
+        let program = [0x20, 0x05, 0x80, 0xB1, 0x20, 0xD0, 0xFE];
+
+        let lines = disassemble_range(0x8000, 3, |a| program[(a - 0x8000) as usize], &symbols);
+        let texts: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, ["JSR print", "LDA (ptr),Y", "BNE print"]);
+        assert_eq!(lines[2].to_string(), "$8005  D0 FE     BNE print");
+        assert_eq!(lines[2].next_address(), 0x8007);
+    }
+
+    #[test]
+    fn test_disassemble_around() {
+        // lda #0 / loop: clc / adc #1 / cmp #10 / bne loop
+        let program = [0xA9, 0x00, 0x18, 0x69, 0x01, 0xC9, 0x0A, 0xD0, 0xF9];
+        let read = |a: u16| match a.checked_sub(0x8000) {
+            Some(offset) if (offset as usize) < program.len() => program[offset as usize],
+            _ => 0xEA,
+        };
+
+        let lines = disassemble_around(0x8005, 2, 2, read, &SymbolTable::new());
+        let addresses: Vec<u16> = lines.iter().map(|line| line.address).collect();
+        assert_eq!(addresses, [0x8002, 0x8003, 0x8005, 0x8007]);
+
+        let lines = disassemble_around(0x8000, 0, 1, read, &SymbolTable::new());
+        assert_eq!(lines[0].text, "LDA #$00");
+    }
+}
@@ -1,6 +1,6 @@
 //! Flow Control instructions: JMP, JSR, RTS, BRK, RTI, and branches
 
-use crate::bus::Bus;
+use crate::bus::{AccessKind, Bus};
 use crate::cpu::Cpu;
 use crate::status::Flag;
 
@@ -43,6 +43,7 @@ impl<B: Bus> Cpu<B> {
         self.status.set(Flag::InterruptDisable, true);
 
         // Load IRQ vector
+        self.bus.set_access_kind(AccessKind::Vector);
         self.pc = self.read_word(0xFFFE);
     }
 
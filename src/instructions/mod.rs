@@ -61,6 +61,12 @@ impl Opcode {
     }
 }
 
+/// True for the opcodes that lock up a real 6502 (JAM, also called KIL):
+/// `$x2` apart from $82, $A2, $C2 and $E2
+pub const fn is_jam(code: u8) -> bool {
+    code & 0x0F == 0x02 && !matches!(code, 0x82 | 0xA2 | 0xC2 | 0xE2)
+}
+
 /// Lookup table for all 256 possible opcodes
 /// Invalid opcodes are represented as NOP with 1 cycle
 pub static OPCODES: [Opcode; 256] = create_opcode_table();
@@ -322,5 +328,11 @@ mod tests {
         // Test an illegal opcode
         let illegal = get_opcode(0x02);
         assert_eq!(illegal.mnemonic, "???");
+
+        let jams: Vec<u8> = (0..=255).filter(|&code| is_jam(code)).collect();
+        assert_eq!(
+            jams,
+            [0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xB2, 0xD2, 0xF2]
+        );
     }
 }
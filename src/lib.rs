@@ -8,7 +8,24 @@ pub mod status;
 pub mod cpu;
 pub mod addressing;
 pub mod instructions;
+pub mod symbols;
+pub mod disasm;
+pub mod trace;
+pub mod asm;
+pub mod clock;
+pub mod profile;
+pub mod debug_info;
+pub mod coverage;
+pub mod devices;
+pub mod memory_map;
+pub mod banking;
+pub mod bus_trace;
+pub mod machine;
+pub mod machines;
 
 pub use bus::Bus;
 pub use status::StatusRegister;
 pub use cpu::Cpu;
+pub use symbols::SymbolTable;
+pub use devices::Device;
+pub use memory_map::{MappedBus, MemoryMapBuilder};
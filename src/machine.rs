@@ -0,0 +1,1132 @@
+//! Declarative machine descriptions
+//!
+//! A machine file describes a board in a small TOML subset so new targets can
+//! be run without recompiling:
+//!
+//! ```toml
+//! [machine]
+//! name = "Breadboard 6502"
+//! cpu = "6502"
+//! clock_hz = 1_000_000
+//!
+//! [[region]]
+//! type = "ram"
+//! start = 0x0000
+//! size = 0x4000
+//!
+//! [[region]]
+//! type = "rom"
+//! start = 0x8000
+//! size = 0x8000
+//! file = "rom.bin"      # relative to the machine file
+//!
+//! [[device]]
+//! type = "via6522"
+//! start = 0x6000
+//! size = 0x10
+//! ```
+//!
+//! Region types are `ram`, `rom`, `mirror` (`source`, `source_size`) and
+//! `unmapped` (`value`). Devices may set a `name` for looking them up on the
+//! built bus (it defaults to the type). Any extra keys on a device are passed
+//! to it as options; keys its type does not understand are an error when the
+//! machine is built. A device with `inside = "<name>"` is not mapped on the
+//! bus but placed in the I/O area of the named device (a `c64_memory`).
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::banking::{C64Memory, CnRom, Mmc1, UxRom};
+use crate::devices::acia6551::{NullBackend, SerialBackend, StreamBackend};
+use crate::devices::hd44780::{PortLcd, Wiring};
+use crate::devices::{
+    Acia6551, Console, Device, Hd44780, ParallelPorts, Pia6821, Port, Riot6532, Rriot6530, Timer,
+    Via6522,
+};
+use crate::machines::apple1::Apple1Terminal;
+use crate::machines::kim1::{self, Kim1Io};
+use crate::memory_map::{MapError, MappedBus, MemoryMapBuilder};
+
+/// CPU models a machine can ask for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuVariant {
+    /// Original NMOS 6502
+    Nmos6502,
+}
+
+impl CpuVariant {
+    fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "6502" | "nmos6502" | "mos6502" => Some(CpuVariant::Nmos6502),
+            _ => None,
+        }
+    }
+}
+
+/// A value in a machine file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "\"{s}\""),
+            Value::Integer(n) => write!(f, "{n}"),
+            Value::Float(n) => write!(f, "{n}"),
+            Value::Bool(b) => write!(f, "{b}"),
+        }
+    }
+}
+
+/// Errors from loading or building a machine
+#[derive(Debug)]
+pub enum MachineError {
+    Io(PathBuf, io::Error),
+    /// Syntax error (1-based line number)
+    Parse {
+        line: usize,
+        message: String,
+    },
+    /// Well-formed file with an invalid or missing setting
+    Invalid(String),
+    Map(MapError),
+}
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MachineError::Io(path, e) => write!(f, "{}: {e}", path.display()),
+            MachineError::Parse { line, message } => write!(f, "line {line}: {message}"),
+            MachineError::Invalid(message) => write!(f, "{message}"),
+            MachineError::Map(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for MachineError {}
+
+impl From<MapError> for MachineError {
+    fn from(e: MapError) -> Self {
+        MachineError::Map(e)
+    }
+}
+
+/// A memory region in a machine description
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegionConfig {
+    Ram {
+        start: u16,
+        size: usize,
+    },
+    Rom {
+        start: u16,
+        size: usize,
+        file: Option<PathBuf>,
+    },
+    Mirror {
+        start: u16,
+        size: usize,
+        source: u16,
+        source_size: usize,
+    },
+    Unmapped {
+        start: u16,
+        size: usize,
+        value: u8,
+    },
+}
+
+/// A device instance in a machine description
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceConfig {
+    /// Registry name; defaults to the type, numbered if the type repeats
+    pub name: String,
+    pub kind: String,
+    pub start: u16,
+    pub size: usize,
+    /// Name of the device whose I/O area this one sits in (`inside`), if it
+    /// is not mapped on the bus directly
+    pub inside: Option<String>,
+    /// Remaining keys, interpreted by the device
+    pub options: BTreeMap<String, Value>,
+}
+
+/// A complete machine description
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineConfig {
+    pub name: String,
+    pub cpu: CpuVariant,
+    pub clock_hz: u32,
+    /// Value read from addresses no region covers
+    pub open_bus: u8,
+    pub regions: Vec<RegionConfig>,
+    pub devices: Vec<DeviceConfig>,
+}
+
+impl Default for MachineConfig {
+    /// The layout from `examples/emu.cfg`: 32KB RAM at $0000 and 32KB ROM at $8000
+    fn default() -> Self {
+        Self {
+            name: String::from("default"),
+            cpu: CpuVariant::Nmos6502,
+            clock_hz: 1_000_000,
+            open_bus: 0xFF,
+            regions: vec![
+                RegionConfig::Ram {
+                    start: 0x0000,
+                    size: 0x8000,
+                },
+                RegionConfig::Rom {
+                    start: 0x8000,
+                    size: 0x8000,
+                    file: None,
+                },
+            ],
+            devices: Vec::new(),
+        }
+    }
+}
+
+impl MachineConfig {
+    /// Load a machine file. Relative ROM paths are resolved against the
+    /// directory containing the file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, MachineError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| MachineError::Io(path.to_path_buf(), e))?;
+        let mut config = Self::parse(&text)?;
+
+        let base = path.parent().unwrap_or(Path::new(""));
+        for region in &mut config.regions {
+            if let RegionConfig::Rom {
+                file: Some(file), ..
+            } = region
+                && file.is_relative()
+            {
+                *file = base.join(&*file);
+            }
+        }
+        for device in &mut config.devices {
+            for key in FILE_OPTIONS {
+                if let Some(Value::String(file)) = device.options.get_mut(*key)
+                    && Path::new(file.as_str()).is_relative()
+                {
+                    *file = base.join(&*file).to_string_lossy().into_owned();
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// Parse a machine description from text
+    pub fn parse(text: &str) -> Result<Self, MachineError> {
+        let sections = parse_document(text)?;
+
+        let mut config = MachineConfig {
+            name: String::from("custom"),
+            regions: Vec::new(),
+            ..Self::default()
+        };
+
+        for section in sections {
+            let mut table = Table::new(section.name.clone(), section.entries);
+            match (section.name.as_str(), section.array) {
+                ("machine", false) => {
+                    if let Some(name) = table.take_string("name")? {
+                        config.name = name;
+                    }
+                    if let Some(cpu) = table.take_string("cpu")? {
+                        config.cpu = CpuVariant::parse(&cpu).ok_or_else(|| {
+                            MachineError::Invalid(format!("unsupported CPU variant \"{cpu}\""))
+                        })?;
+                    }
+                    if let Some(clock) = table.take_clock("clock_hz")? {
+                        config.clock_hz = clock;
+                    }
+                    if let Some(value) = table.take_u8("open_bus")? {
+                        config.open_bus = value;
+                    }
+                    table.finish()?;
+                }
+                ("region", true) => config.regions.push(parse_region(&mut table)?),
+                ("device", true) => {
+                    let kind = table.require_string("type")?;
+                    let start = table.require_address("start")?;
+                    let size = table.require_size("size")?;
+                    let name = match table.take_string("name")? {
+                        Some(name) => name,
+                        None => {
+                            let count = config.devices.iter().filter(|d| d.kind == kind).count();
+                            if count == 0 {
+                                kind.clone()
+                            } else {
+                                format!("{kind}_{}", count + 1)
+                            }
+                        }
+                    };
+                    config.devices.push(DeviceConfig {
+                        name,
+                        kind,
+                        start,
+                        size,
+                        inside: table.take_string("inside")?,
+                        options: table.into_rest(),
+                    });
+                }
+                (name, array) => {
+                    let header = if array {
+                        format!("[[{name}]]")
+                    } else {
+                        format!("[{name}]")
+                    };
+                    return Err(MachineError::Invalid(format!("unknown section {header}")));
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Build the bus described by this machine
+    pub fn build(&self) -> Result<MappedBus, MachineError> {
+        let mut builder = MemoryMapBuilder::new().open_bus(self.open_bus);
+
+        for region in &self.regions {
+            builder = match region {
+                RegionConfig::Ram { start, size } => builder.ram(*start, *size),
+                RegionConfig::Rom { start, size, file } => {
+                    let data = match file {
+                        Some(path) => {
+                            fs::read(path).map_err(|e| MachineError::Io(path.clone(), e))?
+                        }
+                        None => Vec::new(),
+                    };
+                    builder.rom(*start, *size, &data)
+                }
+                RegionConfig::Mirror {
+                    start,
+                    size,
+                    source,
+                    source_size,
+                } => builder.mirror(*start, *size, *source, *source_size),
+                RegionConfig::Unmapped { start, size, value } => {
+                    builder.unmapped(*start, *size, *value)
+                }
+            };
+        }
+
+        // Devices inside another's I/O area are made first and handed to it
+        let mut io: BTreeMap<&str, Vec<IoDevice>> = BTreeMap::new();
+        for device in &self.devices {
+            if let Some(parent) = &device.inside {
+                let created = create_device(device, self.clock_hz, Vec::new())?;
+                io.entry(parent).or_default().push((device, created));
+            }
+        }
+        for device in self.devices.iter().filter(|d| d.inside.is_none()) {
+            let children = io.remove(device.name.as_str()).unwrap_or_default();
+            let created = create_device(device, self.clock_hz, children)?;
+            builder = builder.boxed_named_device(&device.name, device.start, device.size, created);
+        }
+        if let Some((parent, children)) = io.into_iter().next() {
+            return Err(MachineError::Invalid(format!(
+                "device \"{}\" is inside \"{parent}\", which is not a device",
+                children[0].0.name
+            )));
+        }
+
+        Ok(builder.build()?)
+    }
+
+    /// True if a device is wired to the emulator's own stdin/stdout, in which
+    /// case the front end must leave the terminal to it
+    pub fn uses_stdio(&self) -> bool {
+        self.devices.iter().any(|device| {
+            SERIAL_DEVICES.contains(&device.kind.as_str()) && serial_backend_name(device) == "stdio"
+        })
+    }
+
+    /// Point every device that takes a `backend` option at `backend`.
+    /// Returns false if the machine has none.
+    pub fn set_serial_backend(&mut self, backend: &str) -> bool {
+        let mut found = false;
+        for device in &mut self.devices {
+            if SERIAL_DEVICES.contains(&device.kind.as_str()) {
+                let value = Value::String(backend.into());
+                device.options.insert(String::from("backend"), value);
+                found = true;
+            }
+        }
+        found
+    }
+
+    /// Take KIM-1 keypads off stdio for a front end that draws device
+    /// screens and passes its keys on: a `kim1_io` on the terminal switches
+    /// to its keypad and display, with no serial backend
+    pub fn detach_keypads(&mut self) {
+        for device in &mut self.devices {
+            if device.kind == "kim1_io" && serial_backend_name(device) == "stdio" {
+                let options = &mut device.options;
+                options.insert(String::from("backend"), Value::String("none".into()));
+                options.insert(String::from("mode"), Value::String("keypad".into()));
+            }
+        }
+    }
+}
+
+/// Device types that take a `backend` option
+const SERIAL_DEVICES: &[&str] = &["acia6551", "console", "apple1_terminal", "kim1_io"];
+
+fn serial_backend_name(config: &DeviceConfig) -> &str {
+    match config.options.get("backend") {
+        Some(Value::String(name)) => name,
+        _ => "stdio",
+    }
+}
+
+/// A device to place in another's I/O area, with its description
+type IoDevice<'a> = (&'a DeviceConfig, Box<dyn Device>);
+
+/// Device options that name files, resolved against the machine file
+const FILE_OPTIONS: &[&str] = &["file", "chr", "basic", "kernal", "chargen"];
+
+/// Instantiate a device by its `type` name. Each type takes the options it
+/// understands; any left over are reported as unknown keys. `io` are the
+/// devices declared `inside` this one.
+fn create_device(
+    config: &DeviceConfig,
+    clock_hz: u32,
+    io: Vec<IoDevice>,
+) -> Result<Box<dyn Device>, MachineError> {
+    if !io.is_empty() && config.kind != "c64_memory" {
+        return Err(MachineError::Invalid(format!(
+            "device \"{}\" is inside \"{}\", which has no I/O area",
+            io[0].0.name, config.name
+        )));
+    }
+    let mut options = Table::new(
+        format!("device \"{}\"", config.name),
+        config.options.clone(),
+    );
+    let device: Box<dyn Device> = match config.kind.as_str() {
+        "via6522" => with_lcd(&mut options, clock_hz, Via6522::new())?,
+        "riot6532" => with_lcd(&mut options, clock_hz, Riot6532::new())?,
+        "rriot6530" => with_lcd(&mut options, clock_hz, Rriot6530::new())?,
+        "pia6821" | "pia6520" => with_lcd(&mut options, clock_hz, Pia6821::new())?,
+        "hd44780" => {
+            let (columns, rows) = lcd_size(&mut options, "size")?.unwrap_or((16, 2));
+            Box::new(Hd44780::with_size(columns, rows, clock_hz))
+        }
+        "acia6551" => Box::new(Acia6551::with_boxed_backend(
+            create_serial_backend(config, &mut options)?,
+            clock_hz,
+        )),
+        "console" => Box::new(Console::with_boxed_backend(create_serial_backend(
+            config,
+            &mut options,
+        )?)),
+        "timer" => Box::new(Timer::new()),
+        "uxrom" => Box::new(UxRom::new(&take_file(&mut options, "file")?)),
+        "cnrom" => Box::new(CnRom::new(
+            &take_file(&mut options, "file")?,
+            &take_file(&mut options, "chr")?,
+        )),
+        "mmc1" => Box::new(Mmc1::new(
+            &take_file(&mut options, "file")?,
+            &take_file(&mut options, "chr")?,
+        )),
+        "c64_memory" => {
+            let mut memory = C64Memory::new(
+                &take_file(&mut options, "basic")?,
+                &take_file(&mut options, "kernal")?,
+                &take_file(&mut options, "chargen")?,
+            );
+            for (child, device) in io {
+                if child.start < 0xD000 || child.start as usize + child.size > 0xE000 {
+                    return Err(MachineError::Invalid(format!(
+                        "device \"{}\" must be within $D000-$DFFF to be inside \"{}\"",
+                        child.name, config.name
+                    )));
+                }
+                memory = memory.with_boxed_io_at(child.start, child.size, device);
+            }
+            Box::new(memory)
+        }
+        "apple1_terminal" => Box::new(Apple1Terminal::with_boxed_backend(
+            create_serial_backend(config, &mut options)?,
+            clock_hz,
+        )),
+        "kim1_io" => {
+            let mode = match options.take_string("mode")?.as_deref() {
+                None | Some("tty") => kim1::Mode::Tty,
+                Some("keypad") => kim1::Mode::Keypad,
+                Some(other) => {
+                    return Err(MachineError::Invalid(format!(
+                        "kim1_io mode must be \"tty\" or \"keypad\", not \"{other}\""
+                    )));
+                }
+            };
+            Box::new(Kim1Io::with_boxed_backend(
+                create_serial_backend(config, &mut options)?,
+                mode,
+                clock_hz,
+            ))
+        }
+        other => {
+            return Err(MachineError::Invalid(format!(
+                "unknown device type \"{other}\""
+            )));
+        }
+    };
+    options.finish()?;
+    Ok(device)
+}
+
+/// Wrap a port chip in `PortLcd` when the device has an `lcd` option. The
+/// wiring defaults to Ben Eater's 8-bit layout and is changed with
+/// `lcd_bus`, `lcd_data`, `lcd_data_shift`, `lcd_control`, `lcd_e`,
+/// `lcd_rw` and `lcd_rs`.
+fn with_lcd<D: Device + ParallelPorts + 'static>(
+    options: &mut Table,
+    clock_hz: u32,
+    chip: D,
+) -> Result<Box<dyn Device>, MachineError> {
+    let Some((columns, rows)) = lcd_size(options, "lcd")? else {
+        return Ok(Box::new(chip));
+    };
+
+    let mut wiring = Wiring::BEN_EATER_8BIT;
+    wiring.four_bit = match options.entries.remove("lcd_bus") {
+        None | Some(Value::Integer(8)) => false,
+        Some(Value::Integer(4)) => true,
+        Some(other) => return Err(invalid_option(options, "lcd_bus", &other)),
+    };
+    if let Some(port) = lcd_port(options, "lcd_data")? {
+        wiring.data = port;
+    }
+    if let Some(port) = lcd_port(options, "lcd_control")? {
+        wiring.control = port;
+    }
+    let max_shift = if wiring.four_bit { 4 } else { 0 };
+    if let Some(shift) = lcd_bit(options, "lcd_data_shift", max_shift)? {
+        wiring.data_shift = shift;
+    }
+    for (key, mask) in [
+        ("lcd_e", &mut wiring.e),
+        ("lcd_rw", &mut wiring.rw),
+        ("lcd_rs", &mut wiring.rs),
+    ] {
+        if let Some(bit) = lcd_bit(options, key, 7)? {
+            *mask = 1 << bit;
+        }
+    }
+
+    let lcd = Hd44780::with_size(columns, rows, clock_hz);
+    Ok(Box::new(PortLcd::new(chip, lcd, wiring)))
+}
+
+/// A panel size such as `"16x2"` or `"20x4"`
+fn lcd_size(options: &mut Table, key: &str) -> Result<Option<(usize, usize)>, MachineError> {
+    let Some(value) = options.entries.remove(key) else {
+        return Ok(None);
+    };
+    let size = match &value {
+        Value::String(size) => size.split_once('x').and_then(|(columns, rows)| {
+            Some((columns.parse::<usize>().ok()?, rows.parse::<usize>().ok()?))
+        }),
+        _ => None,
+    };
+    match size {
+        Some((columns, rows))
+            if matches!(rows, 1 | 2 | 4) && columns > 0 && columns * rows <= 80 =>
+        {
+            Ok(Some((columns, rows)))
+        }
+        _ => Err(invalid_option(options, key, &value)),
+    }
+}
+
+fn lcd_port(options: &mut Table, key: &str) -> Result<Option<Port>, MachineError> {
+    match options.entries.remove(key) {
+        None => Ok(None),
+        Some(Value::String(port)) if port.eq_ignore_ascii_case("a") => Ok(Some(Port::A)),
+        Some(Value::String(port)) if port.eq_ignore_ascii_case("b") => Ok(Some(Port::B)),
+        Some(other) => Err(invalid_option(options, key, &other)),
+    }
+}
+
+fn lcd_bit(options: &mut Table, key: &str, max: i64) -> Result<Option<u8>, MachineError> {
+    match options.entries.remove(key) {
+        None => Ok(None),
+        Some(Value::Integer(bit)) if (0..=max).contains(&bit) => Ok(Some(bit as u8)),
+        Some(other) => Err(invalid_option(options, key, &other)),
+    }
+}
+
+/// The contents of the file named by `key`, or nothing if it is not given
+fn take_file(options: &mut Table, key: &str) -> Result<Vec<u8>, MachineError> {
+    match options.take_string(key)? {
+        Some(path) => fs::read(&path).map_err(|e| MachineError::Io(PathBuf::from(path), e)),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn invalid_option(options: &Table, key: &str, value: &Value) -> MachineError {
+    MachineError::Invalid(format!("{} has an invalid {key}: {value}", options.section))
+}
+
+/// Serial backends selected by a device's `backend` option
+fn create_serial_backend(
+    config: &DeviceConfig,
+    options: &mut Table,
+) -> Result<Box<dyn SerialBackend>, MachineError> {
+    options.entries.remove("backend");
+    match serial_backend_name(config) {
+        "stdio" => Ok(Box::new(StreamBackend::stdio())),
+        "none" => Ok(Box::new(NullBackend)),
+        #[cfg(target_os = "linux")]
+        "pty" => {
+            let pty = crate::devices::acia6551::PtyBackend::open()
+                .map_err(|e| MachineError::Io(PathBuf::from("/dev/ptmx"), e))?;
+            Ok(Box::new(pty))
+        }
+        other => Err(MachineError::Invalid(format!(
+            "unknown serial backend \"{other}\""
+        ))),
+    }
+}
+
+fn parse_region(table: &mut Table) -> Result<RegionConfig, MachineError> {
+    let kind = table.require_string("type")?;
+    let start = table.require_address("start")?;
+    let size = table.require_size("size")?;
+
+    let region = match kind.as_str() {
+        "ram" => RegionConfig::Ram { start, size },
+        "rom" => RegionConfig::Rom {
+            start,
+            size,
+            file: table.take_string("file")?.map(PathBuf::from),
+        },
+        "mirror" => RegionConfig::Mirror {
+            start,
+            size,
+            source: table.require_address("source")?,
+            source_size: table.require_size("source_size")?,
+        },
+        "unmapped" => RegionConfig::Unmapped {
+            start,
+            size,
+            value: table.take_u8("value")?.unwrap_or(0xFF),
+        },
+        other => {
+            return Err(MachineError::Invalid(format!(
+                "unknown region type \"{other}\""
+            )));
+        }
+    };
+
+    table.finish()?;
+    Ok(region)
+}
+
+// ========== Machine file syntax ==========
+
+struct Section {
+    name: String,
+    array: bool,
+    entries: BTreeMap<String, Value>,
+}
+
+/// Split a document into sections. Keys before the first header belong to
+/// an implicit `[machine]` section.
+fn parse_document(text: &str) -> Result<Vec<Section>, MachineError> {
+    let mut sections = vec![Section {
+        name: String::from("machine"),
+        array: false,
+        entries: BTreeMap::new(),
+    }];
+    let mut seen = HashSet::new();
+    // Keys go to this section; `[machine]` goes back to the leading one, so
+    // it can't quietly redefine keys given before it
+    let mut current = 0;
+
+    for (index, raw) in text.lines().enumerate() {
+        let line = strip_comment(raw).trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: &str| MachineError::Parse {
+            line: index + 1,
+            message: message.to_string(),
+        };
+
+        if let Some(name) = line
+            .strip_prefix("[[")
+            .and_then(|rest| rest.strip_suffix("]]"))
+        {
+            sections.push(Section {
+                name: name.trim().to_string(),
+                array: true,
+                entries: BTreeMap::new(),
+            });
+            current = sections.len() - 1;
+        } else if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|rest| rest.strip_suffix(']'))
+        {
+            let name = name.trim();
+            if !seen.insert(name.to_string()) {
+                return Err(error(&format!("duplicate section [{name}]")));
+            }
+            if name == "machine" {
+                // Merge with the implicit leading section
+                current = 0;
+                continue;
+            }
+            sections.push(Section {
+                name: name.to_string(),
+                array: false,
+                entries: BTreeMap::new(),
+            });
+            current = sections.len() - 1;
+        } else if let Some((key, value)) = line.split_once('=') {
+            let key = key.trim();
+            if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(error(&format!("invalid key \"{key}\"")));
+            }
+            let value = parse_value(value.trim()).map_err(|message| error(&message))?;
+            let entries = &mut sections[current].entries;
+            if entries.insert(key.to_string(), value).is_some() {
+                return Err(error(&format!("duplicate key \"{key}\"")));
+            }
+        } else {
+            return Err(error("expected `[section]`, `[[array]]` or `key = value`"));
+        }
+    }
+
+    Ok(sections)
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(text: &str) -> Result<Value, String> {
+    if let Some(inner) = text.strip_prefix('"').and_then(|t| t.strip_suffix('"')) {
+        return parse_string(inner).map(Value::String);
+    }
+    match text {
+        "true" => return Ok(Value::Bool(true)),
+        "false" => return Ok(Value::Bool(false)),
+        _ => {}
+    }
+    parse_number(text).ok_or_else(|| String::from("invalid value"))
+}
+
+/// The inside of a basic TOML string, with its escapes processed
+fn parse_string(text: &str) -> Result<String, String> {
+    let mut string = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Err(String::from("unescaped `\"` in string")),
+            '\\' => {}
+            c => {
+                string.push(c);
+                continue;
+            }
+        }
+        let escaped = match chars.next() {
+            Some('\\') => '\\',
+            Some('"') => '"',
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('b') => '\u{8}',
+            Some('f') => '\u{C}',
+            Some(kind @ ('u' | 'U')) => {
+                let len = if kind == 'u' { 4 } else { 8 };
+                let digits: String = chars.by_ref().take(len).collect();
+                u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .filter(|_| digits.len() == len)
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| format!("invalid escape `\\{kind}{digits}` in string"))?
+            }
+            Some(other) => return Err(format!("invalid escape `\\{other}` in string")),
+            None => return Err(String::from("string ends in a lone `\\`")),
+        };
+        string.push(escaped);
+    }
+    Ok(string)
+}
+
+fn parse_number(text: &str) -> Option<Value> {
+    let digits = text.replace('_', "");
+    if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+        .or_else(|| digits.strip_prefix('$'))
+    {
+        return i64::from_str_radix(hex, 16).ok().map(Value::Integer);
+    }
+    if let Some(bin) = digits.strip_prefix("0b") {
+        return i64::from_str_radix(bin, 2).ok().map(Value::Integer);
+    }
+    if let Ok(n) = digits.parse::<i64>() {
+        return Some(Value::Integer(n));
+    }
+    digits.parse::<f64>().ok().map(Value::Float)
+}
+
+/// Keys of one section, consumed as they are interpreted so that leftovers
+/// can be reported as typos.
+struct Table {
+    section: String,
+    entries: BTreeMap<String, Value>,
+}
+
+impl Table {
+    fn new(section: String, entries: BTreeMap<String, Value>) -> Self {
+        Self { section, entries }
+    }
+
+    fn invalid(&self, key: &str, expected: &str) -> MachineError {
+        MachineError::Invalid(format!("[{}] `{key}` must be {expected}", self.section))
+    }
+
+    fn missing(&self, key: &str) -> MachineError {
+        MachineError::Invalid(format!("[{}] is missing `{key}`", self.section))
+    }
+
+    fn take_string(&mut self, key: &str) -> Result<Option<String>, MachineError> {
+        match self.entries.remove(key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s)),
+            Some(_) => Err(self.invalid(key, "a string")),
+        }
+    }
+
+    fn take_integer(&mut self, key: &str, min: i64, max: i64) -> Result<Option<i64>, MachineError> {
+        match self.entries.remove(key) {
+            None => Ok(None),
+            Some(Value::Integer(n)) if (min..=max).contains(&n) => Ok(Some(n)),
+            Some(_) => Err(self.invalid(key, &format!("an integer from {min} to {max}"))),
+        }
+    }
+
+    fn take_u8(&mut self, key: &str) -> Result<Option<u8>, MachineError> {
+        Ok(self.take_integer(key, 0, 0xFF)?.map(|n| n as u8))
+    }
+
+    /// Clock rates may be written as integers or floats (`1.79e6`)
+    fn take_clock(&mut self, key: &str) -> Result<Option<u32>, MachineError> {
+        match self.entries.remove(key) {
+            None => Ok(None),
+            Some(Value::Integer(n)) if n > 0 && n <= u32::MAX as i64 => Ok(Some(n as u32)),
+            Some(Value::Float(f)) if f >= 1.0 && f <= u32::MAX as f64 => Ok(Some(f as u32)),
+            Some(_) => Err(self.invalid(key, "a positive frequency in Hz")),
+        }
+    }
+
+    fn require_string(&mut self, key: &str) -> Result<String, MachineError> {
+        self.take_string(key)?.ok_or_else(|| self.missing(key))
+    }
+
+    fn require_address(&mut self, key: &str) -> Result<u16, MachineError> {
+        self.take_integer(key, 0, 0xFFFF)?
+            .map(|n| n as u16)
+            .ok_or_else(|| self.missing(key))
+    }
+
+    fn require_size(&mut self, key: &str) -> Result<usize, MachineError> {
+        self.take_integer(key, 1, 0x10000)?
+            .map(|n| n as usize)
+            .ok_or_else(|| self.missing(key))
+    }
+
+    /// Reject keys nobody consumed
+    fn finish(&self) -> Result<(), MachineError> {
+        match self.entries.keys().next() {
+            Some(key) => Err(MachineError::Invalid(format!(
+                "[{}] has unknown key `{key}`",
+                self.section
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn into_rest(self) -> BTreeMap<String, Value> {
+        self.entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+
+    const BOARD: &str = r#"
+# A small test board
+[machine]
+name = "Test board"
+cpu = "6502"
+clock_hz = 1_843_200
+open_bus = 0xEA
+
+[[region]]
+type = "ram"
+start = 0x0000
+size = 0x0800
+
+[[region]]
+type = "mirror"
+start = 0x0800
+size = 0x0800
+source = 0x0000
+source_size = 0x0800
+
+[[region]]
+type = "unmapped"
+start = $4000
+size = 0x1000
+value = 0x00
+
+[[region]]
+type = "rom"    # no file: filled with $FF
+start = 0xF000
+size = 4096
+"#;
+
+    #[test]
+    fn test_parse_machine() {
+        let config = MachineConfig::parse(BOARD).unwrap();
+
+        assert_eq!(config.name, "Test board");
+        assert_eq!(config.cpu, CpuVariant::Nmos6502);
+        assert_eq!(config.clock_hz, 1_843_200);
+        assert_eq!(config.open_bus, 0xEA);
+        assert_eq!(config.regions.len(), 4);
+        assert_eq!(
+            config.regions[1],
+            RegionConfig::Mirror {
+                start: 0x0800,
+                size: 0x0800,
+                source: 0x0000,
+                source_size: 0x0800,
+            }
+        );
+    }
+
+    #[test]
+    fn test_build_machine() {
+        let mut bus = MachineConfig::parse(BOARD).unwrap().build().unwrap();
+
+        bus.write(0x0010, 0x42);
+        assert_eq!(bus.read(0x0810), 0x42);
+        assert_eq!(bus.read(0x4000), 0x00);
+        assert_eq!(bus.read(0x2000), 0xEA); // open bus
+        assert_eq!(bus.read(0xF000), 0xFF);
+    }
+
+    #[test]
+    fn test_float_clock() {
+        let config = MachineConfig::parse("clock_hz = 1.79e6\n").unwrap();
+        assert_eq!(config.clock_hz, 1_790_000);
+    }
+
+    #[test]
+    fn test_device_options() {
+        let text = "[[device]]\ntype = \"acme\"\nstart = 0x6000\nsize = 16\nbaud = 19200\n";
+        let config = MachineConfig::parse(text).unwrap();
+
+        assert_eq!(config.devices[0].kind, "acme");
+        assert_eq!(
+            config.devices[0].options.get("baud"),
+            Some(&Value::Integer(19200))
+        );
+        assert!(matches!(config.build(), Err(MachineError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_device_names() {
+        let via = "[[device]]\ntype = \"via6522\"\nsize = 16\n";
+        let text = format!(
+            "{via}start = 0x6000\n{via}start = 0x6010\n{via}start = 0x6020\nname = \"keyboard\"\n"
+        );
+        let bus = MachineConfig::parse(&text).unwrap().build().unwrap();
+
+        let names: Vec<&str> = bus.devices().map(|d| d.name).collect();
+        assert_eq!(names, ["via6522", "via6522_2", "keyboard"]);
+        assert_eq!(bus.devices().nth(2).unwrap().start, 0x6020);
+    }
+
+    #[test]
+    fn test_serial_backend() {
+        let acia = "[[device]]\ntype = \"acia6551\"\nstart = 0x5000\nsize = 4\n";
+        assert!(MachineConfig::parse(acia).unwrap().uses_stdio());
+
+        let quiet = MachineConfig::parse(&format!("{acia}backend = \"none\"\n")).unwrap();
+        assert!(!quiet.uses_stdio());
+        let mut bus = quiet.build().unwrap();
+        assert_eq!(bus.read(0x5001), 0x10); // TDRE after reset
+
+        let bogus = MachineConfig::parse(&format!("{acia}backend = \"modem\"\n")).unwrap();
+        assert!(matches!(bogus.build(), Err(MachineError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_misspelled_device_option() {
+        let acia = "[[device]]\ntype = \"acia6551\"\nstart = 0x5000\nsize = 4\n";
+        let config = MachineConfig::parse(&format!("{acia}backnd = \"none\"\n")).unwrap();
+        let Err(MachineError::Invalid(message)) = config.build() else {
+            panic!("expected an unknown key");
+        };
+        assert_eq!(message, "[device \"acia6551\"] has unknown key `backnd`");
+
+        let via = "[[device]]\ntype = \"via6522\"\nstart = 0x6000\nsize = 16\n";
+        let config = MachineConfig::parse(&format!("{via}lcd = \"16x2\"\nlcd_buss = 4\n")).unwrap();
+        assert!(matches!(config.build(), Err(MachineError::Invalid(_))));
+        // LCD wiring needs an LCD
+        let config = MachineConfig::parse(&format!("{via}lcd_bus = 4\n")).unwrap();
+        assert!(matches!(config.build(), Err(MachineError::Invalid(_))));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pty_paths_by_name() {
+        let acia = |name: &str, start: u16| {
+            format!(
+                "[[device]]\nname = \"{name}\"\ntype = \"acia6551\"\nstart = {start}\nsize = 4\nbackend = \"pty\"\n"
+            )
+        };
+        let text = format!("{}{}", acia("modem", 0x5000), acia("printer", 0x5004));
+        let bus = MachineConfig::parse(&text).unwrap().build().unwrap();
+        let paths = bus.serial_paths();
+        let names: Vec<&str> = paths.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["modem", "printer"]);
+        assert_ne!(paths[0].1, paths[1].1);
+    }
+
+    #[test]
+    fn test_banked_devices() {
+        let text = "\
+[[device]]
+type = \"c64_memory\"
+start = 0x0000
+size = 0x10000
+
+[[device]]
+type = \"timer\"
+start = 0xDC00
+size = 4
+inside = \"c64_memory\"
+";
+        let mut bus = MachineConfig::parse(text).unwrap().build().unwrap();
+        bus.write(0xDC00, 2);
+        bus.write(0xDC01, 0);
+        bus.write(0xDC02, 0x03); // ENABLE | IRQ
+        for _ in 0..10 {
+            bus.tick();
+        }
+        assert!(bus.irq());
+        bus.write(0xDC03, 0);
+        assert!(!bus.irq());
+        assert_eq!(bus.read(0xE000), 0xFF); // no KERNAL file: empty ROM
+
+        let outside = text.replace("0xDC00", "0xC000");
+        let config = MachineConfig::parse(&outside).unwrap();
+        assert!(matches!(config.build(), Err(MachineError::Invalid(_))));
+        let orphan = text.replace("inside = \"c64_memory\"", "inside = \"c64\"");
+        let config = MachineConfig::parse(&orphan).unwrap();
+        assert!(matches!(config.build(), Err(MachineError::Invalid(_))));
+
+        let mmc1 = "[[device]]\ntype = \"mmc1\"\nstart = 0x6000\nsize = 0xA000\n";
+        let mut bus = MachineConfig::parse(mmc1).unwrap().build().unwrap();
+        bus.write(0x6000, 0x42); // PRG RAM
+        assert_eq!(bus.read(0x6000), 0x42);
+    }
+
+    #[test]
+    fn test_lcd_options() {
+        let via = "[[device]]\ntype = \"via6522\"\nstart = 0x6000\nsize = 16\nlcd = \"20x4\"\n";
+        let bus = MachineConfig::parse(&format!(
+            "{via}lcd_bus = 4\nlcd_control = \"b\"\nlcd_e = 6\n"
+        ))
+        .unwrap()
+        .build()
+        .unwrap();
+        let screens = bus.screens();
+        assert_eq!(screens[0].1, vec![" ".repeat(20); 4]);
+
+        for bad in ["lcd_bus = 6\n", "lcd_data = \"c\"\n", "lcd_rs = 8\n"] {
+            let config = MachineConfig::parse(&format!("{via}{bad}")).unwrap();
+            assert!(matches!(config.build(), Err(MachineError::Invalid(_))));
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(matches!(
+            MachineConfig::parse("cpu = \"65816\"\n"),
+            Err(MachineError::Invalid(_))
+        ));
+        assert!(matches!(
+            MachineConfig::parse("[[region]]\ntype = \"ram\"\nstart = 0\n"),
+            Err(MachineError::Invalid(_))
+        ));
+        assert!(matches!(
+            MachineConfig::parse("[[region]]\ntype = \"ram\"\nstart = 0\nsize = 1\nsise = 2\n"),
+            Err(MachineError::Invalid(_))
+        ));
+        assert!(matches!(
+            MachineConfig::parse("name = \"a\"\nthis is not toml\n"),
+            Err(MachineError::Parse { line: 2, .. })
+        ));
+        // [machine] after other sections can't redefine the leading keys
+        let error = MachineConfig::parse(
+            "name = \"a\"\n[[region]]\ntype = \"ram\"\nstart = 0\nsize = 1\n[machine]\nname = \"b\"\n",
+        )
+        .unwrap_err();
+        assert_eq!(error.to_string(), "line 7: duplicate key \"name\"");
+        for (value, message) in [
+            (r#""a\qb""#, "line 1: invalid escape `\\q` in string"),
+            (r#""a"b""#, "line 1: unescaped `\"` in string"),
+            (r#""\u12""#, "line 1: invalid escape `\\u12` in string"),
+        ] {
+            let error = MachineConfig::parse(&format!("name = {value}\n")).unwrap_err();
+            assert_eq!(error.to_string(), message, "{value}");
+        }
+
+        let text = r#"name = "roms\\wozmon.bin \"1\"\t\u00e9 # not a comment" # comment"#;
+        assert_eq!(
+            MachineConfig::parse(text).unwrap().name,
+            "roms\\wozmon.bin \"1\"\t\u{e9} # not a comment"
+        );
+        assert!(matches!(
+            MachineConfig::parse("[[region]]\ntype = \"ram\"\nstart = 0\nsize = 0x800\n[[region]]\ntype = \"ram\"\nstart = 0x400\nsize = 0x800\n")
+                .unwrap()
+                .build(),
+            Err(MachineError::Map(MapError::Overlap { .. }))
+        ));
+    }
+}
@@ -0,0 +1,275 @@
+//! Apple I
+//!
+//! - $0000-$0FFF: 4KB RAM
+//! - $D010-$D013: 6821 PIA wired to the keyboard and terminal
+//! - $E000-$EFFF: 4KB RAM (where Apple BASIC is usually loaded)
+//! - $FF00-$FFFF: 256 byte ROM for Wozmon, supplied by the user
+//!
+//! The PIA registers are KBD ($D010), KBDCR ($D011), DSP ($D012) and DSPCR
+//! ($D013). Keys arrive on port A with bit 7 set and strobe CA1. Writing DSP
+//! pulls CB2 low; the terminal then holds PB7 high until the character has
+//! been drawn, which on the real machine happens once per video frame.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::devices::acia6551::SerialBackend;
+use crate::devices::pia6821::control;
+use crate::devices::state::StateError;
+use crate::devices::{Device, Pia6821};
+use crate::machine::{CpuVariant, DeviceConfig, MachineConfig, RegionConfig, Value};
+
+/// 14.31818MHz / 14
+pub const CLOCK_HZ: u32 = 1_022_727;
+
+/// The terminal draws one character per 60Hz frame
+const CHARS_PER_SECOND: u32 = 60;
+
+const DSP: u16 = 2;
+
+/// Machine description for `--machine apple1`
+pub fn config() -> MachineConfig {
+    MachineConfig {
+        name: String::from("Apple I"),
+        cpu: CpuVariant::Nmos6502,
+        clock_hz: CLOCK_HZ,
+        open_bus: 0xFF,
+        regions: vec![
+            RegionConfig::Ram {
+                start: 0x0000,
+                size: 0x1000,
+            },
+            RegionConfig::Ram {
+                start: 0xE000,
+                size: 0x1000,
+            },
+            RegionConfig::Rom {
+                start: 0xFF00,
+                size: 0x0100,
+                file: None,
+            },
+        ],
+        devices: vec![DeviceConfig {
+            name: String::from("pia"),
+            kind: String::from("apple1_terminal"),
+            start: 0xD010,
+            size: 4,
+            inside: None,
+            options: BTreeMap::from([(String::from("backend"), Value::String("stdio".into()))]),
+        }],
+    }
+}
+
+/// The Apple I PIA together with the keyboard and video terminal behind it.
+pub struct Apple1Terminal {
+    pia: Pia6821,
+    backend: Box<dyn SerialBackend>,
+    char_cycles: u32,
+    busy: u32,
+    pending: u8,
+}
+
+impl Apple1Terminal {
+    /// `clock_hz` sets how many cycles a character takes to draw
+    pub fn new<S: SerialBackend + 'static>(backend: S, clock_hz: u32) -> Self {
+        Self::with_boxed_backend(Box::new(backend), clock_hz)
+    }
+
+    pub fn with_boxed_backend(backend: Box<dyn SerialBackend>, clock_hz: u32) -> Self {
+        let mut terminal = Self {
+            pia: Pia6821::new(),
+            backend,
+            char_cycles: (clock_hz / CHARS_PER_SECOND).max(1),
+            busy: 0,
+            pending: 0,
+        };
+        terminal.reset();
+        terminal
+    }
+
+    /// True while the terminal is still drawing the last character
+    pub fn is_busy(&self) -> bool {
+        self.busy > 0
+    }
+
+    /// Host keys to Apple I keyboard codes: upper case only, Return is CR
+    /// and backspace becomes Wozmon's rubout character
+    fn map_key(byte: u8) -> u8 {
+        match byte {
+            b'\n' => 0x0D,
+            0x08 | 0x7F => b'_',
+            b'a'..=b'z' => byte - 0x20,
+            _ => byte & 0x7F,
+        }
+    }
+
+    fn poll_keyboard(&mut self) {
+        if self.pia.read(1) & control::C1_FLAG != 0 {
+            return;
+        }
+        if let Some(byte) = self.backend.read_byte() {
+            self.pia.set_port_a_input(Self::map_key(byte) | 0x80);
+            self.pia.set_ca1(true);
+            self.pia.set_ca1(false);
+        }
+    }
+
+    fn draw(&mut self, byte: u8) {
+        match byte & 0x7F {
+            0x0D => self.backend.write_byte(b'\n'),
+            c @ 0x20..=0x5F => self.backend.write_byte(c),
+            // The character generator only has upper case
+            c @ 0x60..=0x7F => self.backend.write_byte(c - 0x20),
+            _ => {}
+        }
+    }
+}
+
+impl Device for Apple1Terminal {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.pia.read(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        let before = self.pia.cb2_output();
+        self.pia.write(offset, value);
+        if offset & 0x03 == DSP && before && !self.pia.cb2_output() {
+            self.pending = self.pia.port_b_output();
+            self.busy = self.char_cycles;
+            self.pia.set_port_b_input(0xFF);
+        }
+    }
+
+    fn tick(&mut self) {
+        self.pia.tick();
+        self.poll_keyboard();
+
+        if self.busy > 0 {
+            self.busy -= 1;
+            if self.busy == 0 {
+                self.draw(self.pending);
+                // PB7 low is "ready"; the CB1 strobe ends the CB2 handshake
+                self.pia.set_port_b_input(0x7F);
+                self.pia.set_cb1(true);
+                self.pia.set_cb1(false);
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        // Strobes idle low; the reset clears any flag lowering them raised
+        self.pia.set_ca1(false);
+        self.pia.set_cb1(false);
+        self.pia.set_port_b_input(0x7F);
+        self.pia.reset();
+        self.busy = 0;
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = self.pia.save_state();
+        state.extend_from_slice(&self.busy.to_le_bytes());
+        state.push(self.pending);
+        state
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let split = state.len().checked_sub(5).ok_or(StateError::Truncated)?;
+        let (pia, rest) = state.split_at(split);
+        self.pia.load_state(pia)?;
+        self.busy = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]);
+        self.pending = rest[4];
+        Ok(())
+    }
+
+    fn serial_path(&self) -> Option<PathBuf> {
+        self.backend.device_path().map(Path::to_path_buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::Cpu;
+    use crate::devices::acia6551::MemoryBackend;
+    use crate::memory_map::{MappedBus, MemoryMapBuilder};
+
+    /// Wozmon's I/O setup followed by a keyboard-to-display echo loop
+    const ECHO: [u8; 33] = [
+        0xA0, 0x7F, // LDY #$7F
+        0x8C, 0x12, 0xD0, // STY DSP      (DDRB: PB7 input)
+        0xA9, 0xA7, // LDA #$A7
+        0x8D, 0x11, 0xD0, // STA KBDCR
+        0x8D, 0x13, 0xD0, // STA DSPCR
+        0xAD, 0x11, 0xD0, // key: LDA KBDCR
+        0x10, 0xFB, //      BPL key
+        0xAD, 0x10, 0xD0, //      LDA KBD
+        0x2C, 0x12, 0xD0, // echo: BIT DSP
+        0x30, 0xFB, //       BMI echo
+        0x8D, 0x12, 0xD0, //       STA DSP
+        0x4C, 0x0D, 0xFF, //       JMP key
+        0xEA,
+    ];
+
+    fn setup(line: &MemoryBackend, clock_hz: u32) -> Cpu<MappedBus> {
+        let mut rom = vec![0xEA; 0x100];
+        rom[..ECHO.len()].copy_from_slice(&ECHO);
+        rom[0xFC..0xFE].copy_from_slice(&[0x00, 0xFF]);
+
+        let bus = MemoryMapBuilder::new()
+            .ram(0x0000, 0x1000)
+            .device(0xD010, 4, Apple1Terminal::new(line.clone(), clock_hz))
+            .rom(0xFF00, 0x100, &rom)
+            .build()
+            .unwrap();
+        let mut cpu = Cpu::new(bus);
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_keyboard_echo() {
+        let line = MemoryBackend::new();
+        let mut cpu = setup(&line, 60_000);
+        line.send(b"hi\n\x7F");
+
+        for _ in 0..20_000 {
+            cpu.execute_instruction();
+        }
+        assert_eq!(line.take_output(), b"HI\n_");
+    }
+
+    #[test]
+    fn test_display_timing() {
+        let line = MemoryBackend::new();
+        let mut cpu = setup(&line, 600_000); // 10,000 cycles per character
+        line.send(b"AB");
+
+        let mut cycles = 0u32;
+        while line.take_output().is_empty() {
+            cpu.step();
+            cycles += 1;
+        }
+        assert!((10_000..10_100).contains(&cycles), "{cycles} cycles");
+
+        // While drawing, PB7 reads high
+        cpu.bus.write(0xD012, b'X' | 0x80);
+        assert_ne!(cpu.bus.read(0xD012) & 0x80, 0);
+    }
+
+    #[test]
+    fn test_preset() {
+        let config = config();
+        assert_eq!(config.devices[0].start, 0xD010);
+        assert!(config.uses_stdio());
+
+        let mut quiet = config.clone();
+        quiet.devices[0]
+            .options
+            .insert("backend".into(), Value::String("none".into()));
+        let mut bus = quiet.build().unwrap();
+        bus.write(0xE123, 0x42);
+        assert_eq!(bus.read(0xE123), 0x42);
+        assert_eq!(bus.read(0xD012) & 0x80, 0);
+    }
+}
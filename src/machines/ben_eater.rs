@@ -0,0 +1,149 @@
+//! Ben Eater's breadboard 6502
+//!
+//! - $0000-$3FFF: 16KB RAM
+//! - $5000-$5FFF: 6551 ACIA (from the serial interface videos)
+//! - $6000-$7FFF: 6522 VIA with the HD44780 LCD on its ports
+//! - $8000-$FFFF: 32KB ROM, laid out as by `examples/emu.cfg`
+//!
+//! The ACIA and VIA only decode their low address bits, so they repeat
+//! through their ranges. The LCD is wired as in the "hello world" videos
+//! (`Wiring::BEN_EATER_8BIT`): port B carries D0-D7 and PA7, PA6 and PA5
+//! drive E, RW and RS.
+
+use std::collections::BTreeMap;
+
+use crate::machine::{CpuVariant, DeviceConfig, MachineConfig, RegionConfig, Value};
+
+pub const CLOCK_HZ: u32 = 1_000_000;
+
+/// Machine description for `--machine ben_eater`
+pub fn config() -> MachineConfig {
+    MachineConfig {
+        name: String::from("Ben Eater 6502"),
+        cpu: CpuVariant::Nmos6502,
+        clock_hz: CLOCK_HZ,
+        open_bus: 0xFF,
+        regions: vec![
+            RegionConfig::Ram {
+                start: 0x0000,
+                size: 0x4000,
+            },
+            RegionConfig::Rom {
+                start: 0x8000,
+                size: 0x8000,
+                file: None,
+            },
+        ],
+        devices: vec![
+            DeviceConfig {
+                name: String::from("acia"),
+                kind: String::from("acia6551"),
+                start: 0x5000,
+                size: 0x1000,
+                inside: None,
+                options: BTreeMap::from([(String::from("backend"), Value::String("none".into()))]),
+            },
+            DeviceConfig {
+                name: String::from("via"),
+                kind: String::from("via6522"),
+                start: 0x6000,
+                size: 0x2000,
+                inside: None,
+                // The default LCD wiring is this board's
+                options: BTreeMap::from([(String::from("lcd"), Value::String("16x2".into()))]),
+            },
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::Cpu;
+
+    /// The LCD routines from the "hello world" video
+    #[rustfmt::skip]
+    const HELLO: &[u8] = &[
+        0xA9, 0xFF, 0x8D, 0x02, 0x60,       // lda #$ff, sta DDRB
+        0xA9, 0xE0, 0x8D, 0x03, 0x60,       // lda #$e0, sta DDRA
+        0xA9, 0x38, 0x20, 0x30, 0x80,       // lda #%00111000, jsr lcd_instruction
+        0xA9, 0x0E, 0x20, 0x30, 0x80,       // lda #%00001110, jsr lcd_instruction
+        0xA9, 0x06, 0x20, 0x30, 0x80,       // lda #%00000110, jsr lcd_instruction
+        0xA2, 0x00,                         // ldx #0
+        0xBD, 0x00, 0x81,                   // print: lda message,x
+        0xF0, 0x06,                         //        beq loop
+        0x20, 0x55, 0x80,                   //        jsr print_char
+        0xE8,                               //        inx
+        0xD0, 0xF5,                         //        bne print
+        0x4C, 0x26, 0x80,                   // loop: jmp loop
+        0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA,
+        // lcd_instruction ($8030)
+        0x20, 0x6B, 0x80,                   // jsr lcd_wait
+        0x8D, 0x00, 0x60,                   // sta PORTB
+        0xA9, 0x00, 0x8D, 0x01, 0x60,       // lda #0, sta PORTA
+        0xA9, 0x80, 0x8D, 0x01, 0x60,       // lda #E, sta PORTA
+        0xA9, 0x00, 0x8D, 0x01, 0x60,       // lda #0, sta PORTA
+        0x60,                               // rts
+        0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA, 0xEA,
+        // print_char ($8055)
+        0x20, 0x6B, 0x80,                   // jsr lcd_wait
+        0x8D, 0x00, 0x60,                   // sta PORTB
+        0xA9, 0x20, 0x8D, 0x01, 0x60,       // lda #RS, sta PORTA
+        0xA9, 0xA0, 0x8D, 0x01, 0x60,       // lda #(RS | E), sta PORTA
+        0xA9, 0x20, 0x8D, 0x01, 0x60,       // lda #RS, sta PORTA
+        0x60,                               // rts
+        // lcd_wait ($806B)
+        0x48,                               // pha
+        0xA9, 0x00, 0x8D, 0x02, 0x60,       // lda #0, sta DDRB
+        0xA9, 0x40, 0x8D, 0x01, 0x60,       // lcdbusy: lda #RW, sta PORTA
+        0xA9, 0xC0, 0x8D, 0x01, 0x60,       // lda #(RW | E), sta PORTA
+        0xAD, 0x00, 0x60,                   // lda PORTB
+        0x29, 0x80,                         // and #%10000000
+        0xD0, 0xEF,                         // bne lcdbusy
+        0xA9, 0xFF, 0x8D, 0x02, 0x60,       // lda #$ff, sta DDRB
+        0x68,                               // pla
+        0x60,                               // rts
+    ];
+
+    #[test]
+    fn test_hello_world() {
+        let mut bus = config().build().unwrap();
+
+        let mut rom = vec![0xEA; 0x8000];
+        rom[..HELLO.len()].copy_from_slice(HELLO);
+        rom[0x100..0x10E].copy_from_slice(b"Hello, world!\0");
+        rom[0x7FFC..0x7FFE].copy_from_slice(&[0x00, 0x80]);
+        bus.load(0x8000, &rom);
+
+        let mut cpu = Cpu::new(bus);
+        cpu.reset();
+        for _ in 0..2000 {
+            cpu.execute_instruction();
+        }
+
+        assert_eq!(
+            cpu.bus.screens(),
+            [(
+                "via",
+                vec![String::from("Hello, world!   "), " ".repeat(16)]
+            )]
+        );
+        // The VIA repeats every 16 bytes through $6000-$7FFF
+        assert_eq!(cpu.bus.read(0x7FF2), 0xFF);
+    }
+
+    #[test]
+    fn test_serial_backend() {
+        let mut config = config();
+        assert!(!config.uses_stdio());
+        assert!(config.set_serial_backend("stdio"));
+        assert!(config.uses_stdio());
+        assert!(config.set_serial_backend("none"));
+        assert!(config.build().is_ok());
+
+        assert!(config.set_serial_backend("modem"));
+        assert!(config.build().is_err());
+        assert!(!MachineConfig::default().set_serial_backend("pty"));
+    }
+}
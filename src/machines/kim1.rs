@@ -0,0 +1,637 @@
+//! MOS KIM-1
+//!
+//! - $0000-$03FF: 1KB RAM
+//! - $1700-$173F: 6530-003 I/O and timer (free for user projects)
+//! - $1740-$177F: 6530-002 I/O and timer, wired to the keypad, display and
+//!   teletype
+//! - $1780-$17FF: the two 6530s' 64-byte RAMs (the user vectors live at
+//!   $17FA-$17FF)
+//! - $1800-$1FFF: 6530-003 and 6530-002 ROMs, supplied by the user
+//! - $F800-$FFFF: mirror of the ROMs so the CPU finds the vectors
+//!
+//! The 6530-002 port B bits 1-4 drive a 74145 decoder: values 0-2 select a
+//! keypad row read back active-low on PA0-PA6, 3 selects the TTY jumper
+//! (PA0 reads low when it is fitted) and 4-9 select a display digit whose
+//! segments are driven on PA0-PA6. The teletype is bit-banged: PA7 is the
+//! serial input and PB0 the output, both idle high.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Path, PathBuf};
+
+use crate::bus::HostRequest;
+use crate::devices::acia6551::SerialBackend;
+use crate::devices::state::{StateError, StateReader, StateWriter};
+use crate::devices::{Device, Rriot6530};
+use crate::machine::{CpuVariant, DeviceConfig, MachineConfig, RegionConfig, Value};
+
+pub const CLOCK_HZ: u32 = 1_000_000;
+
+/// Speed of the bridged teletype; the monitor measures it from the first
+/// RUBOUT, which is sent automatically after reset
+pub const TTY_BAUD: u32 = 1200;
+
+/// Keypad codes, in matrix order: 0-F then AD, DA, +, GO, PC
+pub mod key {
+    pub const AD: u8 = 0x10;
+    pub const DA: u8 = 0x11;
+    pub const PLUS: u8 = 0x12;
+    pub const GO: u8 = 0x13;
+    pub const PC: u8 = 0x14;
+}
+
+/// Segment patterns for hex digits, segment a on bit 0
+const SEGMENTS: [u8; 16] = [
+    0x3F, 0x06, 0x5B, 0x4F, 0x66, 0x6D, 0x7D, 0x07, 0x7F, 0x6F, 0x77, 0x7C, 0x39, 0x5E, 0x79, 0x71,
+];
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+const SAD: u16 = 0;
+const SBD: u16 = 2;
+const TTY_SELECT: u8 = 3;
+const RUBOUT: u8 = 0x7F;
+
+/// Machine description for `--machine kim1`
+pub fn config() -> MachineConfig {
+    MachineConfig {
+        name: String::from("KIM-1"),
+        cpu: CpuVariant::Nmos6502,
+        clock_hz: CLOCK_HZ,
+        open_bus: 0xFF,
+        regions: vec![
+            RegionConfig::Ram {
+                start: 0x0000,
+                size: 0x0400,
+            },
+            RegionConfig::Ram {
+                start: 0x1780,
+                size: 0x0080,
+            },
+            RegionConfig::Rom {
+                start: 0x1800,
+                size: 0x0800,
+                file: None,
+            },
+            RegionConfig::Mirror {
+                start: 0xF800,
+                size: 0x0800,
+                source: 0x1800,
+                source_size: 0x0800,
+            },
+        ],
+        devices: vec![
+            DeviceConfig {
+                name: String::from("rriot003"),
+                kind: String::from("rriot6530"),
+                start: 0x1700,
+                size: 0x40,
+                inside: None,
+                options: BTreeMap::new(),
+            },
+            DeviceConfig {
+                name: String::from("rriot002"),
+                kind: String::from("kim1_io"),
+                start: 0x1740,
+                size: 0x40,
+                inside: None,
+                options: BTreeMap::from([
+                    (String::from("backend"), Value::String("stdio".into())),
+                    (String::from("mode"), Value::String("tty".into())),
+                ]),
+            },
+        ],
+    }
+}
+
+/// What the host terminal is connected to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// TTY jumper fitted: the terminal is the teletype
+    Tty,
+    /// Keys press the keypad and the display is drawn as a line of text
+    Keypad,
+}
+
+/// The 6530-002 together with the keypad, LED display and teletype
+/// interface behind it.
+pub struct Kim1Io {
+    rriot: Rriot6530,
+    backend: Box<dyn SerialBackend>,
+    mode: Mode,
+    bit_cycles: u32,
+    hold_cycles: u32,
+
+    /// Serial input frame still to shift out on PA7, LSB first
+    rx_frame: u16,
+    rx_count: u32,
+    rubout_delay: u32,
+
+    tx_level: bool,
+    tx_count: u32,
+    tx_bit: u8,
+    tx_byte: u8,
+
+    key: Option<u8>,
+    key_cycles: u32,
+    /// Keys from a front end, pressed in turn ahead of the backend's
+    typed: VecDeque<u8>,
+    nmi_cycles: u32,
+    request: Option<HostRequest>,
+
+    segments: [u8; 6],
+    shown: [u8; 6],
+    frame_count: u32,
+}
+
+impl Kim1Io {
+    pub fn new<S: SerialBackend + 'static>(backend: S, mode: Mode, clock_hz: u32) -> Self {
+        Self::with_boxed_backend(Box::new(backend), mode, clock_hz)
+    }
+
+    pub fn with_boxed_backend(backend: Box<dyn SerialBackend>, mode: Mode, clock_hz: u32) -> Self {
+        let mut io = Self {
+            rriot: Rriot6530::new(),
+            backend,
+            mode,
+            bit_cycles: (clock_hz / TTY_BAUD).max(1),
+            // Long enough for the monitor's debounce
+            hold_cycles: (clock_hz / 25).max(1),
+            rx_frame: 0,
+            rx_count: 0,
+            rubout_delay: 0,
+            tx_level: true,
+            tx_count: 0,
+            tx_bit: 0,
+            tx_byte: 0,
+            key: None,
+            key_cycles: 0,
+            typed: VecDeque::new(),
+            nmi_cycles: 0,
+            request: None,
+            segments: [0; 6],
+            shown: [0; 6],
+            frame_count: 0,
+        };
+        io.reset();
+        io
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Segment patterns last lit on the six digits, segment a on bit 0
+    pub fn display(&self) -> [u8; 6] {
+        self.segments
+    }
+
+    /// The display as text ("1C00 4C"), with `?` for patterns that are not
+    /// a hex digit
+    pub fn display_text(&self) -> String {
+        let mut text = String::with_capacity(7);
+        for (i, &pattern) in self.segments.iter().enumerate() {
+            if i == 4 {
+                text.push(' ');
+            }
+            text.push(match SEGMENTS.iter().position(|&s| s == pattern) {
+                Some(digit) => HEX_DIGITS[digit] as char,
+                None if pattern == 0 => ' ',
+                None => '?',
+            });
+        }
+        text
+    }
+
+    /// Hold a keypad key (0x0-0xF or one of `key::*`) down for long enough
+    /// to be scanned, then release it
+    pub fn press(&mut self, code: u8) {
+        self.key = Some(code);
+        self.key_cycles = self.hold_cycles;
+    }
+
+    /// The ST key, which pulls NMI
+    pub fn stop(&mut self) {
+        self.nmi_cycles = self.hold_cycles;
+    }
+
+    fn select(&self) -> u8 {
+        (self.rriot.port_b_output() >> 1) & 0x0F
+    }
+
+    /// Recompute what the keypad, jumper and serial line drive onto port A
+    fn update_inputs(&mut self) {
+        let select = self.select();
+        let mut pins = 0x7F;
+        if let Some(code) = self.key
+            && code / 7 == select
+        {
+            pins &= !(0x40 >> (code % 7));
+        }
+        if select == TTY_SELECT && self.mode == Mode::Tty {
+            pins &= !0x01;
+        }
+        if self.rx_count == 0 || self.rx_frame & 1 != 0 {
+            pins |= 0x80;
+        }
+        self.rriot.set_port_a_input(pins);
+    }
+
+    /// Host bytes to keypad presses in keypad mode. Control keys stand in
+    /// for the named keys: ^A AD, ^D DA, ^G (or Return) GO, ^P PC, ^T ST
+    /// and ^R RS.
+    fn poll_keypad(&mut self) {
+        if self.key_cycles > 0 || self.nmi_cycles > 0 {
+            return;
+        }
+        let Some(byte) = self.typed.pop_front().or_else(|| self.backend.read_byte()) else {
+            return;
+        };
+        match byte {
+            b'0'..=b'9' => self.press(byte - b'0'),
+            b'a'..=b'f' => self.press(byte - b'a' + 10),
+            b'A'..=b'F' => self.press(byte - b'A' + 10),
+            0x01 => self.press(key::AD),
+            0x04 => self.press(key::DA),
+            b'+' => self.press(key::PLUS),
+            0x07 | b'\n' | b'\r' => self.press(key::GO),
+            0x10 => self.press(key::PC),
+            0x14 => self.stop(),
+            0x12 => self.request = Some(HostRequest::Reset),
+            _ => {}
+        }
+    }
+
+    fn tick_keypad(&mut self) {
+        if self.nmi_cycles > 0 {
+            self.nmi_cycles -= 1;
+        }
+        if self.key_cycles > 0 {
+            self.key_cycles -= 1;
+            if self.key_cycles == 0 && self.key.take().is_some() {
+                // Keep the key up for as long again before the next one
+                self.key_cycles = self.hold_cycles;
+            }
+        }
+        self.poll_keypad();
+
+        // Redraw at most 25 times a second
+        self.frame_count += 1;
+        if self.frame_count >= self.hold_cycles {
+            self.frame_count = 0;
+            if self.segments != self.shown {
+                self.shown = self.segments;
+                for byte in format!("\r{}", self.display_text()).bytes() {
+                    self.backend.write_byte(byte);
+                }
+            }
+        }
+    }
+
+    fn capture_display(&mut self) {
+        let select = self.select();
+        let pattern = self.rriot.port_a_output() & 0x7F;
+        if (4..10).contains(&select) && pattern != 0 {
+            self.segments[(select - 4) as usize] = pattern;
+        }
+    }
+
+    /// Teletype keys are upper case (the monitor only knows upper case hex)
+    /// and Return sends CR. The teletype prints what is typed itself.
+    fn send(&mut self, byte: u8) {
+        let byte = match byte {
+            b'\n' => 0x0D,
+            b'a'..=b'z' => byte - 0x20,
+            _ => byte & 0x7F,
+        };
+        if byte != RUBOUT {
+            self.backend
+                .write_byte(if byte == 0x0D { b'\n' } else { byte });
+        }
+        // Start bit, eight data bits, two stop bits
+        self.rx_frame = 0x600 | (byte as u16) << 1;
+        self.rx_count = self.bit_cycles;
+    }
+
+    fn tick_tty(&mut self) {
+        if self.rx_count > 0 {
+            self.rx_count -= 1;
+            if self.rx_count == 0 {
+                self.rx_frame >>= 1;
+                if self.rx_frame != 0 {
+                    self.rx_count = self.bit_cycles;
+                }
+            }
+        } else if self.rubout_delay > 0 {
+            self.rubout_delay -= 1;
+            if self.rubout_delay == 0 {
+                self.send(RUBOUT);
+            }
+        } else if let Some(byte) = self.backend.read_byte() {
+            self.send(byte);
+        }
+
+        // Sample the output in the middle of each bit after a start bit
+        let level = self.rriot.port_b_output() & 0x01 != 0;
+        if self.tx_count > 0 {
+            self.tx_count -= 1;
+            if self.tx_count == 0 {
+                self.tx_byte |= (level as u8) << self.tx_bit;
+                self.tx_bit += 1;
+                if self.tx_bit < 8 {
+                    self.tx_count = self.bit_cycles;
+                } else {
+                    match self.tx_byte & 0x7F {
+                        0x00 | RUBOUT => {}
+                        byte => self.backend.write_byte(byte),
+                    }
+                }
+            }
+        } else if self.tx_level && !level {
+            self.tx_count = self.bit_cycles * 3 / 2;
+            self.tx_bit = 0;
+            self.tx_byte = 0;
+        }
+        self.tx_level = level;
+    }
+}
+
+impl Device for Kim1Io {
+    fn read(&mut self, offset: u16) -> u8 {
+        self.rriot.read(offset)
+    }
+
+    fn write(&mut self, offset: u16, value: u8) {
+        self.rriot.write(offset, value);
+        if self.mode == Mode::Keypad && matches!(offset & 0x07, SAD | SBD) {
+            self.capture_display();
+        }
+        self.update_inputs();
+    }
+
+    fn tick(&mut self) {
+        self.rriot.tick();
+        match self.mode {
+            Mode::Tty => self.tick_tty(),
+            Mode::Keypad => self.tick_keypad(),
+        }
+        self.update_inputs();
+    }
+
+    fn reset(&mut self) {
+        self.rriot.reset();
+        self.rx_frame = 0;
+        self.rx_count = 0;
+        self.tx_count = 0;
+        self.tx_level = true;
+        // Give the monitor time to reach its baud rate detection
+        self.rubout_delay = match self.mode {
+            Mode::Tty => self.bit_cycles * 50,
+            Mode::Keypad => 0,
+        };
+        self.update_inputs();
+    }
+
+    fn irq(&self) -> bool {
+        self.rriot.irq()
+    }
+
+    fn nmi(&self) -> bool {
+        self.nmi_cycles > 0
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.rriot.interrupts_enabled()
+    }
+
+    fn host_request(&mut self) -> Option<HostRequest> {
+        self.request.take()
+    }
+
+    fn serial_path(&self) -> Option<PathBuf> {
+        self.backend.device_path().map(Path::to_path_buf)
+    }
+
+    fn screen(&self) -> Vec<String> {
+        match self.mode {
+            Mode::Tty => Vec::new(),
+            Mode::Keypad => vec![self.display_text()],
+        }
+    }
+
+    fn key(&mut self, byte: u8) -> bool {
+        if self.mode == Mode::Keypad {
+            self.typed.push_back(byte);
+        }
+        self.mode == Mode::Keypad
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        let rriot = self.rriot.save_state();
+        StateWriter::new()
+            .u8(rriot.len() as u8)
+            .bytes(&rriot)
+            .u16(self.rx_frame)
+            .u32(self.rx_count)
+            .u32(self.rubout_delay)
+            .bool(self.tx_level)
+            .u32(self.tx_count)
+            .u8(self.tx_bit)
+            .u8(self.tx_byte)
+            .u8(self.key.unwrap_or(0xFF))
+            .u32(self.key_cycles)
+            .u32(self.nmi_cycles)
+            .bytes(&self.segments)
+            .finish()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(state);
+        let len = r.u8()? as usize;
+        self.rriot.load_state(r.bytes(len)?)?;
+        self.rx_frame = r.u16()?;
+        self.rx_count = r.u32()?;
+        self.rubout_delay = r.u32()?;
+        self.tx_level = r.bool()?;
+        self.tx_count = r.u32()?;
+        self.tx_bit = r.u8()?;
+        self.tx_byte = r.u8()?;
+        self.key = match r.u8()? {
+            0xFF => None,
+            code @ 0..=key::PC => Some(code),
+            code => return Err(StateError::Invalid(format!("keypad key {code:#04X}"))),
+        };
+        self.key_cycles = r.u32()?;
+        self.nmi_cycles = r.u32()?;
+        self.segments.copy_from_slice(r.bytes(6)?);
+        self.shown = [0; 6];
+        r.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::acia6551::MemoryBackend;
+
+    const CLOCK: u32 = 120_000; // 100 cycles per bit
+
+    fn run(io: &mut Kim1Io, cycles: u32) {
+        for _ in 0..cycles {
+            io.tick();
+        }
+    }
+
+    /// Idle the line, then set up the ports the way the monitor's INIT does
+    fn init(io: &mut Kim1Io) {
+        io.write(1, 0x00); // PADD: all inputs
+        io.write(3, 0x3F); // PBDD
+        io.write(SBD, 0x07); // TTY select, PB0 idle high
+    }
+
+    #[test]
+    fn test_tty_jumper_and_input() {
+        let line = MemoryBackend::new();
+        let mut io = Kim1Io::new(line.clone(), Mode::Tty, CLOCK);
+        init(&mut io);
+        assert_eq!(io.read(SAD) & 0x81, 0x80);
+
+        // The automatic RUBOUT: a start bit then ones
+        run(&mut io, 100 * 50 + 1);
+        assert_eq!(io.read(SAD) & 0x80, 0);
+        run(&mut io, 100);
+        assert_ne!(io.read(SAD) & 0x80, 0);
+
+        // 'k' arrives as 'K' = 0x4B, LSB first, sampled mid-bit
+        run(&mut io, 100 * 10);
+        line.send(b"k");
+        run(&mut io, 50);
+        let mut byte = 0u8;
+        for bit in 0..8 {
+            run(&mut io, 100);
+            byte |= ((io.read(SAD) >> 7) & 1) << bit;
+        }
+        assert_eq!(byte, b'K');
+        assert_eq!(line.take_output(), b"K");
+    }
+
+    #[test]
+    fn test_tty_output() {
+        let line = MemoryBackend::new();
+        let mut io = Kim1Io::new(line.clone(), Mode::Tty, CLOCK);
+        init(&mut io);
+        io.rubout_delay = 0;
+
+        // Bit-bang 'A' (0x41): start, data LSB first, stop
+        let byte = b'A';
+        let mut bits = vec![0u8];
+        bits.extend((0..8).map(|bit| (byte >> bit) & 1));
+        bits.extend([1, 1]);
+        for bit in bits {
+            io.write(SBD, 0x06 | bit);
+            run(&mut io, 100);
+        }
+        assert_eq!(line.take_output(), b"A");
+    }
+
+    #[test]
+    fn test_keypad_and_display() {
+        let line = MemoryBackend::new();
+        let mut io = Kim1Io::new(line.clone(), Mode::Keypad, CLOCK);
+        io.write(3, 0x3F);
+
+        // Key 5 is row 0, PA1; GO is row 2, PA1
+        line.send(b"5");
+        run(&mut io, 1);
+        io.write(SBD, 0 << 1);
+        assert_eq!(io.read(SAD) & 0x7F, 0x7D);
+        io.write(SBD, 2 << 1);
+        assert_eq!(io.read(SAD) & 0x7F, 0x7F);
+
+        // Released after the hold time, then the next key comes in
+        line.send(b"\n");
+        run(&mut io, CLOCK / 25 * 2 + 1);
+        assert_eq!(io.read(SAD) & 0x7F, 0x7D);
+
+        // No jumper in keypad mode
+        io.write(SBD, TTY_SELECT << 1);
+        assert_eq!(io.read(SAD) & 0x01, 0x01);
+
+        // Light "1C00 4C"
+        io.write(1, 0x7F);
+        for (digit, value) in [1, 0xC, 0, 0, 4, 0xC].into_iter().enumerate() {
+            io.write(SBD, (4 + digit as u8) << 1);
+            io.write(SAD, SEGMENTS[value]);
+            io.write(SAD, 0);
+        }
+        assert_eq!(io.display_text(), "1C00 4C");
+        line.take_output();
+        run(&mut io, CLOCK / 25);
+        assert_eq!(line.take_output(), b"\r1C00 4C");
+    }
+
+    #[test]
+    fn test_stop_and_reset_keys() {
+        let line = MemoryBackend::new();
+        let mut io = Kim1Io::new(line.clone(), Mode::Keypad, CLOCK);
+        line.send(&[0x14]);
+        run(&mut io, 1);
+        assert!(io.nmi());
+        run(&mut io, CLOCK / 25);
+        assert!(!io.nmi());
+
+        line.send(&[0x12]);
+        run(&mut io, 1);
+        assert_eq!(io.host_request(), Some(HostRequest::Reset));
+    }
+
+    #[test]
+    fn test_front_end_keys_and_screen() {
+        let line = MemoryBackend::new();
+        let mut io = Kim1Io::new(line.clone(), Mode::Keypad, CLOCK);
+        io.write(3, 0x3F);
+
+        // Typed keys go ahead of the backend's, one hold time each
+        line.send(b"5");
+        assert!(io.key(0x01));
+        run(&mut io, 1);
+        io.write(SBD, 2 << 1);
+        assert_eq!(io.read(SAD) & 0x7F, 0x6F); // AD is row 2, PA4
+        run(&mut io, CLOCK / 25 * 2);
+        io.write(SBD, 0 << 1);
+        assert_eq!(io.read(SAD) & 0x7F, 0x7D);
+
+        io.write(1, 0x7F);
+        io.write(SBD, 4 << 1);
+        io.write(SAD, SEGMENTS[1]);
+        assert_eq!(io.screen(), vec![String::from("1      ")]);
+
+        let mut tty = Kim1Io::new(MemoryBackend::new(), Mode::Tty, CLOCK);
+        assert!(!tty.key(b'5'));
+        assert!(tty.screen().is_empty());
+    }
+
+    #[test]
+    fn test_preset() {
+        let mut config = config();
+        assert!(config.uses_stdio());
+        config.devices[1]
+            .options
+            .insert("backend".into(), Value::String("none".into()));
+        let mut bus = config.build().unwrap();
+
+        let mut rom = vec![0u8; 0x800];
+        rom[0x7FC..0x7FE].copy_from_slice(&[0x4F, 0x1C]);
+        bus.load(0xF800, &rom);
+        assert_eq!(bus.peek(0x1FFC), Some(0x4F));
+        assert_eq!(bus.peek(0xFFFD), Some(0x1C));
+        assert!(bus.device("rriot003").is_some());
+        assert!(bus.device("rriot002").is_some());
+
+        let mut config = super::config();
+        config.detach_keypads();
+        assert!(!config.uses_stdio());
+        let mut bus = config.build().unwrap();
+        assert!(bus.key(b'5'));
+        assert_eq!(bus.screens().len(), 1);
+    }
+}
@@ -0,0 +1,24 @@
+//! Built-in machine presets
+//!
+//! Each preset is a `MachineConfig` for a well-known board, selected with
+//! `--machine <name>` instead of a machine file. Board-specific glue (such as
+//! the Apple I terminal) lives next to its preset.
+
+pub mod apple1;
+pub mod ben_eater;
+pub mod kim1;
+
+use crate::machine::MachineConfig;
+
+/// Names accepted by `preset`
+pub const PRESETS: &[&str] = &["apple1", "ben_eater", "kim1"];
+
+/// Look up a preset by name
+pub fn preset(name: &str) -> Option<MachineConfig> {
+    match name {
+        "apple1" => Some(apple1::config()),
+        "ben_eater" => Some(ben_eater::config()),
+        "kim1" => Some(kim1::config()),
+        _ => None,
+    }
+}
@@ -1,7 +1,39 @@
-use mos6502::{Bus, Cpu, bus::SimpleBus, instructions::OPCODES, status::Flag};
-use std::{env, fs, process, thread, time::Duration};
+use mos6502::{
+    Bus, Cpu, MappedBus, SymbolTable,
+    asm::assemble,
+    bus::HostRequest,
+    bus_trace::TracingBus,
+    clock::{Speed, Throttle, format_frequency, parse_frequency},
+    coverage::Coverage,
+    debug_info::DebugInfo,
+    disasm::{Line, disassemble, disassemble_range},
+    instructions::OPCODES,
+    machine::MachineConfig,
+    machines,
+    profile::Profiler,
+    status::Flag,
+    trace::{NestestLog, TraceEntry, TraceSink},
+};
+use std::{
+    cell::RefCell,
+    env,
+    fs::{self, File},
+    io::{BufWriter, IsTerminal},
+    path::Path,
+    process,
+    rc::Rc,
+    thread,
+    time::{Duration, Instant},
+};
+
+mod app;
+
+use app::cli::{self, Command, Invocation, Matches};
+use app::headless::{self, Stop};
+use app::{debugger, terminal, trace_diff, tui};
 
 const CLEAR_SCREEN: &str = "\x1b[2J";
+const CLEAR_LINE: &str = "\x1b[K";
 const CURSOR_HOME: &str = "\x1b[H";
 const BOLD: &str = "\x1b[1m";
 const DIM: &str = "\x1b[2m";
@@ -12,10 +44,22 @@ const YELLOW: &str = "\x1b[33m";
 const CYAN: &str = "\x1b[36m";
 const WHITE: &str = "\x1b[37m";
 
-const ROM_START: u16 = 0x8000;
 const ROM_SIZE: usize = 0x8000; // 32KB ROM from $8000-$FFFF
 
-fn display_cpu<B: Bus>(cpu: &mut Cpu<B>, instruction_count: u32, total_cycles: u64) {
+/// Redraw interval of the register display when running at a clock rate
+const FRAME: Duration = Duration::from_millis(33);
+/// Default instruction limit for `profile`, for programs that never stop
+const PROFILE_MAX: u64 = 10_000_000;
+
+/// `--trace` output
+type TraceLog = NestestLog<BufWriter<File>>;
+
+fn display_cpu<B: Bus>(
+    cpu: &mut Cpu<B>,
+    symbols: &SymbolTable,
+    instruction_count: u32,
+    total_cycles: u64,
+) {
     let opcode_byte = cpu.bus.read(cpu.pc);
     let opcode = &OPCODES[opcode_byte as usize];
 
@@ -23,13 +67,19 @@ fn display_cpu<B: Bus>(cpu: &mut Cpu<B>, instruction_count: u32, total_cycles: u
     let operand_str = match opcode.bytes {
         1 => String::new(),
         2 => format!(" ${:02X}", cpu.bus.read(cpu.pc.wrapping_add(1))),
-        3 => format!(
-            " ${:02X}{:02X}",
-            cpu.bus.read(cpu.pc.wrapping_add(2)),
-            cpu.bus.read(cpu.pc.wrapping_add(1))
-        ),
+        3 => {
+            let target = cpu.bus.read_word(cpu.pc.wrapping_add(1));
+            match symbols.name_of(target) {
+                Some(name) => format!(" {name}"),
+                None => format!(" ${target:04X}"),
+            }
+        }
         _ => String::new(),
     };
+    let label = symbols
+        .name_of(cpu.pc)
+        .map(|name| format!("{name}:"))
+        .unwrap_or_default();
 
     print!("{CURSOR_HOME}");
 
@@ -93,7 +143,8 @@ fn display_cpu<B: Bus>(cpu: &mut Cpu<B>, instruction_count: u32, total_cycles: u
         "{BOLD}{CYAN}║{RESET}  {BOLD}{YELLOW}NEXT INSTRUCTION{RESET}                                        {BOLD}{CYAN}║{RESET}"
     );
     println!(
-        "{BOLD}{CYAN}║{RESET}                                                          {BOLD}{CYAN}║{RESET}"
+        "{BOLD}{CYAN}║{RESET}    {YELLOW}{:<54}{RESET}{BOLD}{CYAN}║{RESET}",
+        label
     );
     println!(
         "{BOLD}{CYAN}║{RESET}    {GREEN}${:04X}{RESET}: {BOLD}{WHITE}{}{RESET}{:<12}  {DIM}[{:02X}]{RESET}                          {BOLD}{CYAN}║{RESET}",
@@ -105,113 +156,838 @@ fn display_cpu<B: Bus>(cpu: &mut Cpu<B>, instruction_count: u32, total_cycles: u
     println!("{BOLD}{CYAN}╚══════════════════════════════════════════════════════════╝{RESET}");
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+/// Text displays (LCDs and the like) under the register display
+fn display_screens(screens: &[(&str, Vec<String>)]) {
+    for (name, rows) in screens {
+        let width = rows
+            .iter()
+            .map(|row| row.chars().count())
+            .max()
+            .unwrap_or(0);
+        println!("  {DIM}{name}{RESET}");
+        println!("  {DIM}┌{}┐{RESET}", "─".repeat(width));
+        for row in rows {
+            println!("  {DIM}│{RESET}{BOLD}{GREEN}{row:<width$}{RESET}{DIM}│{RESET}");
+        }
+        println!("  {DIM}└{}┘{RESET}", "─".repeat(width));
+    }
+}
+
+/// One-line register summary for console dumps
+fn register_line<B: Bus>(cpu: &Cpu<B>) -> String {
+    format!(
+        "PC=${:04X} A=${:02X} X=${:02X} Y=${:02X} SP=${:02X} P=${:02X}",
+        cpu.pc,
+        cpu.a,
+        cpu.x,
+        cpu.y,
+        cpu.sp,
+        cpu.status.to_byte()
+    )
+}
+
+/// Execute the instruction at the PC and log it to the `--trace` file.
+/// Returns false if the CPU took an interrupt instead; the instruction
+/// has not run yet, so nothing is logged.
+fn execute_traced(cpu: &mut Cpu<MappedBus>, trace: &mut Option<TraceLog>) -> bool {
+    let entry = trace
+        .as_ref()
+        .map(|_| TraceEntry::capture(cpu, |a| cpu.bus.peek(a).unwrap_or(0xFF)));
+    let interrupts = cpu.interrupts;
+    cpu.execute_instruction();
+    if cpu.interrupts != interrupts {
+        return false;
+    }
+    if let (Some(log), Some(entry)) = (trace, entry)
+        && let Err(e) = log.record(&entry)
+    {
+        eprintln!("{RED}Error:{RESET} Failed to write trace: {e}");
+        process::exit(1);
+    }
+    true
+}
+
+/// Flush the `--trace` file; needed before `process::exit`
+fn finish_trace(trace: &mut Option<TraceLog>) {
+    if let Some(log) = trace
+        && let Err(e) = log.flush()
+    {
+        eprintln!("{RED}Error:{RESET} Failed to write trace: {e}");
+        process::exit(1);
+    }
+}
+
+/// Run a machine whose serial port owns the terminal: no register display
+/// and no delay, until Ctrl-C, a breakpoint, the instruction limit or a
+/// console exit. Returns the exit code the program asked for, if any.
+fn run_serial(
+    cpu: &mut Cpu<MappedBus>,
+    symbols: &SymbolTable,
+    breakpoints: &[u16],
+    max_instructions: Option<u32>,
+    trace: &mut Option<TraceLog>,
+    mut throttle: Option<Throttle>,
+) -> Option<u8> {
+    let guard = terminal::CbreakGuard::enable();
+    terminal::catch_interrupt();
+
+    let mut instruction_count: u32 = 0;
+    let mut breakpoint_hit = false;
+    let mut budget = 0;
 
-    if args.len() < 2 {
-        eprintln!("{BOLD}{WHITE}MOS 6502 Emulator{RESET}");
-        eprintln!();
+    while max_instructions.is_none_or(|max| instruction_count < max) {
+        if terminal::interrupted() {
+            break;
+        }
+        if breakpoints.contains(&cpu.pc) {
+            breakpoint_hit = true;
+            break;
+        }
+        if let Some(throttle) = &mut throttle
+            && budget == 0
+        {
+            budget = throttle.wait();
+            continue;
+        }
+        let before = cpu.total_cycles;
+        if execute_traced(cpu, trace) {
+            instruction_count += 1;
+        }
+        if let Some(throttle) = &mut throttle {
+            // A jammed CPU stops counting cycles, but its clock runs on
+            let cycles = cpu.total_cycles.saturating_sub(before).max(1);
+            throttle.spend(cycles, Instant::now());
+            budget = budget.saturating_sub(cycles);
+        }
+
+        match cpu.bus.host_request() {
+            Some(HostRequest::Exit(code)) => return Some(code),
+            Some(HostRequest::DumpRegisters) => eprintln!("{}", register_line(cpu)),
+            Some(HostRequest::Reset) => cpu.reset(),
+            None => {}
+        }
+    }
+
+    drop(guard);
+
+    let label = symbols
+        .name_of(cpu.pc)
+        .map(|name| format!(" ({name})"))
+        .unwrap_or_default();
+    eprintln!();
+    if breakpoint_hit {
+        eprintln!("{YELLOW}Breakpoint hit at ${:04X}{label}{RESET}", cpu.pc);
+    } else {
         eprintln!(
-            "{DIM}Usage:{RESET} {} <rom.bin> [--delay <ms>] [--max <instructions>]",
-            args[0]
+            "{GREEN}Stopped after {instruction_count} instructions at ${:04X}{label}{RESET}",
+            cpu.pc
         );
-        eprintln!();
-        eprintln!("{DIM}The ROM file should be a 32KB binary image ($8000-$FFFF).{RESET}");
-        eprintln!("{DIM}Reset vector at $FFFC-$FFFD, IRQ/BRK at $FFFE-$FFFF.{RESET}");
-        eprintln!();
-        eprintln!("{DIM}Build ROMs with cc65:{RESET}");
-        eprintln!("  ./bin/cl65 -t none -C examples/emu.cfg -o rom.bin program.s");
-        process::exit(1);
     }
+    if let Some(hz) = throttle.and_then(|throttle| throttle.effective_hz()) {
+        eprintln!("{DIM}Ran at {}{RESET}", format_frequency(hz));
+    }
+    None
+}
+
+/// Why the register display stopped
+enum Halt {
+    Brk,
+    Breakpoint,
+    Exit(u8),
+}
+
+/// Execute the instruction at the PC for the register display, unless it
+/// is a stopping point
+fn display_step(
+    cpu: &mut Cpu<MappedBus>,
+    breakpoints: &[u16],
+    trace: &mut Option<TraceLog>,
+    instruction_count: &mut u32,
+) -> Result<(), Halt> {
+    // BRK stops execution
+    if cpu.bus.read(cpu.pc) == 0x00 {
+        return Err(Halt::Brk);
+    }
+    if breakpoints.contains(&cpu.pc) {
+        return Err(Halt::Breakpoint);
+    }
+
+    if execute_traced(cpu, trace) {
+        *instruction_count += 1;
+    }
+
+    // The register display already shows what a dump would print
+    match cpu.bus.host_request() {
+        Some(HostRequest::Exit(code)) => return Err(Halt::Exit(code)),
+        Some(HostRequest::Reset) => cpu.reset(),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Run one display frame's worth of instructions at the clock rate
+fn display_frame(
+    cpu: &mut Cpu<MappedBus>,
+    breakpoints: &[u16],
+    trace: &mut Option<TraceLog>,
+    throttle: &mut Throttle,
+    instruction_count: &mut u32,
+    max_instructions: u32,
+) -> Result<(), Halt> {
+    let frame_end = Instant::now() + FRAME;
+    while Instant::now() < frame_end && *instruction_count < max_instructions {
+        let mut budget = throttle.wait();
+        while budget > 0 && *instruction_count < max_instructions {
+            let before = cpu.total_cycles;
+            display_step(cpu, breakpoints, trace, instruction_count)?;
+            // A jammed CPU stops counting cycles, but its clock runs on
+            let cycles = cpu.total_cycles.saturating_sub(before).max(1);
+            throttle.spend(cycles, Instant::now());
+            budget = budget.saturating_sub(cycles);
+        }
+    }
+    Ok(())
+}
 
-    let rom_path = &args[1];
+/// The clock line under the register display
+fn display_clock(throttle: &Throttle) {
+    let target = match throttle.target_hz() {
+        Some(hz) => format!("{} ({})", format_frequency(hz), throttle.speed()),
+        None => String::from("turbo"),
+    };
+    let measured = throttle
+        .effective_hz()
+        .map(format_frequency)
+        .unwrap_or_else(|| String::from("-"));
+    println!(
+        "  {DIM}Clock:{RESET} {GREEN}{target}{RESET}  {DIM}measured:{RESET} {GREEN}{measured}{RESET}{CLEAR_LINE}"
+    );
+}
 
-    // Parse optional arguments
-    let mut delay_ms: u64 = 150;
-    let mut max_instructions: u32 = 10000;
+/// Exit for a command line that doesn't fit its command
+fn usage_error(e: cli::Error) -> ! {
+    eprintln!("{RED}Error:{RESET} {e}");
+    eprintln!("{DIM}{}{RESET}", e.hint());
+    process::exit(2);
+}
 
-    let mut i = 2;
-    while i < args.len() {
-        match args[i].as_str() {
-            "--delay" => {
-                i += 1;
-                if i < args.len() {
-                    delay_ms = args[i].parse().unwrap_or(150);
-                }
+/// The `--symbols` files merged into one table
+fn load_symbols(matches: &Matches) -> SymbolTable {
+    let mut symbols = SymbolTable::new();
+    for path in matches.values("symbols") {
+        match SymbolTable::load(path) {
+            Ok(table) => symbols.merge(&table),
+            Err(e) => {
+                eprintln!("{RED}Error:{RESET} Failed to load symbols from '{path}': {e}");
+                process::exit(1);
             }
-            "--max" => {
-                i += 1;
-                if i < args.len() {
-                    max_instructions = args[i].parse().unwrap_or(10000);
-                }
+        }
+    }
+    symbols
+}
+
+/// `--debug-info`, with its labels added to `symbols`
+fn load_debug_info(matches: &Matches, symbols: &mut SymbolTable) -> Option<DebugInfo> {
+    let path = matches.value("debug-info")?;
+    match DebugInfo::load(path) {
+        Ok(info) => {
+            symbols.merge(&info.symbols);
+            Some(info)
+        }
+        Err(e) => {
+            eprintln!("{RED}Error:{RESET} Failed to load debug info from '{path}': {e}");
+            process::exit(1);
+        }
+    }
+}
+
+/// Write an output file or exit
+fn write_output(path: &str, contents: &[u8]) {
+    if let Err(e) = fs::write(path, contents) {
+        eprintln!("{RED}Error:{RESET} Failed to write '{path}': {e}");
+        process::exit(1);
+    }
+}
+
+/// The machine from `--machine` (a file, a built-in preset or the default
+/// 32KB ROM layout) with the ROM loaded so it ends at $FFFF
+fn load_machine(matches: &Matches) -> Result<(MachineConfig, MappedBus), cli::Error> {
+    let rom_path = match (matches.argument(0), matches.value("rom")) {
+        (Some(_), Some(_)) => {
+            return Err(matches.usage(String::from(
+                "give the ROM either as an argument or with --rom, not both",
+            )));
+        }
+        (path, None) | (None, path) => path,
+    };
+    let machine_path = matches.value("machine");
+    if rom_path.is_none() && machine_path.is_none() {
+        return Err(matches.usage(String::from("needs a ROM image or --machine")));
+    }
+
+    let mut machine = match machine_path {
+        Some(path) if !Path::new(path).exists() && machines::preset(path).is_some() => {
+            machines::preset(path).unwrap()
+        }
+        Some(path) => match MachineConfig::load(path) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{RED}Error:{RESET} Failed to load machine '{path}': {e}");
+                eprintln!(
+                    "{DIM}Built-in machines: {}{RESET}",
+                    machines::PRESETS.join(", ")
+                );
+                process::exit(1);
             }
-            _ => {}
+        },
+        None => MachineConfig::default(),
+    };
+    if let Some(backend) = matches.value("serial")
+        && !machine.set_serial_backend(backend)
+    {
+        return Err(matches.usage(format!("'{}' has no serial port", machine.name)));
+    }
+    // The full-screen view draws the KIM-1 display and passes keys on itself
+    if matches.flag("tui") {
+        machine.detach_keypads();
+    }
+
+    let mut bus = match machine.build() {
+        Ok(bus) => bus,
+        Err(e) => {
+            eprintln!("{RED}Error:{RESET} Invalid machine '{}': {e}", machine.name);
+            process::exit(1);
         }
-        i += 1;
+    };
+    for (name, path) in bus.serial_paths() {
+        eprintln!("{name} serial port: {}", path.display());
     }
 
-    // Load ROM file
-    let rom_data = match fs::read(rom_path) {
-        Ok(data) => data,
+    if let Some(rom_path) = rom_path {
+        let rom_data = match fs::read(rom_path) {
+            Ok(data) => data,
+            Err(e) => {
+                eprintln!("{RED}Error:{RESET} Failed to read '{rom_path}': {e}");
+                process::exit(1);
+            }
+        };
+
+        if machine_path.is_none() && rom_data.len() != ROM_SIZE {
+            eprintln!(
+                "{RED}Error:{RESET} ROM must be exactly {} bytes (32KB), got {} bytes",
+                ROM_SIZE,
+                rom_data.len()
+            );
+            eprintln!(
+                "{DIM}Use the linker config in examples/emu.cfg to generate correct ROMs.{RESET}"
+            );
+            process::exit(1);
+        }
+        if rom_data.is_empty() || rom_data.len() > 0x10000 {
+            eprintln!(
+                "{RED}Error:{RESET} ROM must be between 1 and 65536 bytes, got {} bytes",
+                rom_data.len()
+            );
+            process::exit(1);
+        }
+
+        // The image ends at $FFFF so the vectors land in the right place
+        bus.load((0x10000 - rom_data.len()) as u16, &rom_data);
+    }
+
+    Ok((machine, bus))
+}
+
+/// The `--trace` file, if one was asked for
+fn create_trace(matches: &Matches) -> Option<TraceLog> {
+    matches.value("trace").map(|path| match File::create(path) {
+        Ok(file) => NestestLog::new(BufWriter::new(file)),
         Err(e) => {
-            eprintln!("{RED}Error:{RESET} Failed to read '{rom_path}': {e}");
+            eprintln!("{RED}Error:{RESET} Failed to create '{path}': {e}");
+            process::exit(1);
+        }
+    })
+}
+
+/// Reset the machine and run it without a display
+fn run_headless(
+    bus: MappedBus,
+    limits: &headless::Limits,
+    trace: &mut Option<TraceLog>,
+    profiler: Option<&mut Profiler>,
+    coverage: Option<&Rc<RefCell<Coverage>>>,
+) -> (headless::HeadlessCpu, headless::Outcome) {
+    let mut cpu = Cpu::new(TracingBus::new(bus));
+    if let Some(coverage) = coverage {
+        Coverage::attach(coverage, &mut cpu.bus);
+    }
+    cpu.reset();
+    let sink = trace.as_mut().map(|log| log as &mut dyn TraceSink);
+    let outcome = match headless::run(&mut cpu, limits, sink, profiler) {
+        Ok(outcome) => outcome,
+        Err(e) => {
+            eprintln!("{RED}Error:{RESET} Failed to write trace: {e}");
             process::exit(1);
         }
     };
+    finish_trace(trace);
+    (cpu, outcome)
+}
 
-    if rom_data.len() != ROM_SIZE {
+/// Set up the debugger or the full-screen view, which need the terminal
+fn interactive_cpu(machine: &MachineConfig, bus: MappedBus, command: &str) -> debugger::DebugCpu {
+    if machine.uses_stdio() {
         eprintln!(
-            "{RED}Error:{RESET} ROM must be exactly {} bytes (32KB), got {} bytes",
-            ROM_SIZE,
-            rom_data.len()
-        );
-        eprintln!(
-            "{DIM}Use the linker config in examples/emu.cfg to generate correct ROMs.{RESET}"
+            "{RED}Error:{RESET} {command} needs the terminal, but '{}' has a serial port on stdio",
+            machine.name
         );
+        eprintln!("{DIM}Use --serial pty or --serial none, or set the device's backend.{RESET}");
         process::exit(1);
     }
+    let mut cpu = Cpu::new(TracingBus::new(bus));
+    cpu.reset();
+    cpu
+}
+
+/// Leave with the exit code of an interactive session
+fn finish_interactive(result: std::io::Result<Option<u8>>) {
+    match result {
+        Ok(Some(code)) => process::exit(code as i32),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("{RED}Error:{RESET} {e}");
+            process::exit(1);
+        }
+    }
+}
+
+/// `mos6502 run`
+fn run(matches: &Matches) -> Result<(), cli::Error> {
+    matches.exclusive(&["tui", "headless"])?;
+    matches.exclusive(&["tui", "trace"])?;
+    matches.requires("max-cycles", "headless")?;
+    matches.requires("exit-write", "headless")?;
+
+    for option in ["clock", "speed"] {
+        matches.exclusive(&[option, "headless"])?;
+        matches.exclusive(&[option, "delay"])?;
+    }
 
-    // Load ROM into memory at $8000
-    let mut bus = SimpleBus::new();
-    bus.load(ROM_START, &rom_data);
+    let delay_ms: u64 = matches.number("delay")?.unwrap_or(150);
+    let clock_hz = matches.parsed(
+        "clock",
+        parse_frequency,
+        "a frequency such as 1.0227MHz, 985kHz or 1000000Hz",
+    )?;
+    let speed = matches.parsed(
+        "speed",
+        Speed::parse,
+        "a factor from 0.000001 up, such as 0.5 or 50%, or turbo",
+    )?;
+    let max_instructions: Option<u32> = matches.number("max")?;
+    let max_cycles: Option<u64> = matches.number("max-cycles")?;
+    let symbols = load_symbols(matches);
+    let breakpoints = matches.addresses("break", &symbols)?;
+    let exit_write = matches.address("exit-write", &symbols)?;
+    let (machine, bus) = load_machine(matches)?;
+    let mut trace = create_trace(matches);
+
+    // Serial machines are interactive, so they keep to their clock unless
+    // told otherwise; the register display only with --clock or --speed
+    let mut throttle = (clock_hz.is_some() || speed.is_some() || machine.uses_stdio()).then(|| {
+        Throttle::new(
+            clock_hz.unwrap_or(machine.clock_hz),
+            speed.unwrap_or(Speed::Scaled(1.0)),
+        )
+    });
+
+    if matches.flag("headless") {
+        let limits = headless::Limits {
+            max_instructions: max_instructions.map(u64::from),
+            max_cycles,
+            stop_at: breakpoints,
+            exit_write,
+            traps: false,
+        };
+        let (cpu, outcome) = run_headless(bus, &limits, &mut trace, None, None);
+        println!("{}", headless::report(&cpu, &outcome, &symbols));
+        process::exit(outcome.stop.status() as i32);
+    }
+
+    if matches.flag("tui") {
+        let mut cpu = interactive_cpu(&machine, bus, "--tui");
+        finish_interactive(tui::run(
+            &mut cpu,
+            symbols,
+            &breakpoints,
+            delay_ms,
+            throttle,
+        ));
+        return Ok(());
+    }
 
     let mut cpu = Cpu::new(bus);
     cpu.reset();
 
     // Consume reset cycles
-    let mut total_cycles: u64 = 0;
     while cpu.cycles > 0 {
         cpu.step();
-        total_cycles += 1;
+    }
+
+    if machine.uses_stdio() {
+        let exit_code = run_serial(
+            &mut cpu,
+            &symbols,
+            &breakpoints,
+            max_instructions,
+            &mut trace,
+            throttle,
+        );
+        finish_trace(&mut trace);
+        if let Some(code) = exit_code {
+            process::exit(code as i32);
+        }
+        return Ok(());
     }
 
     print!("{CLEAR_SCREEN}");
 
+    // Paced by --delay, one instruction per redraw, unless a clock is set
+    let max_instructions = match throttle {
+        Some(_) => max_instructions.unwrap_or(u32::MAX),
+        None => max_instructions.unwrap_or(10000),
+    };
     let mut instruction_count: u32 = 0;
     let delay = Duration::from_millis(delay_ms);
 
-    while instruction_count < max_instructions {
-        display_cpu(&mut cpu, instruction_count, total_cycles);
-        thread::sleep(delay);
+    let halt = loop {
+        let total_cycles = cpu.total_cycles;
+        display_cpu(&mut cpu, &symbols, instruction_count, total_cycles);
+        display_screens(&cpu.bus.screens());
+        if let Some(throttle) = &throttle {
+            display_clock(throttle);
+        }
+        if instruction_count >= max_instructions {
+            break None;
+        }
 
-        let pc_before = cpu.pc;
-        let opcode_byte = cpu.bus.read(pc_before);
+        let result = match &mut throttle {
+            Some(throttle) => display_frame(
+                &mut cpu,
+                &breakpoints,
+                &mut trace,
+                throttle,
+                &mut instruction_count,
+                max_instructions,
+            ),
+            None => {
+                thread::sleep(delay);
+                display_step(&mut cpu, &breakpoints, &mut trace, &mut instruction_count)
+            }
+        };
+        if let Err(halt) = result {
+            break Some(halt);
+        }
+    };
 
-        // BRK stops execution
-        if opcode_byte == 0x00 {
-            break;
+    let total_cycles = cpu.total_cycles;
+    display_cpu(&mut cpu, &symbols, instruction_count, total_cycles);
+    display_screens(&cpu.bus.screens());
+    if let Some(throttle) = &throttle {
+        display_clock(throttle);
+    }
+    finish_trace(&mut trace);
+
+    println!();
+    match halt {
+        Some(Halt::Breakpoint) => {
+            let label = symbols
+                .name_of(cpu.pc)
+                .map(|name| format!(" ({name})"))
+                .unwrap_or_default();
+            println!("{YELLOW}Breakpoint hit at ${:04X}{label}{RESET}", cpu.pc);
         }
+        Some(Halt::Exit(code)) => {
+            println!("{GREEN}Program exited with code {code}{RESET}");
+            process::exit(code as i32);
+        }
+        Some(Halt::Brk) => println!(
+            "{GREEN}Execution complete! BRK encountered at ${:04X}{RESET}",
+            cpu.pc
+        ),
+        None => println!(
+            "{GREEN}Stopped after {instruction_count} instructions at ${:04X}{RESET}",
+            cpu.pc
+        ),
+    }
+    Ok(())
+}
+
+/// `mos6502 debug`
+fn debug(matches: &Matches) -> Result<(), cli::Error> {
+    let symbols = load_symbols(matches);
+    let breakpoints = matches.addresses("break", &symbols)?;
+    let (machine, bus) = load_machine(matches)?;
+    let mut cpu = interactive_cpu(&machine, bus, "The debugger");
+    finish_interactive(debugger::run(&mut cpu, symbols, &breakpoints));
+    Ok(())
+}
+
+/// `mos6502 disasm`
+fn disasm(matches: &Matches) -> Result<(), cli::Error> {
+    matches.exclusive(&["count", "end"])?;
+    let symbols = load_symbols(matches);
+    let count: usize = matches.number("count")?.unwrap_or(20);
+    let start = matches.address("start", &symbols)?;
+    let end = matches.address("end", &symbols)?;
+    let (_, bus) = load_machine(matches)?;
+
+    let read = |address| bus.peek(address).unwrap_or(0xFF);
+    let start = start.unwrap_or_else(|| u16::from_le_bytes([read(0xFFFC), read(0xFFFD)]));
+    let lines = match end {
+        Some(end) if end <= start => {
+            return Err(matches.usage(format!(
+                "--end ${end:04X} must be after the start address ${start:04X}"
+            )));
+        }
+        Some(end) => {
+            let mut lines = Vec::new();
+            let mut address = start;
+            // Stop at the end of memory rather than wrapping to $0000
+            while address < end
+                && lines
+                    .last()
+                    .is_none_or(|line: &Line| line.address < address)
+            {
+                let line = disassemble(address, read, &symbols);
+                address = line.next_address();
+                lines.push(line);
+            }
+            lines
+        }
+        None => disassemble_range(start, count, read, &symbols),
+    };
 
-        cpu.execute_instruction();
-        instruction_count += 1;
-        total_cycles += OPCODES[opcode_byte as usize].cycles as u64;
+    for line in lines {
+        if let Some(name) = symbols.name_of(line.address) {
+            println!("{name}:");
+        }
+        println!("  {line}");
     }
+    Ok(())
+}
 
-    display_cpu(&mut cpu, instruction_count, total_cycles);
+/// `mos6502 asm`
+fn asm(matches: &Matches) -> Result<(), cli::Error> {
+    let size: usize = matches.number("size")?.unwrap_or(ROM_SIZE);
+    if !(1..=0x10000).contains(&size) {
+        return Err(matches.usage(format!(
+            "--size must be between 1 and 65536 bytes, got {size}"
+        )));
+    }
+    let fill = matches.byte("fill")?.unwrap_or(0x00);
+    let source_path = matches.argument(0).unwrap_or_default();
+    let output = match matches.value("output") {
+        Some(path) => path.to_string(),
+        None => Path::new(source_path)
+            .with_extension("bin")
+            .to_string_lossy()
+            .into_owned(),
+    };
 
-    println!();
-    println!(
-        "{GREEN}Execution complete! BRK encountered at ${:04X}{RESET}",
-        cpu.pc
+    let source = match fs::read_to_string(source_path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("{RED}Error:{RESET} Failed to read '{source_path}': {e}");
+            process::exit(1);
+        }
+    };
+    let assembled = assemble(&source).and_then(|program| {
+        let image = program.image(size, fill)?;
+        Ok((program, image))
+    });
+    let (program, image) = match assembled {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("{RED}Error:{RESET} {source_path}: {e}");
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = fs::write(&output, &image) {
+        eprintln!("{RED}Error:{RESET} Failed to write '{output}': {e}");
+        process::exit(1);
+    }
+    if let Some(path) = matches.value("labels") {
+        let labels: String = program
+            .symbols
+            .iter()
+            .map(|(address, name)| format!("{name} = ${address:04X}\n"))
+            .collect();
+        if let Err(e) = fs::write(path, labels) {
+            eprintln!("{RED}Error:{RESET} Failed to write '{path}': {e}");
+            process::exit(1);
+        }
+    }
+
+    let code: usize = program.chunks.iter().map(|chunk| chunk.bytes.len()).sum();
+    println!("{GREEN}Wrote {output}: {size} bytes, {code} assembled{RESET}");
+    Ok(())
+}
+
+/// `mos6502 test`
+fn test(matches: &Matches) -> Result<(), cli::Error> {
+    matches.requires("lcov", "debug-info")?;
+    let mut symbols = load_symbols(matches);
+    let debug_info = load_debug_info(matches, &mut symbols);
+    let success = matches.address("success", &symbols)?;
+    let limits = headless::Limits {
+        max_instructions: matches.number("max")?,
+        max_cycles: matches.number("max-cycles")?,
+        stop_at: success.into_iter().collect(),
+        exit_write: matches.address("exit-write", &symbols)?,
+        traps: true,
+    };
+    let (_, bus) = load_machine(matches)?;
+    let mut trace = create_trace(matches);
+    let coverage = ["coverage", "lcov", "coverage-map"]
+        .iter()
+        .any(|name| matches.value(name).is_some())
+        .then(|| Rc::new(RefCell::new(Coverage::new())));
+    let (cpu, outcome) = run_headless(bus, &limits, &mut trace, None, coverage.as_ref());
+
+    let passed = match outcome.stop {
+        Stop::Address | Stop::Exit(0) => true,
+        Stop::Brk => success.is_none(),
+        _ => false,
+    };
+    if matches.flag("json") {
+        println!("{}", headless::report(&cpu, &outcome, &symbols));
+    } else {
+        let at = symbols.describe(cpu.pc, 0x100);
+        let at = if at.starts_with('$') {
+            at
+        } else {
+            format!("${:04X} ({at})", cpu.pc)
+        };
+        let reason = match outcome.stop {
+            Stop::Brk => format!("BRK at {at}"),
+            Stop::Jam => format!("JAM at {at}"),
+            Stop::Address => format!("reached {at}"),
+            Stop::Exit(code) => format!("exited with code {code} at {at}"),
+            Stop::Trap => format!("trapped at {at}"),
+            Stop::InstructionLimit => format!("instruction limit reached at {at}"),
+            Stop::CycleLimit => format!("cycle limit reached at {at}"),
+        };
+        let verdict = if passed { "PASS" } else { "FAIL" };
+        // Plain text when piped into a CI log or a file
+        let verdict = if std::io::stdout().is_terminal() {
+            let color = if passed { GREEN } else { RED };
+            format!("{BOLD}{color}{verdict}{RESET}")
+        } else {
+            String::from(verdict)
+        };
+        println!(
+            "{verdict} {reason} after {} instructions, {} cycles",
+            outcome.instructions, outcome.cycles
+        );
+    }
+    if let Some(coverage) = coverage {
+        let coverage = coverage.borrow();
+        let bus = cpu.bus.inner();
+        let mut roms: Vec<(u16, u16)> = bus.roms().collect();
+        if roms.is_empty() {
+            roms.push((0x0000, 0xFFFF));
+        }
+        if let Some(path) = matches.value("coverage") {
+            let listing = coverage.listing(&roms, &symbols, |a| bus.peek(a).unwrap_or(0xFF));
+            write_output(path, listing.as_bytes());
+        }
+        if let (Some(path), Some(info)) = (matches.value("lcov"), &debug_info) {
+            write_output(path, coverage.lcov(info).as_bytes());
+        }
+        if let Some(path) = matches.value("coverage-map") {
+            write_output(path, &coverage.bitmap());
+        }
+        if !matches.flag("json") {
+            println!("Coverage: {}", coverage.summary(&roms));
+        }
+    }
+    process::exit(if passed { 0 } else { 1 });
+}
+
+/// `mos6502 profile`
+fn profile(matches: &Matches) -> Result<(), cli::Error> {
+    let symbols = load_symbols(matches);
+    let limits = headless::Limits {
+        max_instructions: Some(matches.number("max")?.unwrap_or(PROFILE_MAX)),
+        max_cycles: matches.number("max-cycles")?,
+        stop_at: matches.addresses("break", &symbols)?,
+        exit_write: matches.address("exit-write", &symbols)?,
+        traps: true,
+    };
+    let top = matches.number("top")?.unwrap_or(20);
+    let (_, bus) = load_machine(matches)?;
+    let mut profiler = Profiler::new();
+    let (cpu, outcome) = run_headless(bus, &limits, &mut None, Some(&mut profiler), None);
+
+    let bus = cpu.bus.inner();
+    let report = profiler.report(&symbols, top, |a| bus.peek(a).unwrap_or(0xFF));
+    match matches.value("output") {
+        Some(path) => write_output(path, report.as_bytes()),
+        None => print!("{report}"),
+    }
+    if let Some(path) = matches.value("folded") {
+        write_output(path, profiler.folded(&symbols).as_bytes());
+    }
+    eprintln!(
+        "{DIM}Stopped ({}) at {} after {} instructions, {} cycles{RESET}",
+        outcome.stop.name(),
+        symbols.describe(cpu.pc, 0x100),
+        outcome.instructions,
+        outcome.cycles
     );
+    Ok(())
+}
+
+/// `mos6502 trace-diff`
+fn diff_traces(matches: &Matches) -> Result<(), cli::Error> {
+    let defaults = trace_diff::Options::default();
+    let options = trace_diff::Options {
+        context: matches.number("context")?.unwrap_or(defaults.context),
+        cycles: !matches.flag("no-cycles"),
+        color: std::io::stdout().is_terminal(),
+    };
+    let ours = matches.argument(0).unwrap_or_default();
+    let reference = matches.argument(1).unwrap_or_default();
+    process::exit(trace_diff::run(ours, reference, &options));
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (spec, matches) = match cli::parse(&args) {
+        Ok(Invocation::Help(text)) if args.is_empty() => {
+            eprint!("{text}");
+            process::exit(2);
+        }
+        Ok(Invocation::Help(text)) => {
+            print!("{text}");
+            return;
+        }
+        Ok(Invocation::Run(spec, matches)) => (spec, matches),
+        Err(e) => usage_error(e),
+    };
+
+    let result = match spec.command {
+        Command::Run => run(&matches),
+        Command::Debug => debug(&matches),
+        Command::Disasm => disasm(&matches),
+        Command::Asm => asm(&matches),
+        Command::Test => test(&matches),
+        Command::Profile => profile(&matches),
+        Command::TraceDiff => diff_traces(&matches),
+    };
+    if let Err(e) = result {
+        usage_error(e);
+    }
 }
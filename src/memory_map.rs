@@ -0,0 +1,801 @@
+//! Memory-map builder
+//!
+//! `MappedBus` is a `Bus` assembled from region descriptors instead of a
+//! hand-written address decoder:
+//! - RAM: readable and writable storage
+//! - ROM: readable storage that ignores CPU writes
+//! - Mirrors: a window that repeats another region (e.g. NES $0000-$07FF x4)
+//! - Unmapped: open-bus ranges that return a fixed value
+//! - Devices: peripherals that receive register offsets
+//!
+//! Regions are checked for overlap when the map is built. Devices are kept
+//! in registration order, which is also the order they are ticked in, and can
+//! be looked up by name for inspection, reset and save states.
+
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::bus::{Bus, HostRequest};
+use crate::devices::{Device, StateError};
+
+/// Size of the 6502 address space
+const ADDRESS_SPACE: usize = 0x10000;
+
+/// Marker in the decode table for addresses no region claims
+const NO_REGION: u16 = u16::MAX;
+
+/// Errors detected while building a memory map
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MapError {
+    /// A region has zero size
+    EmptyRegion { start: u16 },
+    /// A region extends past $FFFF
+    OutOfRange { start: u16, size: usize },
+    /// Two regions claim the same address
+    Overlap {
+        first: (u16, u16),
+        second: (u16, u16),
+    },
+    /// ROM contents are larger than the region they were given
+    RomTooLarge { start: u16, size: usize, len: usize },
+    /// A mirror's source range is not fully backed by a single RAM/ROM/device region
+    InvalidMirror { start: u16, source: u16 },
+    /// Two devices were given the same name
+    DuplicateDevice(String),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapError::EmptyRegion { start } => write!(f, "region at ${start:04X} has zero size"),
+            MapError::OutOfRange { start, size } => write!(
+                f,
+                "region at ${start:04X} with size ${size:X} extends past $FFFF"
+            ),
+            MapError::Overlap { first, second } => write!(
+                f,
+                "region ${:04X}-${:04X} overlaps ${:04X}-${:04X}",
+                second.0, second.1, first.0, first.1
+            ),
+            MapError::RomTooLarge { start, size, len } => write!(
+                f,
+                "ROM at ${start:04X} is {len} bytes but the region is only {size} bytes"
+            ),
+            MapError::InvalidMirror { start, source } => write!(
+                f,
+                "mirror at ${start:04X} points at ${source:04X}, which is not a single RAM, ROM or device region"
+            ),
+            MapError::DuplicateDevice(name) => {
+                write!(f, "more than one device is named \"{name}\"")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MapError {}
+
+/// Region descriptor passed to the builder
+enum RegionSpec {
+    Ram,
+    Rom(Vec<u8>),
+    Mirror { source: u16, source_size: usize },
+    Unmapped(u8),
+    Device {
+        name: Option<String>,
+        device: Box<dyn Device>,
+    },
+}
+
+/// What an address decodes to once the map is built
+enum RegionKind {
+    Ram(Vec<u8>),
+    Rom(Vec<u8>),
+    Mirror { source: u16, source_size: usize },
+    Unmapped(u8),
+    Device(usize),
+}
+
+struct Region {
+    start: u16,
+    end: u16,
+    kind: RegionKind,
+}
+
+struct DeviceSlot {
+    name: String,
+    start: u16,
+    end: u16,
+    device: Box<dyn Device>,
+}
+
+/// A registered device as reported by `MappedBus::devices`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceInfo<'a> {
+    pub name: &'a str,
+    pub start: u16,
+    pub end: u16,
+}
+
+/// Builder for `MappedBus`.
+///
+/// ```
+/// use mos6502::memory_map::MemoryMapBuilder;
+///
+/// let bus = MemoryMapBuilder::new()
+///     .ram(0x0000, 0x0800)
+///     .mirror(0x0800, 0x1800, 0x0000, 0x0800)
+///     .rom(0x8000, 0x8000, &[0xEA; 0x8000])
+///     .build()
+///     .unwrap();
+/// ```
+pub struct MemoryMapBuilder {
+    regions: Vec<(u16, usize, RegionSpec)>,
+    open_bus: u8,
+}
+
+impl Default for MemoryMapBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryMapBuilder {
+    pub fn new() -> Self {
+        Self {
+            regions: Vec::new(),
+            open_bus: 0xFF,
+        }
+    }
+
+    /// Value returned for addresses not covered by any region (default $FF)
+    pub fn open_bus(mut self, value: u8) -> Self {
+        self.open_bus = value;
+        self
+    }
+
+    /// Zero-initialised RAM
+    pub fn ram(mut self, start: u16, size: usize) -> Self {
+        self.regions.push((start, size, RegionSpec::Ram));
+        self
+    }
+
+    /// Write-protected ROM. `data` is placed at `start`; any remaining space
+    /// in the region is filled with $FF.
+    pub fn rom(mut self, start: u16, size: usize, data: &[u8]) -> Self {
+        self.regions
+            .push((start, size, RegionSpec::Rom(data.to_vec())));
+        self
+    }
+
+    /// Repeat `source_size` bytes starting at `source` across `size` bytes at `start`
+    pub fn mirror(mut self, start: u16, size: usize, source: u16, source_size: usize) -> Self {
+        self.regions.push((
+            start,
+            size,
+            RegionSpec::Mirror {
+                source,
+                source_size,
+            },
+        ));
+        self
+    }
+
+    /// A range that ignores writes and always reads as `value`
+    pub fn unmapped(mut self, start: u16, size: usize, value: u8) -> Self {
+        self.regions
+            .push((start, size, RegionSpec::Unmapped(value)));
+        self
+    }
+
+    /// Attach a device. It sees offsets relative to `start`. Unnamed devices
+    /// are called `device0`, `device1`, ... in registration order.
+    pub fn device<D: Device + 'static>(self, start: u16, size: usize, device: D) -> Self {
+        self.boxed_device(start, size, Box::new(device))
+    }
+
+    /// Attach an already boxed device
+    pub fn boxed_device(mut self, start: u16, size: usize, device: Box<dyn Device>) -> Self {
+        self.regions
+            .push((start, size, RegionSpec::Device { name: None, device }));
+        self
+    }
+
+    /// Attach a device that can be found again with `MappedBus::device`
+    pub fn named_device<D: Device + 'static>(
+        self,
+        name: &str,
+        start: u16,
+        size: usize,
+        device: D,
+    ) -> Self {
+        self.boxed_named_device(name, start, size, Box::new(device))
+    }
+
+    pub fn boxed_named_device(
+        mut self,
+        name: &str,
+        start: u16,
+        size: usize,
+        device: Box<dyn Device>,
+    ) -> Self {
+        let name = Some(name.to_string());
+        self.regions
+            .push((start, size, RegionSpec::Device { name, device }));
+        self
+    }
+
+    pub fn build(self) -> Result<MappedBus, MapError> {
+        let mut decode = vec![NO_REGION; ADDRESS_SPACE].into_boxed_slice();
+        let mut regions: Vec<Region> = Vec::with_capacity(self.regions.len());
+        let mut devices: Vec<DeviceSlot> = Vec::new();
+
+        for (start, size, spec) in self.regions {
+            if size == 0 {
+                return Err(MapError::EmptyRegion { start });
+            }
+            if start as usize + size > ADDRESS_SPACE {
+                return Err(MapError::OutOfRange { start, size });
+            }
+            let end = (start as usize + size - 1) as u16;
+
+            if let Some(existing) = (start..=end)
+                .map(|addr| decode[addr as usize])
+                .find(|&index| index != NO_REGION)
+            {
+                let other = &regions[existing as usize];
+                return Err(MapError::Overlap {
+                    first: (other.start, other.end),
+                    second: (start, end),
+                });
+            }
+
+            let kind = match spec {
+                RegionSpec::Ram => RegionKind::Ram(vec![0; size]),
+                RegionSpec::Rom(data) => {
+                    if data.len() > size {
+                        return Err(MapError::RomTooLarge {
+                            start,
+                            size,
+                            len: data.len(),
+                        });
+                    }
+                    let mut contents = data;
+                    contents.resize(size, 0xFF);
+                    RegionKind::Rom(contents)
+                }
+                RegionSpec::Mirror {
+                    source,
+                    source_size,
+                } => {
+                    if source_size == 0 || source as usize + source_size > ADDRESS_SPACE {
+                        return Err(MapError::InvalidMirror { start, source });
+                    }
+                    RegionKind::Mirror {
+                        source,
+                        source_size,
+                    }
+                }
+                RegionSpec::Unmapped(value) => RegionKind::Unmapped(value),
+                RegionSpec::Device { name, device } => {
+                    let name = name.unwrap_or_else(|| format!("device{}", devices.len()));
+                    if devices.iter().any(|slot| slot.name == name) {
+                        return Err(MapError::DuplicateDevice(name));
+                    }
+                    devices.push(DeviceSlot {
+                        name,
+                        start,
+                        end,
+                        device,
+                    });
+                    RegionKind::Device(devices.len() - 1)
+                }
+            };
+
+            let index = regions.len() as u16;
+            decode[start as usize..=end as usize].fill(index);
+            regions.push(Region { start, end, kind });
+        }
+
+        // Mirrors must point into exactly one backing region
+        for region in &regions {
+            if let RegionKind::Mirror {
+                source,
+                source_size,
+            } = region.kind
+            {
+                let first = decode[source as usize];
+                let last = decode[source as usize + source_size - 1];
+                let valid = first != NO_REGION
+                    && first == last
+                    && !matches!(
+                        regions[first as usize].kind,
+                        RegionKind::Mirror { .. } | RegionKind::Unmapped(_)
+                    );
+                if !valid {
+                    return Err(MapError::InvalidMirror {
+                        start: region.start,
+                        source,
+                    });
+                }
+            }
+        }
+
+        Ok(MappedBus {
+            decode,
+            regions,
+            devices,
+            open_bus: self.open_bus,
+        })
+    }
+}
+
+/// A bus assembled from region descriptors. See `MemoryMapBuilder`.
+pub struct MappedBus {
+    decode: Box<[u16]>,
+    regions: Vec<Region>,
+    devices: Vec<DeviceSlot>,
+    open_bus: u8,
+}
+
+impl MappedBus {
+    /// Registered devices in tick order
+    pub fn devices(&self) -> impl Iterator<Item = DeviceInfo<'_>> {
+        self.devices.iter().map(|slot| DeviceInfo {
+            name: &slot.name,
+            start: slot.start,
+            end: slot.end,
+        })
+    }
+
+    pub fn device(&self, name: &str) -> Option<&dyn Device> {
+        self.devices
+            .iter()
+            .find(|slot| slot.name == name)
+            .map(|slot| slot.device.as_ref())
+    }
+
+    pub fn device_mut(&mut self, name: &str) -> Option<&mut dyn Device> {
+        match self.devices.iter_mut().find(|slot| slot.name == name) {
+            Some(slot) => Some(slot.device.as_mut()),
+            None => None,
+        }
+    }
+
+    /// Text displays of the devices that have one, keyed by name
+    pub fn screens(&self) -> Vec<(&str, Vec<String>)> {
+        self.devices
+            .iter()
+            .map(|slot| (slot.name.as_str(), slot.device.screen()))
+            .filter(|(_, rows)| !rows.is_empty())
+            .collect()
+    }
+
+    /// Where terminal programs can connect to serial lines, keyed by device
+    /// name
+    pub fn serial_paths(&self) -> Vec<(&str, PathBuf)> {
+        self.devices
+            .iter()
+            .filter_map(|slot| Some((slot.name.as_str(), slot.device.serial_path()?)))
+            .collect()
+    }
+
+    /// Pass a key typed in a front end to the first device that takes it
+    pub fn key(&mut self, byte: u8) -> bool {
+        self.devices.iter_mut().any(|slot| slot.device.key(byte))
+    }
+
+    /// First and last address of each ROM region
+    pub fn roms(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.regions
+            .iter()
+            .filter(|region| matches!(region.kind, RegionKind::Rom(_)))
+            .map(|region| (region.start, region.end))
+    }
+
+    /// Saved state of every device, keyed by name
+    pub fn save_devices(&self) -> Vec<(String, Vec<u8>)> {
+        self.devices
+            .iter()
+            .map(|slot| (slot.name.clone(), slot.device.save_state()))
+            .collect()
+    }
+
+    /// Restore states produced by `save_devices`
+    pub fn load_devices(&mut self, states: &[(String, Vec<u8>)]) -> Result<(), StateError> {
+        for (name, state) in states {
+            self.device_mut(name)
+                .ok_or_else(|| StateError::UnknownDevice(name.clone()))?
+                .load_state(state)?;
+        }
+        Ok(())
+    }
+
+    /// Follow mirrors to the backing address
+    fn resolve(&self, address: u16) -> (u16, u16) {
+        let index = self.decode[address as usize];
+        if index == NO_REGION {
+            return (index, address);
+        }
+
+        let region = &self.regions[index as usize];
+        match region.kind {
+            RegionKind::Mirror {
+                source,
+                source_size,
+            } => {
+                let offset = (address - region.start) as usize % source_size;
+                let target = source + offset as u16;
+                (self.decode[target as usize], target)
+            }
+            _ => (index, address),
+        }
+    }
+
+    /// Copy data into RAM or ROM regardless of write protection.
+    /// Addresses that are not backed by RAM/ROM are skipped.
+    pub fn load(&mut self, address: u16, data: &[u8]) {
+        for (i, &byte) in data.iter().enumerate() {
+            let (index, target) = self.resolve(address.wrapping_add(i as u16));
+            if index == NO_REGION {
+                continue;
+            }
+            let region = &mut self.regions[index as usize];
+            let offset = (target - region.start) as usize;
+            match &mut region.kind {
+                RegionKind::Ram(memory) | RegionKind::Rom(memory) => memory[offset] = byte,
+                _ => {}
+            }
+        }
+    }
+
+    /// Read RAM/ROM without side effects. Device and unmapped addresses
+    /// return `None`.
+    pub fn peek(&self, address: u16) -> Option<u8> {
+        let (index, target) = self.resolve(address);
+        if index == NO_REGION {
+            return None;
+        }
+        let region = &self.regions[index as usize];
+        match &region.kind {
+            RegionKind::Ram(memory) | RegionKind::Rom(memory) => {
+                Some(memory[(target - region.start) as usize])
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Bus for MappedBus {
+    fn read(&mut self, address: u16) -> u8 {
+        let (index, target) = self.resolve(address);
+        if index == NO_REGION {
+            return self.open_bus;
+        }
+
+        let region = &self.regions[index as usize];
+        let offset = target - region.start;
+        match &region.kind {
+            RegionKind::Ram(memory) | RegionKind::Rom(memory) => memory[offset as usize],
+            RegionKind::Unmapped(value) => *value,
+            RegionKind::Device(device) => self.devices[*device].device.read(offset),
+            RegionKind::Mirror { .. } => unreachable!("mirrors are resolved before access"),
+        }
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        let (index, target) = self.resolve(address);
+        if index == NO_REGION {
+            return;
+        }
+
+        let region = &mut self.regions[index as usize];
+        let offset = target - region.start;
+        match &mut region.kind {
+            RegionKind::Ram(memory) => memory[offset as usize] = value,
+            RegionKind::Rom(_) | RegionKind::Unmapped(_) => {}
+            RegionKind::Device(device) => self.devices[*device].device.write(offset, value),
+            RegionKind::Mirror { .. } => unreachable!("mirrors are resolved before access"),
+        }
+    }
+
+    fn tick(&mut self) {
+        for slot in &mut self.devices {
+            slot.device.tick();
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.devices.iter().any(|slot| slot.device.irq())
+    }
+
+    fn nmi(&self) -> bool {
+        self.devices.iter().any(|slot| slot.device.nmi())
+    }
+
+    fn interrupts_enabled(&self) -> bool {
+        self.devices.iter().any(|slot| slot.device.interrupts_enabled())
+    }
+
+    fn reset(&mut self) {
+        for slot in &mut self.devices {
+            slot.device.reset();
+        }
+    }
+
+    fn host_request(&mut self) -> Option<HostRequest> {
+        self.devices
+            .iter_mut()
+            .find_map(|slot| slot.device.host_request())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::devices::Riot6532;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    /// Device that records the last write and counts ticks
+    struct Latch {
+        value: u8,
+        ticks: Rc<Cell<u32>>,
+    }
+
+    impl Device for Latch {
+        fn read(&mut self, offset: u16) -> u8 {
+            self.value.wrapping_add(offset as u8)
+        }
+
+        fn write(&mut self, _offset: u16, value: u8) {
+            self.value = value;
+        }
+
+        fn tick(&mut self) {
+            self.ticks.set(self.ticks.get() + 1);
+        }
+    }
+
+    #[test]
+    fn test_ram_and_rom() {
+        let mut bus = MemoryMapBuilder::new()
+            .ram(0x0000, 0x8000)
+            .rom(0x8000, 0x8000, &[0x11, 0x22])
+            .build()
+            .unwrap();
+
+        bus.write(0x1234, 0xAB);
+        assert_eq!(bus.read(0x1234), 0xAB);
+
+        assert_eq!(bus.read(0x8000), 0x11);
+        bus.write(0x8000, 0x99); // ignored
+        assert_eq!(bus.read(0x8000), 0x11);
+        assert_eq!(bus.read(0x8002), 0xFF); // ROM padding
+        assert_eq!(bus.roms().collect::<Vec<_>>(), vec![(0x8000, 0xFFFF)]);
+    }
+
+    #[test]
+    fn test_nes_style_mirror() {
+        let mut bus = MemoryMapBuilder::new()
+            .ram(0x0000, 0x0800)
+            .mirror(0x0800, 0x1800, 0x0000, 0x0800)
+            .build()
+            .unwrap();
+
+        bus.write(0x0012, 0x42);
+        assert_eq!(bus.read(0x0812), 0x42);
+        assert_eq!(bus.read(0x1012), 0x42);
+        assert_eq!(bus.read(0x1812), 0x42);
+
+        bus.write(0x1FFF, 0x55);
+        assert_eq!(bus.read(0x07FF), 0x55);
+    }
+
+    #[test]
+    fn test_unmapped_and_open_bus() {
+        let mut bus = MemoryMapBuilder::new()
+            .open_bus(0x00)
+            .unmapped(0x4000, 0x1000, 0xEA)
+            .build()
+            .unwrap();
+
+        assert_eq!(bus.read(0x4123), 0xEA);
+        bus.write(0x4123, 0x00);
+        assert_eq!(bus.read(0x4123), 0xEA);
+        assert_eq!(bus.read(0x0000), 0x00);
+    }
+
+    #[test]
+    fn test_device_region() {
+        let ticks = Rc::new(Cell::new(0));
+        let mut bus = MemoryMapBuilder::new()
+            .device(
+                0x6000,
+                0x10,
+                Latch {
+                    value: 0,
+                    ticks: ticks.clone(),
+                },
+            )
+            .build()
+            .unwrap();
+
+        bus.write(0x6003, 0x40);
+        assert_eq!(bus.read(0x6000), 0x40);
+        assert_eq!(bus.read(0x6002), 0x42);
+
+        bus.tick();
+        bus.tick();
+        assert_eq!(ticks.get(), 2);
+    }
+
+    /// Device whose IRQ/NMI outputs and tick log are controlled by the test
+    struct Lines {
+        id: u8,
+        nmi: Rc<Cell<bool>>,
+        order: Rc<RefCell<Vec<u8>>>,
+        resets: u32,
+    }
+
+    impl Device for Lines {
+        fn read(&mut self, _offset: u16) -> u8 {
+            self.resets as u8
+        }
+
+        fn write(&mut self, _offset: u16, _value: u8) {}
+
+        fn tick(&mut self) {
+            self.order.borrow_mut().push(self.id);
+        }
+
+        fn reset(&mut self) {
+            self.resets += 1;
+        }
+
+        fn nmi(&self) -> bool {
+            self.nmi.get()
+        }
+    }
+
+    fn lines(id: u8, nmi: &Rc<Cell<bool>>, order: &Rc<RefCell<Vec<u8>>>) -> Lines {
+        Lines {
+            id,
+            nmi: nmi.clone(),
+            order: order.clone(),
+            resets: 0,
+        }
+    }
+
+    #[test]
+    fn test_device_registry() {
+        let nmi = Rc::new(Cell::new(false));
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let mut bus = MemoryMapBuilder::new()
+            .named_device("second", 0x7000, 1, lines(2, &nmi, &order))
+            .device(0x6000, 1, lines(1, &nmi, &order))
+            .build()
+            .unwrap();
+
+        let names: Vec<&str> = bus.devices().map(|info| info.name).collect();
+        assert_eq!(names, ["second", "device1"]);
+
+        // Ticks go out in registration order, not address order
+        bus.tick();
+        bus.tick();
+        assert_eq!(*order.borrow(), [2, 1, 2, 1]);
+
+        bus.reset();
+        assert_eq!(bus.read(0x6000), 1);
+        bus.device_mut("second").unwrap().reset();
+        assert_eq!(bus.read(0x7000), 2);
+        assert!(bus.device("third").is_none());
+
+        let result = MemoryMapBuilder::new()
+            .named_device("twin", 0x6000, 1, lines(1, &nmi, &order))
+            .named_device("twin", 0x7000, 1, lines(2, &nmi, &order))
+            .build();
+        assert_eq!(result.err(), Some(MapError::DuplicateDevice("twin".into())));
+    }
+
+    #[test]
+    fn test_shared_device_and_state() {
+        let riot = Rc::new(RefCell::new(Riot6532::new()));
+        let mut bus = MemoryMapBuilder::new()
+            .named_device("riot", 0x0000, 0x100, riot.clone())
+            .build()
+            .unwrap();
+
+        bus.write(0x0010, 0x5A);
+        assert_eq!(riot.borrow().read_ram(0x10), 0x5A);
+
+        let saved = bus.save_devices();
+        bus.write(0x0010, 0x00);
+        bus.load_devices(&saved).unwrap();
+        assert_eq!(bus.read(0x0010), 0x5A);
+
+        let bogus = vec![("riot".to_string(), vec![0; 3])];
+        assert_eq!(bus.load_devices(&bogus), Err(StateError::Truncated));
+        let unknown = vec![("tia".to_string(), Vec::new())];
+        assert!(matches!(
+            bus.load_devices(&unknown),
+            Err(StateError::UnknownDevice(_))
+        ));
+    }
+
+    #[test]
+    fn test_nmi_is_edge_triggered() {
+        let nmi = Rc::new(Cell::new(false));
+        let order = Rc::new(RefCell::new(Vec::new()));
+        let mut rom = vec![0xEA; 0x100]; // NOPs
+        rom[0xFA..].copy_from_slice(&[0x80, 0xFF, 0x00, 0xFF, 0x00, 0xFF]);
+        rom[0x80] = 0xE8; // NMI handler: INX, then NOPs
+        let bus = MemoryMapBuilder::new()
+            .ram(0x0000, 0x0200)
+            .rom(0xFF00, 0x100, &rom)
+            .device(0x6000, 1, lines(1, &nmi, &order))
+            .build()
+            .unwrap();
+
+        let mut cpu = Cpu::new(bus);
+        cpu.reset();
+        cpu.execute_instruction();
+
+        nmi.set(true);
+        for _ in 0..10 {
+            cpu.execute_instruction();
+        }
+        // Held low, the line only triggers once
+        assert_eq!(cpu.x, 1);
+
+        nmi.set(false);
+        cpu.execute_instruction();
+        nmi.set(true);
+        cpu.execute_instruction();
+        cpu.execute_instruction();
+        assert_eq!(cpu.x, 2);
+    }
+
+    #[test]
+    fn test_overlap_is_rejected() {
+        let result = MemoryMapBuilder::new()
+            .ram(0x0000, 0x4000)
+            .rom(0x3000, 0x1000, &[])
+            .build();
+
+        assert_eq!(
+            result.err(),
+            Some(MapError::Overlap {
+                first: (0x0000, 0x3FFF),
+                second: (0x3000, 0x3FFF),
+            })
+        );
+    }
+
+    #[test]
+    fn test_invalid_regions() {
+        let result = MemoryMapBuilder::new().ram(0xF000, 0x2000).build();
+        assert!(matches!(result, Err(MapError::OutOfRange { .. })));
+
+        let result = MemoryMapBuilder::new().rom(0x8000, 2, &[1, 2, 3]).build();
+        assert!(matches!(result, Err(MapError::RomTooLarge { .. })));
+
+        let result = MemoryMapBuilder::new()
+            .mirror(0x0800, 0x0800, 0x0000, 0x0800)
+            .build();
+        assert!(matches!(result, Err(MapError::InvalidMirror { .. })));
+    }
+
+    #[test]
+    fn test_load_bypasses_rom_protection() {
+        let mut bus = MemoryMapBuilder::new()
+            .rom(0xFF00, 0x100, &[])
+            .build()
+            .unwrap();
+
+        bus.load(0xFFFC, &[0x00, 0x80]);
+        assert_eq!(bus.read_word(0xFFFC), 0x8000);
+        assert_eq!(bus.peek(0xFFFC), Some(0x00));
+        assert_eq!(bus.peek(0x1000), None);
+    }
+}
@@ -0,0 +1,596 @@
+//! Cycle profiling per instruction and per subroutine
+//!
+//! A `Profiler` is fed every instruction a run executes: front ends take a
+//! `Sample` before the instruction and hand it to `record` afterwards. Each
+//! instruction address accumulates its execution count, its cycles and the
+//! penalty cycles it paid (`Cpu::penalties`): page crossings on indexed
+//! accesses and branches, and taken branches.
+//!
+//! Subroutines are followed through JSR and RTS with a shadow call stack.
+//! Every routine gets its own ("self") cycles and its inclusive cycles,
+//! which also count everything it called. Interrupts and BRK enter their
+//! handler like a call, and RTI returns from it. A return is matched by the
+//! stack pointer rather than by pairing calls and returns, so code that
+//! drops its return address (`PLA PLA RTS`) or resets the stack with TXS
+//! unwinds the right number of frames.
+//!
+//! Cycles before the first JSR belong to the entry point, the routine at the
+//! reset vector.
+//!
+//! `folded` exports the cycles of every call path in the folded-stack
+//! format read by flame graph tools (`flamegraph.pl`, inferno, speedscope):
+//!
+//! ```text
+//! reset;print;delay 1200
+//! reset;[interrupt] irq 70
+//! ```
+//!
+//! Programs that manipulate the stack directly take the shadow stack out of
+//! step with the real one. Each place that happens is counted as a `Desync`
+//! and listed in the report, so their callers' figures can be taken with a
+//! pinch of salt.
+
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+use crate::disasm::disassemble;
+use crate::symbols::SymbolTable;
+
+const BRK: u8 = 0x00;
+const JSR: u8 = 0x20;
+const RTI: u8 = 0x40;
+const RTS: u8 = 0x60;
+const TXS: u8 = 0x9A;
+
+/// Stack level of the entry point's frame, above any 8-bit stack pointer
+const ENTRY_SP: u16 = 0x100;
+
+/// Counts for one instruction address
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PcStats {
+    pub instructions: u64,
+    pub cycles: u64,
+    /// Cycles lost to page crossings
+    pub page_crossings: u64,
+    /// Cycles lost to taken branches
+    pub branches_taken: u64,
+}
+
+/// Counts for one subroutine, interrupt handler or the entry point, keyed
+/// by the address it was entered at
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RoutineStats {
+    pub calls: u64,
+    /// Instructions executed in the routine itself
+    pub instructions: u64,
+    /// Cycles spent in the routine itself
+    pub self_cycles: u64,
+    /// Cycles from entry to return, including the routines it called
+    pub total_cycles: u64,
+}
+
+/// How a frame on the shadow stack was entered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FrameKind {
+    /// The code running from reset
+    Entry,
+    Call,
+    /// An NMI or IRQ handler
+    Interrupt,
+    Brk,
+}
+
+/// A way the program's stack and the shadow call stack fell out of step
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Desync {
+    /// A return or TXS discarded calls that never returned, as `PLA PLA RTS`
+    /// does
+    Unwound(usize),
+    /// RTS or RTI with no call to return from, as in RTS jump tables
+    UnmatchedReturn,
+    /// A return went somewhere other than just after its call, e.g. past
+    /// inline data
+    Redirected { to: u16, expected: u16 },
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Desync::Unwound(1) => write!(f, "dropped a call that never returned"),
+            Desync::Unwound(n) => write!(f, "dropped {n} calls that never returned"),
+            Desync::UnmatchedReturn => write!(f, "returned with no call to return from"),
+            Desync::Redirected { to, expected } => {
+                write!(f, "returned to ${to:04X} instead of ${expected:04X}")
+            }
+        }
+    }
+}
+
+/// CPU state before an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    pub pc: u16,
+    pub sp: u8,
+    /// The opcode at the PC
+    pub opcode: u8,
+    cycles: u64,
+    interrupts: u64,
+}
+
+impl Sample {
+    /// Take a sample before the next instruction; `opcode` is the byte at
+    /// the PC, read without side effects (e.g. `MappedBus::peek`)
+    pub fn of<B: Bus>(cpu: &Cpu<B>, opcode: u8) -> Self {
+        Self {
+            pc: cpu.pc,
+            sp: cpu.sp,
+            opcode,
+            cycles: cpu.total_cycles,
+            interrupts: cpu.interrupts,
+        }
+    }
+}
+
+/// One active call on the shadow stack
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    entry: u16,
+    /// Stack pointer before the call; a return brings it back to this
+    sp: u16,
+    /// Where a return should go
+    return_to: u16,
+    /// `Profiler::cycles` when the routine was entered
+    start: u64,
+    /// Index of the call path ending here in `Profiler::paths`
+    path: usize,
+}
+
+/// A call path: its caller's path and the frame that ends it
+type Path = (Option<usize>, FrameKind, u16);
+
+/// Accumulates instruction and subroutine costs over a run
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    pcs: HashMap<u16, PcStats>,
+    routines: HashMap<u16, RoutineStats>,
+    stack: Vec<Frame>,
+    paths: Vec<Path>,
+    path_ids: HashMap<Path, usize>,
+    /// Self cycles of each path
+    path_cycles: Vec<u64>,
+    desyncs: HashMap<(u16, Desync), u64>,
+    instructions: u64,
+    cycles: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Instructions recorded
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    /// Cycles recorded, including interrupt entries
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Record what the CPU did since `before`: one instruction, or the
+    /// entry into an interrupt handler
+    pub fn record<B: Bus>(&mut self, before: Sample, cpu: &Cpu<B>) {
+        let cycles = cpu.total_cycles.saturating_sub(before.cycles);
+        if self.stack.is_empty() {
+            self.enter(FrameKind::Entry, before.pc, ENTRY_SP, before.pc);
+        }
+        self.cycles += cycles;
+
+        let sp = before.sp as u16;
+        if cpu.interrupts != before.interrupts {
+            // Nothing ran at the PC; the handler was called instead
+            self.enter(FrameKind::Interrupt, cpu.pc, sp, before.pc);
+            self.charge(cycles, 0);
+            return;
+        }
+
+        self.instructions += 1;
+        let stats = self.pcs.entry(before.pc).or_default();
+        stats.instructions += 1;
+        stats.cycles += cycles;
+        stats.page_crossings += cpu.penalties.page_crossing as u64;
+        stats.branches_taken += cpu.penalties.branch_taken as u64;
+        self.charge(cycles, 1);
+
+        if cpu.halted {
+            return;
+        }
+        match before.opcode {
+            JSR => self.enter(FrameKind::Call, cpu.pc, sp, before.pc.wrapping_add(3)),
+            BRK => self.enter(FrameKind::Brk, cpu.pc, sp, before.pc.wrapping_add(2)),
+            RTS | RTI => match self.leave(cpu.sp) {
+                None => self.desync(before.pc, Desync::UnmatchedReturn),
+                Some((frame, popped)) => {
+                    if popped > 1 {
+                        self.desync(before.pc, Desync::Unwound(popped - 1));
+                    }
+                    if cpu.pc != frame.return_to {
+                        let expected = frame.return_to;
+                        self.desync(
+                            before.pc,
+                            Desync::Redirected {
+                                to: cpu.pc,
+                                expected,
+                            },
+                        );
+                    }
+                }
+            },
+            TXS => {
+                if let Some((_, popped)) = self.leave(cpu.sp) {
+                    self.desync(before.pc, Desync::Unwound(popped));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Where the shadow stack lost track of the program's stack, with how
+    /// often each happened, by address
+    pub fn desyncs(&self) -> Vec<(u16, Desync, u64)> {
+        let mut desyncs: Vec<(u16, Desync, u64)> = self
+            .desyncs
+            .iter()
+            .map(|(&(pc, desync), &count)| (pc, desync, count))
+            .collect();
+        desyncs.sort();
+        desyncs
+    }
+
+    /// Self cycles of every call path in the folded-stack format, one
+    /// `outer;inner cycles` line each, with routines named from `symbols`
+    pub fn folded(&self, symbols: &SymbolTable) -> String {
+        let mut lines: Vec<String> = Vec::new();
+        for (id, &cycles) in self.path_cycles.iter().enumerate() {
+            if cycles == 0 {
+                continue;
+            }
+            let mut names = Vec::new();
+            let mut next = Some(id);
+            while let Some(id) = next {
+                let (parent, kind, entry) = self.paths[id];
+                let name = symbols.describe(entry, 0x100);
+                names.push(match kind {
+                    FrameKind::Entry | FrameKind::Call => name,
+                    FrameKind::Interrupt => format!("[interrupt] {name}"),
+                    FrameKind::Brk => format!("[brk] {name}"),
+                });
+                next = parent;
+            }
+            names.reverse();
+            lines.push(format!("{} {cycles}", names.join(";")));
+        }
+        lines.sort();
+        lines.iter().map(|line| format!("{line}\n")).collect()
+    }
+
+    /// Counts for every instruction address that ran, hottest first
+    pub fn hot_spots(&self) -> Vec<(u16, PcStats)> {
+        let mut spots: Vec<(u16, PcStats)> = self.pcs.iter().map(|(&pc, &s)| (pc, s)).collect();
+        spots.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+        spots
+    }
+
+    /// Counts for every routine entered, by inclusive cycles. Routines
+    /// still running are counted up to now.
+    pub fn routines(&self) -> Vec<(u16, RoutineStats)> {
+        let mut routines = self.routines.clone();
+        for (depth, frame) in self.stack.iter().enumerate() {
+            if self.stack[..depth].iter().all(|f| f.entry != frame.entry) {
+                let stats = routines.entry(frame.entry).or_default();
+                stats.total_cycles += self.cycles - frame.start;
+            }
+        }
+        let mut routines: Vec<(u16, RoutineStats)> = routines.into_iter().collect();
+        routines.sort_by(|a, b| {
+            b.1.total_cycles
+                .cmp(&a.1.total_cycles)
+                .then(b.1.self_cycles.cmp(&a.1.self_cycles))
+                .then(a.0.cmp(&b.0))
+        });
+        routines
+    }
+
+    /// A text report of the `top` hottest instructions and routines.
+    /// Addresses are labelled from `symbols` when it has any; `read`
+    /// supplies memory for the disassembly and should not have side
+    /// effects.
+    pub fn report<F: FnMut(u16) -> u8>(
+        &self,
+        symbols: &SymbolTable,
+        top: usize,
+        mut read: F,
+    ) -> String {
+        let labels = !symbols.is_empty();
+        let label = |address: u16| {
+            if labels {
+                format!("{:<16} ", symbols.describe(address, 0x100))
+            } else {
+                String::new()
+            }
+        };
+        let heading = if labels {
+            format!("{:<16} ", "Label")
+        } else {
+            String::new()
+        };
+        let percent = |cycles: u64| 100.0 * cycles as f64 / self.cycles.max(1) as f64;
+
+        let mut out = format!(
+            "{} instructions, {} cycles\n\nHot spots\n",
+            self.instructions, self.cycles
+        );
+        let _ = writeln!(
+            out,
+            "Address  {heading}{:>10} {:>12} {:>6} {:>8} {:>8}  Instruction",
+            "Count", "Cycles", "%", "Page", "Branch"
+        );
+        for (pc, stats) in self.hot_spots().into_iter().take(top) {
+            let line = disassemble(pc, &mut read, symbols);
+            let _ = writeln!(
+                out,
+                "${pc:04X}    {}{:>10} {:>12} {:>5.1}% {:>8} {:>8}  {}",
+                label(pc),
+                stats.instructions,
+                stats.cycles,
+                percent(stats.cycles),
+                stats.page_crossings,
+                stats.branches_taken,
+                line.text
+            );
+        }
+
+        out.push_str("\nSubroutines\n");
+        let _ = writeln!(
+            out,
+            "Entry    {heading}{:>10} {:>12} {:>6} {:>12} {:>6}",
+            "Calls", "Total", "%", "Self", "%"
+        );
+        for (entry, stats) in self.routines().into_iter().take(top) {
+            let _ = writeln!(
+                out,
+                "${entry:04X}    {}{:>10} {:>12} {:>5.1}% {:>12} {:>5.1}%",
+                label(entry),
+                stats.calls,
+                stats.total_cycles,
+                percent(stats.total_cycles),
+                stats.self_cycles,
+                percent(stats.self_cycles)
+            );
+        }
+
+        let desyncs = self.desyncs();
+        if !desyncs.is_empty() {
+            out.push_str("\nStack desyncs\n");
+            let _ = writeln!(
+                out,
+                "Address  {heading}{:>10}  {:<16} Problem",
+                "Count", "Instruction"
+            );
+            for (pc, desync, count) in desyncs.into_iter().take(top) {
+                let line = disassemble(pc, &mut read, symbols);
+                let _ = writeln!(
+                    out,
+                    "${pc:04X}    {}{count:>10}  {:<16} {desync}",
+                    label(pc),
+                    line.text
+                );
+            }
+        }
+        out
+    }
+
+    fn enter(&mut self, kind: FrameKind, entry: u16, sp: u16, return_to: u16) {
+        self.routines.entry(entry).or_default().calls += 1;
+        let key = (self.stack.last().map(|f| f.path), kind, entry);
+        let path = *self.path_ids.entry(key).or_insert_with(|| {
+            self.paths.push(key);
+            self.path_cycles.push(0);
+            self.paths.len() - 1
+        });
+        self.stack.push(Frame {
+            entry,
+            sp,
+            return_to,
+            start: self.cycles,
+            path,
+        });
+    }
+
+    /// Charge the current routine for its own work
+    fn charge(&mut self, cycles: u64, instructions: u64) {
+        if let Some(frame) = self.stack.last() {
+            let stats = self.routines.entry(frame.entry).or_default();
+            stats.self_cycles += cycles;
+            stats.instructions += instructions;
+            self.path_cycles[frame.path] += cycles;
+        }
+    }
+
+    /// Pop every call the stack pointer has returned past, giving the
+    /// outermost one and how many were popped
+    fn leave(&mut self, sp: u8) -> Option<(Frame, usize)> {
+        let mut popped = None;
+        while let Some(frame) = self.stack.last().copied()
+            && frame.sp <= sp as u16
+        {
+            self.stack.pop();
+            popped = Some((frame, popped.map_or(1, |(_, n)| n + 1)));
+            // A recursive call's cycles are already in the outer call's
+            if self.stack.iter().all(|f| f.entry != frame.entry) {
+                self.routines.entry(frame.entry).or_default().total_cycles +=
+                    self.cycles - frame.start;
+            }
+        }
+        popped
+    }
+
+    fn desync(&mut self, pc: u16, desync: Desync) {
+        *self.desyncs.entry((pc, desync)).or_default() += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::SimpleBus;
+
+    fn setup(program: &[u8]) -> Cpu<SimpleBus> {
+        let mut bus = SimpleBus::new();
+        bus.load(0x8000, program);
+        bus.write(0xFFFC, 0x00);
+        bus.write(0xFFFD, 0x80);
+        let mut cpu = Cpu::new(bus);
+        cpu.reset();
+        while cpu.cycles > 0 {
+            cpu.step();
+        }
+        cpu
+    }
+
+    fn run(cpu: &mut Cpu<SimpleBus>, profiler: &mut Profiler, instructions: usize) {
+        for _ in 0..instructions {
+            let opcode = cpu.bus.read(cpu.pc);
+            let before = Sample::of(cpu, opcode);
+            cpu.execute_instruction();
+            profiler.record(before, cpu);
+        }
+    }
+
+    #[test]
+    fn test_instruction_costs() {
+        // LDX #$03; loop: LDA $10FF,X; DEX; BNE loop
+        let mut cpu = setup(&[0xA2, 0x03, 0xBD, 0xFF, 0x10, 0xCA, 0xD0, 0xFA]);
+        let mut profiler = Profiler::new();
+        run(&mut cpu, &mut profiler, 10);
+
+        assert_eq!(profiler.instructions(), 10);
+        assert_eq!(profiler.cycles(), 2 + 3 * 5 + 3 * 2 + 3 + 3 + 2);
+        let spots = profiler.hot_spots();
+        assert_eq!(spots[0].0, 0x8002);
+        assert_eq!(
+            spots[0].1,
+            PcStats {
+                instructions: 3,
+                cycles: 15,
+                page_crossings: 3,
+                branches_taken: 0,
+            }
+        );
+        let branch = spots.iter().find(|(pc, _)| *pc == 0x8006).unwrap().1;
+        assert_eq!((branch.cycles, branch.branches_taken), (8, 2));
+    }
+
+    #[test]
+    fn test_subroutines() {
+        // JSR outer; NOP; outer: JSR inner; JSR inner; RTS; inner: NOP; RTS
+        let mut cpu = setup(&[
+            0x20, 0x04, 0x80, 0xEA, 0x20, 0x0B, 0x80, 0x20, 0x0B, 0x80, 0x60, 0xEA, 0x60,
+        ]);
+        let mut profiler = Profiler::new();
+        run(&mut cpu, &mut profiler, 8);
+        assert_eq!(cpu.pc, 0x8003);
+
+        let routines: HashMap<u16, RoutineStats> = profiler.routines().into_iter().collect();
+        let inner = routines[&0x800B];
+        assert_eq!((inner.calls, inner.instructions), (2, 4));
+        assert_eq!((inner.self_cycles, inner.total_cycles), (16, 16));
+        let outer = routines[&0x8004];
+        assert_eq!((outer.calls, outer.self_cycles), (1, 18));
+        assert_eq!(outer.total_cycles, 34);
+        let entry = routines[&0x8000];
+        assert_eq!((entry.self_cycles, entry.total_cycles), (6, 40));
+        assert_eq!(profiler.routines()[0].0, 0x8000);
+
+        // Returning past a dropped return address unwinds both frames
+        let mut cpu = setup(&[
+            0x20, 0x04, 0x80, 0xEA, 0x20, 0x08, 0x80, 0x60, 0x68, 0x68, 0x60,
+        ]);
+        let mut profiler = Profiler::new();
+        run(&mut cpu, &mut profiler, 5);
+        assert_eq!(cpu.pc, 0x8003);
+        assert_eq!(profiler.stack.len(), 1);
+        assert_eq!(profiler.desyncs(), vec![(0x800A, Desync::Unwound(1), 1)]);
+    }
+
+    #[test]
+    fn test_folded_stacks() {
+        // main: JSR sub; NOP; sub: NOP; RTS, with an NMI to nmi: RTI
+        let mut cpu = setup(&[0x20, 0x04, 0x80, 0xEA, 0xEA, 0x60]);
+        cpu.bus.load(0xFFFA, &[0x00, 0x90]);
+        cpu.bus.write(0x9000, 0x40);
+        let mut profiler = Profiler::new();
+        run(&mut cpu, &mut profiler, 1);
+        cpu.request_nmi();
+        run(&mut cpu, &mut profiler, 5);
+        assert_eq!(cpu.pc, 0x8004);
+
+        let symbols = SymbolTable::parse("main = $8000\nsub = $8004\nnmi = $9000\n").unwrap();
+        assert_eq!(
+            profiler.folded(&symbols),
+            "main 8\nmain;sub 8\nmain;sub;[interrupt] nmi 13\n"
+        );
+        assert!(profiler.desyncs().is_empty());
+    }
+
+    #[test]
+    fn test_desyncs() {
+        let mut program = vec![0xEA; 0x15];
+        // LDA #$80; PHA; LDA #$08; PHA; RTS: jumps to $8009
+        program[..7].copy_from_slice(&[0xA9, 0x80, 0x48, 0xA9, 0x08, 0x48, 0x60]);
+        // JSR skip; .byte 0; skip: TSX; INC $0101,X; RTS: returns past the byte
+        program[0x09..0x0D].copy_from_slice(&[0x20, 0x10, 0x80, 0x00]);
+        program[0x10..0x15].copy_from_slice(&[0xBA, 0xFE, 0x01, 0x01, 0x60]);
+        let mut cpu = setup(&program);
+        let mut profiler = Profiler::new();
+        run(&mut cpu, &mut profiler, 9);
+        assert_eq!(cpu.pc, 0x800D);
+
+        let redirected = Desync::Redirected {
+            to: 0x800D,
+            expected: 0x800C,
+        };
+        assert_eq!(
+            profiler.desyncs(),
+            vec![
+                (0x8006, Desync::UnmatchedReturn, 1),
+                (0x8014, redirected, 1)
+            ]
+        );
+        let report = profiler.report(&SymbolTable::new(), 10, |a| cpu.bus.read(a));
+        assert!(report.contains("Stack desyncs"));
+        assert!(report.contains("RTS              returned to $800D instead of $800C"));
+        assert_eq!(profiler.stack.len(), 1);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut cpu = setup(&[0x20, 0x04, 0x80, 0xEA, 0xEA, 0x60]);
+        let mut profiler = Profiler::new();
+        run(&mut cpu, &mut profiler, 3);
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("delay", 0x8004);
+        let report = profiler.report(&symbols, 2, |a| cpu.bus.read(a));
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "3 instructions, 14 cycles");
+        assert!(lines[4].starts_with("$8000    $8000 "), "{}", lines[4]);
+        assert!(lines[4].ends_with("JSR delay"), "{}", lines[4]);
+        assert!(lines[9].starts_with("$8000    $8000 "), "{}", lines[9]);
+        assert!(report.contains("$8004    delay"));
+        assert_eq!(lines.len(), 11);
+    }
+}
@@ -0,0 +1,441 @@
+//! Symbol tables for address <-> name lookups
+//!
+//! Labels can be loaded from the formats produced by common 6502 toolchains:
+//! - VICE label files (`al C:8000 .reset`), which is also what `ld65 -Ln` writes
+//! - ld65 map files (`ld65 -m`), using the "Exports list" sections
+//! - Plain assignment lists (`reset = $8000`, `reset := $8000`)
+//!
+//! The same table is used to resolve symbolic addresses typed by the user,
+//! e.g. `--break loop` or `loop+2`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Supported symbol file formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolFormat {
+    /// VICE monitor labels, one `al <addr> .<name>` per line
+    Vice,
+    /// ld65 map file; only the exports lists are read
+    Ld65Map,
+    /// `NAME = $ADDR` lines
+    Assignments,
+}
+
+impl SymbolFormat {
+    /// Guess the format of a symbol file from its contents
+    pub fn detect(text: &str) -> Self {
+        if text
+            .lines()
+            .any(|line| line.trim_start().starts_with("Exports list"))
+        {
+            return SymbolFormat::Ld65Map;
+        }
+
+        let first = text
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !is_comment(line));
+
+        match first {
+            Some(line) if line.starts_with("al ") => SymbolFormat::Vice,
+            _ => SymbolFormat::Assignments,
+        }
+    }
+}
+
+/// Errors produced while loading a symbol file
+#[derive(Debug)]
+pub enum SymbolError {
+    Io(io::Error),
+    /// A line that could not be understood (1-based line number)
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymbolError::Io(e) => write!(f, "{e}"),
+            SymbolError::Parse { line, message } => write!(f, "line {line}: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for SymbolError {}
+
+impl From<io::Error> for SymbolError {
+    fn from(e: io::Error) -> Self {
+        SymbolError::Io(e)
+    }
+}
+
+/// Bidirectional mapping between label names and addresses.
+///
+/// Several names may share an address (e.g. `nmi` and `irq` pointing at the
+/// same `RTI`); the first one inserted is the preferred name for display.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    by_name: HashMap<String, u16>,
+    by_address: BTreeMap<u16, Vec<String>>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load a symbol file, detecting its format from the contents
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SymbolError> {
+        let text = fs::read_to_string(path)?;
+        Self::parse(&text)
+    }
+
+    /// Parse symbols from text, detecting the format from the contents
+    pub fn parse(text: &str) -> Result<Self, SymbolError> {
+        Self::parse_format(text, SymbolFormat::detect(text))
+    }
+
+    /// Parse symbols from text in a specific format
+    pub fn parse_format(text: &str, format: SymbolFormat) -> Result<Self, SymbolError> {
+        let mut table = Self::new();
+        match format {
+            SymbolFormat::Vice => table.parse_vice(text)?,
+            SymbolFormat::Ld65Map => table.parse_ld65_map(text)?,
+            SymbolFormat::Assignments => table.parse_assignments(text)?,
+        }
+        Ok(table)
+    }
+
+    /// Add a symbol. Re-defining a name moves it to the new address.
+    pub fn insert(&mut self, name: &str, address: u16) {
+        if let Some(old) = self.by_name.insert(name.to_string(), address)
+            && let Some(names) = self.by_address.get_mut(&old)
+        {
+            names.retain(|n| n != name);
+            if names.is_empty() {
+                self.by_address.remove(&old);
+            }
+        }
+        self.by_address
+            .entry(address)
+            .or_default()
+            .push(name.to_string());
+    }
+
+    /// Add all symbols from another table
+    pub fn merge(&mut self, other: &SymbolTable) {
+        for (address, names) in &other.by_address {
+            for name in names {
+                self.insert(name, *address);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Look up the address of a label
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied()
+    }
+
+    /// Preferred label at exactly this address
+    pub fn name_of(&self, address: u16) -> Option<&str> {
+        self.by_address
+            .get(&address)
+            .and_then(|names| names.first())
+            .map(String::as_str)
+    }
+
+    /// All labels at exactly this address
+    pub fn names_at(&self, address: u16) -> &[String] {
+        self.by_address
+            .get(&address)
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+
+    /// Closest label at or below `address`, with the offset from it.
+    /// Used to print addresses as `loop+2`.
+    pub fn nearest(&self, address: u16) -> Option<(&str, u16)> {
+        self.by_address
+            .range(..=address)
+            .next_back()
+            .and_then(|(base, names)| names.first().map(|n| (n.as_str(), address - base)))
+    }
+
+    /// Format an address as `name`, `name+off` (within `max_offset`) or `$XXXX`
+    pub fn describe(&self, address: u16, max_offset: u16) -> String {
+        match self.nearest(address) {
+            Some((name, 0)) => name.to_string(),
+            Some((name, offset)) if offset <= max_offset => format!("{name}+{offset}"),
+            _ => format!("${address:04X}"),
+        }
+    }
+
+    /// Iterate over all symbols in address order
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.by_address
+            .iter()
+            .flat_map(|(addr, names)| names.iter().map(move |n| (*addr, n.as_str())))
+    }
+
+    /// Resolve an address typed by the user.
+    ///
+    /// Accepts `$C000`, `0xC000`, `%1010`, plain decimal, label names
+    /// and `label+offset` / `label-offset`.
+    pub fn resolve(&self, text: &str) -> Option<u16> {
+        let text = text.trim();
+        if let Some(value) = parse_number(text) {
+            return Some(value);
+        }
+        if let Some(address) = self.address_of(text) {
+            return Some(address);
+        }
+
+        let split = text.rfind(['+', '-'])?;
+        let (name, rest) = text.split_at(split);
+        let base = self.address_of(name.trim())?;
+        let offset = parse_number(rest[1..].trim())?;
+        if rest.starts_with('+') {
+            Some(base.wrapping_add(offset))
+        } else {
+            Some(base.wrapping_sub(offset))
+        }
+    }
+
+    fn parse_vice(&mut self, text: &str) -> Result<(), SymbolError> {
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || is_comment(line) {
+                continue;
+            }
+
+            let mut parts = line.split_whitespace();
+            let (Some("al"), Some(addr), Some(name)) = (parts.next(), parts.next(), parts.next())
+            else {
+                return Err(parse_error(index, "expected `al <address> .<label>`"));
+            };
+
+            // VICE prefixes addresses with a memory space, e.g. `C:8000`
+            let addr = addr.rsplit(':').next().unwrap_or(addr);
+            let address = parse_hex(addr)
+                .ok_or_else(|| parse_error(index, &format!("invalid address `{addr}`")))?;
+            self.insert(name.trim_start_matches('.'), address);
+        }
+        Ok(())
+    }
+
+    fn parse_ld65_map(&mut self, text: &str) -> Result<(), SymbolError> {
+        let mut in_exports = false;
+
+        for (index, line) in text.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.starts_with("Exports list") {
+                in_exports = true;
+                continue;
+            }
+            if !in_exports || trimmed.is_empty() || trimmed.starts_with('-') {
+                continue;
+            }
+            // Any other section header ends the exports list
+            if trimmed.ends_with(':') {
+                in_exports = false;
+                continue;
+            }
+
+            // Each line holds up to two `name value flags` triples
+            let fields: Vec<&str> = trimmed.split_whitespace().collect();
+            for entry in fields.chunks(3) {
+                let [name, value, _flags] = entry else {
+                    return Err(parse_error(index, "malformed exports entry"));
+                };
+                let address = parse_hex(value)
+                    .ok_or_else(|| parse_error(index, &format!("invalid address `{value}`")))?;
+                self.insert(name, address);
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_assignments(&mut self, text: &str) -> Result<(), SymbolError> {
+        for (index, line) in text.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let Some((name, value)) = line.split_once('=') else {
+                return Err(parse_error(index, "expected `NAME = $ADDR`"));
+            };
+            let name = name.trim().trim_end_matches(':').trim();
+            let value = value.trim();
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(parse_error(index, &format!("invalid label `{name}`")));
+            }
+            let address = parse_number(value)
+                .ok_or_else(|| parse_error(index, &format!("invalid address `{value}`")))?;
+            self.insert(name, address);
+        }
+        Ok(())
+    }
+}
+
+/// Parse a numeric address: `$FF`, `0xFF`, `%11111111` or decimal
+pub fn parse_number(text: &str) -> Option<u16> {
+    let text = text.trim();
+    if let Some(hex) = text.strip_prefix('$') {
+        parse_hex(hex)
+    } else if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        parse_hex(hex)
+    } else if let Some(bin) = text.strip_prefix('%') {
+        u16::from_str_radix(bin, 2).ok()
+    } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+        text.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Parse bare hex digits. ld65 writes 24-bit values (`008000`), so leading
+/// zeros beyond four digits are accepted as long as the value fits.
+fn parse_hex(text: &str) -> Option<u16> {
+    if text.is_empty() {
+        return None;
+    }
+    u32::from_str_radix(text, 16)
+        .ok()
+        .and_then(|v| u16::try_from(v).ok())
+}
+
+fn is_comment(line: &str) -> bool {
+    line.starts_with(';') || line.starts_with('#')
+}
+
+fn strip_comment(line: &str) -> &str {
+    match line.find([';', '#']) {
+        Some(pos) => &line[..pos],
+        None => line,
+    }
+}
+
+fn parse_error(index: usize, message: &str) -> SymbolError {
+    SymbolError::Parse {
+        line: index + 1,
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_vice_labels() {
+        let text = "al C:8000 .reset\nal C:8002 .loop\nal 00FFFA .vectors\n";
+        assert_eq!(SymbolFormat::detect(text), SymbolFormat::Vice);
+
+        let table = SymbolTable::parse(text).unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.address_of("reset"), Some(0x8000));
+        assert_eq!(table.address_of("loop"), Some(0x8002));
+        assert_eq!(table.name_of(0xFFFA), Some("vectors"));
+    }
+
+    #[test]
+    fn test_parse_ld65_map() {
+        let text = "\
+Modules list:
+-------------
+count.o:
+    CODE              Offs=000000  Size=00000B  Align=00001  Fill=0000
+
+Exports list by name:
+---------------------
+irq                       00800A RLA    nmi                       00800A RLA
+reset                     008000 RLA
+
+Exports list by value:
+----------------------
+reset                     008000 RLA    irq                       00800A RLA
+
+Imports list:
+-------------
+";
+        assert_eq!(SymbolFormat::detect(text), SymbolFormat::Ld65Map);
+
+        let table = SymbolTable::parse(text).unwrap();
+        assert_eq!(table.len(), 3);
+        assert_eq!(table.address_of("reset"), Some(0x8000));
+        assert_eq!(table.names_at(0x800A).len(), 2);
+    }
+
+    #[test]
+    fn test_parse_assignments() {
+        let text = "; zero page\nptr = $20\nscreen := 0x0400 ; comment\nCOUNT = 10\n";
+        let table = SymbolTable::parse(text).unwrap();
+
+        assert_eq!(table.address_of("ptr"), Some(0x0020));
+        assert_eq!(table.address_of("screen"), Some(0x0400));
+        assert_eq!(table.address_of("COUNT"), Some(10));
+    }
+
+    #[test]
+    fn test_parse_error_reports_line() {
+        let err = SymbolTable::parse("ptr = $20\nbogus line\n").unwrap_err();
+        match err {
+            SymbolError::Parse { line, .. } => assert_eq!(line, 2),
+            other => panic!("unexpected error: {other}"),
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut table = SymbolTable::new();
+        table.insert("loop", 0x8002);
+
+        assert_eq!(table.resolve("loop"), Some(0x8002));
+        assert_eq!(table.resolve("loop+3"), Some(0x8005));
+        assert_eq!(table.resolve("loop-$02"), Some(0x8000));
+        assert_eq!(table.resolve("$C000"), Some(0xC000));
+        assert_eq!(table.resolve("0xc000"), Some(0xC000));
+        assert_eq!(table.resolve("49152"), Some(0xC000));
+        assert_eq!(table.resolve("%1010"), Some(10));
+        assert_eq!(table.resolve("missing"), None);
+    }
+
+    #[test]
+    fn test_nearest_and_describe() {
+        let mut table = SymbolTable::new();
+        table.insert("reset", 0x8000);
+        table.insert("loop", 0x8002);
+
+        assert_eq!(table.nearest(0x8004), Some(("loop", 2)));
+        assert_eq!(table.nearest(0x7FFF), None);
+        assert_eq!(table.describe(0x8002, 8), "loop");
+        assert_eq!(table.describe(0x8004, 8), "loop+2");
+        assert_eq!(table.describe(0x9000, 8), "$9000");
+    }
+
+    #[test]
+    fn test_redefine_moves_symbol() {
+        let mut table = SymbolTable::new();
+        table.insert("start", 0x1000);
+        table.insert("start", 0x2000);
+
+        assert_eq!(table.address_of("start"), Some(0x2000));
+        assert_eq!(table.name_of(0x1000), None);
+        assert_eq!(table.len(), 1);
+    }
+}
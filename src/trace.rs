@@ -0,0 +1,401 @@
+//! Instruction traces in the nestest / Nintendulator log format
+//!
+//! One line per instruction, captured before it executes:
+//!
+//! ```text
+//! C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:7
+//! ```
+//!
+//! The disassembly shows the effective address and the value there, e.g.
+//! `LDA ($80),Y = 0200 @ 0205 = 5A`, so traces can be diffed line by line
+//! against reference logs from other emulators. This is a CPU-only
+//! emulator, so the `PPU:` column is left out; `P` always shows the unused
+//! bit set and B clear, as the reference logs do.
+//!
+//! Front ends capture a `TraceEntry` before each instruction and hand it to
+//! a `TraceSink`. Memory is read through a closure, as in `disasm`, so the
+//! trace doesn't disturb devices. `LogLine` reads the register columns back
+//! from our logs and other emulators' for comparison.
+
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::addressing::AddressingMode;
+use crate::bus::Bus;
+use crate::cpu::Cpu;
+use crate::instructions::{OPCODES, is_jam};
+use crate::status::Flag;
+
+/// CPU state at the start of one instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    /// Opcode and operand bytes
+    pub bytes: Vec<u8>,
+    /// Assembler text with the effective address and value, e.g. `STA $0200 = 00`
+    pub text: String,
+    /// Not an official opcode; marked with `*` in the log
+    pub illegal: bool,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    /// Cycles since reset, as in `Cpu::total_cycles`
+    pub cycle: u64,
+}
+
+impl TraceEntry {
+    /// Capture the instruction at the PC. `read` should not have side
+    /// effects (e.g. `MappedBus::peek`).
+    pub fn capture<B: Bus, F: FnMut(u16) -> u8>(cpu: &Cpu<B>, mut read: F) -> Self {
+        let pc = cpu.pc;
+        let code = read(pc);
+        let opcode = &OPCODES[code as usize];
+        let illegal = opcode.mnemonic == "???";
+        let length = if illegal { 1 } else { opcode.bytes as u16 };
+        let bytes: Vec<u8> = (0..length).map(|i| read(pc.wrapping_add(i))).collect();
+
+        let text = if illegal {
+            let name = if is_jam(code) { "JAM" } else { ".byte" };
+            format!("{name} ${code:02X}")
+        } else {
+            let operand = operand(cpu, opcode.mnemonic, opcode.mode, &bytes, &mut read);
+            if operand.is_empty() {
+                opcode.mnemonic.to_string()
+            } else {
+                format!("{} {operand}", opcode.mnemonic)
+            }
+        };
+
+        Self {
+            pc,
+            bytes,
+            text,
+            illegal,
+            a: cpu.a,
+            x: cpu.x,
+            y: cpu.y,
+            p: (cpu.status.to_byte() | 1 << Flag::Unused as u8) & !(1 << Flag::Break as u8),
+            sp: cpu.sp,
+            cycle: cpu.total_cycles,
+        }
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{b:02X}")).collect();
+        let mark = if self.illegal { '*' } else { ' ' };
+        write!(
+            f,
+            "{:04X}  {:<8} {mark}{:<32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+            self.pc,
+            bytes.join(" "),
+            self.text,
+            self.a,
+            self.x,
+            self.y,
+            self.p,
+            self.sp,
+            self.cycle
+        )
+    }
+}
+
+/// Operand text in nestest style
+fn operand<B: Bus, F: FnMut(u16) -> u8>(
+    cpu: &Cpu<B>,
+    mnemonic: &str,
+    mode: AddressingMode,
+    bytes: &[u8],
+    read: &mut F,
+) -> String {
+    let byte = bytes.get(1).copied().unwrap_or(0);
+    let word = u16::from_le_bytes([byte, bytes.get(2).copied().unwrap_or(0)]);
+    let zero_page_word = |read: &mut F, pointer: u8| {
+        u16::from_le_bytes([read(pointer as u16), read(pointer.wrapping_add(1) as u16)])
+    };
+
+    match mode {
+        AddressingMode::Implied => String::new(),
+        AddressingMode::Accumulator => String::from("A"),
+        AddressingMode::Immediate => format!("#${byte:02X}"),
+        AddressingMode::ZeroPage => format!("${byte:02X} = {:02X}", read(byte as u16)),
+        AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+            let (index, name) = if mode == AddressingMode::ZeroPageX {
+                (cpu.x, 'X')
+            } else {
+                (cpu.y, 'Y')
+            };
+            let address = byte.wrapping_add(index);
+            format!(
+                "${byte:02X},{name} @ {address:02X} = {:02X}",
+                read(address as u16)
+            )
+        }
+        AddressingMode::Absolute if matches!(mnemonic, "JMP" | "JSR") => format!("${word:04X}"),
+        AddressingMode::Absolute => format!("${word:04X} = {:02X}", read(word)),
+        AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
+            let (index, name) = if mode == AddressingMode::AbsoluteX {
+                (cpu.x, 'X')
+            } else {
+                (cpu.y, 'Y')
+            };
+            let address = word.wrapping_add(index as u16);
+            format!("${word:04X},{name} @ {address:04X} = {:02X}", read(address))
+        }
+        AddressingMode::IndirectX => {
+            let pointer = byte.wrapping_add(cpu.x);
+            let address = zero_page_word(read, pointer);
+            format!(
+                "(${byte:02X},X) @ {pointer:02X} = {address:04X} = {:02X}",
+                read(address)
+            )
+        }
+        AddressingMode::IndirectY => {
+            let base = zero_page_word(read, byte);
+            let address = base.wrapping_add(cpu.y as u16);
+            format!(
+                "(${byte:02X}),Y = {base:04X} @ {address:04X} = {:02X}",
+                read(address)
+            )
+        }
+        AddressingMode::Indirect => {
+            // The high byte comes from the same page, as on the real chip
+            let high = (word & 0xFF00) | (word.wrapping_add(1) & 0x00FF);
+            let target = u16::from_le_bytes([read(word), read(high)]);
+            format!("(${word:04X}) = {target:04X}")
+        }
+        AddressingMode::Relative => {
+            let target = cpu.pc.wrapping_add(2).wrapping_add(byte as i8 as u16);
+            format!("${target:04X}")
+        }
+    }
+}
+
+/// Receives a `TraceEntry` for each instruction
+pub trait TraceSink {
+    fn record(&mut self, entry: &TraceEntry) -> io::Result<()>;
+
+    /// Push out anything buffered
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps entries in memory
+impl TraceSink for Vec<TraceEntry> {
+    fn record(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        self.push(entry.clone());
+        Ok(())
+    }
+}
+
+/// Writes entries as log lines
+pub struct NestestLog<W: Write> {
+    out: W,
+}
+
+impl<W: Write> NestestLog<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> TraceSink for NestestLog<W> {
+    fn record(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        writeln!(self.out, "{entry}")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// The registers from one line of a trace log, ours or another emulator's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogLine {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    /// `None` for logs whose `CYC:` column counts PPU dots (older nestest
+    /// logs, which end with `SL:`)
+    pub cycle: Option<u64>,
+}
+
+impl LogLine {
+    /// Read the PC and the `A:` `X:` `Y:` `P:` `SP:` `CYC:` columns; other
+    /// columns are ignored. Returns `None` for lines that aren't
+    /// instructions.
+    pub fn parse(line: &str) -> Option<Self> {
+        let pc = u16::from_str_radix(line.get(..4)?, 16).ok()?;
+        if !line[4..].starts_with(' ') {
+            return None;
+        }
+
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        // Register columns come last, so search from the end to stay clear
+        // of the disassembly
+        let field = |key: &str| {
+            let i = tokens.iter().rposition(|token| token.starts_with(key))?;
+            match &tokens[i][key.len()..] {
+                "" => tokens.get(i + 1).copied(),
+                value => Some(value),
+            }
+        };
+        let register = |key: &str| u8::from_str_radix(field(key)?, 16).ok();
+
+        let cycle = if tokens.iter().any(|token| token.starts_with("SL:")) {
+            None
+        } else {
+            field("CYC:").and_then(|value| value.parse().ok())
+        };
+        Some(Self {
+            pc,
+            a: register("A:")?,
+            x: register("X:")?,
+            y: register("Y:")?,
+            p: register("P:")?,
+            sp: register("SP:")?,
+            cycle,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::SimpleBus;
+
+    fn setup_cpu(program: &[u8]) -> Cpu<SimpleBus> {
+        let mut bus = SimpleBus::new();
+        bus.load(0xC000, program);
+        bus.write(0xFFFC, 0x00);
+        bus.write(0xFFFD, 0xC0);
+        let mut cpu = Cpu::new(bus);
+        cpu.reset();
+        while cpu.cycles > 0 {
+            cpu.step();
+        }
+        cpu
+    }
+
+    /// Trace `count` instructions
+    fn trace(cpu: &mut Cpu<SimpleBus>, count: usize) -> Vec<String> {
+        let mut log = NestestLog::new(Vec::new());
+        for _ in 0..count {
+            let memory = cpu.bus.get_memory(0, 0x10000);
+            let entry = TraceEntry::capture(cpu, |a| memory[a as usize]);
+            log.record(&entry).unwrap();
+            cpu.execute_instruction();
+        }
+        let text = String::from_utf8(log.into_inner()).unwrap();
+        text.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn test_nestest_format() {
+        let mut cpu = setup_cpu(&[
+            0x4C, 0x05, 0xC0, // jmp $C005
+            0xEA, 0xEA, //
+            0xA2, 0x00, // ldx #$00
+            0x86, 0x00, // stx $00
+            0x0A, // asl a
+            0x02, // jam
+        ]);
+        assert_eq!(
+            trace(&mut cpu, 5),
+            [
+                "C000  4C 05 C0  JMP $C005                       A:00 X:00 Y:00 P:24 SP:FD CYC:7",
+                "C005  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD CYC:10",
+                "C007  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD CYC:12",
+                "C009  0A        ASL A                           A:00 X:00 Y:00 P:26 SP:FD CYC:15",
+                "C00A  02       *JAM $02                         A:00 X:00 Y:00 P:26 SP:FD CYC:17",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_effective_addresses() {
+        let mut cpu = setup_cpu(&[
+            0xA2, 0x02, // ldx #$02
+            0xA0, 0x05, // ldy #$05
+            0xB5, 0xFF, // lda $FF,x
+            0xBD, 0xFF, 0x02, // lda $02FF,x
+            0xA1, 0x7E, // lda ($7E,x)
+            0xB1, 0x80, // lda ($80),y
+            0x6C, 0xFF, 0x02, // jmp ($02FF)
+        ]);
+        cpu.bus.load(0x0080, &[0x00, 0x02]);
+        cpu.bus.load(0x0200, &[0xC0, 0x11, 0x00, 0x00, 0x00, 0x5A]);
+        cpu.bus.load(0x02FF, &[0x00, 0x33]);
+        cpu.bus.write(0x0301, 0x44);
+
+        let texts: Vec<String> = trace(&mut cpu, 7)
+            .iter()
+            .map(|line| line[16..48].trim_end().to_string())
+            .collect();
+        assert_eq!(
+            texts,
+            [
+                "LDX #$02",
+                "LDY #$05",
+                "LDA $FF,X @ 01 = 00",
+                "LDA $02FF,X @ 0301 = 44",
+                "LDA ($7E,X) @ 80 = 0200 = C0",
+                "LDA ($80),Y = 0200 @ 0205 = 5A",
+                "JMP ($02FF) = C000",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vec_sink() {
+        let mut cpu = setup_cpu(&[0xA9, 0x80]);
+        let mut entries = Vec::new();
+        let memory = cpu.bus.get_memory(0, 0x10000);
+        entries
+            .record(&TraceEntry::capture(&cpu, |a| memory[a as usize]))
+            .unwrap();
+        cpu.execute_instruction();
+        assert_eq!(entries[0].bytes, [0xA9, 0x80]);
+        assert_eq!((entries[0].pc, entries[0].cycle), (0xC000, 7));
+        assert_eq!((cpu.a, cpu.total_cycles), (0x80, 9));
+    }
+
+    #[test]
+    fn test_parse_log_lines() {
+        let ours =
+            "C000  4C 05 C0  JMP $C005                       A:00 X:01 Y:02 P:24 SP:FD CYC:7";
+        let line = LogLine::parse(ours).unwrap();
+        assert_eq!(
+            line,
+            LogLine {
+                pc: 0xC000,
+                a: 0x00,
+                x: 0x01,
+                y: 0x02,
+                p: 0x24,
+                sp: 0xFD,
+                cycle: Some(7),
+            }
+        );
+
+        let nintendulator = "C72D  EA        NOP                             A:00 X:00 Y:00 P:26 SP:FB PPU:  0, 30 CYC:10";
+        assert_eq!(LogLine::parse(nintendulator).unwrap().cycle, Some(10));
+        let old_nestest = "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD CYC:  0 SL:241";
+        let line = LogLine::parse(old_nestest).unwrap();
+        assert_eq!((line.pc, line.sp, line.cycle), (0xC000, 0xFD, None));
+
+        assert_eq!(LogLine::parse(""), None);
+        assert_eq!(LogLine::parse("Trace started"), None);
+    }
+}